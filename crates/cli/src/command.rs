@@ -4,6 +4,7 @@ use chumsky::prelude::*;
 use chumsky::text::*;
 pub use chumsky::Parser;
 use hlbc::Str;
+use hlbc_decompiler::Structuring;
 
 pub type IndexRange = Range<usize>;
 
@@ -16,6 +17,11 @@ pub enum FileOrIndex {
 #[derive(Debug, Clone)]
 pub enum ElementRef {
     String(usize),
+    Int(usize),
+    Float(usize),
+    Type(usize),
+    /// Type and field index
+    Field(usize, usize),
     Global(usize),
     Fn(usize),
 }
@@ -35,6 +41,8 @@ pub enum Command {
     Info,
     /// Show the function to be executed on startup (not the main)
     Entrypoint,
+    /// Check the bytecode for invalid references and ill-typed instructions
+    Verify,
     Int(IndexRange),
     Float(IndexRange),
     String(IndexRange),
@@ -52,10 +60,57 @@ pub enum Command {
     InFile(FileOrIndex),
     FileOf(usize),
     SaveTo(Str),
+    /// Print the assembly listing of a function
+    Disasm(usize),
+    /// Assemble a function listing from a file and add it to the bytecode
+    Asm(Str),
+    /// Compare with another bytecode file
+    Diff(Str),
+    /// Dump the whole bytecode to a file
+    Export(ExportFormat, Str),
+    /// Remove the unused elements, also removing the debug information if true
+    Strip(bool),
+    /// Merge another bytecode file, replacing the methods matching the names.
+    /// The module entrypoint is called from the base entrypoint if true,
+    /// and the result is written to the optional output file.
+    Link(Vec<String>, bool, Option<Str>, Str),
     Callgraph(usize, usize),
+    /// Find the functions calling a function
+    Callers(usize),
+    /// Print the parents, children and virtual table of a class
+    Hierarchy(usize),
+    /// Find the elements unreachable from the entrypoint and the unreachable instructions
+    DeadCode,
+    /// Print statistics about the whole bytecode or a single section
+    Stats(StatsFormat, Option<StatsSection>),
     RefTo(ElementRef),
-    DecompType(usize),
-    Decomp(usize),
+    /// Decompile a class, with the given control flow structuring
+    DecompType(usize, Structuring),
+    /// Decompile a function, with the given control flow structuring
+    Decomp(usize, Structuring),
+    /// Decompile every class and enum to a Haxe source tree in a directory
+    DecompAll(Str, Structuring),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ExportFormat {
+    Json,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum StatsFormat {
+    Text,
+    Json,
+    Csv,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum StatsSection {
+    Opcodes,
+    Functions,
+    Files,
+    Natives,
+    Types,
 }
 
 // Used a default max values for index ranges
@@ -79,13 +134,26 @@ pub fn parse_command(ctx: &ParseContext, line: &str) -> Result<Command, Vec<Simp
 
 /// Parse a list of command separated by ';'
 pub fn parse_commands(ctx: &ParseContext, line: &str) -> Result<Vec<Command>, Vec<Simple<char>>> {
-    commands_parser(ctx).parse(line)
+    command_parser(ctx)
+        .padded()
+        .separated_by(just(';'))
+        .parse(line)
 }
 
-pub fn commands_parser(
+/// Parse the first command of a list separated by ';', returning it with the rest of the line.
+/// Index ranges depend on the bytecode, which can be changed by the previous command.
+pub fn parse_first_command<'a>(
     ctx: &ParseContext,
-) -> impl Parser<char, Vec<Command>, Error = Simple<char>> {
-    command_parser(ctx).padded().separated_by(just(';'))
+    line: &'a str,
+) -> Result<(Command, &'a str), Vec<Simple<char>>> {
+    let (cmd, end) = command_parser(ctx)
+        .padded()
+        .then_ignore(just(';').or_not())
+        .map_with_span(|cmd, span: Range<usize>| (cmd, span.end))
+        .parse(line)?;
+    // Spans are in chars
+    let end = line.char_indices().nth(end).map_or(line.len(), |(i, _)| i);
+    Ok((cmd, &line[end..]))
 }
 
 pub fn command_parser(ctx: &ParseContext) -> impl Parser<char, Command, Error = Simple<char>> {
@@ -112,7 +180,7 @@ pub fn command_parser(ctx: &ParseContext) -> impl Parser<char, Command, Error =
 
     let string = string();
 
-    // We split the parsers in 2 to not overflow the tuple maximum size
+    // We split the parsers to not overflow the tuple maximum size
 
     let core_cmds = choice((
         cmd!("exit" => Exit),
@@ -121,10 +189,125 @@ pub fn command_parser(ctx: &ParseContext) -> impl Parser<char, Command, Error =
         cmd!("wiki" => Wiki),
     ));
 
+    let edit_cmds = choice((
+        cmd!("saveto"; string.clone() => SaveTo),
+        cmd!("disasm"; num() => Disasm),
+        cmd!("asm"; string.clone() => Asm),
+        cmd!("diff"; string.clone() => Diff),
+        cmd!("export")
+            .ignore_then(
+                just("--format")
+                    .padded()
+                    .ignore_then(just("json").to(ExportFormat::Json))
+                    .or_not(),
+            )
+            .then(string.clone().padded())
+            .map(|(format, file)| Export(format.unwrap_or(ExportFormat::Json), file)),
+        cmd!("strip")
+            .ignore_then(just("--debug").padded().or_not())
+            .map(|debug| Strip(debug.is_some())),
+        cmd!("link")
+            .ignore_then(
+                just("--replace")
+                    .padded()
+                    .ignore_then(
+                        filter(|c: &char| !c.is_whitespace() && *c != ',' && *c != ';')
+                            .repeated()
+                            .at_least(1)
+                            .map(|v| v.into_iter().collect())
+                            .separated_by(just(','))
+                            .at_least(1),
+                    )
+                    .or_not(),
+            )
+            .then(just("--no-call").padded().or_not())
+            .then(
+                just("-o")
+                    .padded()
+                    .ignore_then(
+                        filter(|c: &char| !c.is_whitespace() && *c != ';')
+                            .repeated()
+                            .at_least(1)
+                            .collect::<Str>(),
+                    )
+                    .or_not(),
+            )
+            .then(string.clone().padded())
+            .map(|(((replace, no_call), output), file)| {
+                Link(replace.unwrap_or_default(), no_call.is_none(), output, file)
+            }),
+        cmd!("decompall")
+            .ignore_then(structuring())
+            .then(string.clone().padded())
+            .map(|(s, dir)| DecompAll(dir, s)),
+    ));
+
+    let analysis_cmds = choice((
+        cmd!("callgraph")
+            .ignore_then(num())
+            .then(num().padded())
+            .map(|(f, d)| Callgraph(f, d)),
+        cmd!("callers"; num() => Callers),
+        cmd!("hierarchy"; num() => Hierarchy),
+        cmd!("deadcode" => DeadCode),
+        cmd!("stats")
+            .ignore_then(
+                just("--format")
+                    .padded()
+                    .ignore_then(choice((
+                        just("text").to(StatsFormat::Text),
+                        just("json").to(StatsFormat::Json),
+                        just("csv").to(StatsFormat::Csv),
+                    )))
+                    .or_not(),
+            )
+            .then(
+                choice((
+                    just("opcodes").to(StatsSection::Opcodes),
+                    just("functions").to(StatsSection::Functions),
+                    just("files").to(StatsSection::Files),
+                    just("natives").to(StatsSection::Natives),
+                    just("types").to(StatsSection::Types),
+                ))
+                .padded()
+                .or_not(),
+            )
+            .map(|(format, section)| Stats(format.unwrap_or(StatsFormat::Text), section)),
+        cmd!("refto")
+            .ignore_then(choice((
+                just("string@")
+                    .ignore_then(index(ctx.string_max))
+                    .map(ElementRef::String),
+                just("int@")
+                    .ignore_then(index(ctx.int_max))
+                    .map(ElementRef::Int),
+                just("float@")
+                    .ignore_then(index(ctx.float_max))
+                    .map(ElementRef::Float),
+                just("type@")
+                    .ignore_then(index(ctx.type_max))
+                    .map(ElementRef::Type),
+                just("field@")
+                    .ignore_then(index(ctx.type_max))
+                    .then_ignore(just('.'))
+                    .then(num())
+                    .map(|(t, f)| ElementRef::Field(t, f)),
+                just("global@")
+                    .ignore_then(index(ctx.global_max))
+                    .map(ElementRef::Global),
+                just("fn@")
+                    .ignore_then(index(ctx.findex_max))
+                    .map(ElementRef::Fn),
+            )))
+            .map(RefTo),
+    ));
+
     choice((
         core_cmds,
+        edit_cmds,
         cmd!("info" => Info),
         cmd!("entrypoint" => Entrypoint),
+        cmd!("verify" => Verify),
         cmd!("int", "i"; index_range(ctx.int_max) => Int),
         cmd!("float", "f"; index_range(ctx.float_max) => Float),
         cmd!("string", "s"; index_range(ctx.string_max) => String),
@@ -146,23 +329,29 @@ pub fn command_parser(ctx: &ParseContext) -> impl Parser<char, Command, Error =
                 .map(|v| InFile(FileOrIndex::File(v.into_iter().collect()))),
         ))),
         cmd!("fileof"; num() => FileOf),
-        cmd!("saveto"; string => SaveTo),
-        cmd!("callgraph")
-            .ignore_then(num())
-            .then(num().padded())
-            .map(|(f, d)| Callgraph(f, d)),
-        cmd!("refto")
-            .ignore_then(choice((
-                just("string@").ignore_then(num()).map(ElementRef::String),
-                just("global@").ignore_then(num()).map(ElementRef::Global),
-                just("fn@").ignore_then(num()).map(ElementRef::Fn),
-            )))
-            .map(RefTo),
-        cmd!("decomp"; num() => Decomp),
-        cmd!("decompt"; num() => DecompType),
+        analysis_cmds,
+        cmd!("decomp")
+            .ignore_then(structuring())
+            .then(num())
+            .map(|(s, idx)| Decomp(idx, s)),
+        cmd!("decompt")
+            .ignore_then(structuring())
+            .then(num())
+            .map(|(s, idx)| DecompType(idx, s)),
     ))
 }
 
+/// `--cfg` to structure the control flow from the control flow graph
+fn structuring() -> impl Parser<char, Structuring, Error = Simple<char>> + Clone {
+    just("--cfg").padded().or_not().map(|cfg| {
+        if cfg.is_some() {
+            Structuring::Cfg
+        } else {
+            Structuring::Scopes
+        }
+    })
+}
+
 fn string() -> impl Parser<char, Str, Error = Simple<char>> + Clone {
     filter(|c: &char| c != &';')
         .repeated()
@@ -175,6 +364,19 @@ fn num() -> impl Parser<char, usize, Error = Simple<char>> {
         .labelled("positive integer")
 }
 
+/// Parse a single index, lower than max
+fn index(max: usize) -> impl Parser<char, usize, Error = Simple<char>> {
+    num().validate(move |i, span, emit| {
+        if i >= max {
+            emit(Simple::custom(
+                span,
+                format!("index {i} out of bounds (there are {max} elements)"),
+            ))
+        }
+        i
+    })
+}
+
 /// Parse any range, constrained between min and max. Can also parse a single index.
 /// e.g. .., ..3, 4..5, 2,..=9, 14
 fn index_range(max: usize) -> impl Parser<char, IndexRange, Error = Simple<char>> {
@@ -205,8 +407,10 @@ mod tests {
     use chumsky::Parser;
 
     use crate::command::{
-        index_range, parse_command, parse_commands, Command, FileOrIndex, ParseContext,
+        index_range, parse_command, parse_commands, parse_first_command, Command, ElementRef,
+        ExportFormat, FileOrIndex, ParseContext, StatsFormat, StatsSection,
     };
+    use hlbc_decompiler::Structuring;
 
     #[test]
     fn test_index_range() {
@@ -240,7 +444,7 @@ mod tests {
     fn test_index_single() {
         assert_eq!(
             (4..5).sum::<usize>(),
-            index_range(10).parse("4").unwrap().sum()
+            index_range(10).parse("4").unwrap().sum::<usize>()
         );
     }
 
@@ -248,6 +452,12 @@ mod tests {
     fn test_command_simple() {
         let parsed = parse_command(&ParseContext::default(), "exit");
         assert!(matches!(parsed, Ok(Command::Exit)));
+        let parsed = parse_command(&ParseContext::default(), "deadcode");
+        assert!(matches!(parsed, Ok(Command::DeadCode)));
+        let parsed = parse_command(&ParseContext::default(), "strip");
+        assert!(matches!(parsed, Ok(Command::Strip(false))));
+        let parsed = parse_command(&ParseContext::default(), "strip --debug");
+        assert!(matches!(parsed, Ok(Command::Strip(true))));
     }
 
     #[test]
@@ -262,6 +472,113 @@ mod tests {
         assert!(matches!(parsed, Ok(Command::String(_))));
     }
 
+    #[test]
+    fn test_command_decomp() {
+        let parsed = parse_command(&ParseContext::default(), "decomp 12");
+        assert!(matches!(
+            parsed,
+            Ok(Command::Decomp(12, Structuring::Scopes))
+        ));
+        let parsed = parse_command(&ParseContext::default(), "decomp --cfg 12");
+        assert!(matches!(parsed, Ok(Command::Decomp(12, Structuring::Cfg))));
+        let parsed = parse_command(&ParseContext::default(), "decompt --cfg 3");
+        assert!(matches!(
+            parsed,
+            Ok(Command::DecompType(3, Structuring::Cfg))
+        ));
+        let parsed = parse_command(&ParseContext::default(), "decompall --cfg src");
+        assert!(matches!(parsed, Ok(Command::DecompAll(s, Structuring::Cfg)) if s == "src"));
+    }
+
+    #[test]
+    fn test_command_asm() {
+        let parsed = parse_command(&ParseContext::default(), "asm patch.hlasm");
+        assert!(matches!(parsed, Ok(Command::Asm(s)) if s == "patch.hlasm"));
+        let parsed = parse_command(&ParseContext::default(), "disasm 12");
+        assert!(matches!(parsed, Ok(Command::Disasm(12))));
+    }
+
+    #[test]
+    fn test_command_export() {
+        let parsed = parse_command(&ParseContext::default(), "export --format json out.json");
+        assert!(matches!(parsed, Ok(Command::Export(ExportFormat::Json, s)) if s == "out.json"));
+        let parsed = parse_command(&ParseContext::default(), "export out.json");
+        assert!(matches!(parsed, Ok(Command::Export(ExportFormat::Json, s)) if s == "out.json"));
+    }
+
+    #[test]
+    fn test_command_diff() {
+        let parsed = parse_command(&ParseContext::default(), "diff other.hl");
+        assert!(matches!(parsed, Ok(Command::Diff(s)) if s == "other.hl"));
+    }
+
+    #[test]
+    fn test_command_link() {
+        let parsed = parse_command(&ParseContext::default(), "link mod.hl");
+        assert!(
+            matches!(parsed, Ok(Command::Link(r, true, None, s)) if r.is_empty() && s == "mod.hl")
+        );
+        let parsed = parse_command(
+            &ParseContext::default(),
+            "link --replace Main.update,Player mod.hl",
+        );
+        assert!(
+            matches!(parsed, Ok(Command::Link(r, true, None, s)) if r == ["Main.update", "Player"] && s == "mod.hl")
+        );
+        let parsed = parse_command(
+            &ParseContext::default(),
+            "link --no-call -o out.hl my mod.hl",
+        );
+        assert!(
+            matches!(parsed, Ok(Command::Link(r, false, Some(o), s)) if r.is_empty() && o == "out.hl" && s == "my mod.hl")
+        );
+    }
+
+    #[test]
+    fn test_command_stats() {
+        let parsed = parse_command(&ParseContext::default(), "stats");
+        assert!(matches!(
+            parsed,
+            Ok(Command::Stats(StatsFormat::Text, None))
+        ));
+        let parsed = parse_command(&ParseContext::default(), "stats --format csv functions");
+        assert!(matches!(
+            parsed,
+            Ok(Command::Stats(
+                StatsFormat::Csv,
+                Some(StatsSection::Functions)
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_command_refto() {
+        let ctx = ParseContext {
+            int_max: 4,
+            type_max: 13,
+            ..Default::default()
+        };
+        let parsed = parse_command(&ctx, "refto int@3");
+        assert!(matches!(parsed, Ok(Command::RefTo(ElementRef::Int(3)))));
+        let parsed = parse_command(&ctx, "refto field@12.4");
+        assert!(matches!(
+            parsed,
+            Ok(Command::RefTo(ElementRef::Field(12, 4)))
+        ));
+        assert!(parse_command(&ctx, "refto int@4").is_err());
+        assert!(parse_command(&ctx, "refto float@0").is_err());
+    }
+
+    #[test]
+    fn test_command_callers() {
+        let parsed = parse_command(&ParseContext::default(), "callers 42");
+        assert!(matches!(parsed, Ok(Command::Callers(42))));
+        let parsed = parse_command(&ParseContext::default(), "callgraph 42 3");
+        assert!(matches!(parsed, Ok(Command::Callgraph(42, 3))));
+        let parsed = parse_command(&ParseContext::default(), "hierarchy 12");
+        assert!(matches!(parsed, Ok(Command::Hierarchy(12))));
+    }
+
     #[test]
     fn test_command_str() {
         let parsed = parse_command(&ParseContext::default(), "sstr hello world");
@@ -323,4 +640,20 @@ mod tests {
             _ => false,
         });
     }
+
+    #[test]
+    fn test_first_command() {
+        let ctx = ParseContext {
+            string_max: 3,
+            ..Default::default()
+        };
+        let (cmd, rest) = parse_first_command(&ctx, "s .. ; strip;fnamed main").unwrap();
+        assert!(matches!(cmd, Command::String(r) if r == (0..3)));
+        let (cmd, rest) = parse_first_command(&ctx, rest).unwrap();
+        assert!(matches!(cmd, Command::Strip(false)));
+        assert_eq!(rest, "fnamed main");
+        let (cmd, rest) = parse_first_command(&ctx, rest).unwrap();
+        assert!(matches!(cmd, Command::FunctionNamed(s) if s == "main"));
+        assert!(rest.is_empty());
+    }
 }
@@ -1,22 +1,36 @@
 use std::fs;
-use std::io::{stdin, BufReader, BufWriter, Write};
-use std::iter::repeat;
+use std::io::{stdin, BufWriter, Write};
 use std::path::{Path, PathBuf};
 use std::time::{Duration, Instant};
 
+use chumsky::error::SimpleReason;
 use clap::Parser as ClapParser;
 use temp_dir::TempDir;
 use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
 
+use hlbc::analysis::hierarchy::Hierarchy;
+use hlbc::analysis::reachability::{unreachable_ops, Reachability};
+use hlbc::analysis::stats::Stats;
+use hlbc::analysis::xref::{field_owner, Access, Element, XrefIndex};
+use hlbc::builder::BytecodeBuilder;
 use hlbc::fmt::EnhancedFmt;
+use hlbc::link::{link, LinkOptions};
 use hlbc::opcodes::Opcode;
-use hlbc::types::{FunPtr, RefFun, RefGlobal, Type};
+use hlbc::strip::{strip, Pass};
+use hlbc::types::{
+    FunPtr, RefField, RefFloat, RefFun, RefGlobal, RefInt, RefString, RefType, Type,
+};
 use hlbc::*;
 
-use crate::command::{commands_parser, Command, ElementRef, FileOrIndex, ParseContext, Parser};
+use crate::command::{parse_first_command, Command, ElementRef, FileOrIndex, ParseContext};
+use crate::program::Program;
 
 /// Command parser
 mod command;
+/// Bytecode decoded on demand
+mod program;
+/// Statistics report formatting
+mod stats;
 
 #[derive(ClapParser, Debug)]
 #[clap(author, version, about)]
@@ -72,39 +86,42 @@ fn main() -> anyhow::Result<()> {
 
     let start = Instant::now();
 
-    let code = {
-        let mut r = BufReader::new(fs::File::open(&file)?);
-        Bytecode::deserialize(&mut r)?
-    };
+    let mut code = load_bytecode(&mut stdout, &file)?;
 
     if tty {
         println!("Loaded ! ({} ms)", start.elapsed().as_millis());
     }
 
-    let parse_ctx = ParseContext {
-        int_max: code.ints.len(),
-        float_max: code.floats.len(),
-        string_max: code.strings.len(),
-        debug_file_max: code.debug_files.as_ref().map(|v| v.len()).unwrap_or(0),
-        type_max: code.types.len(),
-        global_max: code.globals.len(),
-        native_max: code.natives.len(),
-        constant_max: code.constants.as_ref().map(|v| v.len()).unwrap_or(0),
-        findex_max: code.findex_max(),
-    };
-
-    let parser = commands_parser(&parse_ctx);
+    // Built on the first query, reset when the bytecode changes
+    let mut xrefs = None;
 
     macro_rules! execute_commands {
-        ($code:expr, $commands:expr; $onexit:stmt) => {
-            for cmd in $commands {
+        ($code:expr, $line:expr; $onexit:stmt) => {
+            let mut line: &str = $line;
+            while !line.trim().is_empty() {
+                // Parsed one at a time as the previous command might have changed the bytecode
+                let (cmd, rest) = match parse_first_command(&parse_context($code), line) {
+                    Ok(parsed) => parsed,
+                    Err(errors) => {
+                        // Keep the session alive, the rest of the line is dropped
+                        for e in errors {
+                            if let SimpleReason::Custom(msg) = e.reason() {
+                                println!("Error while parsing command : {msg}");
+                            } else {
+                                println!("Error while parsing command : {e}");
+                            }
+                        }
+                        break;
+                    }
+                };
+                line = rest;
                 match cmd {
                     #[allow(redundant_semicolons)]
                     Command::Exit => {
                         $onexit;
                     }
                     cmd => {
-                        process_command(&mut stdout, $code, cmd)?;
+                        process_command(&mut stdout, $code, &mut xrefs, cmd)?;
                     }
                 }
                 println!();
@@ -114,7 +131,7 @@ fn main() -> anyhow::Result<()> {
 
     // Execute the -c
     if let Some(initial_cmd) = args.command {
-        execute_commands!(&code, parser.parse(initial_cmd.as_str()).expect("Error while parsing command."); return Ok(()));
+        execute_commands!(&mut code, &initial_cmd; return Ok(()));
     }
 
     #[cfg(feature = "watch")]
@@ -134,9 +151,7 @@ fn main() -> anyhow::Result<()> {
 
         println!("Watching file '{}', command : {watch}", args.file.display());
 
-        let commands = parser.parse(watch.as_str()).expect("Can't parse command");
-
-        execute_commands!(&code, commands.clone(); return Ok(()));
+        execute_commands!(&mut code, &watch; return Ok(()));
 
         'watch: loop {
             match rx.recv() {
@@ -146,12 +161,17 @@ fn main() -> anyhow::Result<()> {
                             compile(&args.file, &file)?;
                         }
 
-                        let code = {
-                            let mut r = BufReader::new(fs::File::open(&file)?);
-                            Bytecode::deserialize(&mut r)?
+                        let mut code = match load_bytecode(&mut stdout, &file) {
+                            Ok(code) => code,
+                            // The file might still be written to, wait for the next change
+                            Err(e) => {
+                                println!("{e}");
+                                continue;
+                            }
                         };
+                        xrefs = None;
 
-                        execute_commands!(&code, commands.clone(); break 'watch);
+                        execute_commands!(&mut code, &watch; break 'watch);
                     }
                 }
                 Ok(Err(e)) => {
@@ -177,17 +197,15 @@ fn main() -> anyhow::Result<()> {
         stdin().read_line(&mut line)?;
         stdout.reset()?;
 
-        let commands = parser
-            .parse(line.trim())
-            .expect("Error while parsing command.");
-        execute_commands!(&code, commands; break 'main);
+        execute_commands!(&mut code, line.trim(); break 'main);
     }
     Ok(())
 }
 
 fn process_command(
     stdout: &mut StandardStream,
-    code: &Bytecode,
+    program: &mut Program,
+    xrefs: &mut Option<XrefIndex>,
     cmd: Command,
 ) -> anyhow::Result<()> {
     macro_rules! print_i {
@@ -199,8 +217,8 @@ fn process_command(
     }
 
     macro_rules! require_debug_info {
-        () => {
-            if let Some(debug_files) = &code.debug_files {
+        ($debug_files:expr) => {
+            if let Some(debug_files) = $debug_files {
                 debug_files
             } else {
                 println!("No debug info in this binary");
@@ -209,6 +227,19 @@ fn process_command(
         };
     }
 
+    // Strings and functions are decoded on demand, a malformed one only fails the command
+    macro_rules! decode {
+        ($e:expr) => {
+            match $e {
+                Ok(v) => v,
+                Err(e) => {
+                    print_load_error(stdout, &e)?;
+                    return Ok(());
+                }
+            }
+        };
+    }
+
     match cmd {
         Command::Exit => unreachable!(),
         Command::Help => {
@@ -220,6 +251,7 @@ explain     <opcode>         | Get information about an opcode
 wiki                         | Open the bytecode wiki in a browser
 info                         | General information about the bytecode
 entrypoint                   | Get the bytecode entrypoint
+verify                       | Check the bytecode for errors
 i,int       <idx>            | Get the int at index
 f,float     <idx>            | Get the float at index
 s,string    <idx>            | Get the string at index
@@ -236,11 +268,22 @@ fnn,fnamed  <str>            | Get a function by name
 sfn         <str>            | Find a function by name
 infile      <idx|str>        | Find functions in file
 fileof      <findex>         | Get the file where findex is defined
-refto       <any@idx>        | Find references to a given bytecode element
+refto       <any@idx>        | Find references to a given bytecode element (string, int, float, type, field@type.idx, global, fn)
 saveto      <filename>       | Serialize the bytecode to a file
+disasm      <findex>         | Get the assembly listing of a function
+asm         <filename>       | Assemble a function listing and add it to the bytecode
+diff        <filename>       | Compare with another bytecode file, matching elements by name
+export      [--format json] <filename> | Dump the whole bytecode to a file
+link        [--replace <names>] [--no-call] [-o <out>] <filename> | Merge a bytecode file, replacing the methods named Class.method or all the methods of Class
+strip       [--debug]        | Remove unreachable elements, nops and unused constants, and the debug info with --debug
 callgraph   <findex> <depth> | Create a dot call graph from a function and a max depth
-decomp      <findex>         | Decompile a function
-decompt     <idx>            | Decompile a type
+callers     <findex>         | Find the functions calling a function, including virtual and dynamic calls
+hierarchy   <idx>            | Print the parents, subclasses and virtual table of a class
+deadcode                     | Find the functions, types and globals unreachable from the entrypoint and the unreachable instructions
+stats       [--format text|json|csv] [opcodes|functions|files|natives|types] | Statistics about the code, csv prints a single table (functions by default)
+decomp      [--cfg] <findex> | Decompile a function, --cfg to structure the control flow graph
+decompt     [--cfg] <idx>    | Decompile a type
+decompall   [--cfg] <dir>    | Decompile every class and enum outside of the std to a Haxe source tree
 
 Remember you can use the range notation in place of an index to navigate through data : a..b
 This is the same range notation as Rust and is supported with most commands."#
@@ -248,8 +291,10 @@ This is the same range notation as Rust and is supported with most commands."#
         }
         Command::Explain(s) => {
             if let Some(o) = Opcode::from_name(&s) {
+                let findex = decode!(program.header()).functions[0].findex;
+                let (code, f) = decode!(program.function(findex));
                 print!("{} :\n{}", o.name(), o.description());
-                println!("Example : {}", o.display(code, &code.functions[0], 0, 0));
+                println!("Example : {}", o.display(code, f.as_fn().unwrap(), 0, 0));
             } else {
                 println!("No opcode named '{s}' exists.");
             }
@@ -258,40 +303,49 @@ This is the same range notation as Rust and is supported with most commands."#
         Command::Info => {
             println!(
                 "version: {}\ndebug: {}\nnints: {}\nnfloats: {}\nnstrings: {}\nntypes: {}\nnnatives: {}\nnfunctions: {}\nnconstants: {}",
-                code.version,
-                code.debug_files.is_some(),
-                code.ints.len(),
-                code.floats.len(),
-                code.strings.len(),
-                code.types.len(),
-                code.natives.len(),
-                code.functions.len(),
-                code.constants.as_ref().map_or(0, |c| c.len())
+                program.version(),
+                program.debug_files().is_some(),
+                program.ints().len(),
+                program.floats().len(),
+                program.nstrings(),
+                program.ntypes(),
+                program.nnatives(),
+                program.nfunctions(),
+                program.nconstants()
             );
         }
         Command::Entrypoint => {
+            let code = decode!(program.header());
             println!("{}", code.entrypoint().display_header::<EnhancedFmt>(code));
         }
+        Command::Verify => {
+            let diags = decode!(program.full()).verify();
+            for d in &diags {
+                println!("{d}");
+            }
+            println!("{} problem(s) found", diags.len());
+        }
         Command::Int(range) => {
             for i in range {
                 print_i!(i);
-                println!("{}", code.ints[i]);
+                println!("{}", program.ints()[i]);
             }
         }
         Command::Float(range) => {
             for i in range {
                 print_i!(i);
-                println!("{}", code.floats[i]);
+                println!("{}", program.floats()[i]);
             }
         }
         Command::String(range) => {
             for i in range {
                 print_i!(i);
-                println!("{}", code.strings[i]);
+                println!("{}", decode!(program.string(RefString(i))));
             }
         }
         Command::SearchStr(str) => {
-            for (i, s) in code.strings.iter().enumerate() {
+            for i in 0..program.nstrings() {
+                let s = decode!(program.string(RefString(i)));
                 if s.contains(&*str) {
                     print_i!(i);
                     println!("{}", s);
@@ -299,14 +353,14 @@ This is the same range notation as Rust and is supported with most commands."#
             }
         }
         Command::Debugfile(range) => {
-            let debug_files = require_debug_info!();
+            let debug_files = require_debug_info!(program.debug_files());
             for i in range {
                 print_i!(i);
                 println!("{}", debug_files[i]);
             }
         }
         Command::SearchDebugfile(str) => {
-            let debug_files = require_debug_info!();
+            let debug_files = require_debug_info!(program.debug_files());
             for (i, s) in debug_files.iter().enumerate() {
                 if s.contains(&*str) {
                     print_i!(i);
@@ -315,6 +369,7 @@ This is the same range notation as Rust and is supported with most commands."#
             }
         }
         Command::Type(range) => {
+            let code = decode!(program.header());
             let range_len = range.len();
             for i in range {
                 print_i!(i);
@@ -341,7 +396,11 @@ This is the same range notation as Rust and is supported with most commands."#
                                 println!(
                                     "  {}: {} ({})",
                                     p.name.display::<EnhancedFmt>(code),
-                                    code.resolve(p.findex).display_header::<EnhancedFmt>(code),
+                                    match code.try_resolve(p.findex) {
+                                        Ok(fun) =>
+                                            fun.display_header::<EnhancedFmt>(code).to_string(),
+                                        Err(e) => e.to_string(),
+                                    },
                                     p.pindex
                                 );
                             }
@@ -372,6 +431,7 @@ This is the same range notation as Rust and is supported with most commands."#
             }
         }
         Command::Global(range) => {
+            let code = decode!(program.header());
             for i in range {
                 print_i!(i);
                 println!("{}", code.globals[i].display::<EnhancedFmt>(code));
@@ -383,43 +443,53 @@ This is the same range notation as Rust and is supported with most commands."#
             }
         }
         Command::Native(range) => {
+            let code = decode!(program.header());
             for i in range {
                 print_i!(i);
                 println!("{}", code.natives[i].display::<EnhancedFmt>(code));
             }
         }
         Command::Constant(range) => {
+            let code = decode!(program.header());
             for i in range {
                 print_i!(i);
                 println!("{:#?}", code.constants.as_ref().unwrap()[i]);
             }
         }
         Command::FunctionHeader(range) => {
+            let code = decode!(program.header());
             for findex in range {
                 print_i!(findex);
-                match code.resolve(RefFun(findex)) {
-                    FunPtr::Fun(f) => println!("{}", f.display_header::<EnhancedFmt>(code)),
-                    FunPtr::Native(n) => println!("{}", n.display::<EnhancedFmt>(code)),
+                match code.try_resolve(RefFun(findex)) {
+                    Ok(FunPtr::Fun(f)) => println!("{}", f.display_header::<EnhancedFmt>(code)),
+                    Ok(FunPtr::Native(n)) => println!("{}", n.display::<EnhancedFmt>(code)),
+                    Err(e) => println!("{e}"),
                 }
             }
         }
         Command::Function(range) => {
             for findex in range {
                 print_i!(findex);
-                match code.resolve(RefFun(findex)) {
-                    FunPtr::Fun(f) => println!("{}", f.display::<EnhancedFmt>(code)),
-                    FunPtr::Native(n) => println!("{}", n.display::<EnhancedFmt>(code)),
+                match program.function(RefFun(findex)) {
+                    Ok((code, FunPtr::Fun(f))) => println!("{}", f.display::<EnhancedFmt>(code)),
+                    Ok((code, FunPtr::Native(n))) => {
+                        println!("{}", n.display::<EnhancedFmt>(code))
+                    }
+                    Err(e) => println!("{e}"),
                 }
             }
         }
         Command::FunctionNamed(str) => {
-            if let Some(f) = code.function_by_name(&str) {
-                println!("{}", f.display::<EnhancedFmt>(code));
+            if let Some(f) = decode!(program.header()).function_by_name(&str) {
+                let findex = f.findex;
+                let (code, f) = decode!(program.function(findex));
+                println!("{}", f.as_fn().unwrap().display::<EnhancedFmt>(code));
             } else {
                 println!("unknown '{str}'");
             }
         }
         Command::SearchFunction(str) => {
+            let code = decode!(program.header());
             // TODO search for function
             if let Some(f) = code.function_by_name(&str) {
                 println!("{}", f.display_header::<EnhancedFmt>(code));
@@ -428,7 +498,8 @@ This is the same range notation as Rust and is supported with most commands."#
             }
         }
         Command::InFile(foi) => {
-            let debug_files = require_debug_info!();
+            let code = decode!(program.full());
+            let debug_files = require_debug_info!(&code.debug_files);
             match foi {
                 FileOrIndex::File(str) => {
                     if let Some(idx) =
@@ -468,9 +539,9 @@ This is the same range notation as Rust and is supported with most commands."#
             }
         }
         Command::FileOf(idx) => {
-            let debug_files = require_debug_info!();
-            match code.resolve(RefFun(idx)) {
-                FunPtr::Fun(f) => {
+            let debug_files = require_debug_info!(program.debug_files()).to_vec();
+            match program.function(RefFun(idx)) {
+                Ok((code, FunPtr::Fun(f))) => {
                     let idx = f.debug_info.as_ref().unwrap()[f.ops.len() - 1].0;
                     println!(
                         "{} is in file@{idx} : {}",
@@ -478,24 +549,161 @@ This is the same range notation as Rust and is supported with most commands."#
                         &debug_files[idx]
                     );
                 }
-                FunPtr::Native(n) => {
+                Ok((code, FunPtr::Native(n))) => {
                     println!(
                         "native {} is in the module {}",
                         n.display::<EnhancedFmt>(code),
                         n.lib(code)
                     )
                 }
+                Err(e) => println!("{e}"),
             }
         }
         Command::SaveTo(file) => {
+            let code = decode!(program.full());
             let mut w = BufWriter::new(fs::File::create(&*file)?);
             code.serialize(&mut w)?;
         }
+        Command::Disasm(idx) => match program.function(RefFun(idx)) {
+            Ok((code, FunPtr::Fun(f))) => print!("{}", hlbc::asm::disassemble(code, f)),
+            Ok((code, FunPtr::Native(n))) => {
+                println!("{} is a native", n.display::<EnhancedFmt>(code))
+            }
+            Err(e) => println!("{e}"),
+        },
+        Command::Asm(file) => {
+            let code = decode!(program.full());
+            let src = match fs::read_to_string(&*file) {
+                Ok(src) => src,
+                Err(e) => {
+                    println!("Can't read '{file}' : {e}");
+                    return Ok(());
+                }
+            };
+            let mut builder = BytecodeBuilder::from(std::mem::take(code));
+            match hlbc::asm::assemble(&mut builder, &src) {
+                Ok(fun) => {
+                    println!("Assembled fn@{} ({} ops)", fun.findex.0, fun.ops.len());
+                    builder.insert_function(fun);
+                    *xrefs = None;
+                }
+                Err(e) => println!("{e}"),
+            }
+            *code = builder.build();
+        }
+        Command::Strip(debug) => {
+            let code = decode!(program.full());
+            let mut passes = Pass::DEFAULT.to_vec();
+            if debug {
+                // Before the pools, so the strings only used by debug info are removed too
+                let pools = passes
+                    .iter()
+                    .position(|p| matches!(p, Pass::Pools))
+                    .unwrap_or(passes.len());
+                passes.insert(pools, Pass::DebugInfo);
+            }
+            let stats = strip(code, &passes);
+            *xrefs = None;
+            println!(
+                "Removed {} functions, {} natives, {} types, {} globals, {} instructions, {} strings, {} ints, {} floats",
+                stats.functions,
+                stats.natives,
+                stats.types,
+                stats.globals,
+                stats.ops,
+                stats.strings,
+                stats.ints,
+                stats.floats
+            );
+            println!("Use saveto to write the stripped bytecode");
+        }
+        Command::Link(replace, call_entrypoint, output, file) => {
+            let code = decode!(program.full());
+            let module = match load_bytecode(stdout, Path::new(&*file)) {
+                Ok(module) => decode!(module.into_bytecode()),
+                Err(e) => {
+                    println!("{e:#}");
+                    return Ok(());
+                }
+            };
+            match link(
+                code,
+                module,
+                &LinkOptions {
+                    replace,
+                    call_entrypoint,
+                },
+            ) {
+                Ok(res) => {
+                    *xrefs = None;
+                    println!(
+                        "Added {} types, {} globals, {} natives and {} functions",
+                        res.types_added, res.globals_added, res.natives_added, res.functions_added
+                    );
+                    println!(
+                        "Shared {} types and {} functions",
+                        res.types_merged, res.functions_merged
+                    );
+                    for name in res.replaced {
+                        println!("Replaced {name}");
+                    }
+                    println!(
+                        "Module entrypoint : {}{}",
+                        res.entrypoint.display_header::<EnhancedFmt>(code),
+                        if call_entrypoint {
+                            ", called by the base entrypoint"
+                        } else {
+                            ""
+                        }
+                    );
+                    if let Some(output) = output {
+                        let saved = fs::File::create(&*output)
+                            .map_err(hlbc::Error::from)
+                            .and_then(|f| code.serialize(&mut BufWriter::new(f)));
+                        match saved {
+                            Ok(()) => println!("Saved the linked bytecode to '{output}'"),
+                            Err(e) => println!("Can't write '{output}' : {e}"),
+                        }
+                    } else {
+                        println!("Use saveto to write the linked bytecode");
+                    }
+                }
+                Err(e) => println!("{e}"),
+            }
+        }
+        Command::Diff(file) => {
+            let code = decode!(program.full());
+            let other = match load_bytecode(stdout, Path::new(&*file)) {
+                Ok(other) => decode!(other.into_bytecode()),
+                Err(e) => {
+                    println!("{e:#}");
+                    return Ok(());
+                }
+            };
+            print!("{}", hlbc::diff::diff(code, &other));
+        }
+        Command::Export(format, file) => {
+            #[cfg(feature = "export")]
+            {
+                let code = decode!(program.full());
+                let w = BufWriter::new(fs::File::create(&*file)?);
+                match format {
+                    command::ExportFormat::Json => serde_json::to_writer(w, code)?,
+                }
+            }
+
+            #[cfg(not(feature = "export"))]
+            {
+                let _ = (format, file);
+                println!("hlbc-cli has been built without export support. Build with feature 'export' to enable exporting bytecode");
+            }
+        }
         Command::Callgraph(idx, depth) => {
             #[cfg(feature = "graph")]
             {
                 use hlbc::analysis::graph::{call_graph, display_graph};
 
+                let code = decode!(program.full());
                 let graph = call_graph(code, RefFun(idx), depth);
                 println!("{}", display_graph(&graph, code));
             }
@@ -505,112 +713,357 @@ This is the same range notation as Rust and is supported with most commands."#
                 println!("hlbc-cli has been built without graph support. Build with feature 'graph' to enable callgraph generation");
             }
         }
-        Command::RefTo(elem) => match elem {
-            ElementRef::String(idx) => {
+        Command::Callers(idx) => {
+            #[cfg(feature = "graph")]
+            {
+                use hlbc::analysis::graph::{callers, whole_program_graph, Call};
+
+                let code = decode!(program.full());
+                let graph = whole_program_graph(code);
                 println!(
-                    "Finding references to string@{idx} : {}\n",
-                    code.strings[idx]
+                    "Functions calling {}\n",
+                    RefFun(idx).display_header::<EnhancedFmt>(code)
                 );
-                if let Some(constants) = &code.constants {
-                    for (i, c) in constants.iter().enumerate() {
-                        if c.fields[0] == idx {
+                for (caller, call) in callers(&graph, RefFun(idx)) {
+                    println!(
+                        "{}{}",
+                        caller.display_header::<EnhancedFmt>(code),
+                        match call {
+                            Call::Direct => "",
+                            Call::Closure => " (closure)",
+                            Call::Virtual => " (virtual)",
+                            Call::Dynamic => " (dynamic)",
+                        }
+                    );
+                }
+            }
+
+            #[cfg(not(feature = "graph"))]
+            {
+                let _ = idx;
+                println!("hlbc-cli has been built without graph support. Build with feature 'graph' to enable callgraph generation");
+            }
+        }
+        Command::Hierarchy(idx) => {
+            let code = decode!(program.header());
+            let t = RefType(idx);
+            let h = Hierarchy::new(code);
+            match code.types.get(idx) {
+                Some(Type::Obj(_) | Type::Struct(_)) => {
+                    println!("{}", t.display::<EnhancedFmt>(code));
+                    for parent in h.ancestors(code, t) {
+                        println!("  extends {}", parent.display::<EnhancedFmt>(code));
+                    }
+
+                    if !h.children(t).is_empty() {
+                        println!("\nSubclasses :");
+                    }
+                    fn print_tree(code: &Bytecode, h: &Hierarchy, t: RefType, depth: usize) {
+                        for &child in h.children(t) {
                             println!(
-                                "constant@{i} expanding to global@{} (now also searching for global)",
-                                c.global.0
+                                "{:indent$}{}",
+                                "",
+                                child.display::<EnhancedFmt>(code),
+                                indent = depth * 2
                             );
-                            code.ops().for_each(|(f, (i, o))| match o {
-                                Opcode::GetGlobal { global, .. } => {
-                                    if *global == c.global {
-                                        println!(
-                                            "in {} at {i}: GetGlobal",
-                                            f.display_header::<EnhancedFmt>(code)
-                                        );
-                                    }
-                                }
-                                _ => {}
-                            });
-                            println!();
+                            print_tree(code, h, child, depth + 1);
                         }
                     }
-                }
-                code.ops().for_each(|(f, (i, o))| match o {
-                    Opcode::String { ptr, .. } => {
-                        if ptr.0 == idx {
-                            println!("{} at {i}: String", f.display_header::<EnhancedFmt>(code));
+                    print_tree(code, &h, t, 1);
+
+                    let interfaces = h.interfaces(code, t);
+                    if !interfaces.is_empty() {
+                        println!("\nImplements :");
+                        for v in interfaces {
+                            println!("  {}", v.display::<EnhancedFmt>(code));
                         }
                     }
-                    _ => {}
-                });
+
+                    println!("\nVirtual table :");
+                    for e in h.vtable(code, t) {
+                        print!(
+                            "  #{} {} : {}",
+                            e.pindex,
+                            code[e.name],
+                            e.findex.display_header::<EnhancedFmt>(code)
+                        );
+                        if e.owner != t {
+                            print!(" (inherited from {})", e.owner.display::<EnhancedFmt>(code));
+                        } else if let Some(parent) = h.overridden(e.findex) {
+                            print!(
+                                " (overrides {})",
+                                parent.display_header::<EnhancedFmt>(code)
+                            );
+                        }
+                        println!();
+                    }
+                }
+                Some(Type::Virtual { .. }) => {
+                    println!("{}\n\nImplemented by :", t.display::<EnhancedFmt>(code));
+                    for class in h.implementors(code, t) {
+                        println!("  {}", class.display::<EnhancedFmt>(code));
+                    }
+                }
+                _ => println!("type@{idx} is not a class or a virtual"),
+            }
+        }
+        Command::Stats(format, section) => {
+            let code = decode!(program.full());
+            stats::print_stats(&Stats::new(code), format, section)?;
+        }
+        Command::DeadCode => {
+            let code = decode!(program.full());
+            let reach = Reachability::new(code);
+            let functions = reach.unreachable_functions(code);
+            let types = reach.unreachable_types(code);
+            let globals = reach.unreachable_globals(code);
+            println!(
+                "Reachable : {}/{} functions, {}/{} types, {}/{} globals",
+                code.findex_max() - functions.len(),
+                code.findex_max(),
+                code.types.len() - types.len(),
+                code.types.len(),
+                code.globals.len() - globals.len(),
+                code.globals.len()
+            );
+
+            println!("\nUnreachable functions :");
+            for f in functions {
+                println!("  {}", f.display_header::<EnhancedFmt>(code));
+            }
+            println!("\nUnreachable types :");
+            for t in types {
+                println!("  {}@{}", t.display::<EnhancedFmt>(code), t.0);
             }
-            ElementRef::Global(idx) => {
+            println!("\nUnreachable globals :");
+            for g in globals {
                 println!(
-                    "Finding references to global@{idx} : {}\n",
-                    code.globals[idx].display::<EnhancedFmt>(code)
+                    "  global@{} : {}",
+                    g.0,
+                    code.globals[g.0].display::<EnhancedFmt>(code)
                 );
-                if let Some(constants) = &code.constants {
-                    for (i, c) in constants.iter().enumerate() {
-                        if c.global.0 == idx {
-                            println!("constant@{i} : {:?}", c);
-                        }
+            }
+
+            println!("\nUnreachable instructions :");
+            for f in &code.functions {
+                let ops = unreachable_ops(f);
+                if ops.is_empty() {
+                    continue;
+                }
+                // Group consecutive instructions
+                let mut ranges: Vec<(usize, usize)> = Vec::new();
+                for i in ops {
+                    match ranges.last_mut() {
+                        Some((_, end)) if *end + 1 == i => *end = i,
+                        _ => ranges.push((i, i)),
                     }
                 }
-                println!();
-
-                code.ops().for_each(|(f, (i, o))| match o {
-                    Opcode::GetGlobal { global, .. } | Opcode::SetGlobal { global, .. } => {
-                        if global.0 == idx {
-                            println!(
-                                "{} at {i}: {}",
-                                f.display_header::<EnhancedFmt>(code),
-                                o.name()
-                            );
+                let ranges: Vec<_> = ranges
+                    .into_iter()
+                    .map(|(start, end)| {
+                        if start == end {
+                            start.to_string()
+                        } else {
+                            format!("{start}..={end}")
                         }
-                    }
-                    _ => {}
-                });
-            }
-            ElementRef::Fn(idx) => {
+                    })
+                    .collect();
                 println!(
-                    "Finding references to fn@{idx} : {}\n",
-                    RefFun(idx).display_header::<EnhancedFmt>(code)
+                    "  {} : {}",
+                    f.display_header::<EnhancedFmt>(code),
+                    ranges.join(", ")
                 );
-                code.functions
-                    .iter()
-                    .flat_map(|f| repeat(f).zip(f.find_fun_refs()))
-                    .for_each(|(f, (i, o, fun))| {
-                        if fun.0 == idx {
-                            println!(
-                                "{} at {i}: {}",
-                                f.display_header::<EnhancedFmt>(code),
-                                o.name()
-                            );
+            }
+        }
+        Command::RefTo(elem) => {
+            let code = decode!(program.full());
+            let xrefs = xrefs.get_or_insert_with(|| XrefIndex::new(code));
+            let print_uses = |elem| {
+                for site in xrefs.uses(code, elem) {
+                    println!(
+                        "{} at {}: {} ({})",
+                        site.fun.display_header::<EnhancedFmt>(code),
+                        site.op,
+                        site.fun
+                            .as_fn(code)
+                            .and_then(|f| f.ops.get(site.op))
+                            .map_or("", |o| o.name()),
+                        match site.access {
+                            Access::Read => "read",
+                            Access::Write => "write",
+                            Access::Call => "call",
+                        }
+                    );
+                }
+            };
+            match elem {
+                ElementRef::String(idx) => {
+                    println!(
+                        "Finding references to string@{idx} : {}\n",
+                        code.strings[idx]
+                    );
+                    if let Some(constants) = &code.constants {
+                        for (i, c) in constants.iter().enumerate() {
+                            if c.fields[0] == idx {
+                                println!(
+                                    "constant@{i} expanding to global@{} (now also searching for global)",
+                                    c.global.0
+                                );
+                                print_uses(Element::Global(c.global));
+                                println!();
+                            }
+                        }
+                    }
+                    print_uses(Element::String(RefString(idx)));
+                }
+                ElementRef::Int(idx) => {
+                    println!("Finding references to int@{idx} : {}\n", code.ints[idx]);
+                    print_uses(Element::Int(RefInt(idx)));
+                }
+                ElementRef::Float(idx) => {
+                    println!("Finding references to float@{idx} : {}\n", code.floats[idx]);
+                    print_uses(Element::Float(RefFloat(idx)));
+                }
+                ElementRef::Type(idx) => {
+                    println!(
+                        "Finding references to type@{idx} : {}\n",
+                        code.types[idx].display::<EnhancedFmt>(code)
+                    );
+                    print_uses(Element::Type(RefType(idx)));
+                }
+                ElementRef::Field(t, field) => {
+                    let t = RefType(t);
+                    let owner = field_owner(code, t, RefField(field));
+                    println!(
+                        "Finding references to field {}.{} (declared in {})\n",
+                        t.display::<EnhancedFmt>(code),
+                        t.field(RefField(field), code)
+                            .map(|f| f.name(code))
+                            .unwrap_or_default(),
+                        owner.display::<EnhancedFmt>(code)
+                    );
+                    print_uses(Element::Field(t, RefField(field)));
+                }
+                ElementRef::Global(idx) => {
+                    println!(
+                        "Finding references to global@{idx} : {}\n",
+                        code.globals[idx].display::<EnhancedFmt>(code)
+                    );
+                    if let Some(constants) = &code.constants {
+                        for (i, c) in constants.iter().enumerate() {
+                            if c.global.0 == idx {
+                                println!("constant@{i} : {:?}", c);
+                            }
                         }
-                    });
+                    }
+                    println!();
+                    print_uses(Element::Global(RefGlobal(idx)));
+                }
+                ElementRef::Fn(idx) => {
+                    println!(
+                        "Finding references to fn@{idx} : {}\n",
+                        RefFun(idx).display_header::<EnhancedFmt>(code)
+                    );
+                    print_uses(Element::Fun(RefFun(idx)));
+                }
             }
-        },
-        Command::Decomp(idx) => {
+        }
+        Command::Decomp(idx, structuring) => {
+            // Closures are decompiled along with their parent
+            let code = decode!(program.full());
             if let Some(fun) = RefFun(idx).as_fn(code) {
                 println!(
                     "{}",
-                    hlbc_decompiler::decompile_function(code, fun)
+                    hlbc_decompiler::decompile_function_with(code, fun, structuring)
                         .display(code, &hlbc_decompiler::fmt::FormatOptions::new(2))
                 );
             }
         }
-        Command::DecompType(idx) => {
+        Command::DecompType(idx, structuring) => {
+            let code = decode!(program.full());
             let ty = &code.types[idx];
             match ty {
                 Type::Obj(obj) => {
                     println!("Dumping type@{idx} : {}", ty.display::<EnhancedFmt>(code));
                     println!(
                         "{}",
-                        hlbc_decompiler::decompile_class(code, obj)
+                        hlbc_decompiler::decompile_class_with(code, obj, structuring)
                             .display(code, &hlbc_decompiler::fmt::FormatOptions::new(2))
                     );
                 }
-                _ => println!("Type {idx} is not an obj"),
+                Type::Enum { .. } => {
+                    println!("Dumping type@{idx} : {}", ty.display::<EnhancedFmt>(code));
+                    if let Some(decl) = hlbc_decompiler::decompile_enum(code, ty) {
+                        println!(
+                            "{}",
+                            decl.display(code, &hlbc_decompiler::fmt::FormatOptions::new(2))
+                        );
+                    }
+                }
+                _ => println!("Type {idx} is not an obj or an enum"),
+            }
+        }
+        Command::DecompAll(dir, structuring) => {
+            let code = decode!(program.full());
+            let opts = hlbc_decompiler::fmt::FormatOptions::new(2);
+            let files = hlbc_decompiler::decompile_sources_with(code, structuring);
+            for file in &files {
+                let path = Path::new(&*dir).join(file.path());
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(&path, file.display(code, &opts).to_string())?;
+            }
+            println!("Decompiled {} files to {dir}", files.len());
+        }
+    }
+    Ok(())
+}
+
+/// Bounds of the index ranges in commands
+fn parse_context(program: &Program) -> ParseContext {
+    ParseContext {
+        int_max: program.ints().len(),
+        float_max: program.floats().len(),
+        string_max: program.nstrings(),
+        debug_file_max: program.debug_files().map_or(0, |v| v.len()),
+        type_max: program.ntypes(),
+        global_max: program.nglobals(),
+        native_max: program.nnatives(),
+        constant_max: program.nconstants(),
+        findex_max: program.findex_max(),
+    }
+}
+
+/// Open a bytecode file, reporting where it is malformed if it can't be read.
+/// Only the file structure is read, strings and functions are decoded when accessed.
+fn load_bytecode(stdout: &mut StandardStream, path: &Path) -> anyhow::Result<Program> {
+    let e = match Program::open(path) {
+        Ok(program) => return Ok(program),
+        Err(e) => e,
+    };
+    if let hlbc::Error::MalformedBytecode { .. } = &e {
+        print_load_error(stdout, &e)?;
+        return Err(anyhow::anyhow!("Can't load '{}'", path.display()));
+    }
+    Err(anyhow::Error::new(e).context(format!("Can't load '{}'", path.display())))
+}
+
+/// Print an error from decoding the bytecode, with its location if the bytecode is malformed
+fn print_load_error(stdout: &mut StandardStream, e: &hlbc::Error) -> anyhow::Result<()> {
+    if let hlbc::Error::MalformedBytecode { msg, location } = e {
+        stdout.set_color(ColorSpec::new().set_fg(Some(Color::Red)))?;
+        println!("Malformed bytecode: {msg}");
+        stdout.reset()?;
+        if let Some(location) = location {
+            println!("  section : {}", location.section);
+            if let Some(index) = location.index {
+                println!("  element : #{index}");
             }
+            println!("  offset  : {:#x} ({})", location.offset, location.offset);
         }
+    } else {
+        println!("{e}");
     }
     Ok(())
 }
@@ -0,0 +1,173 @@
+use std::path::Path;
+
+use hlbc::lazy::LazyBytecode;
+use hlbc::types::{FunPtr, RefFun, RefString};
+use hlbc::{Bytecode, Resolve, Str};
+
+/// The bytecode opened by the cli. Opening a file only indexes it, strings and functions are decoded
+/// when a command needs them.
+pub enum Program {
+    Lazy {
+        lazy: Box<LazyBytecode>,
+        /// Every string and the function headers, built by the first command displaying names
+        header: Option<Bytecode>,
+    },
+    /// Completely decoded, required by commands working on the whole program or modifying it
+    Full(Bytecode),
+}
+
+impl Program {
+    pub fn open(path: &Path) -> hlbc::Result<Self> {
+        Ok(Program::Lazy {
+            lazy: Box::new(LazyBytecode::open(path)?),
+            header: None,
+        })
+    }
+
+    /// Decode everything that isn't already
+    pub fn into_bytecode(self) -> hlbc::Result<Bytecode> {
+        match self {
+            Program::Lazy { lazy, .. } => lazy.into_bytecode(),
+            Program::Full(code) => Ok(code),
+        }
+    }
+
+    /// Bytecode with every string and function header. Function bodies might be missing,
+    /// use [Program::function] to get them.
+    pub fn header(&mut self) -> hlbc::Result<&Bytecode> {
+        match self {
+            Program::Lazy { lazy, header } => {
+                if header.is_none() {
+                    *header = Some(lazy.header()?);
+                }
+                Ok(header.as_ref().unwrap())
+            }
+            Program::Full(code) => Ok(code),
+        }
+    }
+
+    /// Get a function with its body, along with the bytecode to display it.
+    pub fn function(&mut self, findex: RefFun) -> hlbc::Result<(&Bytecode, FunPtr<'_>)> {
+        self.header()?;
+        match self {
+            Program::Lazy {
+                lazy,
+                header: Some(header),
+            } => {
+                let fun = match lazy.function_by_findex(findex)? {
+                    Some(f) => FunPtr::Fun(f),
+                    None => header.try_resolve(findex)?,
+                };
+                Ok((header, fun))
+            }
+            Program::Lazy { header: None, .. } => unreachable!(),
+            Program::Full(code) => Ok((code, code.try_resolve(findex)?)),
+        }
+    }
+
+    /// Decode the whole program, it can then be modified.
+    pub fn full(&mut self) -> hlbc::Result<&mut Bytecode> {
+        if let Program::Lazy { lazy, .. } = self {
+            let code = lazy.to_bytecode()?;
+            *self = Program::Full(code);
+        }
+        match self {
+            Program::Full(code) => Ok(code),
+            Program::Lazy { .. } => unreachable!(),
+        }
+    }
+
+    pub fn version(&self) -> u8 {
+        match self {
+            Program::Lazy { lazy, .. } => lazy.version(),
+            Program::Full(code) => code.version,
+        }
+    }
+
+    pub fn ints(&self) -> &[i32] {
+        match self {
+            Program::Lazy { lazy, .. } => lazy.ints(),
+            Program::Full(code) => &code.ints,
+        }
+    }
+
+    pub fn floats(&self) -> &[f64] {
+        match self {
+            Program::Lazy { lazy, .. } => lazy.floats(),
+            Program::Full(code) => &code.floats,
+        }
+    }
+
+    pub fn nstrings(&self) -> usize {
+        match self {
+            Program::Lazy { lazy, .. } => lazy.nstrings(),
+            Program::Full(code) => code.strings.len(),
+        }
+    }
+
+    /// Get a string, decoding it if required
+    pub fn string(&self, s: RefString) -> hlbc::Result<Str> {
+        match self {
+            Program::Lazy { lazy, .. } => lazy.string(s),
+            Program::Full(code) => {
+                code.strings
+                    .get(s.0)
+                    .cloned()
+                    .ok_or(hlbc::Error::InvalidReference {
+                        kind: "string",
+                        index: s.0,
+                    })
+            }
+        }
+    }
+
+    pub fn debug_files(&self) -> Option<&[Str]> {
+        match self {
+            Program::Lazy { lazy, .. } => lazy.debug_files(),
+            Program::Full(code) => code.debug_files.as_deref(),
+        }
+    }
+
+    pub fn ntypes(&self) -> usize {
+        match self {
+            Program::Lazy { lazy, .. } => lazy.types().len(),
+            Program::Full(code) => code.types.len(),
+        }
+    }
+
+    pub fn nglobals(&self) -> usize {
+        match self {
+            Program::Lazy { lazy, .. } => lazy.globals().len(),
+            Program::Full(code) => code.globals.len(),
+        }
+    }
+
+    pub fn nnatives(&self) -> usize {
+        match self {
+            Program::Lazy { lazy, .. } => lazy.natives().len(),
+            Program::Full(code) => code.natives.len(),
+        }
+    }
+
+    pub fn nfunctions(&self) -> usize {
+        match self {
+            Program::Lazy { lazy, .. } => lazy.nfunctions(),
+            Program::Full(code) => code.functions.len(),
+        }
+    }
+
+    pub fn nconstants(&self) -> usize {
+        match self {
+            Program::Lazy { lazy, .. } => lazy.constants().map_or(0, |c| c.len()),
+            Program::Full(code) => code.constants.as_ref().map_or(0, |c| c.len()),
+        }
+    }
+
+    /// Number of findexes, functions and natives share the same index space
+    pub fn findex_max(&self) -> usize {
+        match self {
+            Program::Lazy { lazy, .. } => lazy.natives().len() + lazy.nfunctions(),
+            Program::Full(code) => code.findex_max(),
+        }
+    }
+}
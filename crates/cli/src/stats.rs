@@ -0,0 +1,159 @@
+use hlbc::analysis::stats::{Count, Distribution, Stats};
+
+use crate::command::{StatsFormat, StatsSection};
+
+/// Number of functions listed in the text report
+const LARGEST_FUNCTIONS: usize = 20;
+
+const SECTIONS: [StatsSection; 5] = [
+    StatsSection::Opcodes,
+    StatsSection::Functions,
+    StatsSection::Files,
+    StatsSection::Natives,
+    StatsSection::Types,
+];
+
+pub fn print_stats(
+    stats: &Stats,
+    format: StatsFormat,
+    section: Option<StatsSection>,
+) -> anyhow::Result<()> {
+    match format {
+        StatsFormat::Text => {
+            for s in section
+                .map(|s| vec![s])
+                .unwrap_or_else(|| SECTIONS.to_vec())
+            {
+                print_text(stats, s);
+            }
+        }
+        StatsFormat::Json => {
+            #[cfg(feature = "export")]
+            {
+                let json = match section {
+                    None => serde_json::to_string_pretty(stats)?,
+                    Some(StatsSection::Opcodes) => serde_json::to_string_pretty(&stats.opcodes)?,
+                    Some(StatsSection::Functions) => {
+                        serde_json::to_string_pretty(&stats.functions)?
+                    }
+                    Some(StatsSection::Files) => serde_json::to_string_pretty(&stats.files)?,
+                    Some(StatsSection::Natives) => serde_json::to_string_pretty(&stats.natives)?,
+                    Some(StatsSection::Types) => serde_json::to_string_pretty(&stats.types)?,
+                };
+                println!("{json}");
+            }
+
+            #[cfg(not(feature = "export"))]
+            println!("hlbc-cli has been built without export support. Build with feature 'export' to enable json output");
+        }
+        // A single table, the functions by default
+        StatsFormat::Csv => print_csv(stats, section.unwrap_or(StatsSection::Functions)),
+    }
+    Ok(())
+}
+
+fn print_text(stats: &Stats, section: StatsSection) {
+    match section {
+        StatsSection::Opcodes => {
+            let total: usize = stats.opcodes.iter().map(|c| c.count).sum();
+            println!("Opcodes ({total} instructions) :");
+            for c in &stats.opcodes {
+                println!(
+                    "  {:<16} {:>8} {:>6.2}%",
+                    c.name,
+                    c.count,
+                    c.count as f64 * 100.0 / total as f64
+                );
+            }
+        }
+        StatsSection::Functions => {
+            println!("Functions ({}) :", stats.functions.len());
+            print_distribution("instructions", &stats.ops);
+            print_distribution("registers", &stats.regs);
+            println!("  Largest functions :");
+            for f in stats.largest_functions(LARGEST_FUNCTIONS) {
+                println!(
+                    "    {}@{} : {} instructions, {} registers",
+                    f.name, f.findex.0, f.ops, f.regs
+                );
+            }
+        }
+        StatsSection::Files => {
+            println!("Files ({}) :", stats.files.len());
+            for f in &stats.files {
+                println!(
+                    "  {} : {} functions, {} instructions",
+                    f.file, f.functions, f.ops
+                );
+            }
+        }
+        StatsSection::Natives => {
+            println!("Native libraries ({}) :", stats.natives.len());
+            for n in &stats.natives {
+                println!("  {} : {} natives, {} calls", n.lib, n.natives, n.calls);
+            }
+        }
+        StatsSection::Types => {
+            let total: usize = stats.types.iter().map(|c| c.count).sum();
+            println!("Types ({total}) :");
+            for c in &stats.types {
+                println!("  {:<10} {:>8}", c.name, c.count);
+            }
+        }
+    }
+    println!();
+}
+
+fn print_distribution(name: &str, d: &Distribution) {
+    println!(
+        "  {name} : min {}, max {}, mean {:.1}, median {}, total {}",
+        d.min, d.max, d.mean, d.median, d.total
+    );
+}
+
+fn print_csv(stats: &Stats, section: StatsSection) {
+    let counts = |header: &str, counts: &[Count]| {
+        println!("{header},count");
+        for c in counts {
+            println!("{},{}", c.name, c.count);
+        }
+    };
+    match section {
+        StatsSection::Opcodes => counts("opcode", &stats.opcodes),
+        StatsSection::Functions => {
+            println!("findex,name,ops,regs,file");
+            for f in &stats.functions {
+                println!(
+                    "{},{},{},{},{}",
+                    f.findex.0,
+                    csv_field(&f.name),
+                    f.ops,
+                    f.regs,
+                    f.file.as_deref().map(csv_field).unwrap_or_default()
+                );
+            }
+        }
+        StatsSection::Files => {
+            println!("file,functions,ops");
+            for f in &stats.files {
+                println!("{},{},{}", csv_field(&f.file), f.functions, f.ops);
+            }
+        }
+        StatsSection::Natives => {
+            println!("lib,natives,calls");
+            for n in &stats.natives {
+                println!("{},{},{}", csv_field(&n.lib), n.natives, n.calls);
+            }
+        }
+        StatsSection::Types => counts("category", &stats.types),
+    }
+}
+
+/// Quote a field if needed
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
@@ -1,139 +1,146 @@
-use std::collections::{HashMap, HashSet};
-use std::fmt::Display;
-
+use hlbc::analysis::cfg::{Cfg, EdgeKind};
 use hlbc::opcodes::Opcode;
 use hlbc::types::Function;
-use hlbc::Bytecode;
 
-#[derive(Debug, Clone)]
+/// How control leaves a basic block, targets are block indexes
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub enum ControlFlow {
-    Branch(usize, usize),
+    /// Conditional jump, to `jump` when taken and to `next` otherwise
+    Branch {
+        jump: usize,
+        next: usize,
+    },
     Jump(usize),
-    Return,
+    /// Switch values and their target, out of range values going to `default`
+    Switch {
+        cases: Vec<(usize, usize)>,
+        default: Option<usize>,
+    },
+    /// Start of a try block in `body`, with exceptions caught by `handler`
+    Trap {
+        body: usize,
+        handler: usize,
+    },
+    /// Return, throw or end of the function
+    Exit,
 }
 
 #[derive(Debug, Clone)]
 pub struct BasicBlock<'a> {
-    start: usize,
-    end: usize,
-    ops: &'a [Opcode],
-    cf: ControlFlow,
+    /// Position of the first instruction
+    pub start: usize,
+    pub ops: &'a [Opcode],
+    pub cf: ControlFlow,
 }
 
-impl<'a> BasicBlock<'a> {
-    fn is_end(&self) -> bool {
-        matches!(self.ops.last(), Some(Opcode::Ret { .. }))
+impl BasicBlock<'_> {
+    /// Instructions of the block except the last one if it's a jump
+    pub fn body(&self) -> impl Iterator<Item = (usize, &Opcode)> + '_ {
+        let len = match self.ops.last() {
+            Some(o) if is_jump(o) => self.ops.len() - 1,
+            _ => self.ops.len(),
+        };
+        self.ops[..len]
+            .iter()
+            .enumerate()
+            .map(move |(i, o)| (self.start + i, o))
+    }
+
+    pub fn successors(&self) -> Vec<usize> {
+        match &self.cf {
+            ControlFlow::Branch { jump, next } => vec![*jump, *next],
+            ControlFlow::Jump(to) => vec![*to],
+            ControlFlow::Switch { cases, default } => {
+                let mut succs: Vec<usize> = cases.iter().map(|&(_, to)| to).collect();
+                succs.extend(default);
+                succs.sort_unstable();
+                succs.dedup();
+                succs
+            }
+            ControlFlow::Trap { body, handler } => vec![*body, *handler],
+            ControlFlow::Exit => Vec::new(),
+        }
     }
 }
 
+/// Jumps, switch and trap
+fn is_jump(o: &Opcode) -> bool {
+    matches!(
+        o,
+        Opcode::JTrue { .. }
+            | Opcode::JFalse { .. }
+            | Opcode::JNull { .. }
+            | Opcode::JNotNull { .. }
+            | Opcode::JSLt { .. }
+            | Opcode::JSGte { .. }
+            | Opcode::JSGt { .. }
+            | Opcode::JSLte { .. }
+            | Opcode::JULt { .. }
+            | Opcode::JUGte { .. }
+            | Opcode::JNotLt { .. }
+            | Opcode::JNotGte { .. }
+            | Opcode::JEq { .. }
+            | Opcode::JNotEq { .. }
+            | Opcode::JAlways { .. }
+            | Opcode::Switch { .. }
+            | Opcode::Trap { .. }
+    )
+}
+
+/// Basic blocks of a function, in order of position, the first one being the entry.
+/// Exception edges are replaced by an edge from the block starting the try to the handler,
+/// so the whole try block is dominated by it.
 #[derive(Debug)]
-pub struct BasicBlocks<'a>(pub HashMap<usize, BasicBlock<'a>>);
+pub struct BasicBlocks<'a> {
+    pub blocks: Vec<BasicBlock<'a>>,
+}
 
 impl<'a> BasicBlocks<'a> {
     pub fn new(f: &'a Function) -> BasicBlocks<'a> {
-        let mut start = 0;
-        let mut blocks = HashMap::new();
-        let mut targets = HashSet::new();
-
-        for (i, o) in f.ops.iter().enumerate() {
-            macro_rules! process_branch {
-                ($offset:ident) => {
-                    blocks.insert(
-                        start,
-                        BasicBlock {
-                            start,
-                            end: i,
-                            ops: &f.ops[start..=i],
-                            cf: ControlFlow::Branch(i + $offset as usize + 1, i + 1),
-                        },
-                    );
-                    start = i + 1;
-                    targets.insert(i + $offset as usize);
+        let cfg = Cfg::new(f);
+        let blocks = cfg
+            .blocks
+            .iter()
+            .map(|b| {
+                let edge = |kind| b.succs.iter().find(|e| e.kind == kind).map(|e| e.to);
+                let next = edge(EdgeKind::Next);
+                let cf = match (&f.ops[b.last()], edge(EdgeKind::Jump), next) {
+                    (Opcode::Switch { .. }, _, default) => ControlFlow::Switch {
+                        cases: b
+                            .succs
+                            .iter()
+                            .filter_map(|e| match e.kind {
+                                EdgeKind::Case(i) => Some((i, e.to)),
+                                _ => None,
+                            })
+                            .collect(),
+                        default,
+                    },
+                    (&Opcode::Trap { offset, .. }, _, Some(body)) => {
+                        match cfg.block_of((b.last() as i64 + offset as i64 + 1) as usize) {
+                            Some(handler) if offset > 0 => ControlFlow::Trap { body, handler },
+                            _ => ControlFlow::Jump(body),
+                        }
+                    }
+                    (_, Some(jump), Some(next)) if jump != next => {
+                        ControlFlow::Branch { jump, next }
+                    }
+                    (_, Some(to), _) | (_, None, Some(to)) => ControlFlow::Jump(to),
+                    (_, None, None) => ControlFlow::Exit,
                 };
-            }
-
-            match o {
-                &Opcode::JTrue { cond, offset } => {
-                    process_branch!(offset);
-                }
-                &Opcode::JFalse { cond, offset } => {
-                    process_branch!(offset);
-                }
-                &Opcode::JNull { reg, offset } => {
-                    process_branch!(offset);
-                }
-                &Opcode::JNotNull { reg, offset } => {
-                    process_branch!(offset);
-                }
-                &Opcode::JSLt { a, b, offset } | &Opcode::JULt { a, b, offset } => {
-                    process_branch!(offset);
-                }
-                &Opcode::JSGte { a, b, offset } | &Opcode::JUGte { a, b, offset } => {
-                    process_branch!(offset);
-                }
-                &Opcode::JSGt { a, b, offset } => {
-                    process_branch!(offset);
-                }
-                &Opcode::JSLte { a, b, offset } => {
-                    process_branch!(offset);
-                }
-                &Opcode::JEq { a, b, offset } => {
-                    process_branch!(offset);
+                BasicBlock {
+                    start: b.start,
+                    ops: &f.ops[b.ops()],
+                    cf,
                 }
-                &Opcode::JNotEq { a, b, offset } => {
-                    process_branch!(offset);
-                }
-                &Opcode::JAlways { offset } => {
-                    blocks.insert(
-                        start,
-                        BasicBlock {
-                            start,
-                            end: i,
-                            ops: &f.ops[start..=i],
-                            cf: ControlFlow::Jump(i + offset as usize + 1),
-                        },
-                    );
-                    start = i + 1;
-                    targets.insert(i + offset as usize);
-                }
-                &Opcode::Ret { ret } => {
-                    blocks.insert(
-                        start,
-                        BasicBlock {
-                            start,
-                            end: i,
-                            ops: &f.ops[start..=i],
-                            cf: ControlFlow::Return,
-                        },
-                    );
-                    start = i + 1;
-                }
-                _ => {}
-            }
-            if targets.remove(&i) && start <= i {
-                blocks.insert(
-                    start,
-                    BasicBlock {
-                        start,
-                        end: i,
-                        ops: &f.ops[start..=i],
-                        cf: ControlFlow::Jump(i + 1),
-                    },
-                );
-                start = i + 1;
-            }
-        }
-        Self(blocks)
+            })
+            .collect();
+        Self { blocks }
     }
-}
 
-impl BasicBlock<'_> {
-    pub fn display<'a>(&'a self, ctx: &'a Bytecode, f: &'a Function) -> impl Display + 'a {
-        fmtools::fmt! {
-            for (i, o) in self.ops.iter().enumerate() {
-                {o.display(ctx, f, (self.start + i) as i32, 0)}"\n"
-            }
-        }
+    /// Successors of each block
+    pub fn successors(&self) -> Vec<Vec<usize>> {
+        self.blocks.iter().map(BasicBlock::successors).collect()
     }
 }
 
@@ -153,44 +160,57 @@ mod graph {
 
     use crate::alt::bb::{BasicBlock, BasicBlocks, ControlFlow};
 
+    impl BasicBlock<'_> {
+        pub fn display<'a>(&'a self, ctx: &'a Bytecode, f: &'a Function) -> impl Display + 'a {
+            fmtools::fmt! {
+                for (i, o) in self.ops.iter().enumerate() {
+                    {o.display(ctx, f, (self.start + i) as i32, 0)}"\n"
+                }
+            }
+        }
+    }
+
     impl BasicBlocks<'_> {
         pub fn make_graph(&self, with_end: bool) -> BlockGraph {
-            let mut graph = DiGraphMap::with_capacity(self.0.len() + 2, self.0.len() * 2);
+            let mut graph = DiGraphMap::with_capacity(self.blocks.len() + 2, self.blocks.len() * 2);
 
             graph.add_node(GraphNode::Start);
             if with_end {
                 graph.add_node(GraphNode::End);
             }
-            for bb in self.0.values() {
+            for bb in &self.blocks {
                 graph.add_node(GraphNode::Block(bb));
             }
-            graph.add_edge(
-                GraphNode::Start,
-                GraphNode::Block(self.0.get(&0).unwrap()),
-                "",
-            );
-            for bb in self.0.values() {
+            if let Some(entry) = self.blocks.first() {
+                graph.add_edge(GraphNode::Start, GraphNode::Block(entry), "");
+            }
+            for bb in &self.blocks {
+                let mut edge = |to: usize, label| {
+                    graph.add_edge(
+                        GraphNode::Block(bb),
+                        GraphNode::Block(&self.blocks[to]),
+                        label,
+                    );
+                };
                 match &bb.cf {
-                    ControlFlow::Branch(a, b) => {
-                        graph.add_edge(
-                            GraphNode::Block(bb),
-                            GraphNode::Block(self.0.get(a).unwrap()),
-                            "true",
-                        );
-                        graph.add_edge(
-                            GraphNode::Block(bb),
-                            GraphNode::Block(self.0.get(b).unwrap()),
-                            "false",
-                        );
+                    ControlFlow::Branch { jump, next } => {
+                        edge(*jump, "true");
+                        edge(*next, "false");
+                    }
+                    ControlFlow::Jump(to) => edge(*to, ""),
+                    ControlFlow::Switch { cases, default } => {
+                        for &(_, to) in cases {
+                            edge(to, "case");
+                        }
+                        if let Some(default) = default {
+                            edge(*default, "default");
+                        }
                     }
-                    ControlFlow::Jump(a) => {
-                        graph.add_edge(
-                            GraphNode::Block(bb),
-                            GraphNode::Block(self.0.get(a).unwrap()),
-                            "",
-                        );
+                    ControlFlow::Trap { body, handler } => {
+                        edge(*body, "try");
+                        edge(*handler, "catch");
                     }
-                    ControlFlow::Return => {
+                    ControlFlow::Exit => {
                         if with_end {
                             graph.add_edge(GraphNode::Block(bb), GraphNode::End, "");
                         }
@@ -1,18 +1,1581 @@
-use crate::alt::bb::{BasicBlock, BasicBlocks};
-use std::collections::HashSet;
+//! Control flow structuring on the basic blocks, adapted from
+//! ["Beyond Relooper"](https://dl.acm.org/doi/10.1145/3547621) (Norman Ramsey).
+//!
+//! Code is generated by walking the dominator tree. A block with multiple predecessors (a merge) is
+//! placed right after a labelled block wrapping the code of its immediate dominator, so jumping to it
+//! is a `break`. A loop header is wrapped in a `while (true)`, jumping back to it is a `continue`, and
+//! the exits of the loop are placed after it. The blocks targeted by the remaining jumps (irreducible
+//! control flow) are entries of a loop switching on a state variable, a jump assigning the state.
+//!
+//! The result is then simplified to remove most labels and jumps, and to recover loop conditions,
+//! else branches and short-circuit conditions. Haxe has no labels, the remaining ones are lowered to
+//! flags checked after the nested statements.
 
-struct FlowBlock {}
+use std::collections::HashMap;
 
-fn process(blocks: BasicBlocks<'_>) {
-    //let mut processed = HashSet::with_capacity(blocks.0.len());
-    let mut to_explore = 0;
-    while to_explore >= 0 {
-        let block = blocks.0.get(&to_explore).expect("Invalid graph");
+use hlbc::analysis::cfg::{reverse_postorder, Dominators};
+use hlbc::opcodes::Opcode;
+use hlbc::types::Reg;
+use hlbc::Str;
+
+use crate::alt::bb::{BasicBlocks, ControlFlow};
+use crate::ast::*;
+use crate::{push_op, DecompilerState};
+
+/// Syntax enclosing the code being generated
+enum Context {
+    /// Body of a loop
+    Loop { header: usize, label: usize },
+    /// Labelled block followed by the code of a block
+    Block { follow: usize, label: usize },
+}
+
+struct Structurer<'b, 'c> {
+    state: DecompilerState<'c>,
+    blocks: &'b BasicBlocks<'c>,
+    /// Index in reverse postorder of each block, [usize::MAX] if unreachable
+    rpo: Vec<usize>,
+    /// Children of each block in the dominator tree, in reverse postorder
+    children: Vec<Vec<usize>>,
+    /// Blocks with multiple predecessors, not counting back edges
+    merge: Vec<bool>,
+    loop_header: Vec<bool>,
+    /// Blocks placed after each loop, in reverse postorder
+    loop_exits: Vec<Vec<usize>>,
+    /// Blocks placed after a loop instead of after their immediate dominator
+    hoisted: Vec<bool>,
+    /// State of the blocks reached through the dispatch loop, the entry block being the state 0
+    entries: HashMap<usize, usize>,
+    /// Blocks we found no structured jump to, the code is generated again with them as entries
+    new_entries: Vec<usize>,
+    /// Variable holding the state of the dispatch loop
+    state_var: Expr,
+    /// Label of the dispatch loop
+    dispatch: usize,
+    next_label: usize,
+}
+
+impl<'b, 'c> Structurer<'b, 'c> {
+    fn new(state: DecompilerState<'c>, blocks: &'b BasicBlocks<'c>, entries: &[usize]) -> Self {
+        let n = blocks.blocks.len();
+        let mut succs = blocks.successors();
+        let extra = try_exits(blocks, &succs);
+        for &(a, b) in &extra {
+            if !succs[a].contains(&b) {
+                succs[a].push(b);
+            }
+        }
+        // Visiting the farthest successors first gives an order close to the instructions order
+        let mut dfs_succs = succs.clone();
+        for s in &mut dfs_succs {
+            s.sort_unstable_by(|a, b| b.cmp(a));
+        }
+        let order = reverse_postorder(&dfs_succs, &[0]);
+        let mut rpo = vec![usize::MAX; n];
+        for (i, &b) in order.iter().enumerate() {
+            rpo[b] = i;
+        }
+        let doms = Dominators::new(&succs, &[0]);
+
+        let mut preds = vec![Vec::new(); n];
+        for &b in &order {
+            for &s in &succs[b] {
+                if !preds[s].contains(&b) {
+                    preds[s].push(b);
+                }
+            }
+        }
+
+        let mut merge = vec![false; n];
+        let mut loop_header = vec![false; n];
+        let mut labelled: Vec<usize> = entries.to_vec();
+        // Header, blocks and size of each loop
+        let mut loops: Vec<(usize, Vec<bool>, usize)> = Vec::new();
+        for &b in &order {
+            let forward = preds[b].iter().filter(|&&p| rpo[p] < rpo[b]).count();
+            // There is no code to inline a block at the end of an edge we added
+            merge[b] = forward >= 2 || extra.iter().any(|&(a, x)| x == b && rpo[a] < rpo[b]);
+            let latches: Vec<usize> = preds[b]
+                .iter()
+                .copied()
+                .filter(|&p| rpo[p] >= rpo[b])
+                .collect();
+            let mut body = vec![false; n];
+            body[b] = true;
+            for &latch in &latches {
+                if !doms.dominates(b, latch) {
+                    // Irreducible, no structure for this
+                    if b != 0 && !labelled.contains(&b) {
+                        labelled.push(b);
+                    }
+                    continue;
+                }
+                loop_header[b] = true;
+                let mut stack = vec![latch];
+                while let Some(x) = stack.pop() {
+                    if !body[x] {
+                        body[x] = true;
+                        stack.extend(preds[x].iter().copied());
+                    }
+                }
+            }
+            if loop_header[b] {
+                let size = body.iter().filter(|&&x| x).count();
+                loops.push((b, body, size));
+            }
+        }
+
+        let mut entries = HashMap::new();
+        if !labelled.is_empty() {
+            entries.insert(0, 0);
+        }
+        for b in labelled {
+            if !entries.contains_key(&b) {
+                entries.insert(b, entries.len());
+            }
+        }
+
+        let mut children = vec![Vec::new(); n];
+        for &b in &order {
+            if let Some(d) = doms.immediate_dominator(b) {
+                children[d].push(b);
+            }
+        }
+
+        // Exits of a loop are placed after the outermost loop they leave, unless they are only a
+        // few statements ending with a jump, like a return or a break
+        let mut loop_exits = vec![Vec::new(); n];
+        let mut hoisted = vec![false; n];
+        for &y in &order {
+            let d = match doms.immediate_dominator(y) {
+                Some(d) if !entries.contains_key(&y) => d,
+                _ => continue,
+            };
+            let outermost = loops
+                .iter()
+                .filter(|(_, body, _)| body[d] && !body[y])
+                .max_by_key(|&&(_, _, size)| size);
+            if let Some(&(header, _, _)) = outermost {
+                if merge[y] || succs[header].contains(&y) || !children[y].is_empty() {
+                    loop_exits[header].push(y);
+                    hoisted[y] = true;
+                }
+            }
+        }
+
+        let state_var = Expr::Variable(Reg(state.f.regs.len() as u32), Some(Str::from("state")));
+        Self {
+            state,
+            blocks,
+            rpo,
+            children,
+            merge,
+            loop_header,
+            loop_exits,
+            hoisted,
+            entries,
+            new_entries: Vec::new(),
+            state_var,
+            dispatch: 0,
+            next_label: 1,
+        }
+    }
+
+    /// Code of the function, the entries being the cases of the dispatch loop
+    fn code(&mut self) -> Vec<Statement> {
+        if self.entries.is_empty() {
+            return self.do_tree(0, &mut Vec::new());
+        }
+        let mut entries: Vec<(usize, usize)> = self.entries.iter().map(|(&b, &s)| (s, b)).collect();
+        entries.sort_unstable();
+        let mut cases: Vec<(Vec<Expr>, Vec<Statement>)> = entries
+            .into_iter()
+            .map(|(state, b)| {
+                (
+                    vec![Expr::Constant(Constant::InlineInt(state))],
+                    self.do_tree(b, &mut Vec::new()),
+                )
+            })
+            .collect();
+        // Variables are shared by the cases, they are declared before the loop
+        let mut vars = Vec::new();
+        for (_, stmts) in &mut cases {
+            undeclare(stmts, &mut vars);
+        }
+        let f = self.state.f;
+        let mut stmts: Vec<Statement> = vars
+            .into_iter()
+            .map(|(reg, variable)| Statement::Declare {
+                variable,
+                ty: f.regtype(reg),
+            })
+            .collect();
+        stmts.push(Statement::Assign {
+            declaration: true,
+            variable: self.state_var.clone(),
+            assign: Expr::Constant(Constant::InlineInt(0)),
+        });
+        stmts.push(Statement::Labelled {
+            label: self.dispatch,
+            stmts: vec![Statement::While {
+                cond: cst_bool(true),
+                stmts: vec![Statement::Switch {
+                    arg: self.state_var.clone(),
+                    default: Vec::new(),
+                    cases,
+                }],
+            }],
+        });
+        stmts
+    }
+
+    fn new_label(&mut self) -> usize {
+        self.next_label += 1;
+        self.next_label - 1
+    }
+
+    /// Code of a block and of the blocks it dominates
+    fn do_tree(&mut self, x: usize, ctx: &mut Vec<Context>) -> Vec<Statement> {
+        let merges: Vec<usize> = self.children[x]
+            .iter()
+            .copied()
+            .filter(|&y| self.merge[y] && !self.hoisted[y] && !self.entries.contains_key(&y))
+            .collect();
+        if self.loop_header[x] {
+            let exits = self.loop_exits[x].clone();
+            let exit_labels: Vec<usize> = exits.iter().map(|_| self.new_label()).collect();
+            // The last exit is the outermost block
+            for (&follow, &label) in exits.iter().zip(&exit_labels).rev() {
+                ctx.push(Context::Block { follow, label });
+            }
+            let label = self.new_label();
+            ctx.push(Context::Loop { header: x, label });
+            let body = self.node_within(x, &merges, ctx);
+            ctx.pop();
+            let mut stmts = vec![Statement::Labelled {
+                label,
+                stmts: vec![Statement::While {
+                    cond: cst_bool(true),
+                    stmts: body,
+                }],
+            }];
+            for (&follow, &label) in exits.iter().zip(&exit_labels) {
+                ctx.pop();
+                stmts = vec![Statement::Labelled { label, stmts }];
+                stmts.extend(self.do_tree(follow, ctx));
+            }
+            stmts
+        } else {
+            self.node_within(x, &merges, ctx)
+        }
+    }
+
+    /// Code of a block, nested in labelled blocks followed by the merges it dominates
+    fn node_within(
+        &mut self,
+        x: usize,
+        merges: &[usize],
+        ctx: &mut Vec<Context>,
+    ) -> Vec<Statement> {
+        match merges.split_last() {
+            // The last merge in reverse postorder is the outermost
+            Some((&follow, inner)) => {
+                let label = self.new_label();
+                ctx.push(Context::Block { follow, label });
+                let stmts = self.node_within(x, inner, ctx);
+                ctx.pop();
+                let mut stmts = vec![Statement::Labelled { label, stmts }];
+                stmts.extend(self.do_tree(follow, ctx));
+                stmts
+            }
+            None => self.block_code(x, ctx),
+        }
+    }
+
+    /// Statements of a block followed by its control flow
+    fn block_code(&mut self, x: usize, ctx: &mut Vec<Context>) -> Vec<Statement> {
+        let blocks = self.blocks;
+        let block = &blocks.blocks[x];
+        let f = self.state.f;
+        for (i, o) in block.body() {
+            match o {
+                &Opcode::Ret { ret } => {
+                    let value = if f.regtype(ret).is_void() {
+                        None
+                    } else {
+                        Some(self.state.expr(ret))
+                    };
+                    self.state.push_stmt(Statement::Return(value));
+                }
+                _ => push_op(&mut self.state, i, o),
+            }
+        }
+        let mut stmts = self.state.scopes.take_stmts();
+        match &block.cf {
+            &ControlFlow::Branch { jump, next } => {
+                let cond = match block.ops.last() {
+                    Some(o) => jump_cond(&self.state, o),
+                    None => Expr::Unknown("no condition".to_owned()),
+                };
+                let if_ = self.branch(x, next, ctx);
+                let else_ = self.branch(x, jump, ctx);
+                stmts.push(Statement::IfElse {
+                    cond: not(cond),
+                    if_,
+                    else_,
+                });
+            }
+            &ControlFlow::Jump(to) => stmts.extend(self.branch(x, to, ctx)),
+            ControlFlow::Switch { cases, default } => {
+                let arg = match block.ops.last() {
+                    Some(&Opcode::Switch { reg, .. }) => self.state.expr(reg),
+                    _ => Expr::Unknown("no switch".to_owned()),
+                };
+                // Values going to the same place are grouped in a single case
+                let mut groups: Vec<(Vec<Expr>, usize)> = Vec::new();
+                for &(value, to) in cases {
+                    if Some(to) == *default {
+                        continue;
+                    }
+                    let pattern = Expr::Constant(Constant::InlineInt(value));
+                    match groups.iter_mut().find(|(_, t)| *t == to) {
+                        Some((patterns, _)) => patterns.push(pattern),
+                        None => groups.push((vec![pattern], to)),
+                    }
+                }
+                // The default case comes first in the instructions
+                let default = match default {
+                    Some(default) => self.branch(x, *default, ctx),
+                    None => Vec::new(),
+                };
+                let cases = groups
+                    .into_iter()
+                    .map(|(patterns, to)| (patterns, self.branch(x, to, ctx)))
+                    .collect();
+                stmts.push(Statement::Switch {
+                    arg,
+                    default,
+                    cases,
+                });
+            }
+            &ControlFlow::Trap { body, handler } => {
+                let try_ = self.branch(x, body, ctx);
+                // The exception is stored in a register when entering the handler
+                let exc = match block.ops.last() {
+                    Some(&Opcode::Trap { exc, .. }) => {
+                        let pos = block.start + block.ops.len() - 1;
+                        let var = Expr::Variable(exc, f.var_name(self.state.code, pos));
+                        self.state.reg_state.insert(exc, var.clone());
+                        var
+                    }
+                    _ => Expr::Unknown("no trap".to_owned()),
+                };
+                let catch = self.branch(x, handler, ctx);
+                stmts.push(Statement::Try { stmts: try_ });
+                stmts.push(Statement::Catch { exc, stmts: catch });
+            }
+            ControlFlow::Exit => {}
+        }
+        stmts
+    }
+
+    /// Code going from a block to another, either a jump or the code of the target if it's dominated
+    fn branch(&mut self, from: usize, to: usize, ctx: &mut Vec<Context>) -> Vec<Statement> {
+        let backward = self.rpo[to] <= self.rpo[from];
+        if !backward && !self.merge[to] && !self.hoisted[to] && !self.entries.contains_key(&to) {
+            return self.do_tree(to, ctx);
+        }
+        for c in ctx.iter().rev() {
+            match *c {
+                Context::Loop { header, label } if backward && header == to => {
+                    return vec![Statement::ContinueLabel(label)];
+                }
+                Context::Block { follow, label } if !backward && follow == to => {
+                    return vec![Statement::BreakLabel(label)];
+                }
+                _ => {}
+            }
+        }
+        let state = match self.entries.get(&to) {
+            Some(&state) => state,
+            None => {
+                self.new_entries.push(to);
+                0
+            }
+        };
+        vec![
+            Statement::Assign {
+                declaration: false,
+                variable: self.state_var.clone(),
+                assign: Expr::Constant(Constant::InlineInt(state)),
+            },
+            Statement::ContinueLabel(self.dispatch),
+        ]
+    }
+}
+
+/// Edges from each trap to the blocks following its try block.
+/// The code after a try block is then dominated by the trap, and placed after the catch block instead of inside the try block.
+fn try_exits(blocks: &BasicBlocks, succs: &[Vec<usize>]) -> Vec<(usize, usize)> {
+    let doms = Dominators::new(succs, &[0]);
+    let traps: Vec<(usize, usize, Reg)> = blocks
+        .blocks
+        .iter()
+        .enumerate()
+        .filter_map(|(b, block)| match (&block.cf, block.ops.last()) {
+            (&ControlFlow::Trap { body, .. }, Some(&Opcode::Trap { exc, .. })) => {
+                Some((b, body, exc))
+            }
+            _ => None,
+        })
+        .collect();
+    let mut edges = Vec::new();
+    for (b, block) in blocks.blocks.iter().enumerate() {
+        for o in block.ops {
+            if let &Opcode::EndTrap { exc } = o {
+                // The innermost try block containing this one
+                let trap = traps
+                    .iter()
+                    .filter(|&&(_, body, e)| e == exc && doms.dominates(body, b))
+                    .max_by_key(|&&(_, body, _)| doms.dominators(body).count());
+                if let Some(&(trap, _, _)) = trap {
+                    edges.extend(succs[b].iter().map(|&s| (trap, s)));
+                }
+            }
+        }
+    }
+    edges
+}
+
+/// Negate a condition, distributing over `&&` and `||`
+fn negate(e: Expr) -> Expr {
+    match e {
+        Expr::Op(Operation::And(a, b)) => or(negate(*a), negate(*b)),
+        Expr::Op(Operation::Or(a, b)) => and(negate(*a), negate(*b)),
+        e => not(e),
+    }
+}
+
+/// Condition for a conditional jump to be taken
+fn jump_cond(state: &DecompilerState, o: &Opcode) -> Expr {
+    match *o {
+        Opcode::JTrue { cond, .. } => state.expr(cond),
+        Opcode::JFalse { cond, .. } => not(state.expr(cond)),
+        Opcode::JNull { reg, .. } => eq(state.expr(reg), cst_null()),
+        Opcode::JNotNull { reg, .. } => noteq(state.expr(reg), cst_null()),
+        Opcode::JSLt { a, b, .. } => lt(state.expr(a), state.expr(b)),
+        Opcode::JSGte { a, b, .. } => gte(state.expr(a), state.expr(b)),
+        Opcode::JULt { a, b, .. } => lt(unsigned(state.expr(a)), unsigned(state.expr(b))),
+        Opcode::JUGte { a, b, .. } => gte(unsigned(state.expr(a)), unsigned(state.expr(b))),
+        Opcode::JSGt { a, b, .. } => gt(state.expr(a), state.expr(b)),
+        Opcode::JSLte { a, b, .. } => lte(state.expr(a), state.expr(b)),
+        Opcode::JNotLt { a, b, .. } => not(lt(state.expr(a), state.expr(b))),
+        Opcode::JNotGte { a, b, .. } => not(gte(state.expr(a), state.expr(b))),
+        Opcode::JEq { a, b, .. } => eq(state.expr(a), state.expr(b)),
+        Opcode::JNotEq { a, b, .. } => noteq(state.expr(a), state.expr(b)),
+        _ => Expr::Unknown("no condition".to_owned()),
+    }
+}
+
+/// Decompile a function by structuring its control flow graph
+pub(crate) fn process(state: DecompilerState) -> Vec<Statement> {
+    let (code, f, structuring) = (state.code, state.f, state.structuring);
+    let blocks = BasicBlocks::new(f);
+    if blocks.blocks.is_empty() {
+        return Vec::new();
+    }
+    let mut state = Some(state);
+    let mut entries = Vec::new();
+    loop {
+        let state = state
+            .take()
+            .unwrap_or_else(|| DecompilerState::new(code, f, structuring));
+        let mut structurer = Structurer::new(state, &blocks, &entries);
+        let stmts = structurer.code();
+        // Start again with the blocks we couldn't jump to as entries
+        if structurer.new_entries.is_empty() {
+            // The state variable uses the first register after the ones of the function
+            return simplify(stmts, f.regs.len() as u32 + 1);
+        }
+        entries.extend(structurer.new_entries);
     }
 }
 
+/// Turn the declarations into assignments, returning the variables declared
+fn undeclare(stmts: &mut [Statement], vars: &mut Vec<(Reg, Expr)>) {
+    for stmt in stmts {
+        if let Statement::Assign {
+            declaration: declaration @ true,
+            variable: variable @ Expr::Variable(..),
+            ..
+        } = stmt
+        {
+            *declaration = false;
+            if let Expr::Variable(reg, _) = variable {
+                if !vars.iter().any(|(r, _)| r == reg) {
+                    vars.push((*reg, variable.clone()));
+                }
+            }
+        }
+        for stmts in nested(stmt) {
+            undeclare(stmts, vars);
+        }
+    }
+}
+
+//region SIMPLIFICATION
+
+/// Mutable references to the statement lists nested in a statement
+fn nested(stmt: &mut Statement) -> Vec<&mut Vec<Statement>> {
+    match stmt {
+        Statement::IfElse { if_, else_, .. } => vec![if_, else_],
+        Statement::Switch { default, cases, .. } => {
+            let mut nested: Vec<_> = cases.iter_mut().map(|(_, stmts)| stmts).collect();
+            nested.push(default);
+            nested
+        }
+        Statement::While { stmts, .. }
+        | Statement::Labelled { stmts, .. }
+        | Statement::Try { stmts }
+        | Statement::Catch { stmts, .. } => vec![stmts],
+        _ => Vec::new(),
+    }
+}
+
+/// Is the statement at this position the last to be executed before leaving the list,
+/// a try block being followed by its catch block
+fn is_tail(stmts: &[Statement], i: usize) -> bool {
+    i + 1 == stmts.len()
+        || (i + 2 == stmts.len()
+            && matches!(stmts[i], Statement::Try { .. })
+            && matches!(stmts[i + 1], Statement::Catch { .. }))
+}
+
+/// Labels of the innermost loop
+#[derive(Default, Clone)]
+struct LoopLabels {
+    /// Labels of the blocks ending right after the loop
+    breaks: Vec<usize>,
+    continues: Vec<usize>,
+}
+
+/// Replace labelled jumps by simple `break` and `continue` when they apply to the innermost loop.
+/// `tail` are the labels of the blocks ending with this list, `own` the label of the block if the list is its only content.
+fn resolve_jumps(
+    stmts: &mut [Statement],
+    tail: &[usize],
+    own: Option<usize>,
+    loop_: Option<&LoopLabels>,
+) {
+    let len = stmts.len();
+    for i in 0..len {
+        let stmt_tail = if is_tail(stmts, i) { tail } else { &[] };
+        match &mut stmts[i] {
+            Statement::BreakLabel(label) => {
+                if loop_.map_or(false, |l| l.breaks.contains(label)) {
+                    stmts[i] = Statement::Break;
+                }
+            }
+            Statement::ContinueLabel(label) => {
+                if loop_.map_or(false, |l| l.continues.contains(label)) {
+                    stmts[i] = Statement::Continue;
+                }
+            }
+            Statement::Labelled { label, stmts } => {
+                let mut inner_tail = vec![*label];
+                inner_tail.extend_from_slice(stmt_tail);
+                let own = if stmts.len() == 1 { Some(*label) } else { None };
+                resolve_jumps(stmts, &inner_tail, own, loop_);
+            }
+            Statement::While { stmts, .. } => {
+                let labels = LoopLabels {
+                    breaks: stmt_tail.to_vec(),
+                    continues: own.into_iter().collect(),
+                };
+                resolve_jumps(stmts, &[], None, Some(&labels));
+            }
+            Statement::Try { stmts } => {
+                let try_tail = if i + 2 == len { tail } else { &[] };
+                resolve_jumps(stmts, try_tail, None, loop_);
+            }
+            stmt => {
+                for stmts in nested(stmt) {
+                    resolve_jumps(stmts, stmt_tail, None, loop_);
+                }
+            }
+        }
+    }
+}
+
+/// A single jump
+fn as_jump(stmts: &[Statement]) -> Option<&Statement> {
+    match stmts {
+        [jump @ (Statement::Break
+        | Statement::Continue
+        | Statement::BreakLabel(_)
+        | Statement::ContinueLabel(_)
+        | Statement::Return(None))] => Some(jump),
+        _ => None,
+    }
+}
+
+/// Both lists are the same single jump
+fn same_jumps(a: &[Statement], b: &[Statement]) -> bool {
+    match (as_jump(a), as_jump(b)) {
+        (Some(Statement::Break), Some(Statement::Break)) => true,
+        (Some(Statement::Continue), Some(Statement::Continue)) => true,
+        (Some(Statement::BreakLabel(a)), Some(Statement::BreakLabel(b))) => a == b,
+        (Some(Statement::ContinueLabel(a)), Some(Statement::ContinueLabel(b))) => a == b,
+        (Some(Statement::Return(None)), Some(Statement::Return(None))) => true,
+        _ => false,
+    }
+}
+
+/// Control never reaches the end of these statements
+fn ends_with_jump(stmts: &[Statement]) -> bool {
+    matches!(
+        stmts.last(),
+        Some(
+            Statement::Break
+                | Statement::Continue
+                | Statement::BreakLabel(_)
+                | Statement::ContinueLabel(_)
+                | Statement::Return(_)
+                | Statement::Throw(_)
+        )
+    )
+}
+
+/// Merge nested conditions sharing a branch which is a single jump :
+/// `if (a) { if (b) { x } else { jump } } else { jump }` becomes `if (a && b) { x } else { jump }`
+/// and `if (a) { jump } else { if (b) { jump } else { x } }` becomes `if (a || b) { jump } else { x }`
+fn short_circuit(stmts: &mut [Statement]) {
+    for stmt in stmts {
+        for stmts in nested(stmt) {
+            short_circuit(stmts);
+        }
+        while let Statement::IfElse { cond, if_, else_ } = stmt {
+            let merged = match (&if_[..], &else_[..]) {
+                (
+                    [Statement::IfElse {
+                        cond: inner,
+                        if_: inner_if,
+                        else_: inner_else,
+                    }],
+                    _,
+                ) => {
+                    if same_jumps(else_, inner_else) {
+                        Statement::IfElse {
+                            cond: and(cond.clone(), inner.clone()),
+                            if_: inner_if.clone(),
+                            else_: else_.clone(),
+                        }
+                    } else if same_jumps(else_, inner_if) {
+                        Statement::IfElse {
+                            cond: or(not(cond.clone()), inner.clone()),
+                            if_: else_.clone(),
+                            else_: inner_else.clone(),
+                        }
+                    } else {
+                        break;
+                    }
+                }
+                (
+                    _,
+                    [Statement::IfElse {
+                        cond: inner,
+                        if_: inner_if,
+                        else_: inner_else,
+                    }],
+                ) => {
+                    if same_jumps(if_, inner_if) {
+                        Statement::IfElse {
+                            cond: or(cond.clone(), inner.clone()),
+                            if_: if_.clone(),
+                            else_: inner_else.clone(),
+                        }
+                    } else if same_jumps(if_, inner_else) {
+                        Statement::IfElse {
+                            cond: or(cond.clone(), not(inner.clone())),
+                            if_: if_.clone(),
+                            else_: inner_if.clone(),
+                        }
+                    } else {
+                        break;
+                    }
+                }
+                _ => break,
+            };
+            *stmt = merged;
+        }
+    }
+}
+
+/// Jumps equivalent to reaching the end of a list of statements
+#[derive(Default, Clone)]
+struct Exits {
+    labels: Vec<usize>,
+    continue_: bool,
+    return_: bool,
+}
+
+impl Exits {
+    fn contains(&self, stmt: &Statement) -> bool {
+        match stmt {
+            Statement::BreakLabel(label) => self.labels.contains(label),
+            Statement::Continue => self.continue_,
+            Statement::Return(None) => self.return_,
+            _ => false,
+        }
+    }
+}
+
+/// Remove the jumps going where control would go anyway, and turn
+/// `if (c) { a; break } b` into `if (c) { a } else { b }` when the break goes to the end of the list.
+fn strip_jumps(stmts: &mut Vec<Statement>, exits: &Exits) {
+    while stmts.last().map_or(false, |s| exits.contains(s)) {
+        stmts.pop();
+    }
+    let mut i = 0;
+    while i < stmts.len() {
+        if !is_tail(stmts, i) {
+            if let Statement::IfElse { if_, else_, .. } = &stmts[i] {
+                // A jump to the loop header is usually a continue in the source, keep it
+                let recover = |stmts: &[Statement]| {
+                    stmts.last().map_or(false, |s| {
+                        exits.contains(s) && !matches!(s, Statement::Continue)
+                    })
+                };
+                let exits_if = recover(if_);
+                let exits_else = recover(else_);
+                if exits_if || exits_else {
+                    let rest: Vec<Statement> = stmts.drain(i + 1..).collect();
+                    if let Statement::IfElse { if_, else_, .. } = &mut stmts[i] {
+                        let (exiting, other) = if exits_if { (if_, else_) } else { (else_, if_) };
+                        exiting.pop();
+                        other.extend(rest);
+                    }
+                }
+            }
+        }
+        let stmt_exits = if is_tail(stmts, i) {
+            exits.clone()
+        } else {
+            Exits::default()
+        };
+        match &mut stmts[i] {
+            Statement::Labelled { label, stmts } => {
+                let mut exits = stmt_exits;
+                exits.labels.push(*label);
+                strip_jumps(stmts, &exits);
+            }
+            Statement::While { stmts, .. } => {
+                strip_jumps(
+                    stmts,
+                    &Exits {
+                        continue_: true,
+                        ..Exits::default()
+                    },
+                );
+            }
+            stmt => {
+                for stmts in nested(stmt) {
+                    strip_jumps(stmts, &stmt_exits);
+                }
+            }
+        }
+        i += 1;
+    }
+}
+
+/// Count the jumps to each label
+fn count_labels(stmts: &[Statement], counts: &mut HashMap<usize, usize>) {
+    for stmt in stmts {
+        match stmt {
+            Statement::BreakLabel(label) | Statement::ContinueLabel(label) => {
+                *counts.entry(*label).or_insert(0) += 1
+            }
+            Statement::IfElse { if_, else_, .. } => {
+                count_labels(if_, counts);
+                count_labels(else_, counts);
+            }
+            Statement::Switch { default, cases, .. } => {
+                count_labels(default, counts);
+                for (_, stmts) in cases {
+                    count_labels(stmts, counts);
+                }
+            }
+            Statement::While { stmts, .. }
+            | Statement::Labelled { stmts, .. }
+            | Statement::Try { stmts }
+            | Statement::Catch { stmts, .. } => count_labels(stmts, counts),
+            _ => {}
+        }
+    }
+}
+
+/// Replace labelled blocks nobody jumps to by their content
+fn unwrap_labels(stmts: &mut Vec<Statement>, counts: &HashMap<usize, usize>) {
+    let mut i = 0;
+    while i < stmts.len() {
+        match &mut stmts[i] {
+            Statement::Labelled {
+                label,
+                stmts: inner,
+            } if !counts.contains_key(label) => {
+                let inner = std::mem::take(inner);
+                stmts.splice(i..=i, inner);
+                // Process the spliced statements
+                continue;
+            }
+            stmt => {
+                for stmts in nested(stmt) {
+                    unwrap_labels(stmts, counts);
+                }
+            }
+        }
+        i += 1;
+    }
+}
+
+/// Turn `while (true) { if (c) { a } else { break; } b }` into `while (c) { a b }`
+fn loop_conditions(stmts: &mut [Statement]) {
+    for stmt in stmts {
+        for stmts in nested(stmt) {
+            loop_conditions(stmts);
+        }
+        if let Statement::While { cond, stmts } = stmt {
+            if !matches!(cond, Expr::Constant(Constant::Bool(true))) {
+                continue;
+            }
+            let (new_cond, mut body) = match stmts.first() {
+                Some(Statement::IfElse {
+                    cond: if_cond,
+                    if_,
+                    else_,
+                }) => match (&if_[..], &else_[..]) {
+                    (_, [Statement::Break]) => (if_cond.clone(), if_.clone()),
+                    ([Statement::Break], _) => (negate(if_cond.clone()), else_.clone()),
+                    _ => continue,
+                },
+                _ => continue,
+            };
+            body.extend(stmts.drain(1..));
+            *cond = new_cond;
+            *stmts = body;
+        }
+    }
+}
+
+/// Turn `if (c) {} else { a }` into `if (!c) { a }`
+fn flip_empty_ifs(stmts: &mut [Statement]) {
+    for stmt in stmts {
+        for stmts in nested(stmt) {
+            flip_empty_ifs(stmts);
+        }
+        if let Statement::IfElse { cond, if_, else_ } = stmt {
+            if if_.is_empty() && !else_.is_empty() {
+                *cond = negate(cond.clone());
+                std::mem::swap(if_, else_);
+            }
+        }
+    }
+}
+
+/// Turn `if (c) { a; jump } else { b }` into `if (c) { a; jump } b`
+fn flatten_else(stmts: &mut Vec<Statement>) {
+    let mut i = 0;
+    while i < stmts.len() {
+        for stmts in nested(&mut stmts[i]) {
+            flatten_else(stmts);
+        }
+        if let Statement::IfElse { if_, else_, .. } = &mut stmts[i] {
+            if ends_with_jump(if_) && !else_.is_empty() {
+                let else_ = std::mem::take(else_);
+                stmts.splice(i + 1..i + 1, else_);
+            }
+        }
+        i += 1;
+    }
+}
+
+/// Number the remaining labels in order of appearance
+fn renumber_labels(stmts: &mut [Statement], numbers: &mut HashMap<usize, usize>) {
+    for stmt in stmts {
+        match stmt {
+            Statement::Labelled { label, .. } => {
+                let next = numbers.len();
+                *label = *numbers.entry(*label).or_insert(next);
+            }
+            Statement::BreakLabel(label) | Statement::ContinueLabel(label) => {
+                let next = numbers.len();
+                *label = *numbers.entry(*label).or_insert(next);
+            }
+            _ => {}
+        }
+        for stmts in nested(stmt) {
+            renumber_labels(stmts, numbers);
+        }
+    }
+}
+
+/// Flags set when jumping out of a labelled block
+struct Flags {
+    label: usize,
+    /// The block only holds a loop, breaking it is breaking the block
+    loop_: bool,
+    break_: Expr,
+    continue_: Expr,
+    /// Some jumps set the break flag
+    breaks: bool,
+    continues: bool,
+}
+
+impl Flags {
+    fn set(flag: &Expr) -> Statement {
+        Statement::Assign {
+            declaration: false,
+            variable: flag.clone(),
+            assign: cst_bool(true),
+        }
+    }
+
+    fn check(flag: &Expr, jump: Statement) -> Statement {
+        Statement::IfElse {
+            cond: flag.clone(),
+            if_: vec![jump],
+            else_: Vec::new(),
+        }
+    }
+}
+
+/// Flags possibly set by the lowered jumps
+#[derive(Default, Clone, Copy)]
+struct Jumps {
+    break_: bool,
+    continue_: bool,
+}
+
+impl Jumps {
+    fn merge(&mut self, other: Jumps) {
+        self.break_ |= other.break_;
+        self.continue_ |= other.continue_;
+    }
+}
+
+/// Replace the labelled jumps to a block, `depth` being the number of loops between the statements and the block.
+/// A jump out of nested loops sets a flag and breaks the innermost loop, the flag is checked after each loop.
+/// The statements following a jump to the end of the block are skipped when the flag is set.
+///
+/// Returns the flags possibly set when reaching the end of the statements, and when breaking the innermost loop.
+fn lower_jumps(stmts: &mut Vec<Statement>, flags: &mut Flags, depth: usize) -> (Jumps, Jumps) {
+    let mut falls = Jumps::default();
+    let mut exits = Jumps::default();
+    let mut i = 0;
+    while i < stmts.len() {
+        let mut end = i + 1;
+        let (mut stmt_falls, stmt_exits) = match &mut stmts[i] {
+            Statement::BreakLabel(label) if *label == flags.label => {
+                if depth == 1 && flags.loop_ {
+                    stmts[i] = Statement::Break;
+                    Default::default()
+                } else {
+                    flags.breaks = true;
+                    stmts[i] = Flags::set(&flags.break_);
+                    let set = Jumps {
+                        break_: true,
+                        ..Jumps::default()
+                    };
+                    if depth > 0 {
+                        stmts.insert(i + 1, Statement::Break);
+                        end += 1;
+                        (Jumps::default(), set)
+                    } else {
+                        (set, Jumps::default())
+                    }
+                }
+            }
+            Statement::ContinueLabel(label) if *label == flags.label => {
+                if depth <= 1 {
+                    stmts[i] = Statement::Continue;
+                    Default::default()
+                } else {
+                    flags.continues = true;
+                    stmts[i] = Flags::set(&flags.continue_);
+                    stmts.insert(i + 1, Statement::Break);
+                    end += 1;
+                    let set = Jumps {
+                        continue_: true,
+                        ..Jumps::default()
+                    };
+                    (Jumps::default(), set)
+                }
+            }
+            Statement::While { stmts: body, .. } => {
+                let (_, body_exits) = lower_jumps(body, flags, depth + 1);
+                if depth == 0 && flags.loop_ && flags.continues {
+                    body.insert(
+                        0,
+                        Statement::Assign {
+                            declaration: true,
+                            variable: flags.continue_.clone(),
+                            assign: cst_bool(false),
+                        },
+                    );
+                }
+                // Control goes after the loop with the flags set
+                (body_exits, Jumps::default())
+            }
+            stmt => {
+                let mut stmt_falls = Jumps::default();
+                let mut stmt_exits = Jumps::default();
+                for stmts in nested(stmt) {
+                    let (f, e) = lower_jumps(stmts, flags, depth);
+                    stmt_falls.merge(f);
+                    stmt_exits.merge(e);
+                }
+                (stmt_falls, stmt_exits)
+            }
+        };
+        exits.merge(stmt_exits);
+        // A try block is followed by its catch block
+        if matches!(stmts[i], Statement::Try { .. }) {
+            if let Some(Statement::Catch { stmts: catch, .. }) = stmts.get_mut(i + 1) {
+                let (f, e) = lower_jumps(catch, flags, depth);
+                stmt_falls.merge(f);
+                exits.merge(e);
+                end += 1;
+            }
+        }
+        if depth == 0 {
+            if stmt_falls.break_ {
+                falls.break_ = true;
+                let mut rest: Vec<Statement> = stmts.drain(end..).collect();
+                if !rest.is_empty() {
+                    let (f, e) = lower_jumps(&mut rest, flags, depth);
+                    falls.merge(f);
+                    exits.merge(e);
+                    stmts.push(Statement::IfElse {
+                        cond: not(flags.break_.clone()),
+                        if_: rest,
+                        else_: Vec::new(),
+                    });
+                }
+                break;
+            }
+            i = end;
+            continue;
+        }
+        let mut checks = Vec::new();
+        if stmt_falls.break_ {
+            exits.break_ = true;
+            checks.push(Flags::check(&flags.break_, Statement::Break));
+        }
+        if stmt_falls.continue_ {
+            let jump = if depth == 1 {
+                Statement::Continue
+            } else {
+                exits.continue_ = true;
+                Statement::Break
+            };
+            checks.push(Flags::check(&flags.continue_, jump));
+        }
+        let n = checks.len();
+        stmts.splice(end..end, checks);
+        i = end + n;
+    }
+    (falls, exits)
+}
+
+/// Haxe has no labels, replace the remaining labelled blocks by their content and the jumps to them by flags.
+/// The flags are variables using the registers from `next_reg`.
+fn lower_labels(stmts: &mut Vec<Statement>, next_reg: &mut u32) {
+    let mut i = 0;
+    while i < stmts.len() {
+        for stmts in nested(&mut stmts[i]) {
+            lower_labels(stmts, next_reg);
+        }
+        if let Statement::Labelled {
+            label,
+            stmts: inner,
+        } = &mut stmts[i]
+        {
+            let mut flag = |name: &str| {
+                *next_reg += 1;
+                Expr::Variable(
+                    Reg(*next_reg - 1),
+                    Some(Str::from(format!("{name}{label}"))),
+                )
+            };
+            let mut flags = Flags {
+                label: *label,
+                loop_: matches!(&inner[..], [Statement::While { .. }]),
+                break_: flag("break"),
+                continue_: flag("continue"),
+                breaks: false,
+                continues: false,
+            };
+            let mut inner = std::mem::take(inner);
+            lower_jumps(&mut inner, &mut flags, 0);
+            if flags.breaks {
+                inner.insert(
+                    0,
+                    Statement::Assign {
+                        declaration: true,
+                        variable: flags.break_,
+                        assign: cst_bool(false),
+                    },
+                );
+            }
+            let n = inner.len();
+            stmts.splice(i..=i, inner);
+            i += n;
+        } else {
+            i += 1;
+        }
+    }
+}
+
+/// `next_reg` is the first register unused by the function, for the variables we add
+fn simplify(mut stmts: Vec<Statement>, mut next_reg: u32) -> Vec<Statement> {
+    resolve_jumps(&mut stmts, &[], None, None);
+    short_circuit(&mut stmts);
+    // Bring the jumps at the end of the loops to the top level of their body
+    loop_conditions(&mut stmts);
+    flatten_else(&mut stmts);
+    // Removing a label can put an if in a position where it can be simplified
+    for _ in 0..2 {
+        strip_jumps(
+            &mut stmts,
+            &Exits {
+                return_: true,
+                ..Exits::default()
+            },
+        );
+        let mut counts = HashMap::new();
+        count_labels(&stmts, &mut counts);
+        unwrap_labels(&mut stmts, &counts);
+    }
+    loop_conditions(&mut stmts);
+    flip_empty_ifs(&mut stmts);
+    flatten_else(&mut stmts);
+    renumber_labels(&mut stmts, &mut HashMap::new());
+    lower_labels(&mut stmts, &mut next_reg);
+    stmts
+}
+
+//endregion
+
 #[cfg(test)]
 mod tests {
+    use hlbc::asm::assemble;
+    use hlbc::builder::BytecodeBuilder;
+    use hlbc::opcodes::Operand;
+    use hlbc::types::{Reg, Type};
+
+    use crate::fmt::FormatOptions;
+    use crate::{decompile_code_with, Structuring};
+
+    /// Decompile a function with registers `reg0: void, reg1: i32, reg2: i32, reg3: bool`,
+    /// `@0` being a native `print(i32)`. Writes to `reg1`, `reg2` and `reg3` are named `x`, `y` and `c`.
+    fn decompile(ops: &str) -> String {
+        let mut builder = BytecodeBuilder::new();
+        let void = builder.intern_type(Type::Void);
+        let i32 = builder.intern_type(Type::I32);
+        builder.intern_type(Type::Bool);
+        let t = builder.fun_type(vec![], void);
+        let print = builder.fun_type(vec![i32], void);
+        builder.add_native("std", "print", print);
+        let src = format!("fn new @{}\nreg0 @0\nreg1 @1\nreg2 @1\nreg3 @2\n{ops}", t.0);
+        let mut fun = assemble(&mut builder, &src).unwrap();
+        let names = [
+            builder.string("x"),
+            builder.string("y"),
+            builder.string("c"),
+        ];
+        let assigns = fun
+            .ops
+            .iter()
+            .enumerate()
+            .filter(|(i, o)| o.jump_targets(*i).is_empty())
+            .filter_map(|(i, o)| match o.operands().first() {
+                Some(&Operand::Reg(Reg(r @ 1..=3))) => Some((names[r as usize - 1], i + 1)),
+                _ => None,
+            })
+            .collect();
+        fun.assigns = Some(assigns);
+        builder.insert_function(fun);
+        let code = builder.build();
+        let f = &code.functions[0];
+        let indent = FormatOptions::new(2);
+        decompile_code_with(&code, f, Structuring::Cfg)
+            .iter()
+            .map(|s| s.display(&indent, &code, f).to_string())
+            .flat_map(|s| s.lines().map(str::to_owned).collect::<Vec<_>>())
+            // Skip the comments added before calls
+            .filter(|l| !l.trim_start().starts_with("//"))
+            .map(|l| l + "\n")
+            .collect()
+    }
+
+    /// Compare the decompiled code to the expected lines, ignoring indentation
+    fn check(ops: &str, expected: &str) {
+        let code = decompile(ops);
+        let lines: Vec<&str> = code.lines().map(str::trim).collect();
+        let expected: Vec<&str> = expected
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .collect();
+        assert_eq!(lines, expected, "\n{code}");
+    }
+
+    #[test]
+    fn if_else() {
+        check(
+            r"
+            Int reg1 0
+            Int reg2 1
+            JSGte reg1 reg2 else
+            Call1 reg0 @0 reg1
+            JAlways end
+        else:
+            Call1 reg0 @0 reg2
+        end:
+            Ret reg0
+            ",
+            r"
+            var x = 0;
+            var y = 1;
+            if (x < y) {
+            print(x);
+            } else {
+            print(y);
+            }
+            ",
+        );
+    }
+
+    #[test]
+    fn while_loop() {
+        check(
+            r"
+            Int reg1 0
+            Int reg2 10
+        loop:
+            JSGte reg1 reg2 end
+            Call1 reg0 @0 reg1
+            Incr reg1
+            JAlways loop
+        end:
+            Ret reg0
+            ",
+            r"
+            var x = 0;
+            var y = 10;
+            while (x < y) {
+            print(x);
+            x++;
+            }
+            ",
+        );
+    }
+
+    #[test]
+    fn do_while() {
+        check(
+            r"
+            Int reg1 0
+            Int reg2 10
+        loop:
+            Call1 reg0 @0 reg1
+            Incr reg1
+            JSLt reg1 reg2 loop
+            Ret reg0
+            ",
+            r"
+            var x = 0;
+            var y = 10;
+            while (true) {
+            print(x);
+            x++;
+            if (x >= y) {
+            break;
+            }
+            }
+            ",
+        );
+    }
+
+    #[test]
+    fn break_and_continue() {
+        check(
+            r"
+            Int reg1 0
+            Int reg2 10
+        loop:
+            JSGte reg1 reg2 end
+            Incr reg1
+            JNotEq reg1 reg2 next
+            JAlways loop
+        next:
+            JSLte reg1 reg2 body
+            JAlways end
+        body:
+            Call1 reg0 @0 reg1
+            JAlways loop
+        end:
+            Call1 reg0 @0 reg2
+            Ret reg0
+            ",
+            r"
+            var x = 0;
+            var y = 10;
+            while (x < y) {
+            x++;
+            if (x == y) {
+            continue;
+            }
+            if (x > y) {
+            break;
+            }
+            print(x);
+            }
+            print(y);
+            ",
+        );
+    }
+
+    #[test]
+    fn nested_loops() {
+        check(
+            r"
+            Int reg1 0
+            Int reg2 10
+        outer:
+            JSGte reg1 reg2 end
+            Int reg2 0
+        inner:
+            JSGte reg2 reg1 next
+            JNotEq reg1 reg2 incr
+            JAlways end
+        incr:
+            Incr reg2
+            JAlways inner
+        next:
+            Incr reg1
+            JAlways outer
+        end:
+            Call1 reg0 @0 reg1
+            Ret reg0
+            ",
+            r"
+            var x = 0;
+            var y = 10;
+            var break0 = false;
+            while (x < y) {
+            y = 0;
+            while (y < x) {
+            if (x == y) {
+            break0 = true;
+            break;
+            }
+            y++;
+            }
+            if (break0) {
+            break;
+            }
+            x++;
+            }
+            print(x);
+            ",
+        );
+    }
+
+    #[test]
+    fn continue_outer_loop() {
+        check(
+            r"
+            Int reg1 0
+            Int reg2 10
+        outer:
+            JSGte reg1 reg2 end
+            Incr reg1
+        inner:
+            JSGte reg2 reg1 next
+            JNotEq reg1 reg2 incr
+            JAlways outer
+        incr:
+            Incr reg2
+            JAlways inner
+        next:
+            Call1 reg0 @0 reg1
+            JAlways outer
+        end:
+            Ret reg0
+            ",
+            r"
+            var x = 0;
+            var y = 10;
+            while (x < y) {
+            var continue0 = false;
+            x++;
+            while (y < x) {
+            if (x == y) {
+            continue0 = true;
+            break;
+            }
+            y++;
+            }
+            if (continue0) {
+            continue;
+            }
+            print(x);
+            }
+            ",
+        );
+    }
+
+    #[test]
+    fn short_circuit() {
+        check(
+            r"
+            Int reg1 0
+            Int reg2 1
+            Bool reg3 true
+            JSGte reg1 reg2 or
+            JFalse reg3 or
+            Call1 reg0 @0 reg1
+        or:
+            JSLt reg1 reg2 then
+            JFalse reg3 end
+        then:
+            Call1 reg0 @0 reg2
+        end:
+            Ret reg0
+            ",
+            r"
+            var x = 0;
+            var y = 1;
+            var c = true;
+            if (x < y && c) {
+            print(x);
+            }
+            if (x < y || c) {
+            print(y);
+            }
+            ",
+        );
+    }
+
+    #[test]
+    fn switch() {
+        check(
+            r"
+            Int reg1 0
+            Int reg2 1
+            Switch reg1 [a, b, a] end
+            Call1 reg0 @0 reg2
+            JAlways end
+        a:
+            Call1 reg0 @0 reg1
+            JAlways end
+        b:
+            Incr reg1
+        end:
+            Ret reg0
+            ",
+            r"
+            var x = 0;
+            var y = 1;
+            switch (x) {
+            default:
+            print(y);
+            case 0, 2:
+            print(x);
+            case 1:
+            x++;
+            }
+            ",
+        );
+    }
+
     #[test]
-    fn test_process() {}
+    fn try_catch() {
+        check(
+            r"
+            Int reg1 0
+            Int reg2 10
+        loop:
+            JSGte reg1 reg2 end
+            Trap reg2 catch
+            Call1 reg0 @0 reg1
+            EndTrap reg2
+            JAlways next
+        catch:
+            JAlways end
+        next:
+            Incr reg1
+            JAlways loop
+        end:
+            Ret reg0
+            ",
+            r"
+            var x = 0;
+            var y = 10;
+            while (x < y) {
+            try {
+            print(x);
+            }
+            catch (reg2) {
+            break;
+            }
+            x++;
+            }
+            ",
+        );
+    }
+
+    #[test]
+    fn irreducible() {
+        check(
+            r"
+            Int reg1 0
+            Bool reg3 true
+            JTrue reg3 b
+        a:
+            Call1 reg0 @0 reg1
+        b:
+            Incr reg1
+            JTrue reg3 a
+            Ret reg0
+            ",
+            r"
+            var x:Int;
+            var c:Bool;
+            var state = 0;
+            while (true) {
+            switch (state) {
+            case 0:
+            x = 0;
+            c = true;
+            if (!c) {
+            state = 1;
+            continue;
+            }
+            state = 2;
+            case 1:
+            print(x);
+            state = 2;
+            case 2:
+            x++;
+            if (!c) {
+            return;
+            }
+            state = 1;
+            }
+            }
+            ",
+        );
+    }
 }
@@ -1,2 +1,2 @@
-mod bb;
-mod flow;
+pub(crate) mod bb;
+pub(crate) mod flow;
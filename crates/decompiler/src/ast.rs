@@ -1,12 +1,38 @@
 use std::collections::HashMap;
 
 use hlbc::fmt::EnhancedFmt;
-use hlbc::types::{RefEnumConstruct, RefField, RefFloat, RefFun, RefInt, RefString, RefType, Reg};
-use hlbc::{Bytecode, Str};
+use hlbc::types::{
+    RefBytes, RefEnumConstruct, RefField, RefFloat, RefFun, RefGlobal, RefInt, RefString, RefType,
+    Reg, Type,
+};
+use hlbc::{Bytecode, Resolve, Str};
 
+/// A Haxe module, holding a single type
 #[derive(Debug)]
 pub struct SourceFile {
-    pub class: Class,
+    /// Dotted package path, empty for the root package
+    pub package: Str,
+    /// Fully qualified names of the types used from other packages
+    pub imports: Vec<Str>,
+    pub decl: TypeDecl,
+}
+
+#[derive(Debug)]
+pub enum TypeDecl {
+    Class(Class),
+    Enum(Enum),
+}
+
+#[derive(Debug)]
+pub struct Enum {
+    pub name: Str,
+    pub constructs: Vec<EnumVariant>,
+}
+
+#[derive(Debug)]
+pub struct EnumVariant {
+    pub name: Str,
+    pub params: Vec<RefType>,
 }
 
 #[derive(Debug)]
@@ -32,19 +58,21 @@ pub struct Method {
     pub statements: Vec<Statement>,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Constant {
     InlineInt(usize),
     Int(RefInt),
     Float(RefFloat),
     String(RefString),
+    /// Raw bytes, the source being a string literal or a resource
+    Bytes(RefBytes),
     Bool(bool),
     Null,
     /// 'this' instance
     This,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Operation {
     /// `+`
     Add(Box<Expr>, Box<Expr>),
@@ -60,6 +88,12 @@ pub enum Operation {
     Shl(Box<Expr>, Box<Expr>),
     /// `>>`
     Shr(Box<Expr>, Box<Expr>),
+    /// `/` on unsigned integers, the result being an integer : Std.int((a : UInt) / (b : UInt))
+    UDiv(Box<Expr>, Box<Expr>),
+    /// `%` on unsigned integers : (a : UInt) % (b : UInt)
+    UMod(Box<Expr>, Box<Expr>),
+    /// `>>>`
+    UShr(Box<Expr>, Box<Expr>),
     /// && &
     And(Box<Expr>, Box<Expr>),
     /// || |
@@ -89,7 +123,7 @@ pub enum Operation {
 }
 
 /// Constructor call
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct ConstructorCall {
     pub ty: RefType,
     pub args: Vec<Expr>,
@@ -102,7 +136,7 @@ impl ConstructorCall {
 }
 
 /// Function or method call
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Call {
     pub fun: Expr,
     pub args: Vec<Expr>,
@@ -122,7 +156,7 @@ impl Call {
 }
 
 /// An expression with a value
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Expr {
     /// An anonymous structure : { field: value }
     Anonymous(RefType, HashMap<RefField, Expr>),
@@ -136,11 +170,24 @@ pub enum Expr {
     Constructor(ConstructorCall),
     /// Arrow function (...) -> {...}
     Closure(RefFun, Vec<Statement>),
+    /// Enum value or pattern : Constructor(args)
     EnumConstr(RefType, RefEnumConstruct, Vec<Expr>),
+    /// Parameter of an enum value made with a specific constructor.
+    /// Read into `reg`, which is the binding when it is matched in a switch case.
+    EnumField {
+        value: Box<Expr>,
+        construct: RefEnumConstruct,
+        field: usize,
+        reg: Reg,
+    },
+    /// Index of the constructor of an enum value : value.getIndex()
+    EnumIndex(RefType, Box<Expr>),
     /// Field access : obj.field
     Field(Box<Expr>, Str),
     /// Function reference
     FunRef(RefFun),
+    /// Global variable, a class or enum instance for most of them, the others are displayed as `globalN`
+    Global(RefGlobal),
     /// If/Else expression, both branches expressions types must unify (https://haxe.org/manual/expression-if.html)
     IfElse {
         cond: Box<Expr>,
@@ -151,10 +198,16 @@ pub enum Expr {
     },
     /// Operator
     Op(Operation),
+    /// Type value, for the runtime type information
+    Type(RefType),
+    /// Integer compared as unsigned : (value : UInt)
+    Unsigned(Box<Expr>),
     // For when there should be something, but we don't known what
     Unknown(String),
     /// Variable identifier
     Variable(Reg, Option<Str>),
+    /// Pattern matching anything in a switch case : _
+    Wildcard,
 }
 
 pub const fn cst_int(cst: RefInt) -> Expr {
@@ -173,6 +226,10 @@ pub const fn cst_string(cst: RefString) -> Expr {
     Expr::Constant(Constant::String(cst))
 }
 
+pub const fn cst_bytes(cst: RefBytes) -> Expr {
+    Expr::Constant(Constant::Bytes(cst))
+}
+
 pub const fn cst_null() -> Expr {
     Expr::Constant(Constant::Null)
 }
@@ -181,6 +238,10 @@ pub const fn cst_this() -> Expr {
     Expr::Constant(Constant::This)
 }
 
+pub(crate) fn unsigned(e: Expr) -> Expr {
+    Expr::Unsigned(Box::new(e))
+}
+
 /// Create a shorthand function to create an expression from an operator
 macro_rules! make_op_shorthand {
     ($name:ident, $op:ident, $( $e:ident ),+) => {
@@ -197,6 +258,9 @@ make_op_shorthand!(div, Div, e1, e2);
 make_op_shorthand!(modulo, Mod, e1, e2);
 make_op_shorthand!(shl, Shl, e1, e2);
 make_op_shorthand!(shr, Shr, e1, e2);
+make_op_shorthand!(udiv, UDiv, e1, e2);
+make_op_shorthand!(umod, UMod, e1, e2);
+make_op_shorthand!(ushr, UShr, e1, e2);
 make_op_shorthand!(and, And, e1, e2);
 make_op_shorthand!(or, Or, e1, e2);
 make_op_shorthand!(xor, Xor, e1, e2);
@@ -258,11 +322,15 @@ pub fn field(expr: Expr, obj: RefType, field: RefField, code: &Bytecode) -> Expr
     // FIXME meh
     Expr::Field(
         Box::new(expr),
-        Str::from(field.display::<EnhancedFmt>(code, &code[obj]).to_string()),
+        Str::from(
+            field
+                .display::<EnhancedFmt>(code, code.try_resolve(obj).unwrap_or(&Type::Void))
+                .to_string(),
+        ),
     )
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Statement {
     /// Variable assignment
     Assign {
@@ -271,6 +339,11 @@ pub enum Statement {
         variable: Expr,
         assign: Expr,
     },
+    /// Variable declared without a value : var x:Type;
+    Declare {
+        variable: Expr,
+        ty: RefType,
+    },
     /// Expression statement
     ExprStatement(Expr),
     /// Return an expression or nothing (void)
@@ -285,7 +358,8 @@ pub enum Statement {
     Switch {
         arg: Expr,
         default: Vec<Statement>,
-        cases: Vec<(Expr, Vec<Statement>)>,
+        /// Values matched by each case
+        cases: Vec<(Vec<Expr>, Vec<Statement>)>,
     },
     /// While statement
     While {
@@ -294,11 +368,24 @@ pub enum Statement {
     },
     Break,
     Continue,
+    /// Block that can be exited with a [Statement::BreakLabel], or restarted with a [Statement::ContinueLabel]
+    /// when it only holds a loop. Used when breaking out of the innermost loop isn't enough.
+    /// Haxe has no labels, these are lowered to flags before the end of the decompilation.
+    Labelled {
+        label: usize,
+        stmts: Vec<Statement>,
+    },
+    BreakLabel(usize),
+    ContinueLabel(usize),
     Throw(Expr),
+    /// Runtime error where control flow should never go : throw "assert"
+    Assert,
     Try {
         stmts: Vec<Statement>,
     },
     Catch {
+        /// Variable holding the exception
+        exc: Expr,
         stmts: Vec<Statement>,
     },
     Comment(String),
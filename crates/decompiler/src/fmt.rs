@@ -2,31 +2,33 @@ use std::fmt;
 use std::fmt::{Display, Formatter};
 
 use hlbc::fmt::{BytecodeFmt, EnhancedFmt};
-use hlbc::types::{Function, RefField, Type};
+use hlbc::types::{Function, RefField, RefType, Type};
 use hlbc::Str;
 use hlbc::{Bytecode, Resolve};
 
-use crate::ast::{Class, Constant, ConstructorCall, Expr, Method, Operation, Statement};
-
-const INDENT: &'static str = "                                                                ";
+use crate::ast::{
+    Class, Constant, ConstructorCall, Enum, Expr, Method, Operation, SourceFile, Statement,
+    TypeDecl,
+};
 
 #[derive(Clone)]
 pub struct FormatOptions {
-    indent: &'static str,
+    /// Current indentation width
+    indent: usize,
     inc_indent: usize,
 }
 
 impl FormatOptions {
     pub fn new(inc_indent: usize) -> Self {
         Self {
-            indent: "",
+            indent: 0,
             inc_indent,
         }
     }
 
     pub fn inc_nesting(&self) -> Self {
         FormatOptions {
-            indent: &INDENT[..self.indent.len() + self.inc_indent],
+            indent: self.indent + self.inc_indent,
             ..*self
         }
     }
@@ -34,22 +36,92 @@ impl FormatOptions {
 
 impl Display for FormatOptions {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.indent)
+        write!(f, "{:1$}", "", self.indent)
     }
 }
 
-fn to_haxe_type<'a>(ty: &Type, ctx: &'a Bytecode) -> impl Display + 'a {
+fn to_haxe_type(ty: &Type, ctx: &Bytecode) -> Str {
     use crate::Type::*;
     match ty {
         Void => Str::from_static("Void"),
+        UI8 => Str::from_static("hl.UI8"),
+        UI16 => Str::from_static("hl.UI16"),
         I32 => Str::from_static("Int"),
+        I64 => Str::from_static("hl.I64"),
+        F32 => Str::from_static("Single"),
         F64 => Str::from_static("Float"),
         Bool => Str::from_static("Bool"),
         Bytes => Str::from_static("hl.Bytes"),
-        Dyn => Str::from_static("Dynamic"),
-        Fun(_) => Str::from_static("Function"),
-        Obj(obj) => ctx.resolve(obj.name),
-        _ => Str::from_static("other"),
+        Dyn | Virtual { .. } | DynObj => Str::from_static("Dynamic"),
+        Fun(_) | Method(_) => Str::from_static("Function"),
+        Obj(obj) | Struct(obj) => obj.name(ctx),
+        Array => Str::from_static("hl.NativeArray<Dynamic>"),
+        Type => Str::from_static("hl.Type"),
+        Ref(ty) => Str::from(format!("hl.Ref<{}>", to_haxe_type_of(*ty, ctx))),
+        Abstract { name } => Str::from(format!(
+            "hl.Abstract<\"{}\">",
+            name.display::<EnhancedFmt>(ctx)
+        )),
+        Enum { name, .. } => ctx
+            .try_resolve(*name)
+            .unwrap_or_else(|e| e.placeholder().into()),
+        Null(ty) => Str::from(format!("Null<{}>", to_haxe_type_of(*ty, ctx))),
+        Packed(_) => Str::from_static("other"),
+    }
+}
+
+/// [to_haxe_type] from a type reference, with a placeholder for invalid references
+fn to_haxe_type_of(ty: RefType, ctx: &Bytecode) -> Str {
+    match ctx.try_resolve(ty) {
+        Ok(ty) => to_haxe_type(ty, ctx),
+        Err(e) => e.placeholder().into(),
+    }
+}
+
+/// Arguments and return type of a function, empty if the function type is invalid
+fn signature<'a>(fun: &Function, ctx: &'a Bytecode) -> (&'a [RefType], RefType) {
+    fun.t
+        .as_fun(ctx)
+        .map_or((&[], RefType(0)), |ty| (&ty.args, ty.ret))
+}
+
+impl SourceFile {
+    pub fn display<'a>(&'a self, ctx: &'a Bytecode, opts: &'a FormatOptions) -> impl Display + 'a {
+        fmtools::fmt! { move
+            if !self.package.is_empty() {
+                "package "{self.package}";\n\n"
+            }
+            for import in &self.imports {
+                "import "{import}";\n"
+            }
+            if !self.imports.is_empty() {
+                "\n"
+            }
+            match &self.decl {
+                TypeDecl::Class(class) => {{class.display(ctx, opts)}}
+                TypeDecl::Enum(e) => {{e.display(ctx, opts)}}
+            }
+            "\n"
+        }
+    }
+}
+
+impl Enum {
+    pub fn display<'a>(&'a self, ctx: &'a Bytecode, opts: &'a FormatOptions) -> impl Display + 'a {
+        let new_opts = opts.inc_nesting();
+        fmtools::fmt! { move
+            {opts}"enum "{self.name}" {\n"
+            for c in &self.constructs {
+                {new_opts}{c.name}
+                if !c.params.is_empty() {
+                    "("{fmtools::join(", ", c.params.iter().enumerate().map(move |(i, p)| fmtools::fmt! { move
+                        "p"{i}": "{to_haxe_type_of(*p, ctx)}
+                    }))}")"
+                }
+                ";\n"
+            }
+            {opts}"}"
+        }
     }
 }
 
@@ -59,7 +131,7 @@ impl Class {
         fmtools::fmt! { move
             {opts}"class "{self.name} if let Some(parent) = self.parent.as_ref() { " extends "{parent} } " {\n"
             for f in &self.fields {
-                {new_opts} if f.static_ { "static " } "var "{f.name}": "{to_haxe_type(&ctx[f.ty], ctx)}";\n"
+                {new_opts} if f.static_ { "static " } "var "{f.name}": "{to_haxe_type_of(f.ty, ctx)}";\n"
             }
             for m in &self.methods {
                 "\n"
@@ -73,30 +145,52 @@ impl Class {
 impl Method {
     pub fn display<'a>(&'a self, ctx: &'a Bytecode, opts: &'a FormatOptions) -> impl Display + 'a {
         let new_opts = opts.inc_nesting();
-        let fun = self.fun.as_fn(ctx).unwrap();
         fmtools::fmt! { move
-            {opts} if self.static_ { "static " } if self.dynamic { "dynamic " }
-            "function "{fun.name(ctx)}"("
-            {fmtools::join(", ", fun.args(ctx).iter().enumerate().skip(if self.static_ { 0 } else { 1 })
-                .map(move |(i, arg)| fmtools::fmt! {move
-                    {fun.arg_name(ctx, i).unwrap_or(Str::from("_"))}": "{to_haxe_type(&ctx[*arg], ctx)}
-                }))}
-            ")" if !fun.ty(ctx).ret.is_void() { ": "{to_haxe_type(fun.ret(ctx), ctx)} } " {"
+            if let Some(fun) = self.fun.as_fn(ctx) {
+                let (args, ret) = signature(fun, ctx);
+                {opts} if self.static_ { "static " } if self.dynamic { "dynamic " }
+                // The entrypoint has no name
+                "function " if self.fun == ctx.entrypoint { "main" } else { {fun.name(ctx)} } "("
+                {fmtools::join(", ", args.iter().enumerate().skip(if self.static_ { 0 } else { 1 })
+                    .map(move |(i, arg)| fmtools::fmt! {move
+                        {fun.arg_name(ctx, i).unwrap_or(Str::from("_"))}": "{to_haxe_type_of(*arg, ctx)}
+                    }))}
+                ")" if !ret.is_void() { ": "{to_haxe_type_of(ret, ctx)} } " {"
 
-            if self.statements.is_empty() {
-                "}"
-            } else {
-                "\n"
-                for stmt in &self.statements {
-                    {new_opts}{stmt.display(&new_opts, ctx, fun)}"\n"
+                if self.statements.is_empty() {
+                    "}"
+                } else {
+                    "\n"
+                    for stmt in &self.statements {
+                        {new_opts}{stmt.display(&new_opts, ctx, fun)}"\n"
+                    }
+                    {opts}"}"
                 }
-                {opts}"}"
+            } else {
+                {opts}"// not a function : "{self.fun.display::<EnhancedFmt>(ctx)}
             }
             "\n"
         }
     }
 }
 
+/// The string stored in bytes the way Hashlink stores strings, in null terminated UTF-16
+fn utf16_string(bytes: &[u8]) -> Option<String> {
+    let units: Vec<u16> = bytes
+        .chunks(2)
+        .map(|c| match *c {
+            [lo, hi] => Some(u16::from_le_bytes([lo, hi])),
+            _ => None,
+        })
+        .collect::<Option<_>>()?;
+    match units.split_last() {
+        Some((0, units)) => char::decode_utf16(units.iter().copied())
+            .collect::<Result<_, _>>()
+            .ok(),
+        _ => None,
+    }
+}
+
 impl Constant {
     fn fmt(&self, f: &mut Formatter, code: &Bytecode) -> fmt::Result {
         use Constant::*;
@@ -105,8 +199,23 @@ impl Constant {
             Int(c) => EnhancedFmt.fmt_refint(f, code, c),
             Float(c) => EnhancedFmt.fmt_reffloat(f, code, c),
             String(c) => {
-                write!(f, "\"{}\"", code[c])
+                f.write_str("\"")?;
+                EnhancedFmt.fmt_refstring(f, code, c)?;
+                f.write_str("\"")
             }
+            Bytes(c) => match code.try_resolve(c) {
+                Ok(bytes) => match utf16_string(bytes) {
+                    Some(s) => write!(f, "@:privateAccess \"{}\".bytes", s.escape_default()),
+                    None => {
+                        f.write_str("@:privateAccess haxe.io.Bytes.ofHex(\"")?;
+                        for b in bytes {
+                            write!(f, "{b:02x}")?;
+                        }
+                        f.write_str("\").b")
+                    }
+                },
+                Err(e) => f.write_str(&e.placeholder()),
+            },
             Bool(c) => Display::fmt(&c, f),
             Null => f.write_str("null"),
             This => f.write_str("this"),
@@ -136,11 +245,20 @@ impl Operation {
                 Mod(e1, e2) => {{disp!(e1)}" % "{disp!(e2)}}
                 Shl(e1, e2) => {{disp!(e1)}" << "{disp!(e2)}}
                 Shr(e1, e2) => {{disp!(e1)}" >> "{disp!(e2)}}
-                And(e1, e2) => {{disp!(e1)}" && "{disp!(e2)}}
+                UDiv(e1, e2) => {"Std.int(("{disp!(e1)}" : UInt) / ("{disp!(e2)}" : UInt))"}
+                UMod(e1, e2) => {"("{disp!(e1)}" : UInt) % ("{disp!(e2)}" : UInt)"}
+                UShr(e1, e2) => {{disp!(e1)}" >>> "{disp!(e2)}}
+                And(e1, e2) => {
+                    if is_or(e1) { "("{disp!(e1)}")" } else { {disp!(e1)} }
+                    " && "
+                    if is_or(e2) { "("{disp!(e2)}")" } else { {disp!(e2)} }
+                }
                 Or(e1, e2) => {{disp!(e1)}" || "{disp!(e2)}}
                 Xor(e1, e2) => {{disp!(e1)}" ^ "{disp!(e2)}}
                 Neg(expr) => {"-"{disp!(expr)}}
-                Not(expr) => {"!"{disp!(expr)}}
+                Not(expr) => {
+                    if is_binary(expr) { "!("{disp!(expr)}")" } else { "!"{disp!(expr)} }
+                }
                 Incr(expr) => {{disp!(expr)}"++"}
                 Decr(expr) => {{disp!(expr)}"--"}
                 Eq(e1, e2) => {{disp!(e1)}" == "{disp!(e2)}}
@@ -154,6 +272,21 @@ impl Operation {
     }
 }
 
+fn is_or(e: &Expr) -> bool {
+    matches!(e, Expr::Op(Operation::Or(_, _)))
+}
+
+/// An operation with two operands
+fn is_binary(e: &Expr) -> bool {
+    matches!(
+        e,
+        Expr::Op(op) if !matches!(
+            op,
+            Operation::Neg(_) | Operation::Not(_) | Operation::Incr(_) | Operation::Decr(_)
+        )
+    )
+}
+
 impl Expr {
     pub fn display<'a>(
         &'a self,
@@ -168,8 +301,8 @@ impl Expr {
         }
         fmtools::fmt! { move
             match self {
-                Expr::Anonymous(ty, values) => match &code[*ty] {
-                    Type::Virtual { fields } => {
+                Expr::Anonymous(ty, values) => match code.try_resolve(*ty) {
+                    Ok(Type::Virtual { fields }) => {
                         "{"{ fmtools::join(", ", fields
                             .iter()
                             .enumerate()
@@ -192,25 +325,42 @@ impl Expr {
                     "new "{ty.display::<EnhancedFmt>(code)}"("{fmtools::join(", ", args.iter().map(|e| disp!(e)))}")"
                 }
                 Expr::Closure(f, stmts) => {
-                    let fun = f.as_fn(code).unwrap();
-                    "("{fmtools::join(", ", fun.ty(code).args.iter().enumerate().map(move |(i, arg)|
-                        fmtools::fmt! { move
-                            {fun.arg_name(code, i).unwrap_or(Str::from("_"))}": "{to_haxe_type(&code[*arg], code)}
+                    if let Some(fun) = f.as_fn(code) {
+                        "("{fmtools::join(", ", signature(fun, code).0.iter().enumerate().map(move |(i, arg)|
+                            fmtools::fmt! { move
+                                {fun.arg_name(code, i).unwrap_or(Str::from("_"))}": "{to_haxe_type_of(*arg, code)}
+                            }
+                        ))}") -> {\n"
+                        let indent2 = indent.inc_nesting();
+                        for stmt in stmts {
+                            {indent2}{stmt.display(&indent2, code, fun)}"\n"
                         }
-                    ))}") -> {\n"
-                    let indent2 = indent.inc_nesting();
-                    for stmt in stmts {
-                        {indent2}{stmt.display(&indent2, code, fun)}"\n"
+                        {indent}"}"
+                    } else {
+                        "["{f.display::<EnhancedFmt>(code)}" is not a function]"
                     }
-                    {indent}"}"
                 }
                 Expr::EnumConstr(ty, constr, args) => {
-                    {constr.display::<EnhancedFmt>(code, &code[*ty])}"("{fmtools::join(", ", args.iter().map(|e| disp!(e)))}")"
+                    {constr.display::<EnhancedFmt>(code, code.try_resolve(*ty).unwrap_or(&Type::Void))}
+                    // Constructors without parameters are values
+                    if !args.is_empty() {
+                        "("{fmtools::join(", ", args.iter().map(|e| disp!(e)))}")"
+                    }
+                }
+                Expr::EnumField { value, field, .. } => {
+                    {disp!(value)}"."{field}
+                }
+                Expr::EnumIndex(_, value) => {
+                    {disp!(value)}".getIndex()"
                 }
                 Expr::Field(receiver, name) => {
                     {disp!(receiver)}"."{name}
                 }
                 Expr::FunRef(fun) => {{fun.name(code)}},
+                Expr::Global(global) => match code.try_resolve(*global).and_then(|ty| code.try_resolve(ty)) {
+                    Ok(Type::Obj(obj) | Type::Struct(obj)) => {{obj.name(code)}}
+                    _ => {"global"{global.0}}
+                },
                 Expr::IfElse { cond, if_, else_ } => {
                     "if ("{disp!(cond)}") {\n"
                     let indent2 = indent.inc_nesting();
@@ -224,6 +374,10 @@ impl Expr {
                     {indent}"}"
                 }
                 Expr::Op(op) => {{disp!(op)}},
+                Expr::Type(ty) => {{to_haxe_type_of(*ty, code)}},
+                Expr::Unsigned(value) => {
+                    "("{disp!(value)}" : UInt)"
+                }
                 Expr::Unknown(msg) => {
                      "["{msg}"]"
                 }
@@ -234,6 +388,7 @@ impl Expr {
                         Str::from(x.to_string())
                     }
                 }}
+                Expr::Wildcard => "_",
             }
         }
     }
@@ -260,6 +415,9 @@ impl Statement {
                 } => {
                     if *declaration { "var " } else { "" }{disp!(variable)}" = "{disp!(assign)}";"
                 }
+                Statement::Declare { variable, ty } => {
+                    "var "{disp!(variable)}":"{to_haxe_type_of(*ty, code)}";"
+                }
                 Statement::ExprStatement(expr) => {
                     {disp!(expr)}";"
                 }
@@ -291,8 +449,8 @@ impl Statement {
                             {indent3}{stmt.display(&indent3, code, f)}"\n"
                         }
                     }
-                    for (pattern, stmts) in cases {
-                        {indent2}"case "{disp!(pattern)}":\n"
+                    for (patterns, stmts) in cases {
+                        {indent2}"case "{fmtools::join(", ", patterns.iter().map(|e| disp!(e)))}":\n"
                         for stmt in stmts {
                             {indent3}{stmt.display(&indent3, code, f)}"\n"
                         }
@@ -313,8 +471,25 @@ impl Statement {
                 Statement::Continue => {
                     "continue;"
                 }
+                Statement::Labelled { label, stmts } => {
+                    "label"{label}": {\n"
+                    let indent2 = indent.inc_nesting();
+                    for stmt in stmts {
+                        {indent2}{stmt.display(&indent2, code, f)}"\n"
+                    }
+                    {indent}"}"
+                }
+                Statement::BreakLabel(label) => {
+                    "break label"{label}";"
+                }
+                Statement::ContinueLabel(label) => {
+                    "continue label"{label}";"
+                }
                 Statement::Throw(exc) => {
-                    "throw "{disp!(exc)}
+                    "throw "{disp!(exc)}";"
+                }
+                Statement::Assert => {
+                    "throw \"assert\";"
                 }
                 Statement::Try { stmts } => {
                     "try {\n"
@@ -324,8 +499,8 @@ impl Statement {
                     }
                     {indent}"}"
                 }
-                Statement::Catch { stmts } => {
-                    "catch () {\n"
+                Statement::Catch { exc, stmts } => {
+                    "catch ("{disp!(exc)}") {\n"
                     let indent2 = indent.inc_nesting();
                     for stmt in stmts {
                         {indent2}{stmt.display(&indent2, code, f)}"\n"
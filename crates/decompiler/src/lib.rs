@@ -4,15 +4,16 @@
 //! The decompiler takes bytecode elements as input and outputs [ast] structures that can be displayed.
 
 use std::collections::{HashMap, HashSet};
+use std::path::Path;
 
 use ast::*;
 use hlbc::fmt::EnhancedFmt;
-use hlbc::opcodes::Opcode;
-use hlbc::types::{Function, RefField, RefFun, RefString, Reg, Type, TypeObj};
-use hlbc::{Bytecode, Resolve, Str};
+use hlbc::opcodes::{Opcode, Operand};
+use hlbc::types::{FunPtr, Function, RefField, RefFun, RefString, RefType, Reg, Type, TypeObj};
+use hlbc::{Bytecode, Error, Resolve, Str};
 use scopes::*;
 
-#[cfg(feature = "alt")]
+/// Control flow structuring from the basic blocks of a function
 mod alt;
 /// A simple representation for the Haxe source code generated by the decompiler
 pub mod ast;
@@ -22,6 +23,19 @@ pub mod fmt;
 mod post;
 /// Scope handling structures
 mod scopes;
+/// Source tree of a whole bytecode
+mod sources;
+
+/// Algorithm used to recover control flow structures (conditions, loops, switches) from jumps
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum Structuring {
+    /// Go through the instructions in order and track jump offsets in a stack of scopes
+    #[default]
+    Scopes,
+    /// Build the control flow graph and recover structures from its dominator tree,
+    /// with labelled blocks when the code can't be expressed with ifs, loops and switches only
+    Cfg,
+}
 
 enum ExprCtx {
     Constructor {
@@ -47,10 +61,12 @@ struct DecompilerState<'c> {
     seen: HashSet<Str>,
     f: &'c Function,
     code: &'c Bytecode,
+    // Used for closures
+    structuring: Structuring,
 }
 
 impl<'c> DecompilerState<'c> {
-    fn new(code: &'c Bytecode, f: &'c Function) -> DecompilerState<'c> {
+    fn new(code: &'c Bytecode, f: &'c Function, structuring: Structuring) -> DecompilerState<'c> {
         let scopes = Scopes::new();
         let mut reg_state = HashMap::with_capacity(f.regs.len());
         let expr_ctx = Vec::new();
@@ -58,13 +74,14 @@ impl<'c> DecompilerState<'c> {
 
         let mut start = 0;
         // First argument / First register is 'this'
-        if f.is_method() || code.resolve(f.name) == "__constructor__" {
+        if f.is_method() || f.name(code) == "__constructor__" {
             reg_state.insert(Reg(0), cst_this());
             start = 1;
         }
 
         // Initialize register state with the function arguments
-        for i in start..f.ty(code).args.len() {
+        let nargs = f.t.as_fun(code).map_or(0, |ty| ty.args.len());
+        for i in start..nargs {
             let name = f.arg_name(code, i - start);
             reg_state.insert(Reg(i as u32), Expr::Variable(Reg(i as u32), name.clone()));
             if let Some(name) = name {
@@ -79,6 +96,7 @@ impl<'c> DecompilerState<'c> {
             seen,
             f,
             code,
+            structuring,
         }
     }
 
@@ -117,10 +135,15 @@ impl<'c> DecompilerState<'c> {
         args.iter().map(|&r| self.expr(r)).collect()
     }
 
+    /// Type of a register, assumes registers have been checked by [check_regs]
+    fn reg_type(&self, reg: Reg) -> Result<&'c Type, Error> {
+        self.code.try_resolve(self.f.regtype(reg))
+    }
+
     /// Push a call to a function, which might be a constructor call.
     fn push_call(&mut self, i: usize, dst: Reg, fun: RefFun, args: &[Reg]) {
         if let Some(&ExprCtx::Constructor { reg, pos }) = self.expr_ctx.last() {
-            if reg == args[0] {
+            if args.first() == Some(&reg) {
                 self.push_expr(
                     pos,
                     reg,
@@ -133,17 +156,18 @@ impl<'c> DecompilerState<'c> {
             }
         } else {
             self.push_stmt(comment(fun.display::<EnhancedFmt>(self.code).to_string()));
-            let call = if let Some((func, true)) =
-                fun.as_fn(self.code).map(|func| (func, func.is_method()))
-            {
+            let call = if let (Some(func), Some(&this)) = (
+                fun.as_fn(self.code).filter(|func| func.is_method()),
+                args.first(),
+            ) {
                 call(
-                    Expr::Field(Box::new(self.expr(args[0])), func.name(self.code)),
+                    Expr::Field(Box::new(self.expr(this)), func.name(self.code)),
                     self.args_expr(&args[1..]),
                 )
             } else {
                 call_fun(fun, self.args_expr(args))
             };
-            if fun.ty(self.code).ret.is_void() {
+            if returns_void(self.code, fun) {
                 self.push_stmt(stmt(call));
             } else {
                 self.push_expr(i, dst, call);
@@ -155,7 +179,8 @@ impl<'c> DecompilerState<'c> {
     fn push_jmp(&mut self, i: usize, offset: i32, cond: Expr) {
         if offset > 0 {
             // It's a loop
-            if matches!(self.f.ops[i + offset as usize], Opcode::JAlways { offset } if offset < 0) {
+            if matches!(self.f.ops.get(i + offset as usize), Some(Opcode::JAlways { offset }) if *offset < 0)
+            {
                 if let Some(loop_cond) = self.scopes.last_loop_cond_mut() {
                     if matches!(loop_cond, Expr::Unknown(_)) {
                         //println!("old loop cond : {:?}", loop_cond);
@@ -174,15 +199,96 @@ impl<'c> DecompilerState<'c> {
     }
 }
 
+/// Whether a function returns void, false if the function can't be resolved.
+fn returns_void(code: &Bytecode, fun: RefFun) -> bool {
+    let ty = match code.try_resolve(fun) {
+        Ok(FunPtr::Fun(fun)) => fun.t,
+        Ok(FunPtr::Native(n)) => n.t,
+        Err(_) => return false,
+    };
+    ty.as_fun(code).map_or(false, |ty| ty.ret.is_void())
+}
+
+/// Check every register used by the function exists, since they are used everywhere without checks.
+fn check_regs(f: &Function) -> Result<(), Error> {
+    let nregs = f.regs.len();
+    let invalid = f.ops.iter().find_map(|o| {
+        // Those implicitly use the first register
+        if nregs == 0
+            && matches!(
+                o,
+                Opcode::GetThis { .. } | Opcode::SetThis { .. } | Opcode::CallThis { .. }
+            )
+        {
+            return Some(Reg(0));
+        }
+        o.operands().into_iter().find_map(|o| match o {
+            Operand::Reg(r) if r.0 as usize >= nregs => Some(r),
+            Operand::Regs(regs) => regs.iter().copied().find(|r| r.0 as usize >= nregs),
+            _ => None,
+        })
+    });
+    match invalid {
+        Some(r) => Err(Error::InvalidReference {
+            kind: "register",
+            index: r.0 as usize,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Decompile a closure or a function reference
+fn closure(code: &Bytecode, fun: RefFun, structuring: Structuring) -> Expr {
+    match code.try_resolve(fun) {
+        Ok(FunPtr::Fun(f)) => Expr::Closure(fun, decompile_code_with(code, f, structuring)),
+        Ok(FunPtr::Native(_)) => Expr::FunRef(fun),
+        Err(e) => Expr::Unknown(e.placeholder()),
+    }
+}
+
 /// Decompile a function code to a list of [Statement]s.
-/// This works by analyzing each opcodes in order while trying to reconstruct scopes, contexts and intents.
 pub fn decompile_code(code: &Bytecode, f: &Function) -> Vec<Statement> {
-    let mut state = DecompilerState::new(code, f);
+    decompile_code_with(code, f, Structuring::default())
+}
 
+/// Decompile a function code to a list of [Statement]s, recovering control flow structures with the given algorithm.
+pub fn decompile_code_with(
+    code: &Bytecode,
+    f: &Function,
+    structuring: Structuring,
+) -> Vec<Statement> {
+    if let Err(e) = check_regs(f) {
+        return vec![comment(format!("Can't decompile this function : {e}"))];
+    }
+    let state = DecompilerState::new(code, f, structuring);
+    let mut statements = match structuring {
+        Structuring::Scopes => decompile_scopes(state),
+        Structuring::Cfg => alt::flow::process(state),
+    };
+
+    // AST post processing step !
+    // It makes a single pass for all visitors
+    post::visit(
+        code,
+        &mut statements,
+        &mut [
+            Box::new(post::IfExpressions),
+            Box::new(post::EnumSwitch),
+            Box::new(post::StringConcat),
+            Box::new(post::Itos),
+            Box::new(post::Trace),
+        ],
+    );
+
+    statements
+}
+
+/// Go through the instructions in order while trying to reconstruct scopes, contexts and intents.
+fn decompile_scopes(mut state: DecompilerState) -> Vec<Statement> {
+    let f = state.f;
     let iter = f.ops.iter().enumerate();
     for (i, o) in iter {
-        // Opcodes are grouped by semantic
-        // Control flow first because they are the most important
+        // Control flow first because they are the most important, the other opcodes are handled by push_op
         match o {
             //region CONTROL FLOW
             &Opcode::JTrue { cond, offset } => state.push_jmp(i, offset, not(state.expr(cond))),
@@ -193,18 +299,35 @@ pub fn decompile_code(code: &Bytecode, f: &Function) -> Vec<Statement> {
             &Opcode::JNotNull { reg, offset } => {
                 state.push_jmp(i, offset, eq(state.expr(reg), cst_null()))
             }
-            &Opcode::JSGte { a, b, offset } | &Opcode::JUGte { a, b, offset } => {
+            &Opcode::JSGte { a, b, offset } => {
                 state.push_jmp(i, offset, gt(state.expr(b), state.expr(a)))
             }
+            &Opcode::JUGte { a, b, offset } => state.push_jmp(
+                i,
+                offset,
+                gt(unsigned(state.expr(b)), unsigned(state.expr(a))),
+            ),
             &Opcode::JSGt { a, b, offset } => {
                 state.push_jmp(i, offset, gte(state.expr(b), state.expr(a)))
             }
             &Opcode::JSLte { a, b, offset } => {
                 state.push_jmp(i, offset, lt(state.expr(b), state.expr(a)))
             }
-            &Opcode::JSLt { a, b, offset } | &Opcode::JULt { a, b, offset } => {
+            &Opcode::JSLt { a, b, offset } => {
                 state.push_jmp(i, offset, lte(state.expr(b), state.expr(a)))
             }
+            &Opcode::JULt { a, b, offset } => state.push_jmp(
+                i,
+                offset,
+                lte(unsigned(state.expr(b)), unsigned(state.expr(a))),
+            ),
+            // Not the same as JSGte and JSLt for NaN
+            &Opcode::JNotLt { a, b, offset } => {
+                state.push_jmp(i, offset, lt(state.expr(a), state.expr(b)))
+            }
+            &Opcode::JNotGte { a, b, offset } => {
+                state.push_jmp(i, offset, gte(state.expr(a), state.expr(b)))
+            }
             &Opcode::JEq { a, b, offset } => {
                 state.push_jmp(i, offset, noteq(state.expr(a), state.expr(b)))
             }
@@ -250,7 +373,7 @@ pub fn decompile_code(code: &Bytecode, f: &Function) -> Vec<Statement> {
                     } else if state.scopes.last_loop_start().is_some() {
                         // Check the instruction just before the jump target
                         // If it's a jump backward of a loop
-                        if matches!(f.ops[(i as i32 + offset) as usize], Opcode::JAlways {offset} if offset < 0)
+                        if matches!(f.ops.get((i as i32 + offset) as usize), Some(Opcode::JAlways {offset}) if *offset < 0)
                         {
                             // It's a break condition
                             state.push_stmt(Statement::Break);
@@ -292,9 +415,6 @@ pub fn decompile_code(code: &Bytecode, f: &Function) -> Vec<Statement> {
             //endregion
 
             //region EXCEPTIONS
-            &Opcode::Throw { exc } | &Opcode::Rethrow { exc } => {
-                state.push_stmt(Statement::Throw(state.expr(exc)));
-            }
             &Opcode::Trap { exc, offset } => {
                 state.scopes.push_try(offset + 1);
             }
@@ -302,503 +422,622 @@ pub fn decompile_code(code: &Bytecode, f: &Function) -> Vec<Statement> {
                 // TODO try catch
             }
             //endregion
+            _ => push_op(&mut state, i, o),
+        }
+        state.scopes.advance();
+    }
+    state.scopes.statements()
+}
 
-            //region CONSTANTS
-            &Opcode::Int { dst, ptr } => {
-                state.push_expr(i, dst, cst_int(ptr));
-            }
-            &Opcode::Float { dst, ptr } => {
-                state.push_expr(i, dst, cst_float(ptr));
-            }
-            &Opcode::Bool { dst, value } => {
-                state.push_expr(i, dst, cst_bool(value.0));
-            }
-            &Opcode::String { dst, ptr } => {
-                state.push_expr(i, dst, cst_string(ptr));
-            }
-            &Opcode::Null { dst } => {
-                state.push_expr(i, dst, cst_null());
-            }
-            //endregion
+/// Translate an instruction that doesn't affect control flow, opcodes are grouped by semantic
+fn push_op(state: &mut DecompilerState, i: usize, o: &Opcode) {
+    let code = state.code;
+    let f = state.f;
+    match o {
+        //region EXCEPTIONS
+        // Rethrowing keeps the original stack trace, but it's a throw in the source
+        &Opcode::Throw { exc } | &Opcode::Rethrow { exc } => {
+            state.push_stmt(Statement::Throw(state.expr(exc)));
+        }
+        &Opcode::Assert => {
+            state.push_stmt(Statement::Assert);
+        }
+        // Null checks are inserted by the compiler before accesses
+        &Opcode::NullCheck { .. } => {}
+        //endregion
 
-            //region OPERATORS
-            &Opcode::Mov { dst, src } => {
-                state.push_expr(i, dst, state.expr(src));
-                // Workaround for when the instructions after this one use dst and src interchangeably.
-                state
-                    .reg_state
-                    .insert(src, Expr::Variable(dst, f.var_name(code, i)));
-            }
-            &Opcode::Add { dst, a, b } => {
-                state.push_expr(i, dst, add(state.expr(a), state.expr(b)));
-            }
-            &Opcode::Sub { dst, a, b } => {
-                state.push_expr(i, dst, sub(state.expr(a), state.expr(b)));
-            }
-            &Opcode::Mul { dst, a, b } => {
-                state.push_expr(i, dst, mul(state.expr(a), state.expr(b)));
-            }
-            &Opcode::SDiv { dst, a, b } | &Opcode::UDiv { dst, a, b } => {
-                state.push_expr(i, dst, div(state.expr(a), state.expr(b)));
-            }
-            &Opcode::SMod { dst, a, b } | &Opcode::UMod { dst, a, b } => {
-                state.push_expr(i, dst, modulo(state.expr(a), state.expr(b)));
-            }
-            &Opcode::Shl { dst, a, b } => {
-                state.push_expr(i, dst, shl(state.expr(a), state.expr(b)));
-            }
-            &Opcode::SShr { dst, a, b } | &Opcode::UShr { dst, a, b } => {
-                state.push_expr(i, dst, shr(state.expr(a), state.expr(b)));
-            }
-            &Opcode::And { dst, a, b } => {
-                state.push_expr(i, dst, and(state.expr(a), state.expr(b)));
-            }
-            &Opcode::Or { dst, a, b } => {
-                state.push_expr(i, dst, or(state.expr(a), state.expr(b)));
-            }
-            &Opcode::Xor { dst, a, b } => {
-                state.push_expr(i, dst, xor(state.expr(a), state.expr(b)));
-            }
-            &Opcode::Neg { dst, src } => {
-                state.push_expr(i, dst, neg(state.expr(src)));
-            }
-            &Opcode::Not { dst, src } => {
-                state.push_expr(i, dst, not(state.expr(src)));
-            }
-            &Opcode::Incr { dst } => {
-                // FIXME sometimes it should be an expression
-                state.push_stmt(stmt(incr(state.expr(dst))));
-            }
-            &Opcode::Decr { dst } => {
-                state.push_stmt(stmt(decr(state.expr(dst))));
-            }
-            //endregion
+        //region CONSTANTS
+        &Opcode::Int { dst, ptr } => {
+            state.push_expr(i, dst, cst_int(ptr));
+        }
+        &Opcode::Float { dst, ptr } => {
+            state.push_expr(i, dst, cst_float(ptr));
+        }
+        &Opcode::Bool { dst, value } => {
+            state.push_expr(i, dst, cst_bool(value.0));
+        }
+        &Opcode::String { dst, ptr } => {
+            state.push_expr(i, dst, cst_string(ptr));
+        }
+        &Opcode::Bytes { dst, ptr } => {
+            state.push_expr(i, dst, cst_bytes(ptr));
+        }
+        &Opcode::Null { dst } => {
+            state.push_expr(i, dst, cst_null());
+        }
+        //endregion
 
-            //region CALLS
-            &Opcode::Call0 { dst, fun } => {
-                if fun.ty(code).ret.is_void() {
-                    state.push_stmt(stmt(call_fun(fun, Vec::new())));
-                } else {
-                    state.push_expr(i, dst, call_fun(fun, Vec::new()));
-                }
-            }
-            &Opcode::Call1 { dst, fun, arg0 } => {
-                state.push_call(i, dst, fun, &[arg0]);
-            }
-            &Opcode::Call2 {
-                dst,
-                fun,
-                arg0,
-                arg1,
-            } => {
-                state.push_call(i, dst, fun, &[arg0, arg1]);
+        //region OPERATORS
+        &Opcode::Mov { dst, src } => {
+            state.push_expr(i, dst, state.expr(src));
+            // Workaround for when the instructions after this one use dst and src interchangeably.
+            state
+                .reg_state
+                .insert(src, Expr::Variable(dst, f.var_name(code, i)));
+        }
+        &Opcode::Add { dst, a, b } => {
+            state.push_expr(i, dst, add(state.expr(a), state.expr(b)));
+        }
+        &Opcode::Sub { dst, a, b } => {
+            state.push_expr(i, dst, sub(state.expr(a), state.expr(b)));
+        }
+        &Opcode::Mul { dst, a, b } => {
+            state.push_expr(i, dst, mul(state.expr(a), state.expr(b)));
+        }
+        &Opcode::SDiv { dst, a, b } => {
+            state.push_expr(i, dst, div(state.expr(a), state.expr(b)));
+        }
+        &Opcode::UDiv { dst, a, b } => {
+            state.push_expr(i, dst, udiv(state.expr(a), state.expr(b)));
+        }
+        &Opcode::SMod { dst, a, b } => {
+            state.push_expr(i, dst, modulo(state.expr(a), state.expr(b)));
+        }
+        &Opcode::UMod { dst, a, b } => {
+            state.push_expr(i, dst, umod(state.expr(a), state.expr(b)));
+        }
+        &Opcode::Shl { dst, a, b } => {
+            state.push_expr(i, dst, shl(state.expr(a), state.expr(b)));
+        }
+        &Opcode::SShr { dst, a, b } => {
+            state.push_expr(i, dst, shr(state.expr(a), state.expr(b)));
+        }
+        &Opcode::UShr { dst, a, b } => {
+            state.push_expr(i, dst, ushr(state.expr(a), state.expr(b)));
+        }
+        &Opcode::And { dst, a, b } => {
+            state.push_expr(i, dst, and(state.expr(a), state.expr(b)));
+        }
+        &Opcode::Or { dst, a, b } => {
+            state.push_expr(i, dst, or(state.expr(a), state.expr(b)));
+        }
+        &Opcode::Xor { dst, a, b } => {
+            state.push_expr(i, dst, xor(state.expr(a), state.expr(b)));
+        }
+        &Opcode::Neg { dst, src } => {
+            state.push_expr(i, dst, neg(state.expr(src)));
+        }
+        &Opcode::Not { dst, src } => {
+            state.push_expr(i, dst, not(state.expr(src)));
+        }
+        &Opcode::Incr { dst } => {
+            // FIXME sometimes it should be an expression
+            state.push_stmt(stmt(incr(state.expr(dst))));
+        }
+        &Opcode::Decr { dst } => {
+            state.push_stmt(stmt(decr(state.expr(dst))));
+        }
+        //endregion
+
+        //region CALLS
+        &Opcode::Call0 { dst, fun } => {
+            if returns_void(code, fun) {
+                state.push_stmt(stmt(call_fun(fun, Vec::new())));
+            } else {
+                state.push_expr(i, dst, call_fun(fun, Vec::new()));
             }
-            &Opcode::Call3 {
-                dst,
-                fun,
-                arg0,
-                arg1,
-                arg2,
-            } => {
-                state.push_call(i, dst, fun, &[arg0, arg1, arg2]);
-            }
-            &Opcode::Call4 {
-                dst,
-                fun,
-                arg0,
-                arg1,
-                arg2,
-                arg3,
-            } => {
-                state.push_call(i, dst, fun, &[arg0, arg1, arg2, arg3]);
-            }
-            Opcode::CallN { dst, fun, args } => {
-                if let Some(&ExprCtx::Constructor { reg, pos }) = state.expr_ctx.last() {
-                    if reg == args[0] {
-                        state.push_expr(
-                            pos,
-                            reg,
-                            Expr::Constructor(ConstructorCall::new(
-                                f.regtype(reg),
-                                state.args_expr(&args[1..]),
-                            )),
-                        );
-                    }
+        }
+        &Opcode::Call1 { dst, fun, arg0 } => {
+            state.push_call(i, dst, fun, &[arg0]);
+        }
+        &Opcode::Call2 {
+            dst,
+            fun,
+            arg0,
+            arg1,
+        } => {
+            state.push_call(i, dst, fun, &[arg0, arg1]);
+        }
+        &Opcode::Call3 {
+            dst,
+            fun,
+            arg0,
+            arg1,
+            arg2,
+        } => {
+            state.push_call(i, dst, fun, &[arg0, arg1, arg2]);
+        }
+        &Opcode::Call4 {
+            dst,
+            fun,
+            arg0,
+            arg1,
+            arg2,
+            arg3,
+        } => {
+            state.push_call(i, dst, fun, &[arg0, arg1, arg2, arg3]);
+        }
+        Opcode::CallN { dst, fun, args } => {
+            if let Some(&ExprCtx::Constructor { reg, pos }) = state.expr_ctx.last() {
+                if args.first() == Some(&reg) {
+                    state.push_expr(
+                        pos,
+                        reg,
+                        Expr::Constructor(ConstructorCall::new(
+                            f.regtype(reg),
+                            state.args_expr(&args[1..]),
+                        )),
+                    );
+                }
+            } else {
+                state.push_stmt(comment(fun.display::<EnhancedFmt>(code).to_string()));
+                let call = call_fun(*fun, state.args_expr(args));
+                if returns_void(code, *fun) {
+                    state.push_stmt(stmt(call));
                 } else {
-                    state.push_stmt(comment(fun.display::<EnhancedFmt>(code).to_string()));
-                    let call = call_fun(*fun, state.args_expr(args));
-                    if fun.ty(code).ret.is_void() {
-                        state.push_stmt(stmt(call));
-                    } else {
-                        state.push_expr(i, *dst, call);
-                    }
+                    state.push_expr(i, *dst, call);
                 }
             }
-            Opcode::CallMethod { dst, field, args } => {
+        }
+        Opcode::CallMethod { dst, field, args } => {
+            if let Some(&this) = args.first() {
                 let call = call(
-                    ast::field(state.expr(args[0]), f.regtype(args[0]), *field, code),
+                    ast::field(state.expr(this), f.regtype(this), *field, code),
                     state.args_expr(&args[1..]),
                 );
-                if f.regtype(args[0])
+                if f.regtype(this)
                     .method(field.0, code)
-                    .and_then(|p| p.findex.as_fn(code))
-                    .map(|fun| fun.ty(code).ret.is_void())
+                    .map(|p| returns_void(code, p.findex))
                     .unwrap_or(false)
                 {
                     state.push_stmt(stmt(call));
                 } else {
                     state.push_expr(i, *dst, call);
                 }
+            } else {
+                state.push_expr(i, *dst, Expr::Unknown("missing this".to_owned()));
             }
-            Opcode::CallThis { dst, field, args } => {
-                let method = f.regs[0].method(field.0, code).unwrap();
+        }
+        Opcode::CallThis { dst, field, args } => {
+            if let Some(method) = f.regtype(Reg(0)).method(field.0, code) {
                 let call = call(
                     Expr::Field(Box::new(cst_this()), method.name(code)),
                     state.args_expr(args),
                 );
-                if method
-                    .findex
-                    .as_fn(code)
-                    .map(|fun| fun.ty(code).ret.is_void())
-                    .unwrap_or(false)
-                {
+                if returns_void(code, method.findex) {
                     state.push_stmt(stmt(call));
                 } else {
                     state.push_expr(i, *dst, call);
                 }
+            } else {
+                state.push_expr(i, *dst, Expr::Unknown(format!("unknown method {field}")));
             }
-            Opcode::CallClosure { dst, fun, args } => {
-                let call = call(state.expr(*fun), state.args_expr(args));
-                if f.regtype(*fun)
-                    .as_fun(code)
-                    .map(|ty| ty.ret.is_void())
-                    .unwrap_or(false)
-                {
-                    state.push_stmt(stmt(call));
-                } else {
-                    state.push_expr(i, *dst, call);
-                }
+        }
+        Opcode::CallClosure { dst, fun, args } => {
+            let call = call(state.expr(*fun), state.args_expr(args));
+            if f.regtype(*fun)
+                .as_fun(code)
+                .map(|ty| ty.ret.is_void())
+                .unwrap_or(false)
+            {
+                state.push_stmt(stmt(call));
+            } else {
+                state.push_expr(i, *dst, call);
             }
-            //endregion
+        }
+        //endregion
 
-            //region CLOSURES
-            &Opcode::StaticClosure { dst, fun } => {
-                state.push_stmt(comment(format!(
-                    "closure : {}",
-                    fun.display::<EnhancedFmt>(code)
-                )));
-                state.push_expr(
-                    i,
-                    dst,
-                    Expr::Closure(fun, decompile_code(code, fun.as_fn(code).unwrap())),
-                );
-            }
-            &Opcode::InstanceClosure { dst, obj, fun } => {
-                state.push_stmt(comment(format!(
-                    "closure : {}",
-                    fun.display::<EnhancedFmt>(code)
-                )));
-                match &code[f[obj]] {
-                    // This is an anonymous enum holding the capture for the closure
-                    Type::Enum { .. } => {
-                        state.push_expr(
-                            i,
-                            dst,
-                            Expr::Closure(fun, decompile_code(code, fun.as_fn(code).unwrap())),
-                        );
-                    }
-                    _ => {
-                        state.push_expr(
-                            i,
-                            dst,
-                            Expr::Field(Box::new(state.expr(obj)), fun.name(code)),
-                        );
-                    }
+        //region CLOSURES
+        &Opcode::StaticClosure { dst, fun } => {
+            state.push_stmt(comment(format!(
+                "closure : {}",
+                fun.display::<EnhancedFmt>(code)
+            )));
+            state.push_expr(i, dst, closure(code, fun, state.structuring));
+        }
+        &Opcode::InstanceClosure { dst, obj, fun } => {
+            state.push_stmt(comment(format!(
+                "closure : {}",
+                fun.display::<EnhancedFmt>(code)
+            )));
+            match state.reg_type(obj) {
+                // This is an anonymous enum holding the capture for the closure
+                Ok(Type::Enum { .. }) => {
+                    state.push_expr(i, dst, closure(code, fun, state.structuring));
                 }
-            }
-            //endregion
-
-            //region ACCESSES
-            &Opcode::GetGlobal { dst, global } => {
-                // Is a string
-                if f[dst].0 == 13 {
+                _ => {
                     state.push_expr(
                         i,
                         dst,
-                        cst_string(RefString(
-                            code.globals_initializers
-                                .get(&global)
-                                .and_then(|&x| {
-                                    code.constants
-                                        .as_ref()
-                                        .map(|constants| constants[x].fields[0])
-                                })
-                                .unwrap_or(0),
-                        )),
+                        Expr::Field(Box::new(state.expr(obj)), fun.name(code)),
                     );
-                } else {
-                    match &code[f[dst]] {
-                        Type::Obj(obj) | Type::Struct(obj) => {
-                            state.push_expr(
-                                i,
-                                dst,
-                                Expr::Variable(dst, Some(code[obj.name].to_owned())),
-                            );
-                        }
-                        Type::Enum { .. } => {
-                            state.push_expr(
-                                i,
-                                dst,
-                                Expr::Unknown("unknown enum variant".to_owned()),
-                            );
-                        }
-                        _ => {}
-                    }
                 }
             }
-            &Opcode::Field { dst, obj, field } => {
+        }
+        &Opcode::VirtualClosure { dst, obj, field } => {
+            // The field operand is an index into the virtual fields, not a register
+            state.push_expr(
+                i,
+                dst,
+                ast::field(
+                    state.expr(obj),
+                    f.regtype(obj),
+                    RefField(field.0 as usize),
+                    code,
+                ),
+            );
+        }
+        //endregion
+
+        //region ACCESSES
+        &Opcode::GetGlobal { dst, global } => {
+            // Is a string
+            if f[dst].0 == 13 {
                 state.push_expr(
                     i,
                     dst,
-                    ast::field(state.expr(obj), f.regtype(obj), field, code),
+                    cst_string(RefString(
+                        code.globals_initializers
+                            .get(&global)
+                            .and_then(|&x| {
+                                code.constants
+                                    .as_ref()
+                                    .and_then(|constants| constants.get(x))
+                                    .and_then(|c| c.fields.first().copied())
+                            })
+                            .unwrap_or(0),
+                    )),
                 );
-            }
-            &Opcode::SetField { obj, field, src } => {
-                let ctx = state.expr_ctx.pop();
-                // Might be a SetField for an anonymous structure
-                if let Some(ExprCtx::Anonymous {
-                    pos,
-                    mut fields,
-                    mut remaining,
-                }) = ctx
-                {
-                    fields.insert(field, state.expr(src));
-                    remaining -= 1;
-                    // If we filled all the structure fields, we emit an expr
-                    if remaining == 0 {
-                        state.push_expr(pos, obj, Expr::Anonymous(f.regtype(obj), fields));
-                    } else {
-                        state.expr_ctx.push(ExprCtx::Anonymous {
-                            pos,
-                            fields,
-                            remaining,
-                        });
+            } else {
+                match state.reg_type(dst) {
+                    Ok(Type::Obj(obj) | Type::Struct(obj)) => {
+                        state.push_expr(i, dst, Expr::Variable(dst, Some(obj.name(code))));
+                    }
+                    Ok(Type::Enum { .. }) => {
+                        state.push_expr(i, dst, Expr::Unknown("unknown enum variant".to_owned()));
                     }
-                } else if let Some(ctx) = ctx {
-                    state.expr_ctx.push(ctx);
+                    Ok(_) => state.push_expr(i, dst, Expr::Global(global)),
+                    Err(e) => state.push_expr(i, dst, Expr::Unknown(e.placeholder())),
+                }
+            }
+        }
+        &Opcode::SetGlobal { global, src } => {
+            state.push_stmt(Statement::Assign {
+                declaration: false,
+                variable: Expr::Global(global),
+                assign: state.expr(src),
+            });
+        }
+        &Opcode::Field { dst, obj, field } => {
+            state.push_expr(
+                i,
+                dst,
+                ast::field(state.expr(obj), f.regtype(obj), field, code),
+            );
+        }
+        &Opcode::SetField { obj, field, src } => {
+            let ctx = state.expr_ctx.pop();
+            // Might be a SetField for an anonymous structure
+            if let Some(ExprCtx::Anonymous {
+                pos,
+                mut fields,
+                mut remaining,
+            }) = ctx
+            {
+                fields.insert(field, state.expr(src));
+                remaining -= 1;
+                // If we filled all the structure fields, we emit an expr
+                if remaining == 0 {
+                    state.push_expr(pos, obj, Expr::Anonymous(f.regtype(obj), fields));
                 } else {
-                    // Otherwise this is just a normal field set
-                    state.push_stmt(Statement::Assign {
-                        declaration: false,
-                        variable: ast::field(state.expr(obj), f.regtype(obj), field, code),
-                        assign: state.expr(src),
+                    state.expr_ctx.push(ExprCtx::Anonymous {
+                        pos,
+                        fields,
+                        remaining,
                     });
                 }
-            }
-            &Opcode::GetThis { dst, field } => {
-                state.push_expr(i, dst, ast::field(cst_this(), f.regs[0], field, code));
-            }
-            &Opcode::SetThis { field, src } => {
-                state.push_stmt(Statement::Assign {
-                    declaration: false,
-                    variable: ast::field(cst_this(), f.regs[0], field, code),
-                    assign: state.expr(src),
-                });
-            }
-            &Opcode::DynGet { dst, obj, field } => {
-                state.push_expr(i, dst, array(state.expr(obj), cst_string(field)));
-            }
-            &Opcode::DynSet { obj, field, src } => {
+            } else if let Some(ctx) = ctx {
+                state.expr_ctx.push(ctx);
+            } else {
+                // Otherwise this is just a normal field set
                 state.push_stmt(Statement::Assign {
                     declaration: false,
-                    variable: array(state.expr(obj), cst_string(field)),
+                    variable: ast::field(state.expr(obj), f.regtype(obj), field, code),
                     assign: state.expr(src),
                 });
             }
-            //endregion
-
-            //region VALUES
-            &Opcode::ToDyn { dst, src }
-            | &Opcode::ToSFloat { dst, src }
-            | &Opcode::ToUFloat { dst, src }
-            | &Opcode::ToInt { dst, src }
-            | &Opcode::SafeCast { dst, src }
-            | &Opcode::UnsafeCast { dst, src }
-            | &Opcode::ToVirtual { dst, src } => {
-                state.push_expr(i, dst, state.expr(src));
-            }
-            &Opcode::Ref { dst, src } => {
-                state.push_expr(i, dst, state.expr(src));
-            }
-            &Opcode::Unref { dst, src } => {
-                state.push_expr(i, dst, state.expr(src));
-            }
-            &Opcode::Setref { dst, value } => {
-                state.push_stmt(Statement::Assign {
-                    declaration: false,
-                    variable: state.expr(dst),
-                    assign: state.expr(value),
-                });
-            }
-            &Opcode::RefData { dst, src } => {
-                state.push_expr(i, dst, state.expr(src));
-            }
-            &Opcode::New { dst } => {
-                // Constructor analysis
-                match &code[f[dst]] {
-                    Type::Obj(_) | Type::Struct(_) => {
-                        state
-                            .expr_ctx
-                            .push(ExprCtx::Constructor { reg: dst, pos: i });
-                    }
-                    Type::Virtual { fields } => {
-                        state.expr_ctx.push(ExprCtx::Anonymous {
-                            pos: i,
-                            fields: HashMap::with_capacity(fields.len()),
-                            remaining: fields.len(),
-                        });
-                    }
-                    _ => {
-                        state.push_expr(
-                            i,
-                            dst,
-                            Expr::Constructor(ConstructorCall::new(f.regtype(dst), Vec::new())),
-                        );
-                    }
-                }
-            }
-            //endregion
-
-            //region ENUMS
-            &Opcode::EnumAlloc { dst, construct } => {
-                state.push_expr(
-                    i,
-                    dst,
-                    Expr::EnumConstr(f.regtype(dst), construct, Vec::new()),
-                );
-            }
-            Opcode::MakeEnum {
+        }
+        &Opcode::GetThis { dst, field } => {
+            state.push_expr(
+                i,
                 dst,
-                construct,
-                args,
-            } => {
-                state.push_expr(
-                    i,
-                    *dst,
-                    Expr::EnumConstr(f.regtype(*dst), *construct, state.args_expr(args)),
-                );
-            }
-            &Opcode::EnumIndex { dst, value } => {
-                state.push_expr(
-                    i,
-                    dst,
-                    Expr::Field(Box::new(state.expr(value)), Str::from("constructorIndex")),
-                );
-                //state.push_expr(i, dst, state.expr(value));
-            }
-            &Opcode::EnumField {
+                ast::field(cst_this(), f.regtype(Reg(0)), field, code),
+            );
+        }
+        &Opcode::SetThis { field, src } => {
+            state.push_stmt(Statement::Assign {
+                declaration: false,
+                variable: ast::field(cst_this(), f.regtype(Reg(0)), field, code),
+                assign: state.expr(src),
+            });
+        }
+        &Opcode::DynGet { dst, obj, field } => {
+            state.push_expr(i, dst, array(state.expr(obj), cst_string(field)));
+        }
+        &Opcode::DynSet { obj, field, src } => {
+            state.push_stmt(Statement::Assign {
+                declaration: false,
+                variable: array(state.expr(obj), cst_string(field)),
+                assign: state.expr(src),
+            });
+        }
+        //endregion
+
+        //region VALUES
+        &Opcode::ToDyn { dst, src }
+        | &Opcode::ToSFloat { dst, src }
+        | &Opcode::ToUFloat { dst, src }
+        | &Opcode::ToInt { dst, src }
+        | &Opcode::SafeCast { dst, src }
+        | &Opcode::UnsafeCast { dst, src }
+        | &Opcode::ToVirtual { dst, src } => {
+            state.push_expr(i, dst, state.expr(src));
+        }
+        &Opcode::Ref { dst, src } => {
+            state.push_expr(i, dst, state.expr(src));
+        }
+        &Opcode::Unref { dst, src } => {
+            state.push_expr(i, dst, state.expr(src));
+        }
+        &Opcode::Setref { dst, value } => {
+            state.push_stmt(Statement::Assign {
+                declaration: false,
+                variable: state.expr(dst),
+                assign: state.expr(value),
+            });
+        }
+        &Opcode::RefData { dst, src } => {
+            state.push_expr(i, dst, state.expr(src));
+        }
+        &Opcode::RefOffset { dst, reg, offset } => {
+            state.push_expr(
+                i,
                 dst,
-                value,
-                construct,
-                field,
-            } => {
-                state.push_expr(
-                    i,
-                    dst,
-                    Expr::Field(Box::new(state.expr(value)), Str::from(field.0.to_string())),
-                );
-            }
-            &Opcode::SetEnumField { value, field, src } => match state.expr(value) {
-                Expr::Variable(r, name) => {
-                    state.push_stmt(Statement::Assign {
-                        declaration: false,
-                        variable: Expr::Field(
-                            Box::new(state.expr(value)),
-                            Str::from(field.0.to_string()),
-                        ),
-                        assign: state.expr(src),
+                call(
+                    Expr::Field(Box::new(state.expr(reg)), Str::from("offset")),
+                    vec![state.expr(offset)],
+                ),
+            );
+        }
+        &Opcode::New { dst } => {
+            // Constructor analysis
+            match state.reg_type(dst) {
+                Ok(Type::Obj(_) | Type::Struct(_)) => {
+                    state
+                        .expr_ctx
+                        .push(ExprCtx::Constructor { reg: dst, pos: i });
+                }
+                Ok(Type::Virtual { fields }) => {
+                    state.expr_ctx.push(ExprCtx::Anonymous {
+                        pos: i,
+                        fields: HashMap::with_capacity(fields.len()),
+                        remaining: fields.len(),
                     });
                 }
                 _ => {
-                    state.push_stmt(comment("closure capture"));
-                    state.push_stmt(Statement::Assign {
-                        declaration: false,
-                        variable: Expr::Field(
-                            Box::new(state.expr(value)),
-                            Str::from(field.0.to_string()),
-                        ),
-                        assign: state.expr(src),
-                    });
+                    state.push_expr(
+                        i,
+                        dst,
+                        Expr::Constructor(ConstructorCall::new(f.regtype(dst), Vec::new())),
+                    );
                 }
-            },
-            //endregion
-
-            //region ARRAYS
-            &Opcode::ArraySize { dst, array } => {
-                state.push_expr(
-                    i,
-                    dst,
-                    Expr::Field(Box::new(state.expr(array)), Str::from("length")),
-                );
             }
-            &Opcode::GetArray { dst, array, index } => {
-                state.push_expr(i, dst, ast::array(state.expr(array), state.expr(index)));
-            }
-            &Opcode::SetArray { array, index, src } => {
+        }
+        //endregion
+
+        //region ENUMS
+        &Opcode::EnumAlloc { dst, construct } => {
+            state.push_expr(
+                i,
+                dst,
+                Expr::EnumConstr(f.regtype(dst), construct, Vec::new()),
+            );
+        }
+        Opcode::MakeEnum {
+            dst,
+            construct,
+            args,
+        } => {
+            state.push_expr(
+                i,
+                *dst,
+                Expr::EnumConstr(f.regtype(*dst), *construct, state.args_expr(args)),
+            );
+        }
+        &Opcode::EnumIndex { dst, value } => {
+            state.push_expr(
+                i,
+                dst,
+                Expr::EnumIndex(f.regtype(value), Box::new(state.expr(value))),
+            );
+        }
+        &Opcode::EnumField {
+            dst,
+            value,
+            construct,
+            field,
+        } => {
+            state.push_expr(
+                i,
+                dst,
+                Expr::EnumField {
+                    value: Box::new(state.expr(value)),
+                    construct,
+                    field: field.0,
+                    reg: dst,
+                },
+            );
+        }
+        &Opcode::SetEnumField { value, field, src } => match state.expr(value) {
+            Expr::Variable(r, name) => {
                 state.push_stmt(Statement::Assign {
                     declaration: false,
-                    variable: ast::array(state.expr(array), state.expr(index)),
+                    variable: Expr::Field(
+                        Box::new(state.expr(value)),
+                        Str::from(field.0.to_string()),
+                    ),
                     assign: state.expr(src),
                 });
             }
-            //endregion
-
-            //region MEM
-            &Opcode::GetMem { dst, bytes, index } => {
-                state.push_expr(i, dst, array(state.expr(bytes), state.expr(index)));
-            }
-            &Opcode::SetMem { bytes, index, src } => {
+            _ => {
+                state.push_stmt(comment("closure capture"));
                 state.push_stmt(Statement::Assign {
                     declaration: false,
-                    variable: array(state.expr(bytes), state.expr(index)),
+                    variable: Expr::Field(
+                        Box::new(state.expr(value)),
+                        Str::from(field.0.to_string()),
+                    ),
                     assign: state.expr(src),
                 });
             }
-            //endregion
-            _ => {}
+        },
+        //endregion
+
+        //region ARRAYS
+        &Opcode::ArraySize { dst, array } => {
+            state.push_expr(
+                i,
+                dst,
+                Expr::Field(Box::new(state.expr(array)), Str::from("length")),
+            );
         }
-        state.scopes.advance();
-    }
-    let mut statements = state.scopes.statements();
+        &Opcode::GetArray { dst, array, index } => {
+            state.push_expr(i, dst, ast::array(state.expr(array), state.expr(index)));
+        }
+        &Opcode::SetArray { array, index, src } => {
+            state.push_stmt(Statement::Assign {
+                declaration: false,
+                variable: ast::array(state.expr(array), state.expr(index)),
+                assign: state.expr(src),
+            });
+        }
+        //endregion
 
-    // AST post processing step !
-    // It makes a single pass for all visitors
-    post::visit(
-        code,
-        &mut statements,
-        &mut [
-            Box::new(post::IfExpressions),
-            Box::new(post::StringConcat),
-            Box::new(post::Itos),
-            Box::new(post::Trace),
-        ],
-    );
+        //region MEM
+        &Opcode::GetMem { dst, bytes, index } => {
+            state.push_expr(i, dst, array(state.expr(bytes), state.expr(index)));
+        }
+        &Opcode::SetMem { bytes, index, src } => {
+            state.push_stmt(Statement::Assign {
+                declaration: false,
+                variable: array(state.expr(bytes), state.expr(index)),
+                assign: state.expr(src),
+            });
+        }
+        &Opcode::GetI8 { dst, bytes, index } => {
+            state.push_expr(i, dst, bytes_call(state, bytes, "getUI8", &[index]));
+        }
+        &Opcode::GetI16 { dst, bytes, index } => {
+            state.push_expr(i, dst, bytes_call(state, bytes, "getUI16", &[index]));
+        }
+        &Opcode::SetI8 { bytes, index, src } => {
+            state.push_stmt(stmt(bytes_call(state, bytes, "setUI8", &[index, src])));
+        }
+        &Opcode::SetI16 { bytes, index, src } => {
+            state.push_stmt(stmt(bytes_call(state, bytes, "setUI16", &[index, src])));
+        }
+        //endregion
 
-    statements
+        //region TYPES
+        &Opcode::Type { dst, ty } => {
+            state.push_expr(i, dst, Expr::Type(ty));
+        }
+        &Opcode::GetType { dst, src } => {
+            state.push_expr(
+                i,
+                dst,
+                call(
+                    Expr::Field(
+                        Box::new(Expr::Variable(dst, Some(Str::from("hl.Type")))),
+                        Str::from("getDynamic"),
+                    ),
+                    vec![state.expr(src)],
+                ),
+            );
+        }
+        &Opcode::GetTID { dst, src } => {
+            state.push_expr(
+                i,
+                dst,
+                Expr::Field(Box::new(state.expr(src)), Str::from("kind")),
+            );
+        }
+        //endregion
+        &Opcode::Nop => {}
+        // Handled by the control flow structuring
+        Opcode::JTrue { .. }
+        | Opcode::JFalse { .. }
+        | Opcode::JNull { .. }
+        | Opcode::JNotNull { .. }
+        | Opcode::JSLt { .. }
+        | Opcode::JSGte { .. }
+        | Opcode::JSGt { .. }
+        | Opcode::JSLte { .. }
+        | Opcode::JULt { .. }
+        | Opcode::JUGte { .. }
+        | Opcode::JNotLt { .. }
+        | Opcode::JNotGte { .. }
+        | Opcode::JEq { .. }
+        | Opcode::JNotEq { .. }
+        | Opcode::JAlways { .. }
+        | Opcode::Switch { .. }
+        | Opcode::Label
+        | Opcode::Ret { .. }
+        | Opcode::Trap { .. }
+        | Opcode::EndTrap { .. } => {}
+    }
+}
+
+/// Call to a method of hl.Bytes, for memory accesses with a width
+fn bytes_call(state: &DecompilerState, bytes: Reg, method: &'static str, args: &[Reg]) -> Expr {
+    call(
+        Expr::Field(Box::new(state.expr(bytes)), Str::from(method)),
+        args.iter().map(|&r| state.expr(r)).collect(),
+    )
 }
 
 /// Decompile a function out of context
 pub fn decompile_function(code: &Bytecode, f: &Function) -> Method {
+    decompile_function_with(code, f, Structuring::default())
+}
+
+/// [decompile_function] with the given structuring algorithm
+pub fn decompile_function_with(code: &Bytecode, f: &Function, structuring: Structuring) -> Method {
     Method {
         fun: f.findex,
         static_: true,
         dynamic: false,
-        statements: decompile_code(code, f),
+        statements: decompile_code_with(code, f, structuring),
     }
 }
 
 /// Decompile a class with its static and instance fields and methods.
 pub fn decompile_class(code: &Bytecode, obj: &TypeObj) -> Class {
+    decompile_class_with(code, obj, Structuring::default())
+}
+
+/// [decompile_class] with the given structuring algorithm
+pub fn decompile_class_with(code: &Bytecode, obj: &TypeObj, structuring: Structuring) -> Class {
     let static_type = obj.get_static_type(code);
 
     let mut fields = Vec::new();
@@ -833,13 +1072,20 @@ pub fn decompile_class(code: &Bytecode, obj: &TypeObj) -> Class {
         }
     }
 
+    // Bindings and protos are supposed to always point to functions
+    let body = |fun: RefFun| match code.try_resolve(fun) {
+        Ok(FunPtr::Fun(f)) => decompile_code_with(code, f, structuring),
+        Ok(FunPtr::Native(_)) => vec![comment("native function")],
+        Err(e) => vec![comment(e.to_string())],
+    };
+
     let mut methods = Vec::new();
     for fun in obj.bindings.values() {
         methods.push(Method {
             fun: *fun,
             static_: false,
             dynamic: true,
-            statements: decompile_code(code, fun.as_fn(code).unwrap()),
+            statements: body(*fun),
         })
     }
     if let Some(ty) = static_type {
@@ -848,7 +1094,7 @@ pub fn decompile_class(code: &Bytecode, obj: &TypeObj) -> Class {
                 fun: *fun,
                 static_: true,
                 dynamic: false,
-                statements: decompile_code(code, fun.as_fn(code).unwrap()),
+                statements: body(*fun),
             })
         }
     }
@@ -857,7 +1103,7 @@ pub fn decompile_class(code: &Bytecode, obj: &TypeObj) -> Class {
             fun: f.findex,
             static_: false,
             dynamic: false,
-            statements: decompile_code(code, f.findex.as_fn(code).unwrap()),
+            statements: body(f.findex),
         })
     }
 
@@ -872,15 +1118,125 @@ pub fn decompile_class(code: &Bytecode, obj: &TypeObj) -> Class {
     }
 }
 
+/// Decompile an enum declaration, [None] if the type isn't an enum.
+pub fn decompile_enum(code: &Bytecode, ty: &Type) -> Option<Enum> {
+    match ty {
+        Type::Enum {
+            name, constructs, ..
+        } => Some(Enum {
+            name: code
+                .try_resolve(*name)
+                .unwrap_or_else(|e| e.placeholder().into()),
+            constructs: constructs
+                .iter()
+                .map(|c| EnumVariant {
+                    name: c.name(code),
+                    params: c.params.clone(),
+                })
+                .collect(),
+        }),
+        _ => None,
+    }
+}
+
+/// Decompile every class and enum outside of the standard library, one file per type in a directory
+/// for each package. The entrypoint is decompiled as the main function of a `Main` class.
+pub fn decompile_sources(code: &Bytecode) -> Vec<SourceFile> {
+    decompile_sources_with(code, Structuring::default())
+}
+
+/// [decompile_sources] with the given structuring algorithm
+pub fn decompile_sources_with(code: &Bytecode, structuring: Structuring) -> Vec<SourceFile> {
+    let mut files = Vec::new();
+    let mut paths = HashSet::new();
+    for ty in &code.types {
+        if ty.is_from_std(code) {
+            continue;
+        }
+        match ty {
+            // The static part of a class is decompiled with it
+            Type::Obj(obj) | Type::Struct(obj) if !obj.name(code).starts_with('$') => {
+                let class = decompile_class_with(code, obj, structuring);
+                let name = class.name.clone();
+                sources::push_file(
+                    code,
+                    &mut files,
+                    &mut paths,
+                    &name,
+                    TypeDecl::Class(class),
+                    &sources::class_types(code, obj),
+                );
+            }
+            Type::Enum { .. } => match decompile_enum(code, ty) {
+                // Enums without a name hold closure captures
+                Some(e) if !e.name.is_empty() => {
+                    let name = e.name.clone();
+                    let types: Vec<RefType> = e
+                        .constructs
+                        .iter()
+                        .flat_map(|c| c.params.iter().copied())
+                        .collect();
+                    sources::push_file(
+                        code,
+                        &mut files,
+                        &mut paths,
+                        &name,
+                        TypeDecl::Enum(e),
+                        &types,
+                    );
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    if let Some(entrypoint) = code.entrypoint.as_fn(code) {
+        // Don't overwrite a Main class from the program
+        let name = if paths.contains(Path::new("Main.hx")) {
+            "Entrypoint"
+        } else {
+            "Main"
+        };
+        let class = Class {
+            name: Str::from(name),
+            parent: None,
+            fields: Vec::new(),
+            methods: vec![Method {
+                fun: code.entrypoint,
+                static_: true,
+                dynamic: false,
+                statements: decompile_code_with(code, entrypoint, structuring),
+            }],
+        };
+        sources::push_file(
+            code,
+            &mut files,
+            &mut paths,
+            name,
+            TypeDecl::Class(class),
+            &sources::fun_types(code, code.entrypoint),
+        );
+    }
+    files
+}
+
 #[cfg(test)]
 mod tests {
     use std::fs;
     use std::hint::black_box;
     use std::io::BufReader;
 
+    use hlbc::asm::assemble;
+    use hlbc::builder::BytecodeBuilder;
+    use hlbc::opcodes::Opcode;
+    use hlbc::types::{EnumConstruct, ObjField, RefGlobal, RefType, Reg, Type};
     use hlbc::Bytecode;
 
-    use crate::{decompile_class, decompile_code, decompile_function};
+    use crate::fmt::FormatOptions;
+    use crate::{
+        decompile_class, decompile_code, decompile_code_with, decompile_function, Structuring,
+    };
 
     #[test]
     fn decomp_code_all() {
@@ -954,4 +1310,341 @@ mod tests {
             black_box(decompile_function(&code, f));
         }
     }
+
+    /// Decompile a function from its assembly without the `fn` line. Types `@0` to `@4` are void, i32,
+    /// bytes, dynamic and type, `setup` adds the other elements the function uses.
+    /// The `op_*` tests built with it cover each opcode, the `data/` samples are only compiled by the round trip test.
+    /// Registers are all arguments, so they have a value from the start.
+    fn decompile_ops_with(
+        structuring: Structuring,
+        ops: &str,
+        setup: fn(&mut BytecodeBuilder),
+    ) -> String {
+        let mut builder = BytecodeBuilder::new();
+        let void = builder.intern_type(Type::Void);
+        for ty in [Type::I32, Type::Bytes, Type::Dyn, Type::Type] {
+            builder.intern_type(ty);
+        }
+        setup(&mut builder);
+        let args = ops
+            .lines()
+            .filter(|l| l.starts_with("reg"))
+            .filter_map(|l| l.split_once('@'))
+            .map(|(_, ty)| RefType(ty.parse().unwrap()))
+            .collect();
+        let t = builder.fun_type(args, void);
+        let fun = assemble(&mut builder, &format!("fn new @{}\n{}", t.0, ops)).unwrap();
+        builder.insert_function(fun);
+        let code = builder.build();
+        let f = &code.functions[0];
+        decompile_code_with(&code, f, structuring)
+            .iter()
+            .map(|s| s.display(&FormatOptions::new(2), &code, f).to_string())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn decompile_ops(ops: &str, setup: fn(&mut BytecodeBuilder)) -> String {
+        decompile_ops_with(Structuring::Scopes, ops, setup)
+    }
+
+    /// Registers `reg0` and `reg1` are the operands, `reg2` the result
+    fn decompile_binop(op: &str) -> String {
+        decompile_ops(
+            &format!("reg0 @1\nreg1 @1\nreg2 @1\n{} reg2 reg0 reg1\nRet reg2", op),
+            |_| {},
+        )
+    }
+
+    #[test]
+    fn op_udiv() {
+        assert_eq!(
+            decompile_binop("UDiv"),
+            "return Std.int((reg0 : UInt) / (reg1 : UInt));"
+        );
+    }
+
+    #[test]
+    fn op_umod() {
+        assert_eq!(
+            decompile_binop("UMod"),
+            "return (reg0 : UInt) % (reg1 : UInt);"
+        );
+    }
+
+    #[test]
+    fn op_ushr() {
+        assert_eq!(decompile_binop("UShr"), "return reg0 >>> reg1;");
+    }
+
+    /// Jump over a global assignment when the condition is met
+    fn decompile_jump(structuring: Structuring, jump: &str) -> String {
+        decompile_ops_with(
+            structuring,
+            &format!(
+                "reg0 @1\nreg1 @1\nreg2 @0\n{} reg0 reg1 end\nSetGlobal @0 reg0\nend:\nRet reg2",
+                jump
+            ),
+            |b| {
+                b.add_global(RefType(1));
+            },
+        )
+    }
+
+    #[test]
+    fn op_unsigned_jumps() {
+        assert_eq!(
+            decompile_jump(Structuring::Scopes, "JULt"),
+            "if ((reg1 : UInt) <= (reg0 : UInt)) {\n  global0 = reg0;\n}"
+        );
+        assert_eq!(
+            decompile_jump(Structuring::Scopes, "JUGte"),
+            "if ((reg1 : UInt) > (reg0 : UInt)) {\n  global0 = reg0;\n}"
+        );
+        assert_eq!(
+            decompile_jump(Structuring::Cfg, "JULt"),
+            "if ((reg0 : UInt) >= (reg1 : UInt)) {\n  global0 = reg0;\n}"
+        );
+        assert_eq!(
+            decompile_jump(Structuring::Cfg, "JUGte"),
+            "if ((reg0 : UInt) < (reg1 : UInt)) {\n  global0 = reg0;\n}"
+        );
+    }
+
+    #[test]
+    fn op_not_jumps() {
+        for structuring in [Structuring::Scopes, Structuring::Cfg] {
+            assert_eq!(
+                decompile_jump(structuring, "JNotLt"),
+                "if (reg0 < reg1) {\n  global0 = reg0;\n}"
+            );
+            assert_eq!(
+                decompile_jump(structuring, "JNotGte"),
+                "if (reg0 >= reg1) {\n  global0 = reg0;\n}"
+            );
+        }
+    }
+
+    #[test]
+    fn op_set_global() {
+        let code = decompile_ops("reg0 @1\nSetGlobal @0 reg0", |b| {
+            b.add_global(RefType(1));
+        });
+        assert_eq!(code, "global0 = reg0;");
+    }
+
+    #[test]
+    fn op_get_global() {
+        let code = decompile_ops("reg0 @1\nGetGlobal reg0 @0\nRet reg0", |b| {
+            b.add_global(RefType(1));
+        });
+        assert_eq!(code, "return global0;");
+    }
+
+    #[test]
+    fn op_virtual_closure() {
+        let code = decompile_ops(
+            "reg0 @5\nreg1 @3\nVirtualClosure reg1 reg0 reg0\nRet reg1",
+            |b| {
+                let name = b.string("run");
+                b.intern_type(Type::Virtual {
+                    fields: vec![ObjField {
+                        name,
+                        t: RefType(3),
+                    }],
+                });
+            },
+        );
+        assert_eq!(code, "return reg0.run;");
+    }
+
+    #[test]
+    fn op_rethrow() {
+        assert_eq!(
+            decompile_ops("reg0 @3\nRethrow reg0", |_| {}),
+            "throw reg0;"
+        );
+    }
+
+    #[test]
+    fn op_null_check() {
+        assert_eq!(
+            decompile_ops("reg0 @3\nNullCheck reg0\nRet reg0", |_| {}),
+            "return reg0;"
+        );
+    }
+
+    #[test]
+    fn op_nop() {
+        assert_eq!(
+            decompile_ops("reg0 @3\nNop\nRet reg0", |_| {}),
+            "return reg0;"
+        );
+    }
+
+    #[test]
+    fn op_get_i8() {
+        let code = decompile_ops(
+            "reg0 @2\nreg1 @1\nreg2 @1\nGetI8 reg2 reg0 reg1\nRet reg2",
+            |_| {},
+        );
+        assert_eq!(code, "return reg0.getUI8(reg1);");
+    }
+
+    #[test]
+    fn op_get_i16() {
+        let code = decompile_ops(
+            "reg0 @2\nreg1 @1\nreg2 @1\nGetI16 reg2 reg0 reg1\nRet reg2",
+            |_| {},
+        );
+        assert_eq!(code, "return reg0.getUI16(reg1);");
+    }
+
+    #[test]
+    fn op_set_i8() {
+        let code = decompile_ops("reg0 @2\nreg1 @1\nreg2 @1\nSetI8 reg0 reg1 reg2", |_| {});
+        assert_eq!(code, "reg0.setUI8(reg1, reg2);");
+    }
+
+    #[test]
+    fn op_set_i16() {
+        let code = decompile_ops("reg0 @2\nreg1 @1\nreg2 @1\nSetI16 reg0 reg1 reg2", |_| {});
+        assert_eq!(code, "reg0.setUI16(reg1, reg2);");
+    }
+
+    #[test]
+    fn op_type() {
+        assert_eq!(
+            decompile_ops("reg0 @4\nType reg0 @1\nRet reg0", |_| {}),
+            "return Int;"
+        );
+    }
+
+    #[test]
+    fn op_get_type() {
+        assert_eq!(
+            decompile_ops("reg0 @3\nreg1 @4\nGetType reg1 reg0\nRet reg1", |_| {}),
+            "return hl.Type.getDynamic(reg0);"
+        );
+    }
+
+    #[test]
+    fn op_get_tid() {
+        assert_eq!(
+            decompile_ops("reg0 @4\nreg1 @1\nGetTID reg1 reg0\nRet reg1", |_| {}),
+            "return reg0.kind;"
+        );
+    }
+
+    #[test]
+    fn op_assert() {
+        assert_eq!(decompile_ops("Assert", |_| {}), "throw \"assert\";");
+    }
+
+    #[test]
+    fn op_ref_offset() {
+        let code = decompile_ops(
+            "reg0 @5\nreg1 @1\nreg2 @5\nRefOffset reg2 reg0 reg1\nRet reg2",
+            |b| {
+                b.intern_type(Type::Ref(RefType(1)));
+            },
+        );
+        assert_eq!(code, "return reg0.offset(reg1);");
+    }
+
+    #[test]
+    fn op_bytes() {
+        let code = decompile_ops("reg0 @2\nBytes reg0 @0\nRet reg0", |b| {
+            b.bytes(b"hl\n\x00");
+        });
+        assert_eq!(
+            code,
+            r#"return @:privateAccess haxe.io.Bytes.ofHex("686c0a00").b;"#
+        );
+    }
+
+    #[test]
+    fn op_bytes_string() {
+        let code = decompile_ops("reg0 @2\nBytes reg0 @0\nRet reg0", |b| {
+            b.bytes(b"h\0\xe9\0\n\0\0\0");
+        });
+        assert_eq!(code, r#"return @:privateAccess "h\u{e9}\n".bytes;"#);
+    }
+
+    /// Switch on the value `reg0` of the enum `Kind { Fire; Ice(i32); Rock(i32, i32); }`.
+    /// The parameter read into `reg2` is named `a`, the one read into `reg3` isn't named.
+    fn decompile_enum_switch(structuring: Structuring) -> String {
+        let mut builder = BytecodeBuilder::new();
+        let void = builder.intern_type(Type::Void);
+        let i32 = builder.intern_type(Type::I32);
+        let name = builder.string("Kind");
+        let constructs = [
+            ("Fire", vec![]),
+            ("Ice", vec![i32]),
+            ("Rock", vec![i32, i32]),
+        ]
+        .into_iter()
+        .map(|(name, params)| EnumConstruct {
+            name: builder.string(name),
+            params,
+        })
+        .collect();
+        let kind = builder.add_type(Type::Enum {
+            name,
+            global: RefGlobal(0),
+            constructs,
+        });
+        builder.add_global(i32);
+        let t = builder.fun_type(vec![kind, i32], void);
+        let src = format!(
+            "fn new @{}\nreg0 @{}\nreg1 @1\nreg2 @1\nreg3 @1\nreg4 @0\n{}",
+            t.0,
+            kind.0,
+            r"
+                EnumIndex reg1 reg0
+                Switch reg1 [fire, ice, rock] end
+                JAlways end
+            fire:
+                Int reg3 0
+                SetGlobal @0 reg3
+                JAlways end
+            ice:
+                EnumField reg2 reg0 @1 @0
+                SetGlobal @0 reg2
+                JAlways end
+            rock:
+                EnumField reg3 reg0 @2 @1
+                SetGlobal @0 reg3
+            end:
+                Ret reg4
+            "
+        );
+        let mut fun = assemble(&mut builder, &src).unwrap();
+        let pos = fun
+            .ops
+            .iter()
+            .position(|o| matches!(o, Opcode::EnumField { dst: Reg(2), .. }))
+            .unwrap();
+        fun.assigns = Some(vec![(builder.string("a"), pos + 1)]);
+        builder.insert_function(fun);
+        let code = builder.build();
+        let f = &code.functions[0];
+        decompile_code_with(&code, f, structuring)
+            .iter()
+            .map(|s| s.display(&FormatOptions::new(2), &code, f).to_string())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn enum_switch() {
+        for structuring in [Structuring::Scopes, Structuring::Cfg] {
+            assert_eq!(
+                decompile_enum_switch(structuring),
+                "switch (reg0) {\n  case Fire:\n    global0 = 0;\n  case Ice(a):\n    global0 = a;\n  \
+                case Rock(_, reg3):\n    global0 = reg3;\n}",
+                "{structuring:?}"
+            );
+        }
+    }
 }
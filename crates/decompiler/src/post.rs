@@ -1,6 +1,7 @@
-use hlbc::Bytecode;
+use hlbc::types::{RefEnumConstruct, Type};
+use hlbc::{Bytecode, Resolve};
 
-use crate::ast::{add, ConstructorCall, Expr, Operation, Statement};
+use crate::ast::{add, Constant, ConstructorCall, Expr, Operation, Statement};
 use crate::call_fun;
 
 pub(crate) trait AstVisitor {
@@ -12,7 +13,7 @@ pub(crate) trait AstVisitor {
 pub(crate) fn visit(
     code: &Bytecode,
     stmts: &mut [Statement],
-    visitors: &mut [Box<dyn AstVisitor>],
+    visitors: &mut [Box<dyn AstVisitor + '_>],
 ) {
     // Recurse
     macro_rules! rec {
@@ -35,6 +36,9 @@ pub(crate) fn visit(
                 v!(assign);
                 v!(variable);
             }
+            Statement::Declare { variable, .. } => {
+                v!(variable);
+            }
             Statement::ExprStatement(e) => {
                 v!(e);
             }
@@ -63,13 +67,20 @@ pub(crate) fn visit(
             }
             Statement::Break => {}
             Statement::Continue => {}
+            Statement::Labelled { stmts, .. } => {
+                rec!(stmts);
+            }
+            Statement::BreakLabel(_) => {}
+            Statement::ContinueLabel(_) => {}
             Statement::Throw(e) => {
                 v!(e);
             }
+            Statement::Assert => {}
             Statement::Try { stmts } => {
                 rec!(stmts);
             }
-            Statement::Catch { stmts } => {
+            Statement::Catch { exc, stmts } => {
+                v!(exc);
                 rec!(stmts);
             }
             Statement::Comment(_) => {}
@@ -81,7 +92,11 @@ pub(crate) fn visit(
 }
 
 /// Visit expressions by depth-first recursion into [Expr].
-pub(crate) fn visit_expr(code: &Bytecode, expr: &mut Expr, visitors: &mut [Box<dyn AstVisitor>]) {
+pub(crate) fn visit_expr(
+    code: &Bytecode,
+    expr: &mut Expr,
+    visitors: &mut [Box<dyn AstVisitor + '_>],
+) {
     // Recurse
     macro_rules! rec {
         ($e:expr) => {
@@ -124,10 +139,17 @@ pub(crate) fn visit_expr(code: &Bytecode, expr: &mut Expr, visitors: &mut [Box<d
                 rec!(arg);
             }
         }
+        Expr::EnumField { value, .. } => {
+            rec!(value);
+        }
+        Expr::EnumIndex(_, value) => {
+            rec!(value);
+        }
         Expr::Field(obj, _) => {
             rec!(obj);
         }
         Expr::FunRef(_) => {}
+        Expr::Global(_) => {}
         Expr::IfElse { cond, if_, else_ } => {
             rec!(cond);
             v!(if_);
@@ -162,6 +184,18 @@ pub(crate) fn visit_expr(code: &Bytecode, expr: &mut Expr, visitors: &mut [Box<d
                 rec!(e1);
                 rec!(e2);
             }
+            Operation::UDiv(e1, e2) => {
+                rec!(e1);
+                rec!(e2);
+            }
+            Operation::UMod(e1, e2) => {
+                rec!(e1);
+                rec!(e2);
+            }
+            Operation::UShr(e1, e2) => {
+                rec!(e1);
+                rec!(e2);
+            }
             Operation::And(e1, e2) => {
                 rec!(e1);
                 rec!(e2);
@@ -211,8 +245,13 @@ pub(crate) fn visit_expr(code: &Bytecode, expr: &mut Expr, visitors: &mut [Box<d
                 rec!(e2);
             }
         },
+        Expr::Type(_) => {}
+        Expr::Unsigned(e) => {
+            rec!(e);
+        }
         Expr::Unknown(_) => {}
         Expr::Variable(_, _) => {}
+        Expr::Wildcard => {}
     }
     for visitor in visitors.iter_mut() {
         visitor.visit_expr(code, expr);
@@ -292,6 +331,130 @@ impl AstVisitor for IfExpressions {
     }
 }
 
+/// Restore pattern matching on enums. A switch on the index of the constructor becomes a switch on the
+/// enum value, and the parameters read in each case become the bindings of the pattern.
+/// ```haxe
+/// switch (e.getIndex()) {
+///     case 1:
+///         var a = e.0;
+///         trace(a);
+/// }
+/// ```
+/// becomes this :
+/// ```haxe
+/// switch (e) {
+///     case Ice(a):
+///         trace(a);
+/// }
+/// ```
+pub(crate) struct EnumSwitch;
+
+impl AstVisitor for EnumSwitch {
+    fn visit_stmt(&mut self, code: &Bytecode, stmt: &mut Statement) {
+        if let Statement::Switch { arg, cases, .. } = stmt {
+            let (ty, value) = match arg {
+                Expr::EnumIndex(ty, value) => (*ty, value.as_ref().clone()),
+                _ => return,
+            };
+            let constructs = match code.try_resolve(ty) {
+                Ok(Type::Enum { constructs, .. }) => constructs,
+                _ => return,
+            };
+            // Every case must match a constructor
+            let valid = cases.iter().flat_map(|(patterns, _)| patterns).all(
+                |p| matches!(p, Expr::Constant(Constant::InlineInt(i)) if *i < constructs.len()),
+            );
+            if !valid {
+                return;
+            }
+            for (patterns, stmts) in cases.iter_mut() {
+                for pattern in patterns.iter_mut() {
+                    if let Expr::Constant(Constant::InlineInt(index)) = *pattern {
+                        let construct = RefEnumConstruct(index);
+                        let params = constructs[index].params.len();
+                        let bindings = bind_enum_fields(code, &value, construct, params, stmts);
+                        *pattern = Expr::EnumConstr(ty, construct, bindings);
+                    }
+                }
+            }
+            *arg = value;
+        }
+    }
+}
+
+/// Replace the parameters of an enum value read in a switch case with variables.
+/// Returns the arguments of the pattern, parameters that are never read are matched with a wildcard.
+fn bind_enum_fields(
+    code: &Bytecode,
+    value: &Expr,
+    construct: RefEnumConstruct,
+    params: usize,
+    stmts: &mut Vec<Statement>,
+) -> Vec<Expr> {
+    let mut bindings = vec![None; params];
+    // Named variables are declared in the case, their declaration moves to the pattern
+    stmts.retain(|stmt| match stmt {
+        Statement::Assign {
+            declaration: true,
+            variable: var @ Expr::Variable(..),
+            assign:
+                Expr::EnumField {
+                    value: v,
+                    construct: c,
+                    field,
+                    ..
+                },
+        } if v.as_ref() == value
+            && *c == construct
+            && bindings.get(*field).map_or(false, Option::is_none) =>
+        {
+            bindings[*field] = Some(var.clone());
+            false
+        }
+        _ => true,
+    });
+    visit(
+        code,
+        stmts,
+        &mut [Box::new(BindFields {
+            value,
+            construct,
+            bindings: &mut bindings,
+        })],
+    );
+    bindings
+        .into_iter()
+        .map(|b| b.unwrap_or(Expr::Wildcard))
+        .collect()
+}
+
+/// Replace the parameters of an enum value with their binding, unnamed ones are bound to their register.
+struct BindFields<'a> {
+    value: &'a Expr,
+    construct: RefEnumConstruct,
+    bindings: &'a mut [Option<Expr>],
+}
+
+impl AstVisitor for BindFields<'_> {
+    fn visit_expr(&mut self, _code: &Bytecode, expr: &mut Expr) {
+        let binding = match expr {
+            Expr::EnumField {
+                value,
+                construct,
+                field,
+                reg,
+            } if value.as_ref() == self.value && *construct == self.construct => self
+                .bindings
+                .get_mut(*field)
+                .map(|b| b.get_or_insert_with(|| Expr::Variable(*reg, None)).clone()),
+            _ => None,
+        };
+        if let Some(binding) = binding {
+            *expr = binding;
+        }
+    }
+}
+
 // TODO AST-PP switch expressions
 
 /// Restore string concatenation. They are translated to calls to \_\_add__ at compilation.
@@ -333,9 +496,9 @@ impl AstVisitor for Itos {
     fn visit_expr(&mut self, code: &Bytecode, expr: &mut Expr) {
         let var = match expr {
             Expr::Call(call) => match call.fun {
-                Expr::FunRef(fun) if fun.name(code) == "__alloc__" => match &call.args[0] {
-                    Expr::Call(call) => match call.fun {
-                        Expr::FunRef(fun) if fun.name(code) == "itos" => Some(call.args[0].clone()),
+                Expr::FunRef(fun) if fun.name(code) == "__alloc__" => match call.args.first() {
+                    Some(Expr::Call(call)) => match call.fun {
+                        Expr::FunRef(fun) if fun.name(code) == "itos" => call.args.first().cloned(),
                         _ => None,
                     },
                     _ => None,
@@ -361,8 +524,9 @@ impl AstVisitor for Trace {
                 Expr::Field(obj, field) => match obj.as_ref() {
                     Expr::Variable(_, _) => {
                         if field == "trace" {
-                            let trace = code.function_by_name(field).unwrap();
-                            Some(call_fun(trace.findex, vec![call.args[0].clone()]))
+                            code.function_by_name(field)
+                                .zip(call.args.first())
+                                .map(|(trace, arg)| call_fun(trace.findex, vec![arg.clone()]))
                         } else {
                             None
                         }
@@ -19,7 +19,7 @@ pub(crate) enum ScopeData {
     Switch {
         arg: Expr,
         offsets: Vec<usize>,
-        cases: Vec<(Expr, Vec<Statement>)>,
+        cases: Vec<(Vec<Expr>, Vec<Statement>)>,
     },
     SwitchCase {
         pattern: Expr,
@@ -29,7 +29,7 @@ pub(crate) enum ScopeData {
         cond: Expr,
     },
     Try,
-    Catch,
+    Catch(Expr),
 }
 
 #[derive(Debug)]
@@ -71,7 +71,10 @@ impl Scope {
                 stmts: self.stmts,
             },
             ScopeData::Try => Statement::Try { stmts: self.stmts },
-            ScopeData::Catch => Statement::Catch { stmts: self.stmts },
+            ScopeData::Catch(exc) => Statement::Catch {
+                exc,
+                stmts: self.stmts,
+            },
             _ => {
                 unreachable!()
             }
@@ -96,6 +99,11 @@ impl Scopes {
         self.scopes.last_mut().unwrap().stmts.push(stmt);
     }
 
+    /// Take the statements pushed in the last scope so far
+    pub(crate) fn take_stmts(&mut self) -> Vec<Statement> {
+        std::mem::take(&mut self.scopes.last_mut().unwrap().stmts)
+    }
+
     pub(crate) fn advance(&mut self) {
         let mut stmt = None;
         for i in (0..self.scopes.len()).rev() {
@@ -108,7 +116,7 @@ impl Scopes {
                 if let ScopeData::Switch { cases, .. } = &mut scope.data {
                     let case = self.scopes.remove(i);
                     if let ScopeData::SwitchCase { pattern } = case.data {
-                        cases.push((pattern, case.stmts));
+                        cases.push((vec![pattern], case.stmts));
                     }
                 }
                 stmt = Some(scope.make_stmt());
@@ -182,7 +190,7 @@ impl Scopes {
         let previous = {
             let scope = self.scopes.pop().unwrap();
             match scope.data {
-                ScopeData::SwitchCase { pattern } => Some((pattern, scope.stmts)),
+                ScopeData::SwitchCase { pattern } => Some((vec![pattern], scope.stmts)),
                 _ => {
                     self.scopes.push(scope);
                     None
@@ -225,9 +233,9 @@ impl Scopes {
             .push(Scope::new(ScopeType::Len(len), ScopeData::Try))
     }
 
-    pub(crate) fn push_catch(&mut self, len: i32) {
+    pub(crate) fn push_catch(&mut self, len: i32, exc: Expr) {
         self.scopes
-            .push(Scope::new(ScopeType::Len(len), ScopeData::Catch))
+            .push(Scope::new(ScopeType::Len(len), ScopeData::Catch(exc)))
     }
 
     //region QUERIES
@@ -0,0 +1,213 @@
+use std::collections::{BTreeSet, HashSet};
+use std::path::PathBuf;
+
+use hlbc::types::{FunPtr, RefFun, RefType, Type, TypeObj};
+use hlbc::{Bytecode, Resolve, Str};
+
+use crate::ast::{SourceFile, TypeDecl};
+
+impl SourceFile {
+    /// Path of the file relative to the source root, following the package.
+    /// Characters that can't appear in a Haxe identifier are replaced, so the path stays in the root.
+    pub fn path(&self) -> PathBuf {
+        let mut path: PathBuf = self
+            .package
+            .split('.')
+            .filter(|p| !p.is_empty())
+            .map(sanitize)
+            .collect();
+        path.push(format!("{}.hx", sanitize(self.decl.name())));
+        path
+    }
+}
+
+impl TypeDecl {
+    pub fn name(&self) -> &Str {
+        match self {
+            TypeDecl::Class(class) => &class.name,
+            TypeDecl::Enum(e) => &e.name,
+        }
+    }
+
+    pub(crate) fn set_name(&mut self, name: Str) {
+        match self {
+            TypeDecl::Class(class) => class.name = name,
+            TypeDecl::Enum(e) => e.name = name,
+        }
+    }
+}
+
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+/// Add the file for a type from its fully qualified name.
+/// `paths` holds the paths of the files already added, a type whose path is taken is renamed with a number.
+pub(crate) fn push_file(
+    code: &Bytecode,
+    files: &mut Vec<SourceFile>,
+    paths: &mut HashSet<PathBuf>,
+    name: &str,
+    mut decl: TypeDecl,
+    types: &[RefType],
+) {
+    let (package, short) = split_name(name);
+    decl.set_name(Str::from(short));
+    let mut file = SourceFile {
+        package: Str::from(package),
+        imports: imports(code, package, types),
+        decl,
+    };
+    let mut n = 1;
+    while !paths.insert(file.path()) {
+        file.decl.set_name(Str::from(format!("{short}_{n}")));
+        n += 1;
+    }
+    files.push(file);
+}
+
+/// Split a fully qualified type name into its package and its name
+pub(crate) fn split_name(name: &str) -> (&str, &str) {
+    name.rsplit_once('.').unwrap_or(("", name))
+}
+
+/// Types used by a class : parent, fields, and the registers of its methods
+pub(crate) fn class_types(code: &Bytecode, obj: &TypeObj) -> Vec<RefType> {
+    let mut types = Vec::new();
+    for obj in Some(obj).into_iter().chain(obj.get_static_type(code)) {
+        types.extend(obj.super_);
+        types.extend(obj.own_fields.iter().map(|f| f.t));
+        for fun in obj
+            .bindings
+            .values()
+            .chain(obj.protos.iter().map(|p| &p.findex))
+        {
+            types.extend(fun_types(code, *fun));
+        }
+    }
+    types
+}
+
+/// Types of the registers and the return value of a function
+pub(crate) fn fun_types(code: &Bytecode, fun: RefFun) -> Vec<RefType> {
+    match code.try_resolve(fun) {
+        Ok(FunPtr::Fun(f)) => {
+            let mut types = f.regs.clone();
+            types.extend(f.t.as_fun(code).map(|t| t.ret));
+            types
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Sorted names of the types from other packages than `package`
+pub(crate) fn imports(code: &Bytecode, package: &str, types: &[RefType]) -> Vec<Str> {
+    types
+        .iter()
+        .filter_map(|&ty| type_name(code, ty))
+        .filter(|name| {
+            let (pkg, _) = split_name(name);
+            !pkg.is_empty() && pkg != package
+        })
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+/// Name of the class or enum behind a type
+fn type_name(code: &Bytecode, ty: RefType) -> Option<Str> {
+    match code.try_resolve(ty).ok()? {
+        // The static part of a class is named after the class
+        Type::Obj(obj) | Type::Struct(obj) => {
+            let name = obj.name(code);
+            Some(Str::from(name.strip_prefix('$').unwrap_or(&name)))
+        }
+        Type::Enum { name, .. } => code.try_resolve(*name).ok().filter(|n| !n.is_empty()),
+        Type::Null(inner) => type_name(code, *inner),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use hlbc::asm::assemble;
+    use hlbc::builder::BytecodeBuilder;
+    use hlbc::types::{EnumConstruct, ObjField, RefGlobal, Type};
+
+    use crate::decompile_sources;
+    use crate::fmt::FormatOptions;
+
+    #[test]
+    fn source_tree() {
+        let mut builder = BytecodeBuilder::new();
+        let void = builder.intern_type(Type::Void);
+        let i32 = builder.intern_type(Type::I32);
+        let name = builder.string("game.Kind");
+        let constructs = vec![
+            EnumConstruct {
+                name: builder.string("Fire"),
+                params: vec![],
+            },
+            EnumConstruct {
+                name: builder.string("Ice"),
+                params: vec![i32],
+            },
+        ];
+        let kind = builder.add_type(Type::Enum {
+            name,
+            global: RefGlobal(0),
+            constructs,
+        });
+        let entity = builder.add_class("game.Entity", None, vec![], vec![]);
+        let field = ObjField {
+            name: builder.string("kind"),
+            t: kind,
+        };
+        builder.add_class("game.world.Player", Some(entity), vec![field], vec![]);
+        builder.add_class("../../evil", None, vec![], vec![]);
+        // Same path once sanitized
+        builder.add_class("_._evil", None, vec![], vec![]);
+        let t = builder.fun_type(vec![], void);
+        let fun = assemble(&mut builder, &format!("fn new @{}\nreg0 @0\nRet reg0", t.0)).unwrap();
+        let findex = fun.findex;
+        builder.insert_function(fun);
+        builder.set_entrypoint(findex);
+        let code = builder.build();
+
+        let files = decompile_sources(&code);
+        let paths: Vec<PathBuf> = files.iter().map(|f| f.path()).collect();
+        assert_eq!(
+            paths,
+            [
+                PathBuf::from("game/Kind.hx"),
+                PathBuf::from("game/Entity.hx"),
+                PathBuf::from("game/world/Player.hx"),
+                PathBuf::from("_/_evil.hx"),
+                PathBuf::from("_/_evil_1.hx"),
+                PathBuf::from("Main.hx"),
+            ]
+        );
+        let opts = FormatOptions::new(2);
+        let display: Vec<String> = files
+            .iter()
+            .map(|f| f.display(&code, &opts).to_string())
+            .collect();
+        assert_eq!(
+            display[0],
+            "package game;\n\nenum Kind {\n  Fire;\n  Ice(p0: Int);\n}\n"
+        );
+        assert_eq!(
+            display[2],
+            "package game.world;\n\nimport game.Entity;\nimport game.Kind;\n\n\
+            class Player extends game.Entity {\n  var kind: game.Kind;\n}\n"
+        );
+        assert_eq!(
+            display[5],
+            "class Main {\n\n  static function main() {}\n}\n"
+        );
+    }
+}
@@ -0,0 +1,163 @@
+//! Recompilation round trip : each sample of `data/` is compiled with the Haxe compiler, decompiled
+//! to a source tree with each structuring algorithm, recompiled, and both bytecode files are compared structurally.
+//! The fidelity of each sample can't drop below the one stored in `data/roundtrip.txt`.
+//! Skipped when `haxe` can't be found. Run with `--nocapture` to see the fidelity of each sample,
+//! and with `HLBC_BLESS=1` to update the baseline.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use hlbc::diff::similarity;
+use hlbc::Bytecode;
+use hlbc_decompiler::fmt::FormatOptions;
+use hlbc_decompiler::{decompile_sources_with, Structuring};
+use temp_dir::TempDir;
+
+/// Fidelity (in percent) required from the samples that aren't in the baseline
+const MIN_FIDELITY: f64 = 50.0;
+
+fn haxe_available() -> bool {
+    Command::new("haxe")
+        .arg("-version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map_or(false, |s| s.success())
+}
+
+/// Compile a main class to bytecode, the error being the compiler output
+fn compile(class_path: &Path, main: &str, bytecode: &Path) -> Result<(), String> {
+    let output = Command::new("haxe")
+        .arg("-cp")
+        .arg(class_path)
+        .arg("-hl")
+        .arg(bytecode)
+        .arg("-main")
+        .arg(main)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| e.to_string())?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(String::from_utf8_lossy(&output.stderr).into_owned())
+    }
+}
+
+fn write_sources(code: &Bytecode, structuring: Structuring, dir: &Path) -> io::Result<()> {
+    let opts = FormatOptions::new(2);
+    for file in decompile_sources_with(code, structuring) {
+        let path = dir.join(file.path());
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, file.display(code, &opts).to_string())?;
+    }
+    Ok(())
+}
+
+fn samples(data: &Path) -> Vec<PathBuf> {
+    let mut samples: Vec<PathBuf> = fs::read_dir(data)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().map_or(false, |ext| ext == "hx"))
+        .collect();
+    samples.sort();
+    samples
+}
+
+/// Minimum fidelity by sample and structuring, one `<sample> <structuring> <percent>` per line
+fn read_baseline(path: &Path) -> BTreeMap<(String, String), f64> {
+    let content = fs::read_to_string(path).unwrap_or_default();
+    content
+        .lines()
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| {
+            let mut parts = l.split_whitespace();
+            let sample = parts.next().unwrap().to_string();
+            let structuring = parts.next().unwrap().to_string();
+            let fidelity = parts.next().unwrap().parse().unwrap();
+            ((sample, structuring), fidelity)
+        })
+        .collect()
+}
+
+fn write_baseline(path: &Path, baseline: &BTreeMap<(String, String), f64>) {
+    let mut content = String::from(
+        "# Minimum fidelity (in percent) of each sample after a round trip, 0 if it doesn't recompile.\n\
+        # Generated by the roundtrip test of the decompiler with HLBC_BLESS=1.\n",
+    );
+    for ((sample, structuring), fidelity) in baseline {
+        writeln!(content, "{sample} {structuring} {fidelity:.1}").unwrap();
+    }
+    fs::write(path, content).unwrap();
+}
+
+#[test]
+fn roundtrip() {
+    if !haxe_available() {
+        eprintln!("haxe not found in the PATH, skipping the round trip");
+        return;
+    }
+    let data = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../data");
+    let baseline_path = data.join("roundtrip.txt");
+    let baseline = read_baseline(&baseline_path);
+    let bless = std::env::var_os("HLBC_BLESS").is_some();
+
+    let mut results = BTreeMap::new();
+    let mut regressions = Vec::new();
+    for sample in &samples(&data) {
+        let main = sample.file_stem().unwrap().to_str().unwrap();
+        let dir = TempDir::new().unwrap();
+        let original = dir.path().join("original.hl");
+        // Not a decompiler problem, but the sample must be fixed
+        if let Err(e) = compile(&data, main, &original) {
+            regressions.push(format!("{main} doesn't compile : {e}"));
+            continue;
+        }
+        let code = Bytecode::from_file(&original).unwrap();
+
+        for (structuring, name) in [(Structuring::Scopes, "scopes"), (Structuring::Cfg, "cfg")] {
+            let src = dir.path().join(name);
+            write_sources(&code, structuring, &src).unwrap();
+            let recompiled = dir.path().join(format!("{name}.hl"));
+            let fidelity = match compile(&src, main, &recompiled) {
+                Ok(()) => {
+                    let sim = similarity(&code, &Bytecode::from_file(&recompiled).unwrap());
+                    println!("{main:<16} {name:<6} {sim}");
+                    sim.score() * 100.0
+                }
+                Err(e) => {
+                    println!(
+                        "{main:<16} {name:<6} doesn't recompile : {}",
+                        e.lines().next().unwrap_or_default()
+                    );
+                    0.0
+                }
+            };
+            let key = (main.to_string(), name.to_string());
+            let min = baseline.get(&key).copied().unwrap_or(MIN_FIDELITY);
+            // The baseline is rounded to 0.1%
+            if fidelity < min - 0.05 {
+                regressions.push(format!(
+                    "{main} ({name}) : {fidelity:.1}% instead of at least {min:.1}%"
+                ));
+            }
+            results.insert(key, fidelity);
+        }
+    }
+
+    if bless {
+        write_baseline(&baseline_path, &results);
+    } else {
+        assert!(
+            regressions.is_empty(),
+            "Round trip regressions :\n{}",
+            regressions.join("\n")
+        );
+    }
+}
@@ -18,6 +18,8 @@ pub fn derive_opcode_helper(input: proc_macro::TokenStream) -> proc_macro::Token
     let i = 0..variants.len() as u8;
 
     let initr = variants.iter().map(|v| read_variant(name, v));
+    let i2 = i.clone();
+    let skipr = variants.iter().map(skip_variant);
     let initw = variants
         .iter()
         .enumerate()
@@ -51,6 +53,15 @@ pub fn derive_opcode_helper(input: proc_macro::TokenStream) -> proc_macro::Token
         }
         acc.trim().to_string()
     });
+    let vopnds = variants.iter().map(|v| operands_variant(name, v, false));
+    let vopnds_mut = variants.iter().map(|v| operands_variant(name, v, true));
+    let vdst = variants.iter().filter_map(|v| {
+        let vname = &v.ident;
+        v.fields
+            .iter()
+            .any(|f| f.ident.as_ref().unwrap() == "dst" && ident(&f.ty) == "Reg")
+            .then(|| quote!(#name::#vname { dst, .. } => Some(*dst),))
+    });
     let vdefault_init = variants.iter().map(|v| {
         let vname = &v.ident;
         let finit = v.fields.iter().map(|f| {
@@ -76,10 +87,24 @@ pub fn derive_opcode_helper(input: proc_macro::TokenStream) -> proc_macro::Token
                 let op = r.read_u8()?;
                 match op {
                     #( #i => #initr, )*
-                    other => Err(crate::Error::MalformedBytecode(format!("Unknown opcode {}", op))),
+                    other => Err(crate::Error::malformed(format!("Unknown opcode {}", op))),
                 }
             }
 
+            /// Advance the reader past an instruction without decoding it
+            pub fn skip(r: &mut impl std::io::Read) -> crate::Result<()> {
+
+                use byteorder::ReadBytesExt;
+                use crate::read::{read_vari, read_varu};
+
+                let op = r.read_u8()?;
+                match op {
+                    #( #i2 => { #skipr } )*
+                    other => return Err(crate::Error::malformed(format!("Unknown opcode {}", op))),
+                }
+                Ok(())
+            }
+
             /// Encode an instruction
             pub fn write(&self, w: &mut impl std::io::Write) -> crate::Result<()> {
 
@@ -108,6 +133,32 @@ pub fn derive_opcode_helper(input: proc_macro::TokenStream) -> proc_macro::Token
                 }
             }
 
+            /// Get the opcode arguments, in declaration order.
+            pub fn operands(&self) -> Vec<crate::opcodes::Operand<'_>> {
+                use crate::opcodes::Operand;
+
+                match self {
+                    #( #vopnds )*
+                }
+            }
+
+            /// Get mutable access to the opcode arguments, in declaration order.
+            pub fn operands_mut(&mut self) -> Vec<crate::opcodes::OperandMut<'_>> {
+                use crate::opcodes::OperandMut as Operand;
+
+                match self {
+                    #( #vopnds_mut )*
+                }
+            }
+
+            /// Get the `dst` register of the opcode, usually the one being written.
+            pub fn dst(&self) -> Option<crate::types::Reg> {
+                match self {
+                    #( #vdst )*
+                    _ => None,
+                }
+            }
+
             /// Get an opcode from its name. Returns a default value for the variant.
             pub fn from_name(name: &str) -> Option<Self> {
                 match name {
@@ -215,6 +266,64 @@ fn read_variant(enum_name: &Ident, v: &Variant) -> TokenStream {
     }
 }
 
+fn skip_variant(v: &Variant) -> TokenStream {
+    let fskip = v.fields.iter().map(|f| match ident(&f.ty).as_str() {
+        "Vec<JumpOffset>" => quote! {
+            for _ in 0..read_varu(r)? {
+                read_vari(r)?;
+            }
+        },
+        "Vec<Reg>" => quote! {
+            for _ in 0..r.read_u8()? {
+                read_vari(r)?;
+            }
+        },
+        "usize" | "i32" | "JumpOffset" | "Reg" | "RefInt" | "RefFloat" | "RefBytes"
+        | "RefString" | "RefType" | "ValBool" | "RefFun" | "RefField" | "RefGlobal"
+        | "RefEnumConstruct" => quote! {
+            read_vari(r)?;
+        },
+        _ => TokenStream::default(),
+    });
+    quote! {
+        #( #fskip )*
+    }
+}
+
+fn operands_variant(enum_name: &Ident, v: &Variant, mutable: bool) -> TokenStream {
+    let vname = &v.ident;
+    let fname = v.fields.iter().map(|f| &f.ident);
+    let fopnd = v.fields.iter().filter_map(|f| {
+        let fname = f.ident.as_ref().unwrap();
+        // Copy values for immutable access, pass the reference otherwise
+        let val = if mutable {
+            quote!(#fname)
+        } else {
+            quote!(*#fname)
+        };
+        match ident(&f.ty).as_str() {
+            "Reg" => Some(quote!(Operand::Reg(#val))),
+            "Vec<Reg>" => Some(quote!(Operand::Regs(#fname))),
+            "JumpOffset" => Some(quote!(Operand::Offset(#val))),
+            "Vec<JumpOffset>" => Some(quote!(Operand::Offsets(#fname))),
+            "RefInt" => Some(quote!(Operand::Int(#val))),
+            "RefFloat" => Some(quote!(Operand::Float(#val))),
+            "RefBytes" => Some(quote!(Operand::Bytes(#val))),
+            "RefString" => Some(quote!(Operand::String(#val))),
+            "RefType" => Some(quote!(Operand::Type(#val))),
+            "RefFun" => Some(quote!(Operand::Fun(#val))),
+            "RefField" => Some(quote!(Operand::Field(#val))),
+            "RefGlobal" => Some(quote!(Operand::Global(#val))),
+            "RefEnumConstruct" => Some(quote!(Operand::EnumConstruct(#val))),
+            "ValBool" => Some(quote!(Operand::Bool(#val))),
+            _ => None,
+        }
+    });
+    quote! {
+        #enum_name::#vname { #( #fname, )* } => vec![#( #fopnd, )*],
+    }
+}
+
 fn write_variant(enum_name: &Ident, v: &Variant, i: u8) -> TokenStream {
     let vname = &v.ident;
     let fname = v.fields.iter().map(|f| &f.ident);
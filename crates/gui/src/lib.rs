@@ -1,27 +1,56 @@
 use std::cell::Cell;
-use std::fs;
-use std::io::BufReader;
+use std::path::Path;
 use std::rc::Rc;
 
 use eframe::egui;
-use eframe::egui::{CentralPanel, Frame, Margin, ScrollArea, TopBottomPanel, Ui, Vec2};
+use eframe::egui::{
+    CentralPanel, Color32, Frame, Grid, Margin, ScrollArea, TopBottomPanel, Ui, Vec2,
+};
 use egui_dock::{DockArea, NodeIndex, Tree};
+use once_cell::unsync::OnceCell;
 use poll_promise::Promise;
 
+use hlbc::analysis::hierarchy::Hierarchy;
+use hlbc::analysis::xref::XrefIndex;
 use hlbc::fmt::EnhancedFmt;
-use hlbc::types::{RefFun, RefGlobal, RefString, RefType};
-use hlbc::Bytecode;
+use hlbc::lazy::LazyBytecode;
+use hlbc::types::{FunPtr, RefFun, RefGlobal, RefString, RefType};
+use hlbc::{Bytecode, Resolve};
 
 use crate::views::{
-    AppView, ClassesView, DynamicTabViewer, FunctionsView, GlobalsView, InfoView, StringsView,
-    SyncInspectorView,
+    AppView, ClassesView, DiffView, DynamicTabViewer, FunctionsView, GlobalsView, InfoView,
+    StatsView, StringsView, SyncInspectorView,
 };
 
 mod views;
 
+/// A bytecode file opened in the background. It is only indexed,
+/// strings and function bodies are decoded when a view needs them.
+pub struct LoadedFile {
+    name: String,
+    lazy: LazyBytecode,
+}
+
+impl LoadedFile {
+    pub fn open(path: &Path) -> hlbc::Result<Self> {
+        Ok(Self::new(
+            path.display().to_string(),
+            LazyBytecode::open(path)?,
+        ))
+    }
+
+    pub fn from_bytes(name: String, bytes: Vec<u8>) -> hlbc::Result<Self> {
+        Ok(Self::new(name, LazyBytecode::from_bytes(bytes)?))
+    }
+
+    fn new(name: String, lazy: LazyBytecode) -> Self {
+        Self { name, lazy }
+    }
+}
+
 pub struct App {
     /// Asynchronous loader for bytecode
-    loader: Option<Promise<hlbc::Result<Option<(String, Bytecode)>>>>,
+    loader: Option<Promise<hlbc::Result<Option<LoadedFile>>>>,
     /// Some when a file is loaded
     ctx: Option<AppCtxHandle>,
     // Dock
@@ -30,11 +59,13 @@ pub struct App {
     options_window_open: bool,
     about_window_open: bool,
     status: String,
+    /// Error from the last attempt at loading a file, shown in a window
+    load_error: Option<hlbc::Error>,
 }
 
 impl App {
     pub fn new(
-        loader: Option<Promise<hlbc::Result<Option<(String, Bytecode)>>>>,
+        loader: Option<Promise<hlbc::Result<Option<LoadedFile>>>>,
         style: egui_dock::Style,
     ) -> Self {
         Self {
@@ -45,6 +76,7 @@ impl App {
             options_window_open: false,
             about_window_open: false,
             status: String::from("Loading bytecode ..."),
+            load_error: None,
         }
     }
 }
@@ -54,16 +86,18 @@ impl eframe::App for App {
         {
             if let Some(loader) = self.loader.take() {
                 match loader.try_take() {
-                    Ok(Ok(Some((file, code)))) => {
-                        self.ctx = Some(AppCtxHandle::new(AppCtx::new_from_code(file, code)));
+                    Ok(Ok(Some(file))) => {
+                        self.ctx = Some(AppCtxHandle::new(AppCtx::new_from_file(file)));
                         self.tree = default_tabs();
                         self.status = String::from("Loaded bytecode successfully");
+                        self.load_error = None;
                     }
                     Ok(Ok(None)) => {
                         // No file has been picked
                     }
                     Ok(Err(e)) => {
-                        println!("{e}");
+                        self.status = String::from("Failed to load bytecode");
+                        self.load_error = Some(e);
                     }
                     Err(loader) => {
                         self.loader = Some(loader);
@@ -75,6 +109,11 @@ impl eframe::App for App {
             if let Some(tab) = self.ctx.as_ref().and_then(|app| app.take_tab_to_open()) {
                 self.tree[NodeIndex::root().right()].append_tab(tab);
             }
+
+            if let Some(e) = self.ctx.as_ref().and_then(|app| app.take_decode_error()) {
+                self.status = String::from("Failed to decode bytecode");
+                self.load_error = Some(e);
+            }
         }
 
         TopBottomPanel::top("menu bar")
@@ -89,13 +128,10 @@ impl eframe::App for App {
                                     if let Some(file) =
                                         rfd::AsyncFileDialog::new().pick_file().await
                                     {
-                                        Some((
-                                            file.file_name(),
-                                            Bytecode::deserialize(&mut &file.read().await[..])
-                                                .unwrap(),
-                                        ))
+                                        LoadedFile::from_bytes(file.file_name(), file.read().await)
+                                            .map(Some)
                                     } else {
-                                        None
+                                        Ok(None)
                                     }
                                 }));
                             }
@@ -103,12 +139,7 @@ impl eframe::App for App {
                             {
                                 self.loader = Some(Promise::spawn_thread("bg_loader", || {
                                     if let Some(file) = rfd::FileDialog::new().pick_file() {
-                                        Ok(Some((
-                                            file.display().to_string(),
-                                            Bytecode::deserialize(&mut BufReader::new(
-                                                fs::File::open(&file)?,
-                                            ))?,
-                                        )))
+                                        LoadedFile::open(&file).map(Some)
                                     } else {
                                         Ok(None)
                                     }
@@ -130,6 +161,14 @@ impl eframe::App for App {
                                 self.tree[NodeIndex::root().right()]
                                     .append_tab(Box::<InfoView>::default());
                             }
+                            if ui.button("Diff").clicked() {
+                                self.tree[NodeIndex::root().right()]
+                                    .append_tab(Box::<DiffView>::default());
+                            }
+                            if ui.button("Stats").clicked() {
+                                self.tree[NodeIndex::root().right()]
+                                    .append_tab(Box::<StatsView>::default());
+                            }
                             #[cfg(feature = "search")]
                             if ui.button("Search").clicked() {
                                 self.tree[NodeIndex::root().right()]
@@ -191,6 +230,17 @@ impl eframe::App for App {
                 // TODO about page
             });
 
+        if let Some(e) = &self.load_error {
+            let mut open = true;
+            egui::Window::new("Can't load bytecode")
+                .open(&mut open)
+                .collapsible(false)
+                .show(ctx, |ui| load_error_ui(ui, e));
+            if !open {
+                self.load_error = None;
+            }
+        }
+
         if let Some(appctx) = self.ctx.clone() {
             DockArea::new(&mut self.tree)
                 .scroll_area_in_tabs(false)
@@ -208,6 +258,29 @@ impl eframe::App for App {
     }
 }
 
+fn load_error_ui(ui: &mut Ui, e: &hlbc::Error) {
+    let hlbc::Error::MalformedBytecode { msg, location } = e else {
+        ui.colored_label(Color32::LIGHT_RED, e.to_string());
+        return;
+    };
+    ui.colored_label(Color32::LIGHT_RED, format!("Malformed bytecode: {msg}"));
+    if let Some(location) = location {
+        Grid::new("load_error_location").show(ui, |ui| {
+            ui.label("Section");
+            ui.label(location.section.to_string());
+            ui.end_row();
+            if let Some(index) = location.index {
+                ui.label("Element");
+                ui.label(format!("#{index}"));
+                ui.end_row();
+            }
+            ui.label("Offset");
+            ui.label(format!("{:#x} ({})", location.offset, location.offset));
+            ui.end_row();
+        });
+    }
+}
+
 fn default_tabs() -> Tree<Box<dyn AppView>> {
     let mut tree: Tree<Box<dyn AppView>> = Tree::new(vec![
         Box::<SyncInspectorView>::default(),
@@ -247,8 +320,60 @@ impl AppCtxHandle {
         self.0.file.clone()
     }
 
+    /// Strings and function headers, enough to display names and signatures.
+    /// Function bodies are available with [AppCtxHandle::function] and [AppCtxHandle::full_code].
     fn code(&self) -> &Bytecode {
-        &self.0.code
+        self.0.code.get_or_init(|| match self.0.lazy.header() {
+            Ok(code) => code,
+            Err(e) => {
+                self.0.decode_error.set(Some(e));
+                self.0.lazy.header_lossy()
+            }
+        })
+    }
+
+    /// Get a function with its body, decoded on first access
+    fn function(&self, fun: RefFun) -> FunPtr<'_> {
+        match self.0.lazy.function_by_findex(fun) {
+            Ok(Some(f)) => FunPtr::Fun(f),
+            Ok(None) => self.code().resolve(fun),
+            Err(e) => {
+                self.0.decode_error.set(Some(e));
+                // Only the header is available
+                self.code().resolve(fun)
+            }
+        }
+    }
+
+    /// The whole program decoded, for views going through every function.
+    /// Decoded the first time it is needed.
+    fn full_code(&self) -> &Bytecode {
+        self.0
+            .full_code
+            .get_or_init(|| match self.0.lazy.to_bytecode() {
+                Ok(code) => code,
+                Err(e) => {
+                    self.0.decode_error.set(Some(e));
+                    // Only the headers are available
+                    self.code().clone()
+                }
+            })
+    }
+
+    fn take_decode_error(&self) -> Option<hlbc::Error> {
+        self.0.decode_error.take()
+    }
+
+    /// Cross-references index, built the first time it is needed
+    fn xrefs(&self) -> &XrefIndex {
+        self.0
+            .xrefs
+            .get_or_init(|| XrefIndex::new(self.full_code()))
+    }
+
+    /// Class hierarchy, built the first time it is needed
+    fn hierarchy(&self) -> &Hierarchy {
+        self.0.hierarchy.get_or_init(|| Hierarchy::new(self.code()))
     }
 
     /// mut lock
@@ -271,20 +396,33 @@ impl AppCtxHandle {
 
 struct AppCtx {
     file: String,
-    code: Bytecode,
+    /// Strings and function headers, decoded the first time they are needed
+    code: OnceCell<Bytecode>,
+    /// The opened file, to decode function bodies on demand
+    lazy: LazyBytecode,
+    full_code: OnceCell<Bytecode>,
+    /// Error while decoding a part of the bytecode after it has been opened
+    decode_error: Cell<Option<hlbc::Error>>,
     selected: Cell<ItemSelection>,
     /// To open a tab from another tab.
     /// This can't be done directly because this would need a mutable reference to a tree and the tree owns the tab.
     new_tab: Cell<Option<Box<dyn AppView>>>,
+    xrefs: OnceCell<XrefIndex>,
+    hierarchy: OnceCell<Hierarchy>,
 }
 
 impl AppCtx {
-    fn new_from_code(file: String, code: Bytecode) -> Self {
+    fn new_from_file(file: LoadedFile) -> Self {
         Self {
-            file,
-            code,
+            file: file.name,
+            code: OnceCell::new(),
+            lazy: file.lazy,
+            full_code: OnceCell::new(),
+            decode_error: Cell::new(None),
             selected: Cell::new(ItemSelection::None),
             new_tab: Cell::new(None),
+            xrefs: OnceCell::new(),
+            hierarchy: OnceCell::new(),
         }
     }
 }
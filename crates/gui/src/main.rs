@@ -1,8 +1,7 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")] // hide console window on Windows in release
 
-use std::io::BufReader;
+use std::env;
 use std::path::PathBuf;
-use std::{env, fs};
 
 use eframe::egui::Vec2;
 use eframe::egui_wgpu::WgpuConfiguration;
@@ -10,8 +9,7 @@ use eframe::wgpu;
 use eframe::wgpu::PowerPreference;
 use poll_promise::Promise;
 
-use hlbc::Bytecode;
-use hlbc_gui::App;
+use hlbc_gui::{App, LoadedFile};
 
 use crate::theme::build_style;
 
@@ -37,12 +35,9 @@ fn main() -> eframe::Result<()> {
             let loader = if args.is_empty() {
                 None
             } else {
-                let path = PathBuf::from(args.clone());
+                let path = PathBuf::from(args);
                 Some(Promise::spawn_thread("bg_loader", move || {
-                    Ok(Some((
-                        args,
-                        Bytecode::deserialize(&mut BufReader::new(fs::File::open(&path)?))?,
-                    )))
+                    LoadedFile::open(&path).map(Some)
                 }))
             };
 
@@ -1,6 +1,8 @@
+use std::collections::HashSet;
 use std::ops::Deref;
 
-use eframe::egui::{Color32, RichText, Ui, WidgetText};
+use eframe::egui::collapsing_header::CollapsingState;
+use eframe::egui::{Color32, Id, RichText, ScrollArea, Ui, WidgetText};
 
 use hlbc::fmt::EnhancedFmt;
 use hlbc::types::{RefType, Type};
@@ -11,7 +13,11 @@ use crate::{AppCtxHandle, AppView, ItemSelection};
 #[derive(Default)]
 pub(crate) struct ClassesView {
     show_std: bool,
+    /// Show the classes as a tree following the hierarchy
+    tree: bool,
     cache: Vec<RefType>,
+    /// Classes shown in the tree, the ones with a subclass shown are kept
+    tree_cache: HashSet<RefType>,
     cache_valid: bool,
 }
 
@@ -37,31 +43,84 @@ impl AppView for ClassesView {
                 }
             }
 
+            let h = ctx.hierarchy();
+            self.tree_cache = HashSet::new();
+            for &t in &self.cache {
+                self.tree_cache.insert(t);
+                self.tree_cache.extend(h.ancestors(code, t));
+            }
+
             self.cache_valid = true;
         }
 
-        if ui.checkbox(&mut self.show_std, "Show stdlib").changed() {
-            self.cache_valid = false;
-        }
+        ui.horizontal(|ui| {
+            if ui.checkbox(&mut self.show_std, "Show stdlib").changed() {
+                self.cache_valid = false;
+            }
+            ui.checkbox(&mut self.tree, "Hierarchy");
+        });
 
         ui.add_space(6.0);
 
-        list_view(
-            ui,
-            ctx,
-            self.cache.len(),
-            |i| self.cache[i],
-            ItemSelection::Class,
-            |ctx, t| t.display::<EnhancedFmt>(ctx.code()).to_string(),
-            Some(|ui: &mut Ui, ctx: &AppCtxHandle, t| {
-                if ui.small_button("Open in inspector").clicked() {
-                    let tab = InspectorView::new(ItemSelection::Class(t), ctx.code().deref());
-                    ctx.open_tab(tab);
-                }
-                if ui.small_button("Decompile").clicked() {
-                    ctx.open_tab(DecompilerView::default());
-                }
-            }),
-        );
+        if self.tree {
+            ScrollArea::both()
+                .auto_shrink([false, false])
+                .show(ui, |ui| {
+                    for &t in ctx.hierarchy().roots() {
+                        self.class_tree(ui, &ctx, t);
+                    }
+                });
+        } else {
+            list_view(
+                ui,
+                ctx,
+                self.cache.len(),
+                |i| self.cache[i],
+                ItemSelection::Class,
+                |ctx, t| t.display::<EnhancedFmt>(ctx.code()).to_string(),
+                Some(class_context_menu),
+            );
+        }
+    }
+}
+
+impl ClassesView {
+    fn class_tree(&self, ui: &mut Ui, ctx: &AppCtxHandle, t: RefType) {
+        if !self.tree_cache.contains(&t) {
+            return;
+        }
+        let label = |ui: &mut Ui| {
+            let res = ui
+                .selectable_label(
+                    ctx.selected() == ItemSelection::Class(t),
+                    t.display::<EnhancedFmt>(ctx.code()).to_string(),
+                )
+                .context_menu(|ui| class_context_menu(ui, ctx, t));
+            if res.clicked() {
+                ctx.set_selected(ItemSelection::Class(t));
+            }
+        };
+        let children = ctx.hierarchy().children(t);
+        if children.iter().any(|c| self.tree_cache.contains(c)) {
+            CollapsingState::load_with_default_open(ui.ctx(), Id::new(("classes_tree", t)), false)
+                .show_header(ui, label)
+                .body(|ui| {
+                    for &child in children {
+                        self.class_tree(ui, ctx, child);
+                    }
+                });
+        } else {
+            label(ui);
+        }
+    }
+}
+
+fn class_context_menu(ui: &mut Ui, ctx: &AppCtxHandle, t: RefType) {
+    if ui.small_button("Open in inspector").clicked() {
+        let tab = InspectorView::new(ItemSelection::Class(t), ctx.code().deref());
+        ctx.open_tab(tab);
+    }
+    if ui.small_button("Decompile").clicked() {
+        ctx.open_tab(DecompilerView::default());
     }
 }
@@ -1,5 +1,3 @@
-use std::ops::Deref;
-
 use eframe::egui::text::{LayoutJob, LayoutSection};
 use eframe::egui::util::cache::{ComputerMut, FrameCache};
 use eframe::egui::{
@@ -34,8 +32,8 @@ impl AppView for DecompilerView {
 
     fn ui(&mut self, ui: &mut Ui, ctx: AppCtxHandle) {
         if ctx.selected() != self.cache_selected {
-            let code = ctx.code();
-            let code = code.deref();
+            // Closures and methods are decompiled along with their parent
+            let code = ctx.full_code();
 
             self.output = match ctx.selected() {
                 ItemSelection::Fun(fun) => match code.resolve(fun) {
@@ -0,0 +1,195 @@
+use eframe::egui::{Button, CollapsingHeader, Color32, RichText, ScrollArea, Ui, WidgetText};
+use poll_promise::Promise;
+
+use hlbc::diff::{diff, BytecodeDiff, Change, OpEdit};
+use hlbc::Bytecode;
+
+use crate::views::AppView;
+use crate::{AppCtxHandle, ItemSelection};
+
+/// Name and content of the picked file, none if the dialog was cancelled
+type Loader = Promise<hlbc::Result<Option<(String, Bytecode)>>>;
+
+/// Compare the opened bytecode with another file
+#[derive(Default)]
+pub(crate) struct DiffView {
+    loader: Option<Loader>,
+    /// Name of the other file and its differences with the opened one
+    diff: Option<(String, BytecodeDiff)>,
+    error: Option<String>,
+}
+
+impl DiffView {
+    fn pick_file(&mut self) {
+        #[cfg(target_arch = "wasm32")]
+        {
+            self.loader = Some(Promise::spawn_async(async {
+                if let Some(file) = rfd::AsyncFileDialog::new().pick_file().await {
+                    Bytecode::deserialize(&mut &file.read().await[..])
+                        .map(|code| Some((file.file_name(), code)))
+                } else {
+                    Ok(None)
+                }
+            }));
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            self.loader = Some(Promise::spawn_thread("diff_loader", || {
+                if let Some(file) = rfd::FileDialog::new().pick_file() {
+                    Ok(Some((
+                        file.display().to_string(),
+                        Bytecode::from_file(&file)?,
+                    )))
+                } else {
+                    Ok(None)
+                }
+            }));
+        }
+    }
+}
+
+fn change_color(change: Change) -> Color32 {
+    match change {
+        Change::Added => Color32::LIGHT_GREEN,
+        Change::Removed => Color32::LIGHT_RED,
+        Change::Changed => Color32::LIGHT_YELLOW,
+    }
+}
+
+fn change_symbol(change: Change) -> &'static str {
+    match change {
+        Change::Added => "+",
+        Change::Removed => "-",
+        Change::Changed => "~",
+    }
+}
+
+impl AppView for DiffView {
+    fn title(&self) -> WidgetText {
+        RichText::new("Diff").color(Color32::WHITE).into()
+    }
+
+    fn ui(&mut self, ui: &mut Ui, ctx: AppCtxHandle) {
+        if let Some(loader) = self.loader.take() {
+            match loader.try_take() {
+                Ok(Ok(Some((file, other)))) => {
+                    self.diff = Some((file, diff(ctx.full_code(), &other)));
+                    self.error = None;
+                }
+                Ok(Ok(None)) => {}
+                Ok(Err(e)) => self.error = Some(e.to_string()),
+                Err(loader) => {
+                    self.loader = Some(loader);
+                    ui.ctx().request_repaint();
+                }
+            }
+        }
+
+        ui.horizontal(|ui| {
+            if ui
+                .add_enabled(self.loader.is_none(), Button::new("Compare with ..."))
+                .clicked()
+            {
+                self.pick_file();
+            }
+            if self.loader.is_some() {
+                ui.spinner();
+            } else if let Some((file, _)) = &self.diff {
+                ui.label(file);
+            }
+        });
+        if let Some(e) = &self.error {
+            ui.colored_label(Color32::LIGHT_RED, e);
+        }
+        let Some((_, diff)) = &self.diff else {
+            return;
+        };
+        if diff.is_empty() {
+            ui.label("No differences");
+            return;
+        }
+
+        ScrollArea::vertical()
+            .id_source("diff_scroll_area")
+            .auto_shrink([false, false])
+            .show(ui, |ui| {
+                CollapsingHeader::new(format!("Types ({})", diff.types.len()))
+                    .default_open(true)
+                    .show(ui, |ui| {
+                        for t in &diff.types {
+                            ui.label(
+                                RichText::new(format!("{} {}", change_symbol(t.change), t.name))
+                                    .monospace()
+                                    .color(change_color(t.change)),
+                            );
+                        }
+                    });
+                CollapsingHeader::new(format!("Functions ({})", diff.functions.len()))
+                    .default_open(true)
+                    .show(ui, |ui| {
+                        for (i, fun) in diff.functions.iter().enumerate() {
+                            let title = RichText::new(format!(
+                                "{} {}",
+                                change_symbol(fun.change),
+                                fun.name
+                            ))
+                            .monospace()
+                            .color(change_color(fun.change));
+                            if fun.change != Change::Changed {
+                                let label = ui.selectable_label(false, title);
+                                // Only functions from the opened file can be selected
+                                if let (true, Some(old)) = (label.clicked(), fun.old) {
+                                    ctx.set_selected(ItemSelection::Fun(old));
+                                }
+                                continue;
+                            }
+                            let header = CollapsingHeader::new(title)
+                                .id_source(("diff_fn", i))
+                                .show(ui, |ui| {
+                                    if let Some((old, new)) = &fun.signature {
+                                        ui.label(
+                                            RichText::new(format!("signature {old} -> {new}"))
+                                                .monospace(),
+                                        );
+                                    }
+                                    for edit in &fun.ops {
+                                        let (text, color) = match edit {
+                                            OpEdit::Removed { pos, op } => {
+                                                (format!("- {pos:>4}: {op}"), Color32::LIGHT_RED)
+                                            }
+                                            OpEdit::Added { pos, op } => {
+                                                (format!("+ {pos:>4}: {op}"), Color32::LIGHT_GREEN)
+                                            }
+                                        };
+                                        ui.label(RichText::new(text).monospace().color(color));
+                                    }
+                                });
+                            if let (true, Some(old)) = (header.header_response.clicked(), fun.old) {
+                                ctx.set_selected(ItemSelection::Fun(old));
+                            }
+                        }
+                    });
+                CollapsingHeader::new(format!(
+                    "Strings ({} added, {} removed)",
+                    diff.strings_added.len(),
+                    diff.strings_removed.len()
+                ))
+                .show(ui, |ui| {
+                    for s in &diff.strings_added {
+                        ui.label(
+                            RichText::new(format!("+ {:?}", s.as_str()))
+                                .monospace()
+                                .color(Color32::LIGHT_GREEN),
+                        );
+                    }
+                    for s in &diff.strings_removed {
+                        ui.label(
+                            RichText::new(format!("- {:?}", s.as_str()))
+                                .monospace()
+                                .color(Color32::LIGHT_RED),
+                        );
+                    }
+                });
+            });
+    }
+}
@@ -4,9 +4,10 @@ use eframe::egui::{
     Color32, Grid, Link, RichText, ScrollArea, TextEdit, TextStyle, Ui, WidgetText,
 };
 
+use hlbc::analysis::xref::{Access, Element};
 use hlbc::fmt::EnhancedFmt;
 use hlbc::types::{FunPtr, RefField, RefFun, RefGlobal, RefString, RefType};
-use hlbc::{Bytecode, Resolve};
+use hlbc::Bytecode;
 
 use crate::{AppCtxHandle, AppView, ItemSelection};
 
@@ -100,9 +101,28 @@ fn inspector_link(ui: &mut Ui, ctx: AppCtxHandle, item: ItemSelection) {
     }
 }
 
+/// Every instruction using an element, found with the cross-references index
+fn references_ui(ui: &mut Ui, ctx: AppCtxHandle, id: &str, elem: Element) {
+    let uses = ctx.xrefs().uses(ctx.full_code(), elem);
+    ui.collapsing(format!("References ({})", uses.len()), |ui| {
+        Grid::new(id).striped(true).num_columns(3).show(ui, |ui| {
+            for site in uses {
+                inspector_link(ui, ctx.clone(), ItemSelection::Fun(site.fun));
+                ui.monospace(format!("at {}", site.op));
+                ui.label(match site.access {
+                    Access::Read => "read",
+                    Access::Write => "write",
+                    Access::Call => "call",
+                });
+                ui.end_row();
+            }
+        });
+    });
+}
+
 fn function_inspector(ui: &mut Ui, ctx: AppCtxHandle, fun: RefFun) {
     let code = ctx.code();
-    match code.resolve(fun) {
+    match ctx.function(fun) {
         FunPtr::Fun(f) => {
             ui.heading(format!("Function : {}@{}", f.name(code), f.findex.0));
             if let Some(parent) = f.parent {
@@ -126,6 +146,12 @@ fn function_inspector(ui: &mut Ui, ctx: AppCtxHandle, fun: RefFun) {
                         }
                     });
             });
+            references_ui(
+                ui,
+                ctx.clone(),
+                "inspector::function::references",
+                Element::Fun(fun),
+            );
 
             ui.add_space(6.0);
             ScrollArea::vertical()
@@ -155,6 +181,13 @@ fn function_inspector(ui: &mut Ui, ctx: AppCtxHandle, fun: RefFun) {
             ui.label(format!("function name : {}", n.name(code)));
             ui.label(format!("function index : {}", n.findex.0))
                 .on_hover_text("This is the native function unique index in the function pool.");
+            ui.separator();
+            references_ui(
+                ui,
+                ctx.clone(),
+                "inspector::native::references",
+                Element::Fun(fun),
+            );
         }
     }
 }
@@ -224,6 +257,14 @@ fn class_inspector(ui: &mut Ui, ctx: AppCtxHandle, t: RefType) {
                     });
             });
         }
+
+        ui.add_space(6.0);
+        references_ui(
+            ui,
+            ctx.clone(),
+            "inspector::class::references",
+            Element::Type(t),
+        );
     } else {
         ui.label("Invalid type");
     }
@@ -245,10 +286,19 @@ fn global_inspector(ui: &mut Ui, ctx: AppCtxHandle, g: RefGlobal) {
     } else {
         ui.label("This global is initialized with code");
     }
+
+    ui.separator();
+    references_ui(ui, ctx, "inspector::global::references", Element::Global(g));
 }
 
 fn string_inspector(ui: &mut Ui, ctx: AppCtxHandle, s: RefString) {
     ui.heading(format!("String@{}", s.0));
+    references_ui(
+        ui,
+        ctx.clone(),
+        "inspector::string::references",
+        Element::String(s),
+    );
     ui.separator();
     ui.add_space(4.0);
     TextEdit::multiline(&mut &*ctx.code()[s].to_string())
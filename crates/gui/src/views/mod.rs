@@ -7,12 +7,14 @@ use egui_dock::TabViewer;
 pub(crate) use callgraph::*;
 pub(crate) use classes::*;
 pub(crate) use decompiler::*;
+pub(crate) use diff::*;
 pub(crate) use functions::*;
 pub(crate) use globals::*;
 pub(crate) use info::*;
 pub(crate) use inspector::*;
 #[cfg(feature = "search")]
 pub(crate) use search::*;
+pub(crate) use stats::*;
 pub(crate) use strings::*;
 
 use crate::{AppCtxHandle, ItemSelection};
@@ -21,12 +23,14 @@ use crate::{AppCtxHandle, ItemSelection};
 mod callgraph;
 mod classes;
 mod decompiler;
+mod diff;
 mod functions;
 mod globals;
 mod info;
 mod inspector;
 #[cfg(feature = "search")]
 mod search;
+mod stats;
 mod strings;
 
 /// Tab viewer with dynamic dispatch because I don't care
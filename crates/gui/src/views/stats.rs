@@ -0,0 +1,269 @@
+use std::cmp::Ordering;
+
+use eframe::egui::{
+    Button, Color32, Label, RichText, ScrollArea, SelectableLabel, TextStyle, Ui, WidgetText,
+};
+
+use hlbc::analysis::stats::{Distribution, Stats};
+use hlbc::types::RefFun;
+
+use crate::views::{singleline_simple, AppView};
+use crate::{AppCtxHandle, ItemSelection};
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+enum Section {
+    #[default]
+    Functions,
+    Opcodes,
+    Files,
+    Natives,
+    Types,
+}
+
+impl Section {
+    const ALL: [Section; 5] = [
+        Section::Functions,
+        Section::Opcodes,
+        Section::Files,
+        Section::Natives,
+        Section::Types,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Section::Functions => "Functions",
+            Section::Opcodes => "Opcodes",
+            Section::Files => "Files",
+            Section::Natives => "Natives",
+            Section::Types => "Types",
+        }
+    }
+
+    fn columns(self) -> &'static [&'static str] {
+        match self {
+            Section::Functions => &["Function", "Instructions", "Registers", "File"],
+            Section::Opcodes => &["Opcode", "Count"],
+            Section::Files => &["File", "Functions", "Instructions"],
+            Section::Natives => &["Library", "Natives", "Calls"],
+            Section::Types => &["Category", "Count"],
+        }
+    }
+}
+
+enum Cell {
+    Text(String),
+    Num(usize),
+}
+
+impl Cell {
+    fn cmp(&self, other: &Cell) -> Ordering {
+        match (self, other) {
+            (Cell::Num(a), Cell::Num(b)) => a.cmp(b),
+            (Cell::Text(a), Cell::Text(b)) => a.cmp(b),
+            (Cell::Num(_), Cell::Text(_)) => Ordering::Less,
+            (Cell::Text(_), Cell::Num(_)) => Ordering::Greater,
+        }
+    }
+
+    fn text(&self) -> String {
+        match self {
+            Cell::Text(s) => s.clone(),
+            Cell::Num(n) => n.to_string(),
+        }
+    }
+}
+
+struct Row {
+    /// Function selected when clicking the row
+    fun: Option<RefFun>,
+    cells: Vec<Cell>,
+}
+
+/// Statistics about the code as sortable tables
+#[derive(Default)]
+pub(crate) struct StatsView {
+    stats: Option<Stats>,
+    section: Section,
+    /// Column to sort by and if the order is descending
+    sort: Option<(usize, bool)>,
+    rows: Vec<Row>,
+    rows_valid: bool,
+}
+
+impl StatsView {
+    fn build_rows(&mut self) {
+        let stats = match &self.stats {
+            Some(stats) => stats,
+            None => return,
+        };
+        self.rows = match self.section {
+            Section::Functions => stats
+                .functions
+                .iter()
+                .map(|f| Row {
+                    fun: Some(f.findex),
+                    cells: vec![
+                        Cell::Text(format!("{}@{}", f.name, f.findex.0)),
+                        Cell::Num(f.ops),
+                        Cell::Num(f.regs),
+                        Cell::Text(f.file.as_deref().unwrap_or_default().to_string()),
+                    ],
+                })
+                .collect(),
+            Section::Opcodes => stats
+                .opcodes
+                .iter()
+                .map(|c| Row {
+                    fun: None,
+                    cells: vec![Cell::Text(c.name.to_string()), Cell::Num(c.count)],
+                })
+                .collect(),
+            Section::Files => stats
+                .files
+                .iter()
+                .map(|f| Row {
+                    fun: None,
+                    cells: vec![
+                        Cell::Text(f.file.to_string()),
+                        Cell::Num(f.functions),
+                        Cell::Num(f.ops),
+                    ],
+                })
+                .collect(),
+            Section::Natives => stats
+                .natives
+                .iter()
+                .map(|n| Row {
+                    fun: None,
+                    cells: vec![
+                        Cell::Text(n.lib.to_string()),
+                        Cell::Num(n.natives),
+                        Cell::Num(n.calls),
+                    ],
+                })
+                .collect(),
+            Section::Types => stats
+                .types
+                .iter()
+                .map(|c| Row {
+                    fun: None,
+                    cells: vec![Cell::Text(c.name.to_string()), Cell::Num(c.count)],
+                })
+                .collect(),
+        };
+        if let Some((col, desc)) = self.sort {
+            self.rows.sort_by(|a, b| {
+                let ord = a.cells[col].cmp(&b.cells[col]);
+                if desc {
+                    ord.reverse()
+                } else {
+                    ord
+                }
+            });
+        }
+        self.rows_valid = true;
+    }
+}
+
+fn distribution_label(ui: &mut Ui, name: &str, d: &Distribution) {
+    ui.label(format!(
+        "{name} : min {}, max {}, mean {:.1}, median {}, total {}",
+        d.min, d.max, d.mean, d.median, d.total
+    ));
+}
+
+impl AppView for StatsView {
+    fn title(&self) -> WidgetText {
+        RichText::new("Stats").color(Color32::WHITE).into()
+    }
+
+    fn ui(&mut self, ui: &mut Ui, ctx: AppCtxHandle) {
+        if self.stats.is_none() {
+            self.stats = Some(Stats::new(ctx.full_code()));
+            self.rows_valid = false;
+        }
+
+        ui.horizontal(|ui| {
+            for section in Section::ALL {
+                if ui
+                    .selectable_label(self.section == section, section.name())
+                    .clicked()
+                {
+                    self.section = section;
+                    self.sort = None;
+                    self.rows_valid = false;
+                }
+            }
+        });
+        if self.section == Section::Functions {
+            if let Some(stats) = &self.stats {
+                distribution_label(ui, "Instructions", &stats.ops);
+                distribution_label(ui, "Registers", &stats.regs);
+            }
+        }
+        ui.add_space(6.0);
+
+        if !self.rows_valid {
+            self.build_rows();
+        }
+
+        let columns = self.section.columns();
+        // The first column holds the names
+        let width = |col: usize| if col == 0 { 320.0 } else { 100.0 };
+        let height = ui.text_style_height(&TextStyle::Button);
+
+        ui.horizontal(|ui| {
+            for (i, &name) in columns.iter().enumerate() {
+                let label = match self.sort {
+                    Some((col, true)) if col == i => format!("{name} ⏷"),
+                    Some((col, false)) if col == i => format!("{name} ⏶"),
+                    _ => name.to_string(),
+                };
+                if ui
+                    .add_sized([width(i), height], Button::new(label))
+                    .clicked()
+                {
+                    // Numbers are sorted largest first
+                    let desc = matches!(self.rows.first().map(|r| &r.cells[i]), Some(Cell::Num(_)));
+                    self.sort = match self.sort {
+                        Some((col, d)) if col == i => Some((i, !d)),
+                        _ => Some((i, desc)),
+                    };
+                    self.rows_valid = false;
+                }
+            }
+        });
+
+        ScrollArea::both().auto_shrink([false, false]).show_rows(
+            ui,
+            height,
+            self.rows.len(),
+            |ui, range| {
+                for row in &self.rows[range] {
+                    ui.horizontal(|ui| {
+                        for (i, cell) in row.cells.iter().enumerate() {
+                            let text = singleline_simple(ui, cell.text());
+                            match row.fun {
+                                Some(fun) if i == 0 => {
+                                    let selected = ctx.selected() == ItemSelection::Fun(fun);
+                                    if ui
+                                        .add_sized(
+                                            [width(i), height],
+                                            SelectableLabel::new(selected, text),
+                                        )
+                                        .clicked()
+                                    {
+                                        ctx.set_selected(ItemSelection::Fun(fun));
+                                    }
+                                }
+                                _ => {
+                                    ui.add_sized([width(i), height], Label::new(text));
+                                }
+                            }
+                        }
+                    });
+                }
+            },
+        );
+    }
+}
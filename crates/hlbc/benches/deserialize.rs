@@ -0,0 +1,123 @@
+//! Compare the serial and parallel loaders.
+//!
+//! ```shell
+//! cargo bench -p hlbc --features parallel -- [files...]
+//! ```
+//!
+//! Without arguments, this uses the files of the `test_deserialize_*` tests that exist on this machine,
+//! or a generated file if there are none.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use hlbc::builder::BytecodeBuilder;
+use hlbc::opcodes::Opcode;
+use hlbc::types::{RefFun, Reg, Type};
+use hlbc::Bytecode;
+
+const ITERATIONS: u32 = 10;
+const N: i32 = 50_000;
+
+fn sample_files() -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = [
+        "E:\\Games\\Wartales\\hlboot.dat",
+        "E:\\Games\\Northgard\\hlboot.dat",
+    ]
+    .into_iter()
+    .map(PathBuf::from)
+    .collect();
+    if let Ok(dir) = fs::read_dir("../../data") {
+        files.extend(
+            dir.flatten()
+                .map(|e| e.path())
+                .filter(|p| p.extension().map_or(false, |ext| ext == "hl")),
+        );
+    }
+    files.retain(|p| p.exists());
+    files
+}
+
+/// A program with many functions, for when no real file is available
+fn generated() -> Vec<u8> {
+    let mut builder = BytecodeBuilder::new();
+    let void = builder.intern_type(Type::Void);
+    let i32 = builder.intern_type(Type::I32);
+    let t = builder.fun_type(vec![i32], i32);
+    for i in 0..N {
+        let s = builder.string(&format!("string {i}"));
+        let k = builder.int(i);
+        let mut ops = Vec::new();
+        for _ in 0..20 {
+            ops.push(Opcode::Int {
+                dst: Reg(1),
+                ptr: k,
+            });
+            ops.push(Opcode::String {
+                dst: Reg(2),
+                ptr: s,
+            });
+            ops.push(Opcode::Add {
+                dst: Reg(0),
+                a: Reg(0),
+                b: Reg(1),
+            });
+            ops.push(Opcode::CallN {
+                dst: Reg(1),
+                fun: RefFun(0),
+                args: vec![Reg(0), Reg(1)],
+            });
+        }
+        ops.push(Opcode::Ret { ret: Reg(0) });
+        builder.add_function(t, vec![i32, i32, void], ops);
+    }
+    let mut data = Vec::new();
+    builder.build().serialize(&mut data).unwrap();
+    data
+}
+
+fn bench(name: &str, data: &[u8], load: impl Fn(&[u8]) -> hlbc::Result<Bytecode>) -> Duration {
+    // Warmup
+    load(data).unwrap();
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        load(data).unwrap();
+    }
+    let elapsed = start.elapsed() / ITERATIONS;
+    println!("  {name:<10} {elapsed:?}");
+    elapsed
+}
+
+fn main() {
+    // cargo bench passes --bench
+    let args: Vec<PathBuf> = env::args()
+        .skip(1)
+        .filter(|a| !a.starts_with("--"))
+        .map(PathBuf::from)
+        .collect();
+    let files = if args.is_empty() {
+        sample_files()
+    } else {
+        args
+    };
+
+    let inputs: Vec<(String, Vec<u8>)> = if files.is_empty() {
+        vec![("generated".to_string(), generated())]
+    } else {
+        files
+            .into_iter()
+            .map(|p| (p.display().to_string(), fs::read(&p).unwrap()))
+            .collect()
+    };
+
+    for (name, data) in inputs {
+        println!("{name} ({} KiB)", data.len() / 1024);
+        let serial = bench("serial", &data, |mut data| Bytecode::deserialize(&mut data));
+        let parallel = bench("parallel", &data, Bytecode::deserialize_parallel);
+        println!(
+            "  speedup    {:.2}x",
+            serial.as_secs_f64() / parallel.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,706 @@
+//! Control-flow graph of a function, with dominators and natural loops.
+//!
+//! Blocks are split on jump targets, [Opcode::Label] and after every instruction that transfers
+//! control. Exception edges go from every block lexically inside a [Opcode::Trap] region to its
+//! handler. This is conservative, a block after an [Opcode::EndTrap] in the region still has one.
+
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::ops::Range;
+
+use crate::types::Function;
+use crate::{Bytecode, Opcode};
+
+/// Why control can go from a block to another
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum EdgeKind {
+    /// Falling through to the next instruction, including when a conditional jump isn't taken
+    /// and the default case of a switch
+    Next,
+    /// A jump being taken, conditional or not
+    Jump,
+    /// Case of a switch
+    Case(usize),
+    /// An exception thrown in a trap region, caught by the handler
+    Exception,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Edge {
+    /// Target block
+    pub to: usize,
+    pub kind: EdgeKind,
+}
+
+#[derive(Debug, Clone)]
+pub struct BasicBlock {
+    /// Position of the first instruction
+    pub start: usize,
+    /// Position after the last instruction
+    pub end: usize,
+    pub succs: Vec<Edge>,
+    /// Blocks with an edge to this one, without duplicates
+    pub preds: Vec<usize>,
+}
+
+impl BasicBlock {
+    pub fn ops(&self) -> Range<usize> {
+        self.start..self.end
+    }
+
+    /// Position of the last instruction
+    pub fn last(&self) -> usize {
+        self.end - 1
+    }
+}
+
+/// Control-flow graph of a function. The entry block is always the first one.
+#[derive(Debug, Clone)]
+pub struct Cfg {
+    pub blocks: Vec<BasicBlock>,
+    /// Block of each instruction
+    block_of: Vec<usize>,
+}
+
+/// Absolute position of a jump target, if it is inside the function
+fn target(ops: &[Opcode], pos: usize, offset: i32) -> Option<usize> {
+    let target = pos as i64 + offset as i64 + 1;
+    if target >= 0 && (target as usize) < ops.len() {
+        Some(target as usize)
+    } else {
+        None
+    }
+}
+
+/// Targets of a branching instruction, with the kind of edge leading there.
+/// Returns false if control can't reach the next instruction.
+fn branches(ops: &[Opcode], pos: usize, out: &mut Vec<(usize, EdgeKind)>) -> bool {
+    match &ops[pos] {
+        Opcode::JTrue { offset, .. }
+        | Opcode::JFalse { offset, .. }
+        | Opcode::JNull { offset, .. }
+        | Opcode::JNotNull { offset, .. }
+        | Opcode::JSLt { offset, .. }
+        | Opcode::JSGte { offset, .. }
+        | Opcode::JSGt { offset, .. }
+        | Opcode::JSLte { offset, .. }
+        | Opcode::JULt { offset, .. }
+        | Opcode::JUGte { offset, .. }
+        | Opcode::JNotLt { offset, .. }
+        | Opcode::JNotGte { offset, .. }
+        | Opcode::JEq { offset, .. }
+        | Opcode::JNotEq { offset, .. } => {
+            out.extend(target(ops, pos, *offset).map(|t| (t, EdgeKind::Jump)));
+            true
+        }
+        Opcode::JAlways { offset } => {
+            out.extend(target(ops, pos, *offset).map(|t| (t, EdgeKind::Jump)));
+            false
+        }
+        // Out of range values continue to the next instruction
+        Opcode::Switch { offsets, .. } => {
+            out.extend(
+                offsets.iter().enumerate().filter_map(|(i, &offset)| {
+                    Some((target(ops, pos, offset)?, EdgeKind::Case(i)))
+                }),
+            );
+            true
+        }
+        Opcode::Ret { .. } | Opcode::Throw { .. } | Opcode::Rethrow { .. } => false,
+        _ => true,
+    }
+}
+
+/// Does this instruction end a block
+fn ends_block(op: &Opcode) -> bool {
+    matches!(
+        op,
+        Opcode::JTrue { .. }
+            | Opcode::JFalse { .. }
+            | Opcode::JNull { .. }
+            | Opcode::JNotNull { .. }
+            | Opcode::JSLt { .. }
+            | Opcode::JSGte { .. }
+            | Opcode::JSGt { .. }
+            | Opcode::JSLte { .. }
+            | Opcode::JULt { .. }
+            | Opcode::JUGte { .. }
+            | Opcode::JNotLt { .. }
+            | Opcode::JNotGte { .. }
+            | Opcode::JEq { .. }
+            | Opcode::JNotEq { .. }
+            | Opcode::JAlways { .. }
+            | Opcode::Switch { .. }
+            | Opcode::Ret { .. }
+            | Opcode::Throw { .. }
+            | Opcode::Rethrow { .. }
+            | Opcode::Trap { .. }
+            | Opcode::EndTrap { .. }
+    )
+}
+
+impl Cfg {
+    pub fn new(f: &Function) -> Self {
+        let ops = &f.ops[..];
+        let n = ops.len();
+
+        // Trap position and handler position
+        let traps: Vec<(usize, usize)> = ops
+            .iter()
+            .enumerate()
+            .filter_map(|(i, o)| match o {
+                Opcode::Trap { offset, .. } => Some((i, target(ops, i, *offset)?)),
+                _ => None,
+            })
+            .collect();
+
+        let mut leader = vec![false; n + 1];
+        leader[0] = true;
+        let mut targets = Vec::new();
+        for (i, o) in ops.iter().enumerate() {
+            if matches!(o, Opcode::Label) {
+                leader[i] = true;
+            }
+            if ends_block(o) {
+                leader[i + 1] = true;
+            }
+            targets.clear();
+            branches(ops, i, &mut targets);
+            for &(t, _) in &targets {
+                leader[t] = true;
+            }
+        }
+        for &(_, handler) in &traps {
+            leader[handler] = true;
+        }
+
+        let mut blocks = Vec::new();
+        let mut block_of = vec![0; n];
+        for i in 0..n {
+            if leader[i] {
+                blocks.push(BasicBlock {
+                    start: i,
+                    end: i + 1,
+                    succs: Vec::new(),
+                    preds: Vec::new(),
+                });
+            } else {
+                blocks.last_mut().unwrap().end = i + 1;
+            }
+            block_of[i] = blocks.len() - 1;
+        }
+
+        for (b, block) in blocks.iter_mut().enumerate() {
+            let last = block.last();
+            targets.clear();
+            let next = branches(ops, last, &mut targets);
+            let mut succs: Vec<Edge> = targets
+                .iter()
+                .map(|&(t, kind)| Edge {
+                    to: block_of[t],
+                    kind,
+                })
+                .collect();
+            if next && last + 1 < n {
+                succs.push(Edge {
+                    to: b + 1,
+                    kind: EdgeKind::Next,
+                });
+            }
+            // The innermost region is the one starting last
+            let start = block.start;
+            if let Some(&(_, handler)) = traps
+                .iter()
+                .filter(|&&(trap, handler)| trap < start && start < handler)
+                .max_by_key(|&&(trap, _)| trap)
+            {
+                succs.push(Edge {
+                    to: block_of[handler],
+                    kind: EdgeKind::Exception,
+                });
+            }
+            block.succs = succs;
+        }
+
+        for b in 0..blocks.len() {
+            for i in 0..blocks[b].succs.len() {
+                let to = blocks[b].succs[i].to;
+                if !blocks[to].preds.contains(&b) {
+                    blocks[to].preds.push(b);
+                }
+            }
+        }
+
+        Self { blocks, block_of }
+    }
+
+    /// Block containing the instruction at this position
+    pub fn block_of(&self, pos: usize) -> Option<usize> {
+        self.block_of.get(pos).copied()
+    }
+
+    pub fn successors(&self, b: usize) -> impl Iterator<Item = usize> + '_ {
+        self.blocks[b].succs.iter().map(|e| e.to)
+    }
+
+    pub fn predecessors(&self, b: usize) -> &[usize] {
+        &self.blocks[b].preds
+    }
+
+    /// Blocks leaving the function (return or throw outside a trap)
+    pub fn exits(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.blocks.len()).filter(|&b| self.blocks[b].succs.is_empty())
+    }
+
+    /// Blocks reachable from the entry, in reverse postorder
+    pub fn reverse_postorder(&self) -> Vec<usize> {
+        let succs: Vec<Vec<usize>> = (0..self.blocks.len())
+            .map(|b| self.successors(b).collect())
+            .collect();
+        reverse_postorder(&succs, &[0])
+    }
+
+    pub fn dominators(&self) -> Dominators {
+        let succs: Vec<Vec<usize>> = (0..self.blocks.len())
+            .map(|b| self.successors(b).collect())
+            .collect();
+        let preds: Vec<Vec<usize>> = self.blocks.iter().map(|b| b.preds.clone()).collect();
+        Dominators::compute(&succs, &preds, &[0], self.blocks.len())
+    }
+
+    /// Dominators of the reversed graph. Exits have no immediate post-dominator,
+    /// and blocks that never leave the function (infinite loops) have no post-dominators at all.
+    pub fn post_dominators(&self) -> Dominators {
+        let succs: Vec<Vec<usize>> = self.blocks.iter().map(|b| b.preds.clone()).collect();
+        let preds: Vec<Vec<usize>> = (0..self.blocks.len())
+            .map(|b| self.successors(b).collect())
+            .collect();
+        let exits: Vec<usize> = self.exits().collect();
+        Dominators::compute(&succs, &preds, &exits, self.blocks.len())
+    }
+
+    /// Natural loops, from back edges to a block dominating their source.
+    /// Loops sharing a header are merged. Sorted by header.
+    pub fn natural_loops(&self, doms: &Dominators) -> Vec<Loop> {
+        let mut loops: Vec<Loop> = Vec::new();
+        for b in 0..self.blocks.len() {
+            for h in self.successors(b) {
+                if !doms.dominates(h, b) {
+                    continue;
+                }
+                let i = match loops.iter().position(|l| l.header == h) {
+                    Some(i) => i,
+                    None => {
+                        loops.push(Loop {
+                            header: h,
+                            latches: Vec::new(),
+                            blocks: vec![h],
+                        });
+                        loops.len() - 1
+                    }
+                };
+                let l = &mut loops[i];
+                if !l.latches.contains(&b) {
+                    l.latches.push(b);
+                }
+                // Everything reaching the latch without going through the header
+                let mut stack = vec![b];
+                while let Some(x) = stack.pop() {
+                    if l.blocks.contains(&x) {
+                        continue;
+                    }
+                    l.blocks.push(x);
+                    stack.extend(self.predecessors(x).iter().copied());
+                }
+            }
+        }
+        for l in &mut loops {
+            l.blocks.sort_unstable();
+            l.latches.sort_unstable();
+        }
+        loops.sort_by_key(|l| l.header);
+        loops
+    }
+
+    /// Generate dot language
+    pub fn display<'a>(&'a self, code: &'a Bytecode, f: &'a Function) -> CfgDisplay<'a> {
+        CfgDisplay { cfg: self, code, f }
+    }
+}
+
+/// Nodes of a graph reachable from the roots, in reverse postorder.
+/// Roots outside of the graph are ignored, so an empty graph has an empty order.
+pub fn reverse_postorder(succs: &[Vec<usize>], roots: &[usize]) -> Vec<usize> {
+    let mut visited = vec![false; succs.len()];
+    let mut order = Vec::with_capacity(succs.len());
+    // Node and index of the next successor to visit
+    let mut stack = Vec::new();
+    for &root in roots.iter().rev() {
+        if root >= succs.len() || visited[root] {
+            continue;
+        }
+        visited[root] = true;
+        stack.push((root, 0));
+        while let Some((node, i)) = stack.last_mut() {
+            if let Some(&next) = succs[*node].get(*i) {
+                *i += 1;
+                if !visited[next] {
+                    visited[next] = true;
+                    stack.push((next, 0));
+                }
+            } else {
+                order.push(*node);
+                stack.pop();
+            }
+        }
+    }
+    order.reverse();
+    order
+}
+
+/// Dominator tree, as computed by [Cfg::dominators] or [Cfg::post_dominators]
+#[derive(Debug, Clone)]
+pub struct Dominators {
+    idom: Vec<Option<usize>>,
+    reachable: Vec<bool>,
+}
+
+impl Dominators {
+    /// Dominators of any graph, given the successors of each node.
+    /// Useful to work on a [Cfg] with some edges removed or added.
+    pub fn new(succs: &[Vec<usize>], roots: &[usize]) -> Self {
+        let mut preds = vec![Vec::new(); succs.len()];
+        for (b, succs) in succs.iter().enumerate() {
+            for &s in succs {
+                if !preds[s].contains(&b) {
+                    preds[s].push(b);
+                }
+            }
+        }
+        Self::compute(succs, &preds, roots, succs.len())
+    }
+
+    /// Iterative algorithm from "A Simple, Fast Dominance Algorithm" (Cooper, Harvey, Kennedy).
+    /// With multiple roots, a virtual root with an edge to each of them is used.
+    /// Roots outside of the graph are ignored.
+    fn compute(succs: &[Vec<usize>], preds: &[Vec<usize>], roots: &[usize], n: usize) -> Self {
+        const VIRTUAL: usize = usize::MAX;
+        let roots: Vec<usize> = roots.iter().copied().filter(|&r| r < n).collect();
+        let roots = &roots[..];
+        let order = reverse_postorder(succs, roots);
+        let mut rpo_index = vec![usize::MAX; n];
+        for (i, &b) in order.iter().enumerate() {
+            rpo_index[b] = i;
+        }
+        let index = |b: usize| if b == VIRTUAL { 0 } else { rpo_index[b] + 1 };
+
+        let mut idom = vec![None; n];
+        for &root in roots {
+            idom[root] = Some(VIRTUAL);
+        }
+        let intersect = |idom: &[Option<usize>], mut a: usize, mut b: usize| {
+            while a != b {
+                while index(a) > index(b) {
+                    a = idom[a].unwrap();
+                }
+                while index(b) > index(a) {
+                    b = idom[b].unwrap();
+                }
+            }
+            a
+        };
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &b in &order {
+                if roots.contains(&b) {
+                    continue;
+                }
+                let mut new = None;
+                for &p in &preds[b] {
+                    if idom[p].is_none() {
+                        continue;
+                    }
+                    new = Some(match new {
+                        None => p,
+                        Some(new) => intersect(&idom, p, new),
+                    });
+                }
+                if new.is_some() && idom[b] != new {
+                    idom[b] = new;
+                    changed = true;
+                }
+            }
+        }
+
+        let reachable = idom.iter().map(Option::is_some).collect();
+        let idom = idom
+            .into_iter()
+            .map(|d| d.filter(|&d| d != VIRTUAL))
+            .collect();
+        Self { idom, reachable }
+    }
+
+    /// Closest strict dominator of a block.
+    /// [None] for the root or if the block is unreachable.
+    pub fn immediate_dominator(&self, b: usize) -> Option<usize> {
+        self.idom[b]
+    }
+
+    /// Is the block reachable from the root
+    pub fn is_reachable(&self, b: usize) -> bool {
+        self.reachable[b]
+    }
+
+    /// Does `a` dominate `b`. A block dominates itself.
+    pub fn dominates(&self, a: usize, b: usize) -> bool {
+        if !self.reachable[b] {
+            return false;
+        }
+        let mut x = b;
+        loop {
+            if x == a {
+                return true;
+            }
+            match self.idom[x] {
+                Some(d) => x = d,
+                None => return false,
+            }
+        }
+    }
+
+    /// Dominators of a block, from itself to the root
+    pub fn dominators(&self, b: usize) -> impl Iterator<Item = usize> + '_ {
+        let first = if self.reachable[b] { Some(b) } else { None };
+        std::iter::successors(first, move |&x| self.idom[x])
+    }
+}
+
+/// A natural loop
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Loop {
+    /// The only entry of the loop, dominating every block of it
+    pub header: usize,
+    /// Sources of the back edges to the header
+    pub latches: Vec<usize>,
+    /// Every block in the loop, including the header
+    pub blocks: Vec<usize>,
+}
+
+static INDENT: &str = "    ";
+
+pub struct CfgDisplay<'a> {
+    cfg: &'a Cfg,
+    code: &'a Bytecode,
+    f: &'a Function,
+}
+
+impl Display for CfgDisplay<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "digraph {{")?;
+        writeln!(
+            f,
+            "{}fontname=\"Jetbrains Mono,Fira Code,monospace\"",
+            INDENT
+        )?;
+        writeln!(
+            f,
+            "{}node [fontname=\"Jetbrains Mono,Fira Code,monospace\" style=filled fillcolor=\"#f8f8f8\"]",
+            INDENT
+        )?;
+        writeln!(
+            f,
+            "{}edge [fontname=\"Jetbrains Mono,Fira Code,monospace\"]",
+            INDENT
+        )?;
+
+        for (b, block) in self.cfg.blocks.iter().enumerate() {
+            let mut label = String::new();
+            for i in block.ops() {
+                let op = self.f.ops[i].display(self.code, self.f, i as i32, 11);
+                label += &format!("{i:>3}: {op}")
+                    .replace('\\', "\\\\")
+                    .replace('"', "\\\"");
+                label += "\\l";
+            }
+            writeln!(
+                f,
+                "{}{} [ label = \"{}\" fontsize=13 shape=box color=\"#b20400\" fillcolor=\"#edd6d5\" ]",
+                INDENT, b, label
+            )?;
+        }
+        for (b, block) in self.cfg.blocks.iter().enumerate() {
+            for edge in &block.succs {
+                let label = match edge.kind {
+                    EdgeKind::Next => String::new(),
+                    EdgeKind::Jump => String::from("jump"),
+                    EdgeKind::Case(i) => format!("case {i}"),
+                    EdgeKind::Exception => String::from("catch"),
+                };
+                let style = if edge.kind == EdgeKind::Exception {
+                    " style=dashed"
+                } else {
+                    ""
+                };
+                writeln!(
+                    f,
+                    "{}{} -> {} [ label = \"{}\"{} ]",
+                    INDENT, b, edge.to, label, style
+                )?;
+            }
+        }
+        writeln!(f, "}}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::analysis::cfg::{Cfg, Edge, EdgeKind, Loop};
+    use crate::types::{Function, RefFun, RefString, RefType, Reg};
+    use crate::Opcode;
+
+    fn fun(ops: Vec<Opcode>) -> Function {
+        Function {
+            name: RefString(0),
+            t: RefType(0),
+            findex: RefFun(0),
+            regs: vec![RefType(0)],
+            ops,
+            debug_info: None,
+            assigns: None,
+            parent: None,
+        }
+    }
+
+    fn ranges(cfg: &Cfg) -> Vec<(usize, usize)> {
+        cfg.blocks.iter().map(|b| (b.start, b.end)).collect()
+    }
+
+    #[test]
+    fn if_else() {
+        let r = Reg(0);
+        let cfg = Cfg::new(&fun(vec![
+            Opcode::JFalse { cond: r, offset: 2 },
+            Opcode::Nop,
+            Opcode::JAlways { offset: 1 },
+            Opcode::Nop,
+            Opcode::Ret { ret: r },
+        ]));
+        assert_eq!(ranges(&cfg), vec![(0, 1), (1, 3), (3, 4), (4, 5)]);
+        assert_eq!(
+            cfg.blocks[0].succs,
+            vec![
+                Edge {
+                    to: 2,
+                    kind: EdgeKind::Jump
+                },
+                Edge {
+                    to: 1,
+                    kind: EdgeKind::Next
+                }
+            ]
+        );
+        assert_eq!(cfg.predecessors(3), &[1, 2]);
+
+        let doms = cfg.dominators();
+        assert_eq!(doms.immediate_dominator(0), None);
+        assert_eq!(doms.immediate_dominator(1), Some(0));
+        assert_eq!(doms.immediate_dominator(2), Some(0));
+        assert_eq!(doms.immediate_dominator(3), Some(0));
+        assert!(!doms.dominates(1, 3));
+        assert_eq!(doms.dominators(3).collect::<Vec<_>>(), vec![3, 0]);
+
+        let pdoms = cfg.post_dominators();
+        assert_eq!(pdoms.immediate_dominator(0), Some(3));
+        assert_eq!(pdoms.immediate_dominator(1), Some(3));
+        assert_eq!(pdoms.immediate_dominator(3), None);
+        assert!(cfg.natural_loops(&doms).is_empty());
+    }
+
+    #[test]
+    fn loops() {
+        let r = Reg(0);
+        // while (r) { if (r) continue; }
+        let cfg = Cfg::new(&fun(vec![
+            Opcode::Label,
+            Opcode::JFalse { cond: r, offset: 3 },
+            Opcode::JFalse { cond: r, offset: 1 },
+            Opcode::JAlways { offset: -4 },
+            Opcode::JAlways { offset: -5 },
+            Opcode::Ret { ret: r },
+            Opcode::Nop,
+        ]));
+        assert_eq!(
+            ranges(&cfg),
+            vec![(0, 2), (2, 3), (3, 4), (4, 5), (5, 6), (6, 7)]
+        );
+        let doms = cfg.dominators();
+        assert_eq!(
+            cfg.natural_loops(&doms),
+            vec![Loop {
+                header: 0,
+                latches: vec![2, 3],
+                blocks: vec![0, 1, 2, 3],
+            }]
+        );
+        // Unreachable
+        assert!(!doms.is_reachable(5));
+        assert!(!doms.dominates(0, 5));
+    }
+
+    #[test]
+    fn switch_and_trap() {
+        let r = Reg(0);
+        let cfg = Cfg::new(&fun(vec![
+            Opcode::Trap { exc: r, offset: 5 },
+            Opcode::Switch {
+                reg: r,
+                offsets: vec![1, 2],
+                end: 2,
+            },
+            Opcode::Nop,
+            Opcode::Nop,
+            Opcode::Throw { exc: r },
+            Opcode::EndTrap { exc: r },
+            Opcode::Ret { ret: r },
+        ]));
+        assert_eq!(
+            ranges(&cfg),
+            vec![(0, 1), (1, 2), (2, 3), (3, 4), (4, 5), (5, 6), (6, 7)]
+        );
+        let kinds = |b: usize| {
+            cfg.blocks[b]
+                .succs
+                .iter()
+                .map(|e| (e.to, e.kind))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(kinds(0), vec![(1, EdgeKind::Next)]);
+        assert_eq!(
+            kinds(1),
+            vec![
+                (3, EdgeKind::Case(0)),
+                (4, EdgeKind::Case(1)),
+                (2, EdgeKind::Next),
+                (6, EdgeKind::Exception)
+            ]
+        );
+        assert_eq!(kinds(4), vec![(6, EdgeKind::Exception)]);
+        // The handler is the only exit
+        assert_eq!(cfg.exits().collect::<Vec<_>>(), vec![6]);
+        let pdoms = cfg.post_dominators();
+        assert!(pdoms.dominates(6, 0));
+    }
+
+    /// Functions from [crate::lazy::LazyBytecode::header] have no instructions
+    #[test]
+    fn empty() {
+        let cfg = Cfg::new(&fun(vec![]));
+        assert!(cfg.blocks.is_empty());
+        assert!(cfg.reverse_postorder().is_empty());
+        let doms = cfg.dominators();
+        assert!(cfg.natural_loops(&doms).is_empty());
+        cfg.post_dominators();
+    }
+}
@@ -0,0 +1,501 @@
+//! Register dataflow over a function's [Cfg]: reaching definitions, def-use chains and liveness.
+//!
+//! Registers whose address is taken with [Opcode::Ref] can be written through the reference.
+//! [Opcode::Setref] and calls are treated as possible writes of all of them, which don't hide
+//! earlier definitions.
+
+use crate::analysis::cfg::{Cfg, EdgeKind};
+use crate::opcodes::Operand;
+use crate::types::{Function, Reg};
+use crate::Opcode;
+
+impl Opcode {
+    /// Register written by this instruction.
+    /// [Opcode::Trap] writes the exception register when it is caught.
+    pub fn def(&self) -> Option<Reg> {
+        match self {
+            // Writes through the reference in dst
+            Opcode::Setref { .. } => None,
+            Opcode::Trap { exc, .. } => Some(*exc),
+            _ => self.dst(),
+        }
+    }
+
+    /// Registers read by this instruction, in operand order
+    pub fn uses(&self) -> Vec<Reg> {
+        // dst is always the first operand
+        let skip = match self {
+            Opcode::Incr { .. } | Opcode::Decr { .. } | Opcode::Setref { .. } => 0,
+            Opcode::Trap { .. } | Opcode::EndTrap { .. } => return Vec::new(),
+            _ => self.dst().is_some() as usize,
+        };
+        self.operands()
+            .into_iter()
+            .skip(skip)
+            .flat_map(|o| match o {
+                Operand::Reg(r) => vec![r],
+                Operand::Regs(regs) => regs.to_vec(),
+                _ => Vec::new(),
+            })
+            .collect()
+    }
+
+    /// Can this instruction write through a reference
+    fn writes_refs(&self) -> bool {
+        matches!(
+            self,
+            Opcode::Setref { .. }
+                | Opcode::Call0 { .. }
+                | Opcode::Call1 { .. }
+                | Opcode::Call2 { .. }
+                | Opcode::Call3 { .. }
+                | Opcode::Call4 { .. }
+                | Opcode::CallN { .. }
+                | Opcode::CallMethod { .. }
+                | Opcode::CallThis { .. }
+                | Opcode::CallClosure { .. }
+        )
+    }
+}
+
+/// Where the value of a register comes from
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Def {
+    /// Value at the function entry: an argument, or a register never written before
+    Entry,
+    /// Written by the instruction at this position
+    Op(usize),
+}
+
+/// Fixed size set of indices
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct BitSet(Vec<u64>);
+
+impl BitSet {
+    fn new(len: usize) -> Self {
+        Self(vec![0; (len + 63) / 64])
+    }
+
+    fn insert(&mut self, i: usize) {
+        self.0[i / 64] |= 1 << (i % 64);
+    }
+
+    fn remove(&mut self, i: usize) {
+        self.0[i / 64] &= !(1 << (i % 64));
+    }
+
+    fn contains(&self, i: usize) -> bool {
+        self.0[i / 64] & (1 << (i % 64)) != 0
+    }
+
+    /// Returns true if the set changed
+    fn union(&mut self, other: &BitSet) -> bool {
+        let mut changed = false;
+        for (a, b) in self.0.iter_mut().zip(&other.0) {
+            let new = *a | b;
+            changed |= new != *a;
+            *a = new;
+        }
+        changed
+    }
+}
+
+/// A definition of a register
+#[derive(Debug, Copy, Clone)]
+struct DefInfo {
+    def: Def,
+    reg: Reg,
+    /// Definitely writes the register, hiding the previous definitions
+    kills: bool,
+}
+
+/// Reaching definitions, def-use chains and liveness of the registers of a function
+#[derive(Debug, Clone)]
+pub struct Dataflow {
+    cfg: Cfg,
+    defs: Vec<DefInfo>,
+    /// Definition ids of each instruction
+    op_defs: Vec<Vec<usize>>,
+    /// Definition ids of each register
+    reg_defs: Vec<Vec<usize>>,
+    /// Definitions reaching the start of each block
+    reach_in: Vec<BitSet>,
+    /// Registers live at the end of each block, including the ones live in exception handlers
+    live_out: Vec<BitSet>,
+    /// Registers live in the exception handlers of each block
+    live_exc: Vec<BitSet>,
+    /// Instructions reading each definition
+    def_uses: Vec<Vec<usize>>,
+}
+
+impl Dataflow {
+    pub fn new(f: &Function) -> Self {
+        Self::with_cfg(f, Cfg::new(f))
+    }
+
+    pub fn with_cfg(f: &Function, cfg: Cfg) -> Self {
+        let nregs = f.regs.len();
+        let mut defs: Vec<DefInfo> = (0..nregs)
+            .map(|r| DefInfo {
+                def: Def::Entry,
+                reg: Reg(r as u32),
+                kills: true,
+            })
+            .collect();
+
+        let mut address_taken = Vec::new();
+        for o in &f.ops {
+            if let Opcode::Ref { src, .. } = o {
+                if !address_taken.contains(src) {
+                    address_taken.push(*src);
+                }
+            }
+        }
+        let mut op_defs = Vec::with_capacity(f.ops.len());
+        for (i, o) in f.ops.iter().enumerate() {
+            let mut ids = Vec::new();
+            if let Some(reg) = o.def().filter(|r| (r.0 as usize) < nregs) {
+                ids.push(defs.len());
+                defs.push(DefInfo {
+                    def: Def::Op(i),
+                    reg,
+                    kills: true,
+                });
+            }
+            if o.writes_refs() {
+                for &reg in address_taken.iter().filter(|r| (r.0 as usize) < nregs) {
+                    ids.push(defs.len());
+                    defs.push(DefInfo {
+                        def: Def::Op(i),
+                        reg,
+                        kills: false,
+                    });
+                }
+            }
+            op_defs.push(ids);
+        }
+        let mut reg_defs = vec![Vec::new(); nregs];
+        for (id, d) in defs.iter().enumerate() {
+            reg_defs[d.reg.0 as usize].push(id);
+        }
+
+        let mut flow = Self {
+            reach_in: vec![BitSet::new(defs.len()); cfg.blocks.len()],
+            live_out: vec![BitSet::new(nregs); cfg.blocks.len()],
+            live_exc: vec![BitSet::new(nregs); cfg.blocks.len()],
+            def_uses: vec![Vec::new(); defs.len()],
+            cfg,
+            defs,
+            op_defs,
+            reg_defs,
+        };
+        flow.compute_reaching(f);
+        flow.compute_liveness(f);
+        flow.compute_chains(f);
+        flow
+    }
+
+    fn apply_defs(&self, set: &mut BitSet, pos: usize) {
+        for &id in &self.op_defs[pos] {
+            let d = self.defs[id];
+            if d.kills {
+                for &other in &self.reg_defs[d.reg.0 as usize] {
+                    set.remove(other);
+                }
+            }
+            set.insert(id);
+        }
+    }
+
+    fn compute_reaching(&mut self, f: &Function) {
+        if self.cfg.blocks.is_empty() {
+            return;
+        }
+        for r in 0..f.regs.len() {
+            self.reach_in[0].insert(r);
+        }
+        let order = self.cfg.reverse_postorder();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &b in &order {
+                let block = &self.cfg.blocks[b];
+                let mut out = self.reach_in[b].clone();
+                // An exception can happen anywhere in the block
+                let mut any = self.reach_in[b].clone();
+                for pos in block.ops() {
+                    self.apply_defs(&mut out, pos);
+                    any.union(&out);
+                }
+                for e in &self.cfg.blocks[b].succs {
+                    let set = if e.kind == EdgeKind::Exception {
+                        &any
+                    } else {
+                        &out
+                    };
+                    changed |= self.reach_in[e.to].union(set);
+                }
+            }
+        }
+    }
+
+    /// Transfer function of liveness for a single instruction, going backward
+    fn apply_liveness(&self, live: &mut BitSet, o: &Opcode, nregs: usize) {
+        if let Some(r) = o.def().filter(|r| (r.0 as usize) < nregs) {
+            live.remove(r.0 as usize);
+        }
+        for r in o.uses() {
+            if (r.0 as usize) < nregs {
+                live.insert(r.0 as usize);
+            }
+        }
+    }
+
+    fn live_in_block(&self, f: &Function, b: usize) -> BitSet {
+        let block = &self.cfg.blocks[b];
+        let mut live = self.live_out[b].clone();
+        for pos in block.ops().rev() {
+            self.apply_liveness(&mut live, &f.ops[pos], f.regs.len());
+            live.union(&self.live_exc[b]);
+        }
+        live
+    }
+
+    fn compute_liveness(&mut self, f: &Function) {
+        let nregs = f.regs.len();
+        let mut order = self.cfg.reverse_postorder();
+        order.reverse();
+        let mut live_in = vec![BitSet::new(nregs); self.cfg.blocks.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &b in &order {
+                let mut out = BitSet::new(nregs);
+                let mut exc = BitSet::new(nregs);
+                for e in &self.cfg.blocks[b].succs {
+                    out.union(&live_in[e.to]);
+                    if e.kind == EdgeKind::Exception {
+                        exc.union(&live_in[e.to]);
+                    }
+                }
+                self.live_out[b] = out;
+                self.live_exc[b] = exc;
+                let new = self.live_in_block(f, b);
+                changed |= live_in[b].union(&new);
+            }
+        }
+    }
+
+    fn compute_chains(&mut self, f: &Function) {
+        for b in self.cfg.reverse_postorder() {
+            let mut set = self.reach_in[b].clone();
+            for pos in self.cfg.blocks[b].ops() {
+                for r in f.ops[pos].uses() {
+                    for &id in self.reg_defs.get(r.0 as usize).into_iter().flatten() {
+                        if set.contains(id) && !self.def_uses[id].contains(&pos) {
+                            self.def_uses[id].push(pos);
+                        }
+                    }
+                }
+                self.apply_defs(&mut set, pos);
+            }
+        }
+    }
+
+    pub fn cfg(&self) -> &Cfg {
+        &self.cfg
+    }
+
+    /// Definitions of a register that may reach an instruction, i.e. where the register
+    /// may have been last written before executing it. Empty if the instruction is unreachable.
+    pub fn reaching_defs(&self, pos: usize, reg: Reg) -> Vec<Def> {
+        let (Some(b), Some(ids)) = (self.cfg.block_of(pos), self.reg_defs.get(reg.0 as usize))
+        else {
+            return Vec::new();
+        };
+        let mut set = self.reach_in[b].clone();
+        for p in self.cfg.blocks[b].start..pos {
+            self.apply_defs(&mut set, p);
+        }
+        let mut defs: Vec<Def> = ids
+            .iter()
+            .filter(|&&id| set.contains(id))
+            .map(|&id| self.defs[id].def)
+            .collect();
+        defs.sort_unstable();
+        defs.dedup();
+        defs
+    }
+
+    /// Position of the instruction that last wrote a register before an instruction,
+    /// if there is exactly one.
+    pub fn last_def(&self, pos: usize, reg: Reg) -> Option<usize> {
+        match self.reaching_defs(pos, reg)[..] {
+            [Def::Op(def)] => Some(def),
+            _ => None,
+        }
+    }
+
+    /// Instructions reading the value written by the instruction at this position (def-use chain)
+    pub fn uses_of(&self, def: usize) -> Vec<usize> {
+        let mut uses: Vec<usize> = self
+            .op_defs
+            .get(def)
+            .into_iter()
+            .flatten()
+            .flat_map(|&id| self.def_uses[id].iter().copied())
+            .collect();
+        uses.sort_unstable();
+        uses.dedup();
+        uses
+    }
+
+    /// Instructions reading a register value from the function entry (e.g. an argument)
+    pub fn uses_of_entry(&self, reg: Reg) -> Vec<usize> {
+        let mut uses = self
+            .def_uses
+            .get(reg.0 as usize)
+            .cloned()
+            .unwrap_or_default();
+        uses.sort_unstable();
+        uses
+    }
+
+    /// Is the register value needed by the instruction at this position or any instruction after it
+    pub fn is_live(&self, f: &Function, pos: usize, reg: Reg) -> bool {
+        let Some(b) = self.cfg.block_of(pos) else {
+            return false;
+        };
+        if reg.0 as usize >= f.regs.len() {
+            return false;
+        }
+        let mut live = self.live_out[b].clone();
+        for p in (pos..self.cfg.blocks[b].end).rev() {
+            self.apply_liveness(&mut live, &f.ops[p], f.regs.len());
+            live.union(&self.live_exc[b]);
+        }
+        live.contains(reg.0 as usize)
+    }
+
+    /// Positions of the instructions where the register is live (see [Dataflow::is_live])
+    pub fn live_range(&self, f: &Function, reg: Reg) -> Vec<usize> {
+        let mut range = Vec::new();
+        if reg.0 as usize >= f.regs.len() {
+            return range;
+        }
+        for (b, block) in self.cfg.blocks.iter().enumerate() {
+            let mut live = self.live_out[b].clone();
+            let start = range.len();
+            for p in block.ops().rev() {
+                self.apply_liveness(&mut live, &f.ops[p], f.regs.len());
+                live.union(&self.live_exc[b]);
+                if live.contains(reg.0 as usize) {
+                    range.push(p);
+                }
+            }
+            range[start..].reverse();
+        }
+        range
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::analysis::dataflow::{Dataflow, Def};
+    use crate::types::{Function, RefFun, RefInt, RefString, RefType, Reg};
+    use crate::Opcode;
+
+    fn fun(nregs: usize, ops: Vec<Opcode>) -> Function {
+        Function {
+            name: RefString(0),
+            t: RefType(0),
+            findex: RefFun(0),
+            regs: vec![RefType(0); nregs],
+            ops,
+            debug_info: None,
+            assigns: None,
+            parent: None,
+        }
+    }
+
+    #[test]
+    fn defs_and_uses() {
+        let op = Opcode::Add {
+            dst: Reg(0),
+            a: Reg(0),
+            b: Reg(1),
+        };
+        assert_eq!(op.def(), Some(Reg(0)));
+        assert_eq!(op.uses(), vec![Reg(0), Reg(1)]);
+        let op = Opcode::Incr { dst: Reg(2) };
+        assert_eq!(op.def(), Some(Reg(2)));
+        assert_eq!(op.uses(), vec![Reg(2)]);
+        let op = Opcode::Setref {
+            dst: Reg(0),
+            value: Reg(1),
+        };
+        assert_eq!(op.def(), None);
+        assert_eq!(op.uses(), vec![Reg(0), Reg(1)]);
+    }
+
+    #[test]
+    fn reaching_and_liveness() {
+        let (a, b) = (Reg(0), Reg(1));
+        // if (b) a = 1 else a = 2; return a
+        let f = fun(
+            2,
+            vec![
+                Opcode::JFalse { cond: b, offset: 2 },
+                Opcode::Int {
+                    dst: a,
+                    ptr: RefInt(0),
+                },
+                Opcode::JAlways { offset: 1 },
+                Opcode::Int {
+                    dst: a,
+                    ptr: RefInt(1),
+                },
+                Opcode::Ret { ret: a },
+            ],
+        );
+        let flow = Dataflow::new(&f);
+        assert_eq!(flow.reaching_defs(4, a), vec![Def::Op(1), Def::Op(3)]);
+        assert_eq!(flow.last_def(4, a), None);
+        assert_eq!(flow.last_def(2, a), Some(1));
+        assert_eq!(flow.reaching_defs(0, a), vec![Def::Entry]);
+        assert_eq!(flow.uses_of(1), vec![4]);
+        assert_eq!(flow.uses_of_entry(b), vec![0]);
+
+        // b is only needed by the first jump, a is dead before being written
+        assert_eq!(flow.live_range(&f, b), vec![0]);
+        assert_eq!(flow.live_range(&f, a), vec![2, 4]);
+        assert!(!flow.is_live(&f, 0, a));
+    }
+
+    #[test]
+    fn loops_and_traps() {
+        let (i, e) = (Reg(0), Reg(1));
+        let f = fun(
+            2,
+            vec![
+                Opcode::Trap { exc: e, offset: 4 },
+                Opcode::Label,
+                Opcode::Incr { dst: i },
+                Opcode::JFalse {
+                    cond: i,
+                    offset: -3,
+                },
+                Opcode::EndTrap { exc: e },
+                Opcode::Ret { ret: i },
+            ],
+        );
+        let flow = Dataflow::new(&f);
+        // From the entry or the previous iteration
+        assert_eq!(flow.reaching_defs(2, i), vec![Def::Entry, Def::Op(2)]);
+        // The handler sees every value of i
+        assert_eq!(flow.reaching_defs(5, i), vec![Def::Entry, Def::Op(2)]);
+        assert_eq!(flow.reaching_defs(5, e), vec![Def::Op(0)]);
+        assert!(flow.is_live(&f, 0, i));
+        assert_eq!(flow.uses_of(2), vec![2, 3, 5]);
+    }
+}
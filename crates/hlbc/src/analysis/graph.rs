@@ -7,35 +7,169 @@ use std::fmt::{Display, Formatter};
 pub use petgraph;
 use petgraph::graphmap::DiGraphMap;
 use petgraph::visit::{EdgeRef, IntoEdgeReferences, IntoNodeReferences, NodeIndexable, NodeRef};
+use petgraph::Direction;
 
-use crate::types::{FunPtr, Function, RefFun};
+use crate::analysis::dataflow::{Dataflow, Def};
+use crate::analysis::hierarchy::Hierarchy;
+use crate::fmt::EnhancedFmt;
+use crate::types::{FunPtr, Function, RefFun, RefString, RefType, Reg};
 use crate::{Bytecode, Opcode, Resolve, Type};
 
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum Call {
     // Called with Call0, Call1, ...
     Direct,
     // Called a closure
     Closure,
+    // Override of the method called on an object
+    Virtual,
+    // Unresolved call, the target is guessed from the closure type or the field name
+    Dynamic,
+}
+
+impl Call {
+    /// Lower is more certain
+    fn rank(self) -> u8 {
+        match self {
+            Call::Direct | Call::Closure => 0,
+            Call::Virtual => 1,
+            Call::Dynamic => 2,
+        }
+    }
 }
 
 pub type Callgraph = DiGraphMap<RefFun, Call>;
 // Function argument number to function ptr
 type RegCtx = HashMap<usize, RefFun>;
 
+/// Whole program information used to resolve indirect calls
+#[derive(Debug, Clone, Default)]
+pub struct CallResolver {
+    hierarchy: Hierarchy,
+    /// Functions turned into closures, by closure type
+    closures: HashMap<RefType, Vec<RefFun>>,
+    /// Methods and functions bound to fields, by name
+    by_name: HashMap<RefString, Vec<RefFun>>,
+}
+
+impl CallResolver {
+    pub fn new(code: &Bytecode) -> Self {
+        let mut res = Self {
+            hierarchy: Hierarchy::new(code),
+            ..Default::default()
+        };
+        for t in &code.types {
+            if let Some(obj) = t.get_type_obj() {
+                for p in &obj.protos {
+                    res.by_name.entry(p.name).or_default().push(p.findex);
+                }
+                for (field, &fun) in &obj.bindings {
+                    if let Some(field) = obj.fields.get(field.0) {
+                        res.by_name.entry(field.name).or_default().push(fun);
+                        res.closures.entry(field.t).or_default().push(fun);
+                    }
+                }
+            }
+        }
+        for (f, (_, o)) in code.ops() {
+            match *o {
+                Opcode::StaticClosure { dst, fun } | Opcode::InstanceClosure { dst, fun, .. } => {
+                    if let Ok(t) = f.try_regtype(dst) {
+                        res.closures.entry(t).or_default().push(fun);
+                    }
+                }
+                _ => {}
+            }
+        }
+        for funs in res.closures.values_mut().chain(res.by_name.values_mut()) {
+            funs.sort_unstable();
+            funs.dedup();
+        }
+        res
+    }
+
+    /// Possible targets of a call with the receiver type and the field index
+    fn method_call(&self, code: &Bytecode, t: RefType, field: usize) -> Vec<(Call, RefFun)> {
+        match code.try_resolve(t) {
+            Ok(Type::Obj(_) | Type::Struct(_)) => t
+                .find_method(field, code)
+                .map(|p| (Call::Direct, p.findex))
+                .into_iter()
+                .chain(
+                    self.hierarchy
+                        .overrides(code, t, field)
+                        .into_iter()
+                        .map(|fun| (Call::Virtual, fun)),
+                )
+                .collect(),
+            Ok(Type::Virtual { fields }) => fields
+                .get(field)
+                .map(|f| self.named(f.name))
+                .unwrap_or_default(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn named(&self, name: RefString) -> Vec<(Call, RefFun)> {
+        self.by_name
+            .get(&name)
+            .into_iter()
+            .flatten()
+            .map(|&fun| (Call::Dynamic, fun))
+            .collect()
+    }
+
+    /// Functions that could be held by a closure register, when it can't be tracked
+    fn dynamic_closure(
+        &self,
+        code: &Bytecode,
+        f: &Function,
+        flow: &Dataflow,
+        reg: Reg,
+        pos: usize,
+    ) -> Vec<(Call, RefFun)> {
+        if let [Def::Op(def)] = flow.reaching_defs(pos, reg)[..] {
+            match f.ops[def] {
+                Opcode::DynGet { field, .. } => return self.named(field),
+                Opcode::Field { obj, field, .. } => {
+                    if let Ok(Type::Virtual { fields }) =
+                        f.try_regtype(obj).and_then(|t| code.try_resolve(t))
+                    {
+                        if let Some(field) = fields.get(field.0) {
+                            return self.named(field.name);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        f.try_regtype(reg)
+            .ok()
+            .and_then(|t| self.closures.get(&t))
+            .into_iter()
+            .flatten()
+            .map(|&fun| (Call::Dynamic, fun))
+            .collect()
+    }
+}
+
+/// Every call made by a function, with the arguments context to pass down to the callee.
+/// An instruction can call multiple functions if the target is not known statically.
 pub fn find_calls<'a>(
     code: &'a Bytecode,
+    resolver: &'a CallResolver,
     f: &'a Function,
     reg_ctx: &'a RegCtx,
 ) -> impl Iterator<Item = (Call, RefFun, RegCtx)> + 'a {
+    let flow = Dataflow::new(f);
+
     macro_rules! build_ctx {
         ($i:ident; $args:expr) => {{
             let mut tmp = RegCtx::new();
             for (p, arg) in $args.into_iter().enumerate() {
-                if matches!(code[f[*arg]], Type::Fun(_)) {
-                    if let Some(value) = f
-                        .find_last_closure_assign(code, *arg, $i)
-                        .or_else(|| reg_ctx.get(&(arg.0 as usize)).copied())
-                    {
+                let ty = f.try_regtype(*arg).and_then(|t| code.try_resolve(t));
+                if matches!(ty, Ok(Type::Fun(_))) {
+                    if let Some(value) = closure_in(code, resolver, f, &flow, reg_ctx, *arg, $i) {
                         tmp.insert(p, value);
                     }
                 }
@@ -44,86 +178,204 @@ pub fn find_calls<'a>(
         }};
     }
 
-    f.ops.iter().enumerate().filter_map(move |(i, o)| match o {
-        Opcode::Call0 { fun, .. } => Some((Call::Direct, *fun, RegCtx::new())),
-        Opcode::Call1 { fun, arg0, .. } => Some((Call::Direct, *fun, build_ctx!(i; [arg0]))),
-        Opcode::Call2 {
-            fun, arg0, arg1, ..
-        } => Some((Call::Direct, *fun, build_ctx!(i; [arg0, arg1]))),
-        Opcode::Call3 {
-            fun,
-            arg0,
-            arg1,
-            arg2,
-            ..
-        } => Some((Call::Direct, *fun, build_ctx!(i; [arg0, arg1, arg2]))),
-        Opcode::Call4 {
-            fun,
-            arg0,
-            arg1,
-            arg2,
-            arg3,
-            ..
-        } => Some((Call::Direct, *fun, build_ctx!(i; [arg0, arg1, arg2, arg3]))),
-        Opcode::CallN { fun, args, .. } => Some((Call::Direct, *fun, build_ctx!(i; args))),
-        Opcode::CallClosure { fun, args, .. } => f
-            .find_last_closure_assign(code, *fun, i)
-            .map(|f| (Call::Closure, f, build_ctx!(i; args)))
-            .or_else(|| {
-                reg_ctx
-                    .get(&(fun.0 as usize))
-                    .map(|param| (Call::Closure, *param, build_ctx!(i; args)))
-            }),
-        Opcode::CallMethod { field, args, .. } => code[f[args[0]]]
-            .get_type_obj()
-            .map(|o| (Call::Direct, o.protos[field.0].findex, build_ctx!(i; args))),
-        Opcode::CallThis { field, args, .. } => code[f.regs[0]]
-            .get_type_obj()
-            .map(|o| (Call::Direct, o.protos[field.0].findex, build_ctx!(i; args))),
-        _ => None,
+    f.ops.iter().enumerate().flat_map(move |(i, o)| {
+        let (targets, ctx) = match o {
+            Opcode::Call0 { fun, .. } => (vec![(Call::Direct, *fun)], RegCtx::new()),
+            Opcode::Call1 { fun, arg0, .. } => (vec![(Call::Direct, *fun)], build_ctx!(i; [arg0])),
+            Opcode::Call2 {
+                fun, arg0, arg1, ..
+            } => (vec![(Call::Direct, *fun)], build_ctx!(i; [arg0, arg1])),
+            Opcode::Call3 {
+                fun,
+                arg0,
+                arg1,
+                arg2,
+                ..
+            } => (
+                vec![(Call::Direct, *fun)],
+                build_ctx!(i; [arg0, arg1, arg2]),
+            ),
+            Opcode::Call4 {
+                fun,
+                arg0,
+                arg1,
+                arg2,
+                arg3,
+                ..
+            } => (
+                vec![(Call::Direct, *fun)],
+                build_ctx!(i; [arg0, arg1, arg2, arg3]),
+            ),
+            Opcode::CallN { fun, args, .. } => (vec![(Call::Direct, *fun)], build_ctx!(i; args)),
+            Opcode::CallClosure { fun, args, .. } => (
+                match closure_in(code, resolver, f, &flow, reg_ctx, *fun, i) {
+                    Some(target) => vec![(Call::Closure, target)],
+                    None => resolver.dynamic_closure(code, f, &flow, *fun, i),
+                },
+                build_ctx!(i; args),
+            ),
+            Opcode::CallMethod { field, args, .. } => (
+                args.first()
+                    .and_then(|&r| f.try_regtype(r).ok())
+                    .map(|t| resolver.method_call(code, t, field.0))
+                    .unwrap_or_default(),
+                build_ctx!(i; args),
+            ),
+            Opcode::CallThis { field, args, .. } => (
+                f.try_regtype(Reg(0))
+                    .map(|t| resolver.method_call(code, t, field.0))
+                    .unwrap_or_default(),
+                build_ctx!(i; args),
+            ),
+            _ => (Vec::new(), RegCtx::new()),
+        };
+        targets
+            .into_iter()
+            .map(move |(call, fun)| (call, fun, ctx.clone()))
     })
 }
 
+/// Function held by a register before an instruction, following moves between registers.
+/// Values from the function entry are looked up in the arguments context.
+fn closure_in(
+    code: &Bytecode,
+    resolver: &CallResolver,
+    f: &Function,
+    flow: &Dataflow,
+    reg_ctx: &RegCtx,
+    reg: Reg,
+    pos: usize,
+) -> Option<RefFun> {
+    let (mut reg, mut pos) = (reg, pos);
+    // Limit the number of moves to follow in case of cycles
+    for _ in 0..16 {
+        match flow.reaching_defs(pos, reg)[..] {
+            [Def::Entry] => return reg_ctx.get(&(reg.0 as usize)).copied(),
+            [Def::Op(def)] => match f.ops[def] {
+                Opcode::StaticClosure { fun, .. } | Opcode::InstanceClosure { fun, .. } => {
+                    return Some(fun)
+                }
+                // Only known if the method is not overridden
+                Opcode::VirtualClosure { obj, field, .. } => {
+                    let t = f.try_regtype(obj).ok()?;
+                    let pindex = field.0 as usize;
+                    return if resolver.hierarchy.overrides(code, t, pindex).is_empty() {
+                        t.find_method(pindex, code).map(|p| p.findex)
+                    } else {
+                        None
+                    };
+                }
+                Opcode::Field { obj, field, .. } => {
+                    return f
+                        .try_regtype(obj)
+                        .ok()?
+                        .as_obj(code)
+                        .and_then(|o| o.bindings.get(&field).copied())
+                }
+                Opcode::Mov { src, .. } => {
+                    reg = src;
+                    pos = def;
+                }
+                _ => return None,
+            },
+            // Either unreachable or multiple possible values
+            _ => return None,
+        }
+    }
+    None
+}
+
+/// Add a call, keeping the most certain kind if the edge already exists
+fn add_call(g: &mut Callgraph, from: RefFun, to: RefFun, call: Call) {
+    match g.edge_weight_mut(from, to) {
+        Some(prev) if prev.rank() <= call.rank() => {}
+        Some(prev) => *prev = call,
+        None => {
+            g.add_edge(from, to, call);
+        }
+    }
+}
+
 pub fn call_graph(code: &Bytecode, f: RefFun, max_depth: usize) -> Callgraph {
+    let resolver = CallResolver::new(code);
     let mut g = Callgraph::new();
-    match code.resolve(f) {
-        FunPtr::Fun(f) => {
+    match code.try_resolve(f) {
+        Ok(FunPtr::Fun(f)) => {
             g.add_node(f.findex);
-            build_graph_rec(code, &mut g, f, &RegCtx::new(), max_depth);
+            build_graph_rec(code, &resolver, &mut g, f, &RegCtx::new(), max_depth);
         }
-        FunPtr::Native(n) => {
+        Ok(FunPtr::Native(n)) => {
             g.add_node(n.findex);
         }
+        Err(_) => {}
     }
     g
 }
 
-fn build_graph_rec(code: &Bytecode, g: &mut Callgraph, f: &Function, ctx: &RegCtx, depth: usize) {
+fn build_graph_rec(
+    code: &Bytecode,
+    resolver: &CallResolver,
+    g: &mut Callgraph,
+    f: &Function,
+    ctx: &RegCtx,
+    depth: usize,
+) {
     if depth == 0 {
         return;
     }
-    for (call, fun, ctx) in find_calls(code, f, ctx) {
+    for (call, fun, ctx) in find_calls(code, resolver, f, ctx) {
         if fun.is_from_std(code) {
-            match code.resolve(fun) {
-                FunPtr::Fun(fun) => {
+            match code.try_resolve(fun) {
+                Ok(FunPtr::Fun(fun)) => {
                     if !g.contains_node(fun.findex) {
                         g.add_node(fun.findex);
                         //println!("call to {} with args: {:?}", fun.display_header(code), ctx);
-                        build_graph_rec(code, g, fun, &ctx, depth - 1);
+                        build_graph_rec(code, resolver, g, fun, &ctx, depth - 1);
                     }
-                    g.add_edge(f.findex, fun.findex, call);
+                    add_call(g, f.findex, fun.findex, call);
                 }
-                FunPtr::Native(n) => {
+                Ok(FunPtr::Native(n)) => {
                     if !g.contains_node(n.findex) {
                         g.add_node(n.findex);
                     }
-                    g.add_edge(f.findex, n.findex, call);
+                    add_call(g, f.findex, n.findex, call);
                 }
+                // Invalid reference, nothing to see there
+                Err(_) => {}
             }
         }
     }
 }
 
+/// Callgraph of every function in the bytecode. Functions passed as arguments are not tracked
+/// across calls, calls to them appear as [Call::Dynamic].
+pub fn whole_program_graph(code: &Bytecode) -> Callgraph {
+    let resolver = CallResolver::new(code);
+    let ctx = RegCtx::new();
+    let mut g = Callgraph::new();
+    for f in &code.functions {
+        g.add_node(f.findex);
+        for (call, fun, _) in find_calls(code, &resolver, f, &ctx) {
+            if code.try_resolve(fun).is_ok() {
+                add_call(&mut g, f.findex, fun, call);
+            }
+        }
+    }
+    g
+}
+
+/// Functions calling a function in a callgraph
+pub fn callers(g: &Callgraph, f: RefFun) -> impl Iterator<Item = (RefFun, Call)> + '_ {
+    g.neighbors_directed(f, Direction::Incoming)
+        .filter_map(move |caller| Some((caller, *g.edge_weight(caller, f)?)))
+}
+
+/// Functions called by a function in a callgraph
+pub fn callees(g: &Callgraph, f: RefFun) -> impl Iterator<Item = (RefFun, Call)> + '_ {
+    g.neighbors_directed(f, Direction::Outgoing)
+        .filter_map(move |callee| Some((callee, *g.edge_weight(f, callee)?)))
+}
+
 static TYPE: [&str; 2] = ["graph", "digraph"];
 static EDGE: [&str; 2] = ["--", "->"];
 static INDENT: &str = "    ";
@@ -153,10 +405,10 @@ impl Display for GraphDisplay<'_> {
         for node in self.g.node_references() {
             writeln!(
                 f,
-                "{}{} [ label = \"{:?}\" fontsize=18 shape=box color=\"#b20400\" fillcolor=\"#edd6d5\" ]",
+                "{}{} [ label = \"{}\" fontsize=18 shape=box color=\"#b20400\" fillcolor=\"#edd6d5\" ]",
                 INDENT,
                 self.g.to_index(node.id()),
-                self.code.resolve(*node.weight())
+                node.weight().display::<EnhancedFmt>(self.code)
             )?;
         }
         // output all edges
@@ -171,6 +423,8 @@ impl Display for GraphDisplay<'_> {
                 match edge.weight() {
                     Call::Direct => "",
                     Call::Closure => "closure",
+                    Call::Virtual => "virtual",
+                    Call::Dynamic => "dynamic",
                 }
             )?;
         }
@@ -184,3 +438,95 @@ impl Display for GraphDisplay<'_> {
 pub fn display_graph<'a>(g: &'a Callgraph, code: &'a Bytecode) -> GraphDisplay<'a> {
     GraphDisplay { g, code }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::analysis::graph::{callees, callers, whole_program_graph, Call};
+    use crate::builder::BytecodeBuilder;
+    use crate::types::{ObjProto, RefFun, RefType, Reg, Type};
+    use crate::Opcode;
+
+    fn add_method(builder: &mut BytecodeBuilder, t: RefType, name: &str, findex: RefFun) {
+        let name = builder.string(name);
+        builder.class_mut(t).unwrap().protos.push(ObjProto {
+            name,
+            findex,
+            pindex: 0,
+        });
+    }
+
+    #[test]
+    fn virtual_and_dynamic_calls() {
+        let mut builder = BytecodeBuilder::new();
+        let void = builder.intern_type(Type::Void);
+        let base = builder.add_class("Base", None, vec![], vec![]);
+        let child = builder.add_class("Child", Some(base), vec![], vec![]);
+        let method_t = builder.fun_type(vec![base], void);
+        let closure_t = builder.fun_type(vec![], void);
+        let ret = vec![Opcode::Ret { ret: Reg(0) }];
+        let base_m = builder.add_function(method_t, vec![base, void], ret.clone());
+        let child_m = builder.add_function(method_t, vec![child, void], ret.clone());
+        add_method(&mut builder, base, "m", base_m);
+        add_method(&mut builder, child, "m", child_m);
+        let lambda = builder.add_function(closure_t, vec![void], ret);
+        // Closure received as an argument
+        let apply_t = builder.fun_type(vec![closure_t], void);
+        let apply = builder.add_function(
+            apply_t,
+            vec![closure_t, void],
+            vec![
+                Opcode::CallClosure {
+                    dst: Reg(1),
+                    fun: Reg(0),
+                    args: vec![],
+                },
+                Opcode::Ret { ret: Reg(1) },
+            ],
+        );
+        let main = builder.add_function(
+            closure_t,
+            vec![void, base, closure_t],
+            vec![
+                Opcode::New { dst: Reg(1) },
+                Opcode::CallMethod {
+                    dst: Reg(0),
+                    field: Default::default(),
+                    args: vec![Reg(1)],
+                },
+                Opcode::StaticClosure {
+                    dst: Reg(2),
+                    fun: lambda,
+                },
+                Opcode::Call1 {
+                    dst: Reg(0),
+                    fun: apply,
+                    arg0: Reg(2),
+                },
+                Opcode::Ret { ret: Reg(0) },
+            ],
+        );
+        builder.set_entrypoint(main);
+        let code = builder.build();
+        let g = whole_program_graph(&code);
+
+        let mut calls: Vec<_> = callees(&g, main).collect();
+        calls.sort_unstable_by_key(|&(f, _)| f);
+        assert_eq!(
+            calls,
+            [
+                (base_m, Call::Direct),
+                (child_m, Call::Virtual),
+                (apply, Call::Direct)
+            ]
+        );
+        assert_eq!(
+            callees(&g, apply).collect::<Vec<_>>(),
+            [(lambda, Call::Dynamic)]
+        );
+        assert_eq!(
+            callers(&g, child_m).collect::<Vec<_>>(),
+            [(main, Call::Virtual)]
+        );
+        assert_eq!(callers(&g, main).count(), 0);
+    }
+}
@@ -0,0 +1,251 @@
+//! Class hierarchy, method overrides and virtual tables.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::types::{RefFun, RefString, RefType};
+use crate::{Bytecode, Resolve, Type};
+
+/// A slot of the virtual table of a class
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct VtableEntry {
+    /// Method index, see [crate::types::ObjProto::pindex]
+    pub pindex: usize,
+    pub name: RefString,
+    /// Function called for this class
+    pub findex: RefFun,
+    /// Class declaring the method, the class itself or one of its parents
+    pub owner: RefType,
+}
+
+/// Tree of the classes ([Type::Obj] and [Type::Struct]) of a bytecode
+#[derive(Debug, Clone, Default)]
+pub struct Hierarchy {
+    roots: Vec<RefType>,
+    children: HashMap<RefType, Vec<RefType>>,
+    /// Method of a parent class replaced by a method
+    overridden: HashMap<RefFun, RefFun>,
+}
+
+impl Hierarchy {
+    pub fn new(code: &Bytecode) -> Self {
+        let mut h = Self::default();
+        for (i, t) in code.types.iter().enumerate() {
+            if let Some(obj) = t.get_type_obj() {
+                match obj.super_ {
+                    Some(super_) => h.children.entry(super_).or_default().push(RefType(i)),
+                    None => h.roots.push(RefType(i)),
+                }
+            }
+        }
+        for obj in code.types.iter().filter_map(Type::get_type_obj) {
+            if let Some(super_) = obj.super_ {
+                for p in obj.protos.iter().filter(|p| p.pindex >= 0) {
+                    if let Some(parent) = super_.find_method(p.pindex as usize, code) {
+                        if parent.findex != p.findex {
+                            h.overridden.insert(p.findex, parent.findex);
+                        }
+                    }
+                }
+            }
+        }
+        h
+    }
+
+    /// Classes without a parent
+    pub fn roots(&self) -> &[RefType] {
+        &self.roots
+    }
+
+    /// Direct subclasses of a class
+    pub fn children(&self, t: RefType) -> &[RefType] {
+        self.children.get(&t).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    /// Every subclass of a class, in depth first order
+    pub fn descendants(&self, t: RefType) -> Vec<RefType> {
+        let mut res = Vec::new();
+        let mut seen = HashSet::new();
+        let mut stack: Vec<_> = self.children(t).iter().rev().copied().collect();
+        while let Some(t) = stack.pop() {
+            if seen.insert(t) {
+                res.push(t);
+                stack.extend(self.children(t).iter().rev());
+            }
+        }
+        res
+    }
+
+    /// Parents of a class, starting with the direct parent
+    pub fn ancestors(&self, code: &Bytecode, t: RefType) -> Vec<RefType> {
+        let mut res = Vec::new();
+        let mut t = t.as_obj(code).and_then(|obj| obj.super_);
+        // Bounded in case of a malformed hierarchy
+        while let Some(parent) = t {
+            if res.len() >= code.types.len() {
+                break;
+            }
+            res.push(parent);
+            t = parent.as_obj(code).and_then(|obj| obj.super_);
+        }
+        res
+    }
+
+    /// Method of a parent class replaced by a method, if any
+    pub fn overridden(&self, fun: RefFun) -> Option<RefFun> {
+        self.overridden.get(&fun).copied()
+    }
+
+    /// Methods overriding a method index in the subclasses of a class
+    pub fn overrides(&self, code: &Bytecode, t: RefType, pindex: usize) -> Vec<RefFun> {
+        self.descendants(t)
+            .into_iter()
+            .filter_map(|t| t.as_obj(code))
+            .flat_map(|obj| &obj.protos)
+            .filter(|p| p.pindex >= 0 && p.pindex as usize == pindex)
+            .map(|p| p.findex)
+            .collect()
+    }
+
+    /// Virtual table of a class, ordered by method index.
+    /// Methods of the parents are replaced by the overrides.
+    pub fn vtable(&self, code: &Bytecode, t: RefType) -> Vec<VtableEntry> {
+        let mut entries = HashMap::new();
+        for owner in self
+            .ancestors(code, t)
+            .into_iter()
+            .rev()
+            .chain(std::iter::once(t))
+        {
+            for p in owner
+                .as_obj(code)
+                .into_iter()
+                .flat_map(|obj| &obj.protos)
+                .filter(|p| p.pindex >= 0)
+            {
+                entries.insert(
+                    p.pindex as usize,
+                    VtableEntry {
+                        pindex: p.pindex as usize,
+                        name: p.name,
+                        findex: p.findex,
+                        owner,
+                    },
+                );
+            }
+        }
+        let mut vtable: Vec<_> = entries.into_values().collect();
+        vtable.sort_unstable_by_key(|e| e.pindex);
+        vtable
+    }
+
+    /// Whether a class has a field or a method for every field of a virtual type.
+    /// Objects of this class can be converted to the virtual without dynamic lookups failing.
+    pub fn implements(&self, code: &Bytecode, class: RefType, virtual_: RefType) -> bool {
+        let (obj, fields) = match (class.as_obj(code), code.try_resolve(virtual_)) {
+            (Some(obj), Ok(Type::Virtual { fields })) => (obj, fields),
+            _ => return false,
+        };
+        let mut names: HashSet<RefString> = obj.fields.iter().map(|f| f.name).collect();
+        for t in std::iter::once(class).chain(self.ancestors(code, class)) {
+            if let Some(obj) = t.as_obj(code) {
+                names.extend(obj.protos.iter().map(|p| p.name));
+            }
+        }
+        !fields.is_empty() && fields.iter().all(|f| names.contains(&f.name))
+    }
+
+    /// Classes implementing a virtual type, see [Hierarchy::implements]
+    pub fn implementors(&self, code: &Bytecode, virtual_: RefType) -> Vec<RefType> {
+        code.types
+            .iter()
+            .enumerate()
+            .filter(|(_, t)| t.get_type_obj().is_some())
+            .map(|(i, _)| RefType(i))
+            .filter(|&t| self.implements(code, t, virtual_))
+            .collect()
+    }
+
+    /// Virtual types implemented by a class, see [Hierarchy::implements]
+    pub fn interfaces(&self, code: &Bytecode, class: RefType) -> Vec<RefType> {
+        code.types
+            .iter()
+            .enumerate()
+            .filter(|(_, t)| matches!(t, Type::Virtual { .. }))
+            .map(|(i, _)| RefType(i))
+            .filter(|&v| self.implements(code, class, v))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::analysis::hierarchy::{Hierarchy, VtableEntry};
+    use crate::builder::BytecodeBuilder;
+    use crate::types::{ObjField, ObjProto, RefType, Reg, Type};
+    use crate::Opcode;
+
+    #[test]
+    fn classes_and_overrides() {
+        let mut builder = BytecodeBuilder::new();
+        let void = builder.intern_type(Type::Void);
+        let i32 = builder.intern_type(Type::I32);
+        let t = builder.fun_type(vec![], void);
+        let ret = vec![Opcode::Ret { ret: Reg(0) }];
+        let base_a = builder.add_function(t, vec![void], ret.clone());
+        let base_b = builder.add_function(t, vec![void], ret.clone());
+        let child_b = builder.add_function(t, vec![void], ret.clone());
+        let child_c = builder.add_function(t, vec![void], ret);
+        let (a, b, c, x) = (
+            builder.string("a"),
+            builder.string("b"),
+            builder.string("c"),
+            builder.string("x"),
+        );
+        let proto = |name, findex, pindex| ObjProto {
+            name,
+            findex,
+            pindex,
+        };
+        let mut class = |name, super_, protos| {
+            builder.add_class(name, super_, vec![ObjField { name: x, t: i32 }], protos)
+        };
+        let base = class("Base", None, vec![proto(a, base_a, 0), proto(b, base_b, 1)]);
+        let child = class(
+            "Child",
+            Some(base),
+            vec![proto(b, child_b, 1), proto(c, child_c, 2)],
+        );
+        let leaf = class("Leaf", Some(child), vec![]);
+        let virtual_ = builder.add_type(Type::Virtual {
+            fields: vec![ObjField { name: c, t }, ObjField { name: x, t: i32 }],
+        });
+        let code = builder.build();
+        let h = Hierarchy::new(&code);
+
+        assert_eq!(h.roots(), [base]);
+        assert_eq!(h.children(base), [child]);
+        assert_eq!(h.descendants(base), [child, leaf]);
+        assert_eq!(h.ancestors(&code, leaf), [child, base]);
+        assert_eq!(h.overridden(child_b), Some(base_b));
+        assert_eq!(h.overridden(base_b), None);
+        assert_eq!(h.overrides(&code, base, 1), [child_b]);
+
+        let entry = |pindex, name, findex, owner| VtableEntry {
+            pindex,
+            name,
+            findex,
+            owner,
+        };
+        assert_eq!(
+            h.vtable(&code, leaf),
+            [
+                entry(0, a, base_a, base),
+                entry(1, b, child_b, child),
+                entry(2, c, child_c, child)
+            ]
+        );
+
+        assert_eq!(h.implementors(&code, virtual_), [child, leaf]);
+        assert_eq!(h.interfaces(&code, base), Vec::<RefType>::new());
+    }
+}
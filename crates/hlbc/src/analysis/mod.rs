@@ -1,10 +1,16 @@
 use std::iter::repeat;
 
-use crate::types::{FunPtr, Reg};
+use crate::types::{FunPtr, ObjProto, Reg};
 use crate::{Bytecode, Function, Native, Opcode, RefFun, RefType, Resolve, Type, TypeObj};
 
+pub mod cfg;
+pub mod dataflow;
 #[cfg(feature = "graph")]
 pub mod graph;
+pub mod hierarchy;
+pub mod reachability;
+pub mod stats;
+pub mod xref;
 
 impl Bytecode {
     /// Iterate on every instruction of every function
@@ -17,23 +23,23 @@ impl Bytecode {
 
 impl RefFun {
     pub fn is_from_std(&self, code: &Bytecode) -> bool {
-        match code.resolve(*self) {
-            FunPtr::Fun(fun) => fun.is_from_std(code),
-            FunPtr::Native(n) => n.is_from_std(code),
+        match code.try_resolve(*self) {
+            Ok(FunPtr::Fun(fun)) => fun.is_from_std(code),
+            Ok(FunPtr::Native(n)) => n.is_from_std(code),
+            Err(_) => false,
         }
     }
 }
 
 impl Function {
     pub fn is_from_std(&self, code: &Bytecode) -> bool {
-        if let Some(debug_info) = &self.debug_info {
-            // We look at the Ret opcode which is probably not from inlined code.
-            let (file, _) = debug_info[self.ops.len() - 1];
-            let filename = &code.debug_files.as_ref().unwrap()[file];
-            filename.contains("std")
-        } else {
-            false
-        }
+        // We look at the Ret opcode which is probably not from inlined code.
+        self.debug_info
+            .as_ref()
+            .and_then(|debug_info| debug_info.last())
+            .and_then(|&(file, _)| code.debug_files.as_ref()?.get(file))
+            .map(|filename| filename.contains("std"))
+            .unwrap_or(false)
     }
 
     /// Find any outbound references to other functions in a function
@@ -52,6 +58,10 @@ impl Function {
     }
 
     /// Starting from a position in a function, finds the last time a register has been assigned a function
+    #[deprecated(
+        since = "0.7.0",
+        note = "this scans instructions backwards regardless of branches, use `Dataflow::last_def`"
+    )]
     pub fn find_last_closure_assign(
         &self,
         code: &Bytecode,
@@ -66,7 +76,8 @@ impl Function {
                 Opcode::StaticClosure { dst, fun } if dst == reg => Some(fun),
                 Opcode::InstanceClosure { dst, fun, .. } if dst == reg => Some(fun),
                 Opcode::Field { dst, obj, field } if dst == reg => self
-                    .regtype(obj)
+                    .try_regtype(obj)
+                    .ok()?
                     .as_obj(code)
                     .and_then(|o| o.bindings.get(&field).copied()),
                 _ => None,
@@ -76,20 +87,41 @@ impl Function {
 
 impl Native {
     pub fn is_from_std(&self, code: &Bytecode) -> bool {
-        code[self.lib] == "std"
+        code.strings
+            .get(self.lib.0)
+            .map_or(false, |lib| lib == "std")
     }
 }
 
 impl RefType {
     pub fn is_from_std(&self, code: &Bytecode) -> bool {
-        code[*self].is_from_std(code)
+        code.try_resolve(*self)
+            .map_or(false, |ty| ty.is_from_std(code))
+    }
+
+    /// Method bound to a method index (see [ObjProto::pindex]) for this class, looking up the parents.
+    /// This is what [Opcode::CallMethod] and [Opcode::CallThis] call when the object is of this exact class.
+    pub fn find_method<'a>(&self, pindex: usize, code: &'a Bytecode) -> Option<&'a ObjProto> {
+        let mut t = Some(*self);
+        // Bounded in case of a malformed hierarchy
+        for _ in 0..code.types.len() {
+            let obj = t?.as_obj(code)?;
+            if let Some(p) = obj.protos.iter().find(|p| p.pindex as usize == pindex) {
+                return Some(p);
+            }
+            t = obj.super_;
+        }
+        None
     }
 }
 
 impl Type {
+    /// Classes and enums are looked up, every other type is builtin.
+    /// Enums have no functions, so only their name tells if they are from the std (e.g. `haxe.ds.Option`).
     pub fn is_from_std(&self, code: &Bytecode) -> bool {
         match self {
-            Type::Obj(obj) => obj.is_from_std(code),
+            Type::Obj(obj) | Type::Struct(obj) => obj.is_from_std(code),
+            Type::Enum { name, .. } => code.strings.get(name.0).map_or(false, |n| is_std_name(n)),
             _ => true,
         }
     }
@@ -102,12 +134,19 @@ impl TypeObj {
         } else if let Some(&fun) = self.bindings.values().next() {
             fun.is_from_std(code)
         } else {
-            let name = &code[self.name];
-            name.starts_with("hl")
-                || name.starts_with("haxe")
-                || name == "Std"
-                || name == "Sys"
-                || name == "Type"
+            code.strings
+                .get(self.name.0)
+                .map_or(false, |name| is_std_name(name))
         }
     }
 }
+
+/// Guess if a type is from the standard library from its name alone
+fn is_std_name(name: &str) -> bool {
+    name.starts_with("hl")
+        || name.starts_with("haxe")
+        || name == "Std"
+        || name == "Sys"
+        || name == "Type"
+        || name == "ValueType"
+}
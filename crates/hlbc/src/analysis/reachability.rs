@@ -0,0 +1,289 @@
+//! Find the functions, types and globals that can be used by the program and the dead code.
+//!
+//! This is an over-approximation : an element referenced by reachable code is reachable even if it is never
+//! used at runtime. Elements only accessed by reflection with a name (e.g. `Type.resolveClass`) are missed.
+
+use std::collections::HashSet;
+
+use crate::analysis::cfg::Cfg;
+use crate::opcodes::Operand;
+use crate::types::{ConstantField, FunPtr, Function, RefFun, RefGlobal, RefType};
+use crate::{Bytecode, Resolve, Type};
+
+/// Elements reachable from the entrypoint, the global initializers and the methods of reachable classes
+#[derive(Debug, Clone, Default)]
+pub struct Reachability {
+    functions: HashSet<RefFun>,
+    types: HashSet<RefType>,
+    globals: HashSet<RefGlobal>,
+}
+
+#[derive(Default)]
+struct Worklist {
+    reach: Reachability,
+    functions: Vec<RefFun>,
+    types: Vec<RefType>,
+}
+
+impl Worklist {
+    fn fun(&mut self, f: RefFun) {
+        if self.reach.functions.insert(f) {
+            self.functions.push(f);
+        }
+    }
+
+    fn ty(&mut self, t: RefType) {
+        if self.reach.types.insert(t) {
+            self.types.push(t);
+        }
+    }
+
+    fn global(&mut self, code: &Bytecode, g: RefGlobal) {
+        if self.reach.globals.insert(g) {
+            if let Some(&t) = code.globals.get(g.0) {
+                self.ty(t);
+            }
+        }
+    }
+
+    fn visit_fun(&mut self, code: &Bytecode, f: RefFun) {
+        match code.try_resolve(f) {
+            Ok(FunPtr::Fun(f)) => {
+                self.ty(f.t);
+                for &t in &f.regs {
+                    self.ty(t);
+                }
+                for o in &f.ops {
+                    for operand in o.operands() {
+                        match operand {
+                            Operand::Fun(f) => self.fun(f),
+                            Operand::Type(t) => self.ty(t),
+                            Operand::Global(g) => self.global(code, g),
+                            _ => {}
+                        }
+                    }
+                }
+            }
+            Ok(FunPtr::Native(n)) => self.ty(n.t),
+            Err(_) => {}
+        }
+    }
+
+    fn visit_type(&mut self, code: &Bytecode, t: RefType) {
+        match code.types.get(t.0) {
+            Some(Type::Fun(fun) | Type::Method(fun)) => {
+                for &arg in &fun.args {
+                    self.ty(arg);
+                }
+                self.ty(fun.ret);
+            }
+            Some(Type::Obj(obj) | Type::Struct(obj)) => {
+                if let Some(super_) = obj.super_ {
+                    self.ty(super_);
+                }
+                for f in &obj.own_fields {
+                    self.ty(f.t);
+                }
+                for p in &obj.protos {
+                    self.fun(p.findex);
+                }
+                for &f in obj.bindings.values() {
+                    self.fun(f);
+                }
+                // The class global, holding the static fields, is 1-indexed
+                if obj.global.0 >= 1 {
+                    self.global(code, RefGlobal(obj.global.0 - 1));
+                }
+            }
+            Some(Type::Ref(inner) | Type::Null(inner) | Type::Packed(inner)) => self.ty(*inner),
+            Some(Type::Virtual { fields }) => {
+                for f in fields {
+                    self.ty(f.t);
+                }
+            }
+            Some(Type::Enum {
+                global, constructs, ..
+            }) => {
+                for c in constructs {
+                    for &p in &c.params {
+                        self.ty(p);
+                    }
+                }
+                if global.0 >= 1 {
+                    self.global(code, RefGlobal(global.0 - 1));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Reachability {
+    pub fn new(code: &Bytecode) -> Self {
+        let mut work = Worklist::default();
+        work.fun(code.entrypoint);
+        for c in code.constants.iter().flatten() {
+            work.global(code, c.global);
+            for (&value, kind) in c.fields.iter().zip(c.field_kinds(code)) {
+                match kind {
+                    ConstantField::Type => work.ty(RefType(value)),
+                    ConstantField::Global => work.global(code, RefGlobal(value)),
+                    _ => {}
+                }
+            }
+        }
+        loop {
+            if let Some(f) = work.functions.pop() {
+                work.visit_fun(code, f);
+            } else if let Some(t) = work.types.pop() {
+                work.visit_type(code, t);
+            } else {
+                break;
+            }
+        }
+        work.reach
+    }
+
+    pub fn is_fun_reachable(&self, f: RefFun) -> bool {
+        self.functions.contains(&f)
+    }
+
+    pub fn is_type_reachable(&self, t: RefType) -> bool {
+        self.types.contains(&t)
+    }
+
+    pub fn is_global_reachable(&self, g: RefGlobal) -> bool {
+        self.globals.contains(&g)
+    }
+
+    /// Functions and natives never referenced by reachable code, ordered by findex
+    pub fn unreachable_functions(&self, code: &Bytecode) -> Vec<RefFun> {
+        let mut res: Vec<_> = code
+            .functions()
+            .map(|f| f.findex())
+            .filter(|&f| !self.is_fun_reachable(f))
+            .collect();
+        res.sort_unstable();
+        res
+    }
+
+    pub fn unreachable_types(&self, code: &Bytecode) -> Vec<RefType> {
+        (0..code.types.len())
+            .map(RefType)
+            .filter(|&t| !self.is_type_reachable(t))
+            .collect()
+    }
+
+    pub fn unreachable_globals(&self, code: &Bytecode) -> Vec<RefGlobal> {
+        (0..code.globals.len())
+            .map(RefGlobal)
+            .filter(|&g| !self.is_global_reachable(g))
+            .collect()
+    }
+}
+
+/// Instructions that can never be executed, because no path from the function entry leads to them
+/// (e.g. after a [crate::Opcode::Ret], [crate::Opcode::Throw] or [crate::Opcode::JAlways]).
+pub fn unreachable_ops(f: &Function) -> Vec<usize> {
+    let cfg = Cfg::new(f);
+    let mut reachable = vec![false; cfg.blocks.len()];
+    for b in cfg.reverse_postorder() {
+        reachable[b] = true;
+    }
+    cfg.blocks
+        .iter()
+        .zip(reachable)
+        .filter(|(_, reachable)| !reachable)
+        .flat_map(|(b, _)| b.ops())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::analysis::reachability::{unreachable_ops, Reachability};
+    use crate::builder::BytecodeBuilder;
+    use crate::types::{ObjField, ObjProto, Reg, Type};
+    use crate::Opcode;
+
+    #[test]
+    fn reachable_elements() {
+        let mut builder = BytecodeBuilder::new();
+        let void = builder.intern_type(Type::Void);
+        let i32 = builder.intern_type(Type::I32);
+        let t = builder.fun_type(vec![], void);
+        let ret = vec![Opcode::Ret { ret: Reg(0) }];
+        let method = builder.add_function(t, vec![void], ret.clone());
+        let dead = builder.add_function(t, vec![void], ret.clone());
+        let dead_method = builder.add_function(t, vec![void], ret.clone());
+        let native = builder.add_native("std", "print", t);
+        let name = builder.string("Used");
+        let used = builder.add_class(
+            "Used",
+            None,
+            vec![ObjField { name, t: i32 }],
+            vec![ObjProto {
+                name,
+                findex: method,
+                pindex: 0,
+            }],
+        );
+        let name = builder.string("Unused");
+        let unused = builder.add_class(
+            "Unused",
+            None,
+            vec![],
+            vec![ObjProto {
+                name,
+                findex: dead_method,
+                pindex: 0,
+            }],
+        );
+        let init = builder.add_global(i32);
+        let unused_global = builder.add_global(i32);
+        builder.add_constant(init, vec![]);
+        let main = builder.add_function(
+            t,
+            vec![void, used],
+            vec![
+                Opcode::New { dst: Reg(1) },
+                Opcode::Call0 {
+                    dst: Reg(0),
+                    fun: native,
+                },
+                Opcode::Ret { ret: Reg(0) },
+            ],
+        );
+        builder.set_entrypoint(main);
+        let code = builder.build();
+        let reach = Reachability::new(&code);
+
+        assert!(reach.is_fun_reachable(main));
+        assert!(reach.is_fun_reachable(native));
+        assert!(reach.is_fun_reachable(method));
+        assert_eq!(reach.unreachable_functions(&code), [dead, dead_method]);
+        assert!(reach.is_type_reachable(used));
+        assert!(reach.is_type_reachable(i32));
+        assert_eq!(reach.unreachable_types(&code), [unused]);
+        assert!(reach.is_global_reachable(init));
+        assert_eq!(reach.unreachable_globals(&code), [unused_global]);
+    }
+
+    #[test]
+    fn dead_ops() {
+        let mut builder = BytecodeBuilder::new();
+        let void = builder.intern_type(Type::Void);
+        let t = builder.fun_type(vec![], void);
+        let f = builder.add_function(
+            t,
+            vec![void],
+            vec![
+                Opcode::JAlways { offset: 1 },
+                Opcode::Ret { ret: Reg(0) },
+                Opcode::Ret { ret: Reg(0) },
+                Opcode::Throw { exc: Reg(0) },
+            ],
+        );
+        let code = builder.build();
+        assert_eq!(unreachable_ops(f.as_fn(&code).unwrap()), [1, 3]);
+    }
+}
@@ -0,0 +1,297 @@
+//! Statistics about the code of a bytecode, to find the biggest parts of a program.
+
+use std::collections::HashMap;
+
+use crate::diff::function_name;
+use crate::opcodes::Operand;
+use crate::types::{FunPtr, RefFun};
+use crate::{Bytecode, Resolve, Str, Type};
+
+/// Number of occurrences of something
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Count {
+    pub name: &'static str,
+    pub count: usize,
+}
+
+/// Summary of a list of values
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Distribution {
+    pub min: usize,
+    pub max: usize,
+    pub mean: f64,
+    pub median: usize,
+    pub total: usize,
+}
+
+impl Distribution {
+    pub fn new(values: impl Iterator<Item = usize>) -> Self {
+        let mut values: Vec<usize> = values.collect();
+        if values.is_empty() {
+            return Self::default();
+        }
+        values.sort_unstable();
+        let total = values.iter().sum();
+        Self {
+            min: values[0],
+            max: values[values.len() - 1],
+            mean: total as f64 / values.len() as f64,
+            median: values[values.len() / 2],
+            total,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct FunctionStats {
+    pub findex: RefFun,
+    /// `Class.method`, or the position of a closure if there is debug information
+    pub name: String,
+    pub ops: usize,
+    pub regs: usize,
+    /// Debug file of the function
+    pub file: Option<Str>,
+}
+
+/// Code coming from a source file, according to the debug information
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct FileStats {
+    pub file: Str,
+    /// Functions defined in this file
+    pub functions: usize,
+    /// Instructions generated from this file, including the ones inlined in other functions
+    pub ops: usize,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct NativeLibStats {
+    pub lib: Str,
+    /// Natives of this library
+    pub natives: usize,
+    /// Instructions referencing a native of this library
+    pub calls: usize,
+}
+
+/// Whole program statistics
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Stats {
+    /// Instructions by opcode, most used first
+    pub opcodes: Vec<Count>,
+    /// Every function, in order of findex
+    pub functions: Vec<FunctionStats>,
+    /// Instructions per function
+    pub ops: Distribution,
+    /// Registers per function
+    pub regs: Distribution,
+    /// Source files, biggest first
+    pub files: Vec<FileStats>,
+    /// Native libraries, most called first
+    pub natives: Vec<NativeLibStats>,
+    /// Types by category, most common first
+    pub types: Vec<Count>,
+}
+
+impl Stats {
+    pub fn new(code: &Bytecode) -> Self {
+        let mut opcodes = HashMap::new();
+        let mut calls: HashMap<Str, usize> = HashMap::new();
+        for (_, (_, o)) in code.ops() {
+            *opcodes.entry(o.name()).or_insert(0) += 1;
+            for operand in o.operands() {
+                if let Operand::Fun(f) = operand {
+                    if let Ok(FunPtr::Native(n)) = code.try_resolve(f) {
+                        *calls.entry(n.lib(code)).or_insert(0) += 1;
+                    }
+                }
+            }
+        }
+
+        let debug_file = |file: usize| {
+            code.debug_files
+                .as_ref()
+                .and_then(|files| files.get(file))
+                .cloned()
+        };
+        let mut files: HashMap<Str, FileStats> = HashMap::new();
+        fn file_stats(files: &mut HashMap<Str, FileStats>, file: Str) -> &mut FileStats {
+            files.entry(file.clone()).or_insert_with(|| FileStats {
+                file,
+                functions: 0,
+                ops: 0,
+            })
+        }
+        let mut functions = Vec::with_capacity(code.functions.len());
+        for f in &code.functions {
+            // The last instruction is probably not inlined, see Function::is_from_std
+            let file = f
+                .debug_info
+                .as_ref()
+                .and_then(|d| d.last())
+                .and_then(|&(file, _)| debug_file(file));
+            if let Some(file) = &file {
+                file_stats(&mut files, file.clone()).functions += 1;
+            }
+            for &(file, _) in f.debug_info.iter().flatten() {
+                if let Some(file) = debug_file(file) {
+                    file_stats(&mut files, file).ops += 1;
+                }
+            }
+            functions.push(FunctionStats {
+                findex: f.findex,
+                name: function_name(code, f).unwrap_or_else(|| format!("fn@{}", f.findex.0)),
+                ops: f.ops.len(),
+                regs: f.regs.len(),
+                file,
+            });
+        }
+        functions.sort_unstable_by_key(|f| f.findex);
+        let mut files: Vec<_> = files.into_values().collect();
+        files.sort_unstable_by(|a, b| b.ops.cmp(&a.ops).then_with(|| a.file.cmp(&b.file)));
+
+        let mut natives: HashMap<Str, NativeLibStats> = HashMap::new();
+        for n in &code.natives {
+            let lib = n.lib(code);
+            natives
+                .entry(lib.clone())
+                .or_insert_with(|| NativeLibStats {
+                    calls: calls.get(&lib).copied().unwrap_or(0),
+                    lib,
+                    natives: 0,
+                })
+                .natives += 1;
+        }
+        let mut natives: Vec<_> = natives.into_values().collect();
+        natives.sort_unstable_by(|a, b| b.calls.cmp(&a.calls).then_with(|| a.lib.cmp(&b.lib)));
+
+        let mut types = HashMap::new();
+        for t in &code.types {
+            *types.entry(type_category(t)).or_insert(0) += 1;
+        }
+
+        Self {
+            opcodes: sorted_counts(opcodes),
+            ops: Distribution::new(functions.iter().map(|f| f.ops)),
+            regs: Distribution::new(functions.iter().map(|f| f.regs)),
+            functions,
+            files,
+            natives,
+            types: sorted_counts(types),
+        }
+    }
+
+    /// The `n` functions with the most instructions
+    pub fn largest_functions(&self, n: usize) -> Vec<&FunctionStats> {
+        let mut functions: Vec<_> = self.functions.iter().collect();
+        functions.sort_by_key(|f| std::cmp::Reverse(f.ops));
+        functions.truncate(n);
+        functions
+    }
+}
+
+fn sorted_counts(counts: HashMap<&'static str, usize>) -> Vec<Count> {
+    let mut counts: Vec<_> = counts
+        .into_iter()
+        .map(|(name, count)| Count { name, count })
+        .collect();
+    counts.sort_unstable_by(|a, b| b.count.cmp(&a.count).then_with(|| a.name.cmp(b.name)));
+    counts
+}
+
+fn type_category(t: &Type) -> &'static str {
+    match t {
+        Type::Void => "void",
+        Type::UI8 | Type::UI16 | Type::I32 | Type::I64 | Type::F32 | Type::F64 | Type::Bool => {
+            "primitive"
+        }
+        Type::Bytes => "bytes",
+        Type::Dyn => "dynamic",
+        Type::Fun(_) => "function",
+        Type::Obj(_) => "class",
+        Type::Array => "array",
+        Type::Type => "type",
+        Type::Ref(_) => "ref",
+        Type::Virtual { .. } => "virtual",
+        Type::DynObj => "dynobj",
+        Type::Abstract { .. } => "abstract",
+        Type::Enum { .. } => "enum",
+        Type::Null(_) => "null",
+        Type::Method(_) => "method",
+        Type::Struct(_) => "struct",
+        Type::Packed(_) => "packed",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::analysis::stats::{Count, Distribution, Stats};
+    use crate::builder::BytecodeBuilder;
+    use crate::types::{Reg, Type};
+    use crate::Opcode;
+
+    #[test]
+    fn stats() {
+        let mut builder = BytecodeBuilder::new();
+        let void = builder.intern_type(Type::Void);
+        let i32 = builder.intern_type(Type::I32);
+        builder.intern_type(Type::F64);
+        let t = builder.fun_type(vec![], void);
+        let print = builder.add_native("std", "print", t);
+        builder.add_native("std", "log", t);
+        builder.add_native("ui", "draw", t);
+        let small = builder.add_function(t, vec![void], vec![Opcode::Ret { ret: Reg(0) }]);
+        let call = Opcode::Call0 {
+            dst: Reg(0),
+            fun: print,
+        };
+        let big = builder.add_function(
+            t,
+            vec![void, i32, i32],
+            vec![call.clone(), call, Opcode::Ret { ret: Reg(0) }],
+        );
+        let code = builder.build();
+        let stats = Stats::new(&code);
+
+        let count = |name, count| Count { name, count };
+        assert_eq!(stats.opcodes, [count("Call0", 2), count("Ret", 2)]);
+        assert_eq!(
+            stats.types,
+            [
+                count("primitive", 2),
+                count("function", 1),
+                count("void", 1)
+            ]
+        );
+        assert_eq!(
+            stats.ops,
+            Distribution {
+                min: 1,
+                max: 3,
+                mean: 2.0,
+                median: 3,
+                total: 4
+            }
+        );
+        assert_eq!(stats.regs.total, 4);
+        assert_eq!(stats.functions.len(), 2);
+        let largest: Vec<_> = stats
+            .largest_functions(1)
+            .iter()
+            .map(|f| f.findex)
+            .collect();
+        assert_eq!(largest, [big]);
+        assert_eq!(stats.functions[0].findex, small);
+        let natives: Vec<_> = stats
+            .natives
+            .iter()
+            .map(|n| (n.lib.as_str(), n.natives, n.calls))
+            .collect();
+        assert_eq!(natives, [("std", 2, 2), ("ui", 1, 0)]);
+        assert!(stats.files.is_empty());
+    }
+}
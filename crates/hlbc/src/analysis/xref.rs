@@ -0,0 +1,303 @@
+//! Whole program cross-references, to find every instruction using a bytecode element.
+
+use std::collections::HashMap;
+
+use crate::opcodes::Operand;
+use crate::types::{
+    Function, RefBytes, RefField, RefFloat, RefFun, RefGlobal, RefInt, RefString, RefType, Reg,
+};
+use crate::{Bytecode, Opcode, Type};
+
+/// A bytecode element that can be referenced by instructions
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Element {
+    String(RefString),
+    Int(RefInt),
+    Float(RefFloat),
+    Bytes(RefBytes),
+    /// Types referenced by an instruction or instantiated ([Opcode::New], [Opcode::MakeEnum], ...)
+    Type(RefType),
+    /// Field of an object, see [field_owner]
+    Field(RefType, RefField),
+    Global(RefGlobal),
+    /// Function or native
+    Fun(RefFun),
+}
+
+/// How an element is used
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Access {
+    Read,
+    Write,
+    Call,
+}
+
+/// An instruction using an element
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct UseSite {
+    /// Function containing the instruction
+    pub fun: RefFun,
+    /// Position of the instruction in the function
+    pub op: usize,
+    pub access: Access,
+}
+
+/// The type declaring a field. Fields of a class include the ones of its parents,
+/// so the same field can be accessed through any type of the hierarchy.
+pub fn field_owner(code: &Bytecode, t: RefType, field: RefField) -> RefType {
+    let mut t = t;
+    // Bounded in case of a malformed hierarchy
+    for _ in 0..code.types.len() {
+        match t.as_obj(code) {
+            // Fields might not have been flattened yet
+            Some(obj)
+                if obj
+                    .fields
+                    .len()
+                    .checked_sub(obj.own_fields.len())
+                    .map_or(false, |inherited| field.0 < inherited) =>
+            {
+                match obj.super_ {
+                    Some(s) => t = s,
+                    None => break,
+                }
+            }
+            _ => break,
+        }
+    }
+    t
+}
+
+/// Index of every use of every element, built in a single pass over the bytecode
+#[derive(Debug, Clone, Default)]
+pub struct XrefIndex {
+    uses: HashMap<Element, Vec<UseSite>>,
+}
+
+impl XrefIndex {
+    pub fn new(code: &Bytecode) -> Self {
+        let mut index = Self::default();
+        for f in &code.functions {
+            for (i, o) in f.ops.iter().enumerate() {
+                index.add_op(code, f, i, o);
+            }
+        }
+        index
+    }
+
+    fn add(&mut self, code: &Bytecode, elem: Element, site: UseSite) {
+        let elem = match elem {
+            Element::Field(t, field) => Element::Field(field_owner(code, t, field), field),
+            e => e,
+        };
+        self.uses.entry(elem).or_default().push(site);
+    }
+
+    fn add_op(&mut self, code: &Bytecode, f: &Function, i: usize, o: &Opcode) {
+        let site = |access| UseSite {
+            fun: f.findex,
+            op: i,
+            access,
+        };
+        let regtype = |reg: Reg| f.try_regtype(reg).ok();
+
+        // Elements needing the type of a register
+        match *o {
+            Opcode::Field { obj, field, .. } => {
+                if let Some(t) = regtype(obj) {
+                    self.add(code, Element::Field(t, field), site(Access::Read));
+                }
+            }
+            Opcode::SetField { obj, field, .. } => {
+                if let Some(t) = regtype(obj) {
+                    self.add(code, Element::Field(t, field), site(Access::Write));
+                }
+            }
+            Opcode::GetThis { field, .. } => {
+                if let Some(t) = regtype(Reg(0)) {
+                    self.add(code, Element::Field(t, field), site(Access::Read));
+                }
+            }
+            Opcode::SetThis { field, .. } => {
+                if let Some(t) = regtype(Reg(0)) {
+                    self.add(code, Element::Field(t, field), site(Access::Write));
+                }
+            }
+            Opcode::CallMethod {
+                field, ref args, ..
+            } => {
+                if let Some(t) = args.first().and_then(|&r| regtype(r)) {
+                    self.add_method_call(code, t, field, site(Access::Call));
+                }
+            }
+            Opcode::CallThis { field, .. } => {
+                if let Some(t) = regtype(Reg(0)) {
+                    self.add_method_call(code, t, field, site(Access::Call));
+                }
+            }
+            Opcode::New { dst } | Opcode::MakeEnum { dst, .. } | Opcode::EnumAlloc { dst, .. } => {
+                if let Some(t) = regtype(dst) {
+                    self.add(code, Element::Type(t), site(Access::Read));
+                }
+            }
+            _ => {}
+        }
+
+        for operand in o.operands() {
+            let (elem, access) = match operand {
+                Operand::String(s) => (Element::String(s), Access::Read),
+                Operand::Int(v) => (Element::Int(v), Access::Read),
+                Operand::Float(v) => (Element::Float(v), Access::Read),
+                Operand::Bytes(v) => (Element::Bytes(v), Access::Read),
+                Operand::Type(t) => (Element::Type(t), Access::Read),
+                Operand::Global(g) => (
+                    Element::Global(g),
+                    if matches!(o, Opcode::SetGlobal { .. }) {
+                        Access::Write
+                    } else {
+                        Access::Read
+                    },
+                ),
+                Operand::Fun(fun) => (
+                    Element::Fun(fun),
+                    if matches!(
+                        o,
+                        Opcode::StaticClosure { .. } | Opcode::InstanceClosure { .. }
+                    ) {
+                        Access::Read
+                    } else {
+                        Access::Call
+                    },
+                ),
+                _ => continue,
+            };
+            self.add(code, elem, site(access));
+        }
+    }
+
+    /// Methods of a class are resolved to the function called, fields of a virtual are kept as is
+    fn add_method_call(&mut self, code: &Bytecode, t: RefType, field: RefField, site: UseSite) {
+        match code.types.get(t.0) {
+            Some(Type::Obj(_) | Type::Struct(_)) => {
+                if let Some(p) = t.find_method(field.0, code) {
+                    self.add(code, Element::Fun(p.findex), site);
+                }
+            }
+            Some(_) => self.add(code, Element::Field(t, field), site),
+            None => {}
+        }
+    }
+
+    /// Every instruction using an element, in the order of the functions
+    pub fn uses(&self, code: &Bytecode, elem: Element) -> &[UseSite] {
+        let elem = match elem {
+            Element::Field(t, field) => Element::Field(field_owner(code, t, field), field),
+            e => e,
+        };
+        self.uses.get(&elem).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    /// Number of indexed elements
+    pub fn len(&self) -> usize {
+        self.uses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.uses.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::analysis::xref::{field_owner, Access, Element, UseSite, XrefIndex};
+    use crate::builder::BytecodeBuilder;
+    use crate::types::{ObjField, RefField, RefFun, Reg, Type};
+    use crate::Opcode;
+
+    #[test]
+    fn uses() {
+        let mut builder = BytecodeBuilder::new();
+        let void = builder.intern_type(Type::Void);
+        let i32 = builder.intern_type(Type::I32);
+        let t = builder.fun_type(vec![], void);
+        let name = builder.string("Base");
+        let base = builder.add_class("Base", None, vec![ObjField { name, t: i32 }], vec![]);
+        let name = builder.string("Child");
+        let child = builder.add_class("Child", Some(base), vec![ObjField { name, t: i32 }], vec![]);
+        let s = builder.string("hello");
+        let callee = builder.add_function(t, vec![void], vec![Opcode::Ret { ret: Reg(0) }]);
+        let main = builder.add_function(
+            t,
+            vec![void, child, i32],
+            vec![
+                Opcode::String {
+                    dst: Reg(0),
+                    ptr: s,
+                },
+                Opcode::New { dst: Reg(1) },
+                // Inherited field
+                Opcode::SetField {
+                    obj: Reg(1),
+                    field: RefField(0),
+                    src: Reg(2),
+                },
+                Opcode::Field {
+                    dst: Reg(2),
+                    obj: Reg(1),
+                    field: RefField(1),
+                },
+                Opcode::Call0 {
+                    dst: Reg(0),
+                    fun: callee,
+                },
+                Opcode::Ret { ret: Reg(0) },
+            ],
+        );
+        builder.set_entrypoint(main);
+        let code = builder.build();
+        let xrefs = XrefIndex::new(&code);
+
+        let site = |op, access| UseSite {
+            fun: main,
+            op,
+            access,
+        };
+        assert_eq!(
+            xrefs.uses(&code, Element::String(s)),
+            &[site(0, Access::Read)]
+        );
+        assert_eq!(
+            xrefs.uses(&code, Element::Type(child)),
+            &[site(1, Access::Read)]
+        );
+        // Found through the parent or the child type
+        assert_eq!(
+            xrefs.uses(&code, Element::Field(base, RefField(0))),
+            &[site(2, Access::Write)]
+        );
+        assert_eq!(
+            xrefs.uses(&code, Element::Field(child, RefField(0))),
+            &[site(2, Access::Write)]
+        );
+        assert_eq!(
+            xrefs.uses(&code, Element::Field(child, RefField(1))),
+            &[site(3, Access::Read)]
+        );
+        assert_eq!(
+            xrefs.uses(&code, Element::Fun(callee)),
+            &[site(4, Access::Call)]
+        );
+        assert!(xrefs.uses(&code, Element::Fun(RefFun(99))).is_empty());
+    }
+
+    #[test]
+    fn owner_of_unflattened_fields() {
+        let mut builder = BytecodeBuilder::new();
+        let i32 = builder.intern_type(Type::I32);
+        let name = builder.string("x");
+        let t = builder.add_class("Point", None, vec![ObjField { name, t: i32 }], vec![]);
+        let mut code = builder.build();
+        code.types[t.0].get_type_obj_mut().unwrap().fields.clear();
+        assert_eq!(field_owner(&code, t, RefField(0)), t);
+    }
+}
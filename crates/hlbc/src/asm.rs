@@ -0,0 +1,530 @@
+//! The assembly syntax is a line based listing of a single function :
+//! ```text
+//! fn @12 @5           ; findex (or `new`) and function type
+//! reg0 @0             ; register types, in order
+//! reg1 @3
+//! start:              ; a label, it points to the next instruction
+//!     Int reg1 42     ; opcode name followed by its operands in declaration order
+//!     String reg0 "hello"
+//!     JTrue reg1 start
+//!     Ret reg0
+//! ```
+//! Operands are written as :
+//! - registers : `reg3`, lists of registers : `[reg1, reg2]`
+//! - ints and floats : their value (added to the constant pool if needed) or `@index`
+//! - strings : a quoted literal with Rust escapes or `@index`
+//! - booleans : `true` or `false`
+//! - jump offsets : a label, a raw offset or a list of those for `Switch`
+//! - any other reference (types, functions, fields, globals ...) : `@index`
+//!
+//! [disassemble] produces this syntax, so a function can be dumped, edited and assembled back.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::builder::BytecodeBuilder;
+use crate::fmt::EnhancedFmt;
+use crate::opcodes::{Operand, OperandMut};
+use crate::types::{
+    Function, RefBytes, RefEnumConstruct, RefField, RefFloat, RefFun, RefGlobal, RefInt, RefString,
+    RefType, Reg, ValBool,
+};
+use crate::{Bytecode, Error, Opcode, Resolve, Result};
+
+/// Produce an assembly listing of a function that [assemble] can read back.
+pub fn disassemble(code: &Bytecode, fun: &Function) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "fn @{} @{} ; {}",
+        fun.findex.0,
+        fun.t.0,
+        fun.display_header::<EnhancedFmt>(code)
+    );
+    for (i, reg) in fun.regs.iter().enumerate() {
+        let _ = writeln!(
+            out,
+            "reg{i} @{} ; {}",
+            reg.0,
+            reg.display::<EnhancedFmt>(code)
+        );
+    }
+
+    let labels: Vec<bool> = {
+        let mut labels = vec![false; fun.ops.len() + 1];
+        for (i, o) in fun.ops.iter().enumerate() {
+            for target in o.jump_targets(i) {
+                if let Some(l) = usize::try_from(target).ok().and_then(|t| labels.get_mut(t)) {
+                    *l = true;
+                }
+            }
+        }
+        labels
+    };
+
+    for (i, o) in fun.ops.iter().enumerate() {
+        if labels[i] {
+            let _ = writeln!(out, "L{i}:");
+        }
+        let _ = write!(out, "    {}", o.name());
+        for operand in o.operands() {
+            out.push(' ');
+            write_operand(&mut out, code, &labels, i, operand);
+        }
+        out.push('\n');
+    }
+    if labels[fun.ops.len()] {
+        let _ = writeln!(out, "L{}:", fun.ops.len());
+    }
+    out
+}
+
+fn write_operand(out: &mut String, code: &Bytecode, labels: &[bool], pos: usize, o: Operand) {
+    let offset = |out: &mut String, offset: i32| {
+        let target = pos as isize + offset as isize + 1;
+        if usize::try_from(target).map_or(false, |t| labels.get(t) == Some(&true)) {
+            let _ = write!(out, "L{target}");
+        } else {
+            let _ = write!(out, "{offset:+}");
+        }
+    };
+    let _ = match o {
+        Operand::Reg(r) => write!(out, "{r}"),
+        Operand::Regs(regs) => {
+            out.push('[');
+            for (i, r) in regs.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                let _ = write!(out, "{r}");
+            }
+            write!(out, "]")
+        }
+        Operand::Int(i) => match code.try_resolve(i) {
+            Ok(v) => write!(out, "{v}"),
+            Err(_) => write!(out, "@{}", i.0),
+        },
+        Operand::Float(f) => match code.try_resolve(f) {
+            Ok(v) => write!(out, "{v:?}"),
+            Err(_) => write!(out, "@{}", f.0),
+        },
+        Operand::String(s) => match code.strings.get(s.0) {
+            Some(v) => write!(out, "{:?}", v.as_str()),
+            None => write!(out, "@{}", s.0),
+        },
+        Operand::Bool(b) => write!(out, "{}", b.0),
+        Operand::Offset(o) => {
+            offset(out, o);
+            Ok(())
+        }
+        Operand::Offsets(offsets) => {
+            out.push('[');
+            for (i, &o) in offsets.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                offset(out, o);
+            }
+            write!(out, "]")
+        }
+        Operand::Bytes(RefBytes(i))
+        | Operand::Type(RefType(i))
+        | Operand::Fun(RefFun(i))
+        | Operand::Field(RefField(i))
+        | Operand::Global(RefGlobal(i))
+        | Operand::EnumConstruct(RefEnumConstruct(i)) => write!(out, "@{i}"),
+    };
+}
+
+/// Assemble a function listing.
+/// Constants are interned in the builder and `fn new` allocates a new findex.
+/// The resulting function can be added to the bytecode with [BytecodeBuilder::insert_function].
+pub fn assemble(builder: &mut BytecodeBuilder, src: &str) -> Result<Function> {
+    let lines = src
+        .lines()
+        .enumerate()
+        .map(|(i, l)| Ok((i + 1, tokenize(i + 1, l)?)))
+        .filter(|r| !matches!(r, Ok((_, tokens)) if tokens.is_empty()))
+        .collect::<Result<Vec<_>>>()?;
+    let mut lines = lines.into_iter();
+
+    // Header
+    let (line, header) = lines.next().ok_or(Error::AssemblyError {
+        line: 0,
+        msg: "Empty listing".to_string(),
+    })?;
+    let mut header = Tokens::new(line, header);
+    header.expect_word("fn")?;
+    let findex = match header.next()? {
+        Token::Word("new") => None,
+        tok => Some(RefFun(header.reference(tok)?)),
+    };
+    let t = RefType(header.next_reference()?);
+    header.end()?;
+
+    // First pass for registers and labels
+    let mut regs = Vec::new();
+    let mut labels = HashMap::new();
+    let mut ops = Vec::new();
+    for (line, tokens) in lines {
+        match tokens[..] {
+            [Token::Word(name), Token::Colon] => {
+                if labels.insert(name, ops.len()).is_some() {
+                    return Err(asm_err(line, format!("Duplicate label '{name}'")));
+                }
+            }
+            [Token::Word(reg), Token::Word(ty)]
+                if reg.starts_with("reg") && ty.starts_with('@') =>
+            {
+                let mut tokens = Tokens::new(line, tokens);
+                let Reg(i) = tokens.next_reg()?;
+                if i as usize != regs.len() {
+                    return Err(asm_err(line, format!("Expected reg{}", regs.len())));
+                }
+                regs.push(RefType(tokens.next_reference()?));
+            }
+            _ => ops.push((line, tokens)),
+        }
+    }
+
+    let ops = ops
+        .into_iter()
+        .enumerate()
+        .map(|(pos, (line, tokens))| {
+            let mut tokens = Tokens::new(line, tokens);
+            let name = tokens.next_word()?;
+            let mut op = Opcode::from_name(name)
+                .ok_or_else(|| asm_err(line, format!("Unknown opcode '{name}'")))?;
+            for operand in op.operands_mut() {
+                tokens.operand(builder, &labels, pos, operand)?;
+            }
+            tokens.end()?;
+            Ok(op)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(Function {
+        name: RefString(0),
+        t,
+        findex: findex.unwrap_or_else(|| builder.new_findex()),
+        regs,
+        ops,
+        debug_info: None,
+        assigns: None,
+        parent: None,
+    })
+}
+
+fn asm_err(line: usize, msg: String) -> Error {
+    Error::AssemblyError { line, msg }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token<'a> {
+    Word(&'a str),
+    Str(String),
+    Open,
+    Close,
+    Comma,
+    Colon,
+}
+
+fn tokenize(line: usize, src: &str) -> Result<Vec<Token<'_>>> {
+    let mut tokens = Vec::new();
+    let mut chars = src.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            ';' => break,
+            '[' => tokens.push(Token::Open),
+            ']' => tokens.push(Token::Close),
+            ',' => tokens.push(Token::Comma),
+            ':' => tokens.push(Token::Colon),
+            '"' => {
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, '\\')) => s.push(unescape(line, &mut chars)?),
+                        Some((_, c)) => s.push(c),
+                        None => return Err(asm_err(line, "Unterminated string".to_string())),
+                    }
+                }
+                tokens.push(Token::Str(s));
+            }
+            c if c.is_whitespace() => {}
+            _ => {
+                let mut end = src.len();
+                while let Some(&(j, c)) = chars.peek() {
+                    if c.is_whitespace() || ";[],:\"".contains(c) {
+                        end = j;
+                        break;
+                    }
+                    chars.next();
+                }
+                tokens.push(Token::Word(&src[i..end]));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// Rust escape sequences, as produced by the Debug impl of str
+fn unescape(line: usize, chars: &mut impl Iterator<Item = (usize, char)>) -> Result<char> {
+    let invalid = || asm_err(line, "Invalid escape sequence".to_string());
+    Ok(match chars.next().ok_or_else(invalid)?.1 {
+        'n' => '\n',
+        'r' => '\r',
+        't' => '\t',
+        '0' => '\0',
+        '\\' => '\\',
+        '"' => '"',
+        '\'' => '\'',
+        'u' => {
+            if chars.next().map(|(_, c)| c) != Some('{') {
+                return Err(invalid());
+            }
+            let hex: String = chars.map(|(_, c)| c).take_while(|&c| c != '}').collect();
+            u32::from_str_radix(&hex, 16)
+                .ok()
+                .and_then(char::from_u32)
+                .ok_or_else(invalid)?
+        }
+        _ => return Err(invalid()),
+    })
+}
+
+struct Tokens<'a> {
+    line: usize,
+    tokens: std::vec::IntoIter<Token<'a>>,
+}
+
+impl<'a> Tokens<'a> {
+    fn new(line: usize, tokens: Vec<Token<'a>>) -> Self {
+        Self {
+            line,
+            tokens: tokens.into_iter(),
+        }
+    }
+
+    fn err(&self, msg: impl Into<String>) -> Error {
+        asm_err(self.line, msg.into())
+    }
+
+    fn next(&mut self) -> Result<Token<'a>> {
+        self.tokens
+            .next()
+            .ok_or_else(|| self.err("Unexpected end of line"))
+    }
+
+    fn end(&mut self) -> Result<()> {
+        match self.tokens.next() {
+            None => Ok(()),
+            Some(tok) => Err(self.err(format!("Unexpected {tok:?}"))),
+        }
+    }
+
+    fn next_word(&mut self) -> Result<&'a str> {
+        match self.next()? {
+            Token::Word(w) => Ok(w),
+            tok => Err(self.err(format!("Expected a word, found {tok:?}"))),
+        }
+    }
+
+    fn expect_word(&mut self, expected: &str) -> Result<()> {
+        let word = self.next_word()?;
+        if word == expected {
+            Ok(())
+        } else {
+            Err(self.err(format!("Expected '{expected}', found '{word}'")))
+        }
+    }
+
+    fn reference(&self, tok: Token) -> Result<usize> {
+        match tok {
+            Token::Word(w) if w.starts_with('@') => w[1..]
+                .parse()
+                .map_err(|_| self.err(format!("Invalid reference '{w}'"))),
+            tok => Err(self.err(format!("Expected a reference (@index), found {tok:?}"))),
+        }
+    }
+
+    fn next_reference(&mut self) -> Result<usize> {
+        let tok = self.next()?;
+        self.reference(tok)
+    }
+
+    fn next_reg(&mut self) -> Result<Reg> {
+        let word = self.next_word()?;
+        word.strip_prefix("reg")
+            .and_then(|n| n.parse().ok())
+            .map(Reg)
+            .ok_or_else(|| self.err(format!("Expected a register, found '{word}'")))
+    }
+
+    /// Parse a bracketed list
+    fn list<T>(&mut self, mut item: impl FnMut(&mut Self) -> Result<T>) -> Result<Vec<T>> {
+        match self.next()? {
+            Token::Open => {}
+            tok => return Err(self.err(format!("Expected '[', found {tok:?}"))),
+        }
+        let mut items = Vec::new();
+        loop {
+            match self.tokens.as_slice().first() {
+                Some(Token::Close) if items.is_empty() => {
+                    self.tokens.next();
+                    break;
+                }
+                _ => items.push(item(self)?),
+            }
+            match self.next()? {
+                Token::Comma => {}
+                Token::Close => break,
+                tok => return Err(self.err(format!("Expected ',' or ']', found {tok:?}"))),
+            }
+        }
+        Ok(items)
+    }
+
+    /// Either `@index` or a literal value
+    fn constant<T: std::str::FromStr>(
+        &mut self,
+        mut intern: impl FnMut(T) -> usize,
+    ) -> Result<usize> {
+        match self.next()? {
+            Token::Word(w) if w.starts_with('@') => self.reference(Token::Word(w)),
+            Token::Word(w) => w
+                .parse()
+                .map(&mut intern)
+                .map_err(|_| self.err(format!("Invalid constant '{w}'"))),
+            tok => Err(self.err(format!("Expected a constant, found {tok:?}"))),
+        }
+    }
+
+    fn offset(&mut self, labels: &HashMap<&str, usize>, pos: usize) -> Result<i32> {
+        let word = self.next_word()?;
+        if let Some(&target) = labels.get(word) {
+            Ok(target as i32 - pos as i32 - 1)
+        } else {
+            word.parse()
+                .map_err(|_| self.err(format!("Unknown label '{word}'")))
+        }
+    }
+
+    fn operand(
+        &mut self,
+        builder: &mut BytecodeBuilder,
+        labels: &HashMap<&str, usize>,
+        pos: usize,
+        operand: OperandMut,
+    ) -> Result<()> {
+        match operand {
+            OperandMut::Reg(r) => *r = self.next_reg()?,
+            OperandMut::Regs(regs) => *regs = self.list(Self::next_reg)?,
+            OperandMut::Int(i) => *i = RefInt(self.constant(|v| builder.int(v).0)?),
+            OperandMut::Float(f) => *f = RefFloat(self.constant(|v| builder.float(v).0)?),
+            OperandMut::String(s) => {
+                *s = match self.next()? {
+                    Token::Str(value) => builder.string(&value),
+                    tok => RefString(self.reference(tok)?),
+                }
+            }
+            OperandMut::Bool(b) => {
+                *b = match self.next_word()? {
+                    "true" => ValBool(true),
+                    "false" => ValBool(false),
+                    w => return Err(self.err(format!("Expected a boolean, found '{w}'"))),
+                }
+            }
+            OperandMut::Offset(o) => *o = self.offset(labels, pos)?,
+            OperandMut::Offsets(offsets) => *offsets = self.list(|t| t.offset(labels, pos))?,
+            OperandMut::Bytes(b) => *b = RefBytes(self.next_reference()?),
+            OperandMut::Type(t) => *t = RefType(self.next_reference()?),
+            OperandMut::Fun(f) => *f = RefFun(self.next_reference()?),
+            OperandMut::Field(f) => *f = RefField(self.next_reference()?),
+            OperandMut::Global(g) => *g = RefGlobal(self.next_reference()?),
+            OperandMut::EnumConstruct(c) => *c = RefEnumConstruct(self.next_reference()?),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::asm::{assemble, disassemble};
+    use crate::builder::BytecodeBuilder;
+    use crate::opcodes::Opcode;
+    use crate::types::{RefType, Reg, Type};
+    use crate::{Error, Resolve};
+
+    fn builder() -> (BytecodeBuilder, RefType) {
+        let mut builder = BytecodeBuilder::new();
+        let void = builder.intern_type(Type::Void);
+        builder.intern_type(Type::I32);
+        let t = builder.fun_type(vec![], void);
+        (builder, t)
+    }
+
+    #[test]
+    fn assemble_function() {
+        let (mut builder, t) = builder();
+        let fun = assemble(
+            &mut builder,
+            r#"
+            fn new @2 ; comment
+            reg0 @0
+            reg1 @1
+            start:
+                Int reg1 -7
+                String reg0 "a \"quoted\"; string\n"
+                Switch reg1 [start, end] end
+                JAlways start
+            end:
+                Ret reg0
+            "#,
+        )
+        .unwrap();
+        assert_eq!(fun.t, t);
+        assert_eq!(fun.regs, vec![RefType(0), RefType(1)]);
+        assert!(
+            matches!(fun.ops[0], Opcode::Int { dst: Reg(1), ptr } if builder.code().resolve(ptr) == -7)
+        );
+        assert!(
+            matches!(fun.ops[1], Opcode::String { ptr, .. } if builder.code().strings[ptr.0] == "a \"quoted\"; string\n")
+        );
+        assert!(
+            matches!(&fun.ops[2], Opcode::Switch { offsets, end: 1, .. } if offsets == &[-3, 1])
+        );
+        assert!(matches!(fun.ops[3], Opcode::JAlways { offset: -4 }));
+    }
+
+    #[test]
+    fn roundtrip() {
+        let (mut builder, _) = builder();
+        let src = "fn new @2\nreg0 @0\nreg1 @1\nL0:\n    Float reg1 0.5\n    Bool reg1 true\n    JFalse reg1 L0\n    Ret reg0\n";
+        let fun = assemble(&mut builder, src).unwrap();
+        builder.insert_function(fun);
+        let code = builder.build();
+        let listing = disassemble(&code, &code.functions[0]);
+        let mut builder = BytecodeBuilder::from(code);
+        let fun = assemble(&mut builder, &listing).unwrap();
+        assert_eq!(
+            format!("{:?}", fun.ops),
+            format!("{:?}", builder.code().functions[0].ops)
+        );
+        assert_eq!(builder.code().floats, vec![0.5]);
+    }
+
+    #[test]
+    fn errors() {
+        let (mut builder, _) = builder();
+        let line = |src: &str, builder: &mut BytecodeBuilder| match assemble(builder, src) {
+            Err(Error::AssemblyError { line, .. }) => line,
+            other => panic!("{other:?}"),
+        };
+        assert_eq!(line("fn new @2\nNotAnOpcode", &mut builder), 2);
+        assert_eq!(line("fn new @2\n\nRet reg0 reg1", &mut builder), 3);
+        assert_eq!(line("fn new @2\nJAlways nowhere", &mut builder), 2);
+        assert_eq!(line("fn new @2\nreg1 @0", &mut builder), 2);
+        assert_eq!(line("fn new @2\nString reg0 \"abc", &mut builder), 2);
+    }
+}
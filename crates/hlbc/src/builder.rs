@@ -0,0 +1,439 @@
+use std::collections::HashMap;
+
+use crate::opcodes::Opcode;
+use crate::types::{
+    ConstantDef, Function, Native, ObjField, ObjProto, RefBytes, RefFloat, RefFun, RefGlobal,
+    RefInt, RefString, RefType, Type, TypeFun, TypeObj,
+};
+use crate::{Bytecode, Error, Result, Str, MAX_VERSION};
+
+/// Build a new [Bytecode] or patch an existing one.
+///
+/// Constants are interned, new functions and natives are given fresh findexes
+/// and [BytecodeBuilder::build] rebuilds every acceleration structure so the result can be used and serialized right away.
+/// ```
+/// # use hlbc::builder::BytecodeBuilder;
+/// # use hlbc::opcodes::Opcode;
+/// # use hlbc::types::{Reg, Type};
+/// let mut builder = BytecodeBuilder::new();
+/// let void = builder.intern_type(Type::Void);
+/// let t = builder.fun_type(vec![], void);
+/// let main = builder.add_function(t, vec![void], vec![Opcode::Ret { ret: Reg(0) }]);
+/// builder.set_entrypoint(main);
+/// let code = builder.build();
+/// assert!(code.verify().is_empty());
+/// ```
+#[derive(Debug)]
+pub struct BytecodeBuilder {
+    code: Bytecode,
+    ints: HashMap<i32, RefInt>,
+    floats: HashMap<u64, RefFloat>,
+    strings: HashMap<Str, RefString>,
+    next_findex: usize,
+}
+
+impl BytecodeBuilder {
+    /// Start from an empty program using the latest bytecode version.
+    pub fn new() -> Self {
+        Self::from(Bytecode {
+            version: MAX_VERSION,
+            // String 0 is used as the 'no name' value
+            strings: vec![Str::from("")],
+            bytes: Some((Vec::new(), Vec::new())),
+            constants: Some(Vec::new()),
+            ..Default::default()
+        })
+    }
+
+    /// The bytecode being built.
+    /// Lookup structures (e.g. [Bytecode::function_by_name]) are only up to date after [BytecodeBuilder::build].
+    pub fn code(&self) -> &Bytecode {
+        &self.code
+    }
+
+    /// Direct access to the bytecode being built.
+    /// Pools modified through this won't be interned anymore.
+    pub fn code_mut(&mut self) -> &mut Bytecode {
+        &mut self.code
+    }
+
+    /// Get a reference to an i32 constant, adding it to the pool if needed
+    pub fn int(&mut self, value: i32) -> RefInt {
+        let ints = &mut self.code.ints;
+        *self.ints.entry(value).or_insert_with(|| {
+            ints.push(value);
+            RefInt(ints.len() - 1)
+        })
+    }
+
+    /// Get a reference to a f64 constant, adding it to the pool if needed
+    pub fn float(&mut self, value: f64) -> RefFloat {
+        let floats = &mut self.code.floats;
+        *self.floats.entry(value.to_bits()).or_insert_with(|| {
+            floats.push(value);
+            RefFloat(floats.len() - 1)
+        })
+    }
+
+    /// Get a reference to a string constant, adding it to the pool if needed
+    pub fn string(&mut self, value: &str) -> RefString {
+        let strings = &mut self.code.strings;
+        *self
+            .strings
+            .entry(Str::from(value))
+            .or_insert_with_key(|s| {
+                strings.push(s.clone());
+                RefString(strings.len() - 1)
+            })
+    }
+
+    /// Add a bytes constant.
+    ///
+    /// *Requires bytecode v5*
+    pub fn bytes(&mut self, value: &[u8]) -> RefBytes {
+        let (bytes, pos) = self.code.bytes.get_or_insert_with(Default::default);
+        pos.push(bytes.len());
+        bytes.extend_from_slice(value);
+        RefBytes(pos.len() - 1)
+    }
+
+    /// Get the index of a debug file, adding it if needed.
+    /// This enables debug information for the whole bytecode.
+    pub fn debug_file(&mut self, name: &str) -> usize {
+        let files = self.code.debug_files.get_or_insert_with(Vec::new);
+        if let Some(i) = files.iter().position(|f| f == name) {
+            i
+        } else {
+            files.push(Str::from(name));
+            files.len() - 1
+        }
+    }
+
+    /// Add a new type, even if an identical one already exists.
+    pub fn add_type(&mut self, ty: Type) -> RefType {
+        self.code.types.push(ty);
+        RefType(self.code.types.len() - 1)
+    }
+
+    /// Get a reference to a type, adding it if there is no identical type yet.
+    pub fn intern_type(&mut self, ty: Type) -> RefType {
+        match self.code.types.iter().position(|t| *t == ty) {
+            Some(i) => RefType(i),
+            None => self.add_type(ty),
+        }
+    }
+
+    /// Get a reference to a function type, adding it if needed.
+    pub fn fun_type(&mut self, args: Vec<RefType>, ret: RefType) -> RefType {
+        self.intern_type(Type::Fun(TypeFun { args, ret }))
+    }
+
+    /// Add a new class with its own fields and methods.
+    /// Its global can be set afterwards with [BytecodeBuilder::set_class_global],
+    /// and its static bindings with [BytecodeBuilder::class_mut].
+    pub fn add_class(
+        &mut self,
+        name: &str,
+        super_: Option<RefType>,
+        own_fields: Vec<ObjField>,
+        protos: Vec<ObjProto>,
+    ) -> RefType {
+        let name = self.string(name);
+        self.add_type(Type::Obj(TypeObj {
+            name,
+            super_,
+            global: RefGlobal(0),
+            own_fields,
+            protos,
+            bindings: Default::default(),
+            fields: vec![],
+        }))
+    }
+
+    /// Get a class or a struct for modification
+    pub fn class_mut(&mut self, ty: RefType) -> Option<&mut TypeObj> {
+        self.code
+            .types
+            .get_mut(ty.0)
+            .and_then(Type::get_type_obj_mut)
+    }
+
+    /// Set the global holding the static part of a class.
+    /// [TypeObj::global] is stored plus one, 0 meaning no global.
+    pub fn set_class_global(&mut self, ty: RefType, global: RefGlobal) {
+        if let Some(obj) = self.class_mut(ty) {
+            obj.global = RefGlobal(global.0 + 1);
+        }
+    }
+
+    /// Add a new global of the given type.
+    pub fn add_global(&mut self, ty: RefType) -> RefGlobal {
+        self.code.globals.push(ty);
+        RefGlobal(self.code.globals.len() - 1)
+    }
+
+    /// Add an initializer for a global.
+    ///
+    /// *Requires bytecode v4*
+    pub fn add_constant(&mut self, global: RefGlobal, fields: Vec<usize>) {
+        self.code
+            .constants
+            .get_or_insert_with(Vec::new)
+            .push(ConstantDef { global, fields });
+    }
+
+    /// Add a native function, returns its findex.
+    pub fn add_native(&mut self, lib: &str, name: &str, t: RefType) -> RefFun {
+        let native = Native {
+            lib: self.string(lib),
+            name: self.string(name),
+            t,
+            findex: self.new_findex(),
+        };
+        let findex = native.findex;
+        self.code.natives.push(native);
+        findex
+    }
+
+    /// Add a function, returns its findex.
+    pub fn add_function(&mut self, t: RefType, regs: Vec<RefType>, ops: Vec<Opcode>) -> RefFun {
+        let findex = self.new_findex();
+        self.code.functions.push(Function {
+            name: RefString(0),
+            t,
+            findex,
+            regs,
+            ops,
+            debug_info: None,
+            assigns: None,
+            parent: None,
+        });
+        findex
+    }
+
+    /// Get a function for modification
+    pub fn function_mut(&mut self, findex: RefFun) -> Option<&mut Function> {
+        self.code.functions.iter_mut().find(|f| f.findex == findex)
+    }
+
+    /// Replace the registers and instructions of a function.
+    /// Debug information for this function is discarded.
+    pub fn replace_function_body(
+        &mut self,
+        findex: RefFun,
+        regs: Vec<RefType>,
+        ops: Vec<Opcode>,
+    ) -> Result<()> {
+        let fun = self.function_mut(findex).ok_or(Error::InvalidReference {
+            kind: "function",
+            index: findex.0,
+        })?;
+        fun.regs = regs;
+        fun.ops = ops;
+        fun.debug_info = None;
+        fun.assigns = None;
+        Ok(())
+    }
+
+    /// Add a function or replace the function with the same findex.
+    pub fn insert_function(&mut self, fun: Function) {
+        match self.function_mut(fun.findex) {
+            Some(f) => *f = fun,
+            None => {
+                self.next_findex = self.next_findex.max(fun.findex.0 + 1);
+                self.code.functions.push(fun);
+            }
+        }
+    }
+
+    pub fn set_entrypoint(&mut self, findex: RefFun) {
+        self.code.entrypoint = findex;
+    }
+
+    /// Finish building, filling missing debug information and rebuilding lookup structures.
+    pub fn build(mut self) -> Bytecode {
+        if self.code.debug_files.is_some() {
+            // Every instruction needs a debug entry if the bytecode has debug information
+            for f in &mut self.code.functions {
+                let info = f.debug_info.get_or_insert_with(Vec::new);
+                let last = info.last().copied().unwrap_or((0, 0));
+                info.resize(f.ops.len(), last);
+                f.assigns.get_or_insert_with(Vec::new);
+            }
+        }
+        self.code.rebuild_indexes();
+        self.code
+    }
+
+    pub(crate) fn new_findex(&mut self) -> RefFun {
+        self.next_findex += 1;
+        RefFun(self.next_findex - 1)
+    }
+}
+
+impl Default for BytecodeBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Patch an existing bytecode. Existing constants are reused when interning.
+impl From<Bytecode> for BytecodeBuilder {
+    fn from(code: Bytecode) -> Self {
+        let mut ints = HashMap::with_capacity(code.ints.len());
+        for (i, &v) in code.ints.iter().enumerate() {
+            ints.entry(v).or_insert(RefInt(i));
+        }
+        let mut floats = HashMap::with_capacity(code.floats.len());
+        for (i, v) in code.floats.iter().enumerate() {
+            floats.entry(v.to_bits()).or_insert(RefFloat(i));
+        }
+        let mut strings = HashMap::with_capacity(code.strings.len());
+        for (i, v) in code.strings.iter().enumerate() {
+            strings.entry(v.clone()).or_insert(RefString(i));
+        }
+        let next_findex = code
+            .functions
+            .iter()
+            .map(|f| f.findex.0 + 1)
+            .chain(code.natives.iter().map(|n| n.findex.0 + 1))
+            .max()
+            .unwrap_or(0);
+        Self {
+            code,
+            ints,
+            floats,
+            strings,
+            next_findex,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::builder::BytecodeBuilder;
+    use crate::opcodes::Opcode;
+    use crate::types::{FunPtr, ObjField, RefField, RefFun, RefType, Reg, Type};
+    use crate::{Bytecode, Resolve};
+
+    fn roundtrip(code: &Bytecode) -> Bytecode {
+        let mut out = Vec::new();
+        code.serialize(&mut out).unwrap();
+        Bytecode::deserialize(&mut out.as_slice()).unwrap()
+    }
+
+    #[test]
+    fn interning() {
+        let mut builder = BytecodeBuilder::new();
+        let a = builder.string("a");
+        assert_eq!(builder.string("a"), a);
+        assert_ne!(builder.string("b"), a);
+        assert_eq!(builder.int(42), builder.int(42));
+        assert_eq!(builder.float(0.5), builder.float(0.5));
+        let void = builder.intern_type(Type::Void);
+        assert_eq!(builder.intern_type(Type::Void), void);
+        assert_ne!(builder.add_type(Type::Void), void);
+    }
+
+    /// A `$Main.main` function printing an int with a native. Returns (code, main, print).
+    fn program() -> (Bytecode, RefFun, RefFun) {
+        let mut builder = BytecodeBuilder::new();
+        let void = builder.intern_type(Type::Void);
+        let i32 = builder.intern_type(Type::I32);
+        let print_t = builder.fun_type(vec![i32], void);
+        let print = builder.add_native("std", "print", print_t);
+        let main_t = builder.fun_type(vec![], void);
+        let value = builder.int(7);
+        let main = builder.add_function(
+            main_t,
+            vec![void, i32],
+            vec![
+                Opcode::Int {
+                    dst: Reg(1),
+                    ptr: value,
+                },
+                Opcode::Call1 {
+                    dst: Reg(0),
+                    fun: print,
+                    arg0: Reg(1),
+                },
+                Opcode::Ret { ret: Reg(0) },
+            ],
+        );
+        // Static class to give the function a name
+        let name = builder.string("main");
+        let global = builder.add_global(RefType(builder.code().types.len()));
+        let class = builder.add_class("$Main", None, vec![ObjField { name, t: main_t }], vec![]);
+        builder.set_class_global(class, global);
+        let class = builder.class_mut(class).unwrap();
+        class.bindings = [(RefField(0), main)].into_iter().collect();
+        builder.set_entrypoint(main);
+        builder.debug_file("Main.hx");
+        (builder.build(), main, print)
+    }
+
+    #[test]
+    fn build_program() {
+        let (code, main, print) = program();
+        assert!(code.verify().is_empty(), "{:?}", code.verify());
+        let class = code.types.iter().find_map(Type::get_type_obj).unwrap();
+        assert_eq!(class.get_static_type(&code), Some(class));
+        assert_eq!(code.function_by_name("main").unwrap().findex, main);
+        let code = roundtrip(&code);
+        assert!(code.verify().is_empty());
+        assert_eq!(code.entrypoint().ops.len(), 3);
+        assert!(matches!(code.resolve(print), FunPtr::Native(_)));
+        assert_eq!(code.function_by_name("main").unwrap().findex, main);
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn serde_roundtrip() {
+        let (code, main, _) = program();
+        let json = serde_json::to_string(&code).unwrap();
+        let restored: Bytecode = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.function_by_name("main").unwrap().findex, main);
+        assert_eq!(restored.fnames, code.fnames);
+        let (mut a, mut b) = (Vec::new(), Vec::new());
+        code.serialize(&mut a).unwrap();
+        restored.serialize(&mut b).unwrap();
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn patch_function() {
+        let mut builder = BytecodeBuilder::new();
+        let void = builder.intern_type(Type::Void);
+        let t = builder.fun_type(vec![], void);
+        let main = builder.add_function(t, vec![void], vec![Opcode::Ret { ret: Reg(0) }]);
+        builder.set_entrypoint(main);
+        builder.debug_file("Main.hx");
+        let code = roundtrip(&builder.build());
+
+        let mut builder = BytecodeBuilder::from(code);
+        assert_eq!(builder.intern_type(Type::Void), void);
+        let other = builder.add_function(t, vec![void], vec![Opcode::Ret { ret: Reg(0) }]);
+        assert_ne!(other, main);
+        builder
+            .replace_function_body(
+                main,
+                vec![void],
+                vec![
+                    Opcode::Call0 {
+                        dst: Reg(0),
+                        fun: other,
+                    },
+                    Opcode::Ret { ret: Reg(0) },
+                ],
+            )
+            .unwrap();
+        assert!(builder
+            .replace_function_body(RefFun(99), vec![], vec![])
+            .is_err());
+        let code = roundtrip(&builder.build());
+        assert!(code.verify().is_empty());
+        assert_eq!(code.entrypoint().ops.len(), 2);
+        assert_eq!(code.entrypoint().debug_info.as_ref().unwrap().len(), 2);
+        assert_eq!(code.findex_max(), 2);
+    }
+}
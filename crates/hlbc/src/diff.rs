@@ -0,0 +1,816 @@
+//! Elements are matched by name rather than by index, since indexes shift between two builds of the same program :
+//! - types by their name (classes, structs, enums and abstracts)
+//! - functions by their class and field or method name,
+//!   closures by their position in the source file (requires debug information)
+//! - natives by their library and name
+//! - strings by content
+//!
+//! Functions that can't be named (closures without debug information) are ignored.
+//! Instructions are compared with every reference replaced by the element it points to.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+
+use crate::fmt::EnhancedFmt;
+use crate::opcodes::Operand;
+use crate::types::{FunPtr, Function, RefFun, RefType, Reg, Type};
+use crate::{Bytecode, Opcode, Resolve, Str};
+
+/// Maximum number of cells in the table used to compute an opcode level diff
+const MAX_DIFF_CELLS: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Change {
+    Added,
+    Removed,
+    Changed,
+}
+
+impl Change {
+    fn symbol(self) -> char {
+        match self {
+            Change::Added => '+',
+            Change::Removed => '-',
+            Change::Changed => '~',
+        }
+    }
+}
+
+/// A type present in only one of the files or with a different definition
+#[derive(Debug, Clone)]
+pub struct TypeDiff {
+    pub name: String,
+    pub change: Change,
+}
+
+#[derive(Debug, Clone)]
+pub struct FunctionDiff {
+    /// Name used for matching
+    pub name: String,
+    pub change: Change,
+    pub old: Option<RefFun>,
+    pub new: Option<RefFun>,
+    /// Old and new signature if it changed
+    pub signature: Option<(String, String)>,
+    /// Instruction changes for changed functions
+    pub ops: Vec<OpEdit>,
+}
+
+/// A single line of an opcode level diff
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum OpEdit {
+    /// Instruction at this position in the old function has been removed
+    Removed { pos: usize, op: String },
+    /// Instruction at this position in the new function has been added
+    Added { pos: usize, op: String },
+}
+
+/// Differences between two bytecode files, see [diff].
+#[derive(Debug, Clone, Default)]
+pub struct BytecodeDiff {
+    pub types: Vec<TypeDiff>,
+    pub functions: Vec<FunctionDiff>,
+    pub strings_added: Vec<Str>,
+    pub strings_removed: Vec<Str>,
+}
+
+impl BytecodeDiff {
+    pub fn is_empty(&self) -> bool {
+        self.types.is_empty()
+            && self.functions.is_empty()
+            && self.strings_added.is_empty()
+            && self.strings_removed.is_empty()
+    }
+
+    fn count<T>(items: &[T], change: Change, f: impl Fn(&T) -> Change) -> usize {
+        items.iter().filter(|i| f(i) == change).count()
+    }
+}
+
+impl Display for BytecodeDiff {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "No differences");
+        }
+        let summary = |f: &mut Formatter, what: &str, changes: [usize; 3]| {
+            writeln!(
+                f,
+                "{what}: {} added, {} removed, {} changed",
+                changes[0], changes[1], changes[2]
+            )
+        };
+
+        let counts = [Change::Added, Change::Removed, Change::Changed]
+            .map(|c| Self::count(&self.types, c, |t| t.change));
+        summary(f, "Types", counts)?;
+        for t in &self.types {
+            writeln!(f, "{} {}", t.change.symbol(), t.name)?;
+        }
+
+        let counts = [Change::Added, Change::Removed, Change::Changed]
+            .map(|c| Self::count(&self.functions, c, |t| t.change));
+        summary(f, "\nFunctions", counts)?;
+        for fun in &self.functions {
+            write!(f, "{} {}", fun.change.symbol(), fun.name)?;
+            match (fun.old, fun.new) {
+                (Some(old), Some(new)) => writeln!(f, " (fn@{} -> fn@{})", old.0, new.0)?,
+                (Some(i), None) | (None, Some(i)) => writeln!(f, " (fn@{})", i.0)?,
+                (None, None) => writeln!(f)?,
+            }
+            if let Some((old, new)) = &fun.signature {
+                writeln!(f, "    signature {old} -> {new}")?;
+            }
+            for edit in &fun.ops {
+                match edit {
+                    OpEdit::Removed { pos, op } => writeln!(f, "    - {pos:>4}: {op}")?,
+                    OpEdit::Added { pos, op } => writeln!(f, "    + {pos:>4}: {op}")?,
+                }
+            }
+        }
+
+        writeln!(
+            f,
+            "\nStrings: {} added, {} removed",
+            self.strings_added.len(),
+            self.strings_removed.len()
+        )?;
+        for s in &self.strings_added {
+            writeln!(f, "+ {:?}", s.as_str())?;
+        }
+        for s in &self.strings_removed {
+            writeln!(f, "- {:?}", s.as_str())?;
+        }
+        Ok(())
+    }
+}
+
+/// Compare two bytecode files.
+pub fn diff(old: &Bytecode, new: &Bytecode) -> BytecodeDiff {
+    BytecodeDiff {
+        types: diff_types(old, new),
+        functions: diff_functions(old, new),
+        strings_added: new_strings(old, new),
+        strings_removed: new_strings(new, old),
+    }
+}
+
+/// Strings in `b` but not in `a`, in order
+fn new_strings(a: &Bytecode, b: &Bytecode) -> Vec<Str> {
+    let a: HashSet<&Str> = a.strings.iter().collect();
+    let mut seen = HashSet::new();
+    b.strings
+        .iter()
+        .filter(|s| !a.contains(s) && seen.insert(*s))
+        .cloned()
+        .collect()
+}
+
+//region Types
+
+/// Display a type without its index
+fn type_text(code: &Bytecode, t: RefType) -> String {
+    match code.try_resolve(t) {
+        Ok(ty) => ty.display::<EnhancedFmt>(code).to_string(),
+        Err(e) => e.placeholder(),
+    }
+}
+
+pub(crate) fn type_name(code: &Bytecode, ty: &Type) -> Option<String> {
+    match ty {
+        Type::Obj(obj) | Type::Struct(obj) => Some(obj.name(code).to_string()),
+        Type::Enum { .. } | Type::Abstract { .. } => {
+            Some(ty.display::<EnhancedFmt>(code).to_string())
+        }
+        _ => None,
+    }
+}
+
+/// Everything that makes up a type definition, independently of indexes
+pub(crate) fn type_definition(code: &Bytecode, ty: &Type) -> Vec<String> {
+    match ty {
+        Type::Obj(obj) | Type::Struct(obj) => {
+            let mut def = Vec::new();
+            if let Some(s) = obj.super_ {
+                def.push(format!("extends {}", type_text(code, s)));
+            }
+            for f in &obj.own_fields {
+                def.push(format!("{}: {}", f.name(code), type_text(code, f.t)));
+            }
+            for p in &obj.protos {
+                def.push(format!("method {}", p.name(code)));
+            }
+            def
+        }
+        Type::Enum { constructs, .. } => constructs
+            .iter()
+            .map(|c| {
+                let params: Vec<String> = c.params.iter().map(|&p| type_text(code, p)).collect();
+                format!("{}({})", c.name(code), params.join(", "))
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn named_types(code: &Bytecode) -> Vec<(String, &Type)> {
+    with_unique_names(
+        code.types
+            .iter()
+            .filter_map(|t| type_name(code, t).map(|name| (name, t))),
+    )
+}
+
+fn diff_types(old: &Bytecode, new: &Bytecode) -> Vec<TypeDiff> {
+    let old_types = named_types(old);
+    let new_types: HashMap<String, &Type> = named_types(new).into_iter().collect();
+    let mut diffs = Vec::new();
+    let mut matched = HashSet::new();
+    for (name, ty) in &old_types {
+        match new_types.get(name) {
+            Some(new_ty) => {
+                matched.insert(name.as_str());
+                if type_definition(old, ty) != type_definition(new, new_ty) {
+                    diffs.push(TypeDiff {
+                        name: name.clone(),
+                        change: Change::Changed,
+                    });
+                }
+            }
+            None => diffs.push(TypeDiff {
+                name: name.clone(),
+                change: Change::Removed,
+            }),
+        }
+    }
+    for (name, _) in named_types(new) {
+        if !matched.contains(name.as_str()) {
+            diffs.push(TypeDiff {
+                name,
+                change: Change::Added,
+            });
+        }
+    }
+    diffs
+}
+
+//endregion
+
+//region Functions
+
+/// Name of a function independent of its findex
+pub(crate) fn function_name(code: &Bytecode, f: &Function) -> Option<String> {
+    if let Some(parent) = f.parent.and_then(|p| p.as_obj(code)) {
+        Some(format!("{}.{}", parent.name(code), f.name(code)))
+    } else {
+        let (file, line) = f.debug_info.as_ref()?.first()?;
+        let file = code.debug_files.as_ref()?.get(*file)?;
+        Some(format!("<closure {file}:{line}>"))
+    }
+}
+
+/// Name of a function or native, used when formatting references
+fn fun_ref_name(code: &Bytecode, fun: RefFun) -> String {
+    match code.try_resolve(fun) {
+        Ok(FunPtr::Fun(f)) => function_name(code, f).unwrap_or_else(|| format!("fn@{}", fun.0)),
+        Ok(FunPtr::Native(n)) => format!("{}/{}", n.lib(code), n.name(code)),
+        Err(e) => e.placeholder(),
+    }
+}
+
+/// Append a counter to duplicate names so that every name is unique, in order of appearance
+fn with_unique_names<T>(items: impl Iterator<Item = (String, T)>) -> Vec<(String, T)> {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    items
+        .map(|(name, item)| {
+            let count = counts.entry(name.clone()).or_insert(0);
+            *count += 1;
+            if *count > 1 {
+                (format!("{name}#{count}"), item)
+            } else {
+                (name, item)
+            }
+        })
+        .collect()
+}
+
+enum Callable<'a> {
+    Fun(&'a Function),
+    Native(RefFun),
+}
+
+fn named_functions(code: &Bytecode) -> Vec<(String, Callable<'_>)> {
+    with_unique_names(
+        code.functions
+            .iter()
+            .filter_map(|f| function_name(code, f).map(|name| (name, Callable::Fun(f))))
+            .chain(code.natives.iter().map(|n| {
+                (
+                    format!("native {}/{}", n.lib(code), n.name(code)),
+                    Callable::Native(n.findex),
+                )
+            })),
+    )
+}
+
+impl Callable<'_> {
+    fn findex(&self) -> RefFun {
+        match self {
+            Callable::Fun(f) => f.findex,
+            Callable::Native(findex) => *findex,
+        }
+    }
+
+    fn signature(&self, code: &Bytecode) -> String {
+        match self {
+            Callable::Fun(f) => type_text(code, f.t),
+            Callable::Native(findex) => match code.try_resolve(*findex) {
+                Ok(FunPtr::Native(n)) => type_text(code, n.t),
+                _ => String::new(),
+            },
+        }
+    }
+}
+
+/// Type declaring the fields and enum constructors referenced by an instruction
+fn operand_parent<'a>(code: &'a Bytecode, f: &Function, op: &Opcode) -> &'a Type {
+    let reg = match op {
+        Opcode::Field { obj, .. }
+        | Opcode::SetField { obj, .. }
+        | Opcode::VirtualClosure { obj, .. } => Some(*obj),
+        Opcode::GetThis { .. } | Opcode::SetThis { .. } | Opcode::CallThis { .. } => Some(Reg(0)),
+        Opcode::CallMethod { args, .. } => args.first().copied(),
+        Opcode::MakeEnum { dst, .. } | Opcode::EnumAlloc { dst, .. } => Some(*dst),
+        Opcode::EnumField { value, .. } | Opcode::SetEnumField { value, .. } => Some(*value),
+        _ => None,
+    };
+    reg.and_then(|r| f.regs.get(r.0 as usize))
+        .and_then(|&t| code.try_resolve(t).ok())
+        .unwrap_or(&Type::Void)
+}
+
+/// Textual representation of an instruction with references replaced by what they point to
+fn op_text(code: &Bytecode, f: &Function, op: &Opcode) -> String {
+    let parent = operand_parent(code, f, op);
+    let mut text = op.name().to_string();
+    for operand in op.operands() {
+        text.push(' ');
+        text.push_str(&match operand {
+            Operand::Reg(r) => r.to_string(),
+            Operand::Regs(regs) => format!(
+                "[{}]",
+                regs.iter()
+                    .map(|r| r.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Operand::Int(i) => code
+                .try_resolve(i)
+                .map_or_else(|e| e.placeholder(), |v| v.to_string()),
+            Operand::Float(x) => code
+                .try_resolve(x)
+                .map_or_else(|e| e.placeholder(), |v| format!("{v:?}")),
+            Operand::Bytes(b) => code
+                .try_resolve(b)
+                .map_or_else(|e| e.placeholder(), |v| format!("bytes{v:?}")),
+            Operand::String(s) => code
+                .strings
+                .get(s.0)
+                .map_or_else(|| format!("<invalid string@{}>", s.0), |v| format!("{v:?}")),
+            Operand::Type(t) => type_text(code, t),
+            Operand::Fun(fun) => fun_ref_name(code, fun),
+            // Enum parameters have no name
+            Operand::Field(field) if matches!(parent, Type::Enum { .. }) => field.0.to_string(),
+            Operand::Field(field) => field.display::<EnhancedFmt>(code, parent).to_string(),
+            Operand::Global(g) => match code.globals.get(g.0) {
+                Some(&t) => format!("global<{}>", type_text(code, t)),
+                None => format!("<invalid global@{}>", g.0),
+            },
+            Operand::EnumConstruct(c) => c.display::<EnhancedFmt>(code, parent).to_string(),
+            Operand::Bool(b) => b.0.to_string(),
+            Operand::Offset(o) => format!("{o:+}"),
+            Operand::Offsets(offsets) => format!(
+                "[{}]",
+                offsets
+                    .iter()
+                    .map(|o| format!("{o:+}"))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        });
+    }
+    text
+}
+
+fn diff_functions(old: &Bytecode, new: &Bytecode) -> Vec<FunctionDiff> {
+    let old_funs = named_functions(old);
+    let new_funs = named_functions(new);
+    let new_index: HashMap<&str, &Callable> =
+        new_funs.iter().map(|(n, c)| (n.as_str(), c)).collect();
+    let mut matched = HashSet::new();
+    let mut diffs = Vec::new();
+
+    for (name, a) in &old_funs {
+        let Some(b) = new_index.get(name.as_str()) else {
+            diffs.push(FunctionDiff {
+                name: name.clone(),
+                change: Change::Removed,
+                old: Some(a.findex()),
+                new: None,
+                signature: None,
+                ops: Vec::new(),
+            });
+            continue;
+        };
+        matched.insert(name.as_str());
+        let (old_sig, new_sig) = (a.signature(old), b.signature(new));
+        let ops = match (a, b) {
+            (Callable::Fun(a), Callable::Fun(b)) => {
+                let a: Vec<String> = a.ops.iter().map(|o| op_text(old, a, o)).collect();
+                let b: Vec<String> = b.ops.iter().map(|o| op_text(new, b, o)).collect();
+                diff_lines(&a, &b)
+            }
+            _ => Vec::new(),
+        };
+        let signature = if old_sig != new_sig {
+            Some((old_sig, new_sig))
+        } else {
+            None
+        };
+        if signature.is_some() || !ops.is_empty() {
+            diffs.push(FunctionDiff {
+                name: name.clone(),
+                change: Change::Changed,
+                old: Some(a.findex()),
+                new: Some(b.findex()),
+                signature,
+                ops,
+            });
+        }
+    }
+    for (name, b) in &new_funs {
+        if !matched.contains(name.as_str()) {
+            diffs.push(FunctionDiff {
+                name: name.clone(),
+                change: Change::Added,
+                old: None,
+                new: Some(b.findex()),
+                signature: None,
+                ops: Vec::new(),
+            });
+        }
+    }
+    diffs
+}
+
+/// Line diff based on the longest common subsequence
+fn diff_lines(a: &[String], b: &[String]) -> Vec<OpEdit> {
+    // Strip common prefix and suffix
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let (ma, mb) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+    let removed = |i: usize| OpEdit::Removed {
+        pos: prefix + i,
+        op: ma[i].clone(),
+    };
+    let added = |j: usize| OpEdit::Added {
+        pos: prefix + j,
+        op: mb[j].clone(),
+    };
+
+    let (n, m) = (ma.len(), mb.len());
+    if n.saturating_mul(m) > MAX_DIFF_CELLS {
+        // Too big, report the whole section as replaced
+        return (0..n).map(removed).chain((0..m).map(added)).collect();
+    }
+
+    // lcs[i][j] is the length of the lcs of ma[i..] and mb[j..]
+    let mut lcs = vec![0u32; (n + 1) * (m + 1)];
+    let idx = |i: usize, j: usize| i * (m + 1) + j;
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[idx(i, j)] = if ma[i] == mb[j] {
+                lcs[idx(i + 1, j + 1)] + 1
+            } else {
+                lcs[idx(i + 1, j)].max(lcs[idx(i, j + 1)])
+            };
+        }
+    }
+
+    let mut edits = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && ma[i] == mb[j] {
+            i += 1;
+            j += 1;
+        } else if i < n && (j == m || lcs[idx(i + 1, j)] >= lcs[idx(i, j + 1)]) {
+            edits.push(removed(i));
+            i += 1;
+        } else {
+            edits.push(added(j));
+            j += 1;
+        }
+    }
+    edits
+}
+
+//endregion
+
+//region Similarity
+
+/// Structural similarity between two builds of a program, see [similarity].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Similarity {
+    /// Named functions in the old file
+    pub old_functions: usize,
+    /// Named functions in the new file
+    pub new_functions: usize,
+    /// Functions with the same name in both files
+    pub matched: usize,
+    /// Matched functions with the same signature
+    pub same_signature: usize,
+    /// Sum of the opcode shape similarity of the matched functions, each between 0 and 1
+    pub shape: f64,
+}
+
+impl Similarity {
+    /// Between 0 and 1, mean of the ratio of function counts, the ratio of old functions
+    /// found with the same signature and the opcode shape similarity over the old functions.
+    pub fn score(&self) -> f64 {
+        let max = self.old_functions.max(self.new_functions);
+        if max == 0 {
+            return 1.0;
+        }
+        let count = self.old_functions.min(self.new_functions) as f64 / max as f64;
+        let old = self.old_functions.max(1) as f64;
+        (count + self.same_signature as f64 / old + self.shape / old) / 3.0
+    }
+}
+
+impl Display for Similarity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:.1}% ({} -> {} functions, {} matched, {} with the same signature, {:.1}% opcode shape)",
+            self.score() * 100.0,
+            self.old_functions,
+            self.new_functions,
+            self.matched,
+            self.same_signature,
+            if self.matched > 0 {
+                self.shape / self.matched as f64 * 100.0
+            } else {
+                0.0
+            }
+        )
+    }
+}
+
+/// Compare the structure of two builds of the same program, like a decompiled and recompiled one.
+/// Functions are matched by name as in [diff], then their signatures and their sequence of opcodes
+/// without operands are compared.
+pub fn similarity(old: &Bytecode, new: &Bytecode) -> Similarity {
+    fn functions(code: &Bytecode) -> Vec<(String, &Function)> {
+        named_functions(code)
+            .into_iter()
+            .filter_map(|(name, c)| match c {
+                Callable::Fun(f) => Some((name, f)),
+                Callable::Native(_) => None,
+            })
+            .collect()
+    }
+    fn shape(f: &Function) -> Vec<&'static str> {
+        f.ops.iter().map(Opcode::name).collect()
+    }
+
+    let old_funs = functions(old);
+    let new_funs: HashMap<String, &Function> = functions(new).into_iter().collect();
+    let mut similarity = Similarity {
+        old_functions: old_funs.len(),
+        new_functions: new_funs.len(),
+        ..Default::default()
+    };
+    for (name, a) in &old_funs {
+        let Some(b) = new_funs.get(name) else {
+            continue;
+        };
+        similarity.matched += 1;
+        if type_text(old, a.t) == type_text(new, b.t) {
+            similarity.same_signature += 1;
+        }
+        let (a, b) = (shape(a), shape(b));
+        similarity.shape += if a.is_empty() && b.is_empty() {
+            1.0
+        } else {
+            2.0 * lcs_len(&a, &b) as f64 / (a.len() + b.len()) as f64
+        };
+    }
+    similarity
+}
+
+/// Length of the longest common subsequence, only counting the common prefix and suffix when too big
+fn lcs_len<T: Eq>(a: &[T], b: &[T]) -> usize {
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let (ma, mb) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+    if ma.len().saturating_mul(mb.len()) > MAX_DIFF_CELLS {
+        return prefix + suffix;
+    }
+
+    // Single row of the table, row[j] is the length of the lcs of ma[..i] and mb[..j]
+    let mut row = vec![0usize; mb.len() + 1];
+    for x in ma {
+        let mut diag = 0;
+        for (j, y) in mb.iter().enumerate() {
+            let up = row[j + 1];
+            row[j + 1] = if x == y { diag + 1 } else { up.max(row[j]) };
+            diag = up;
+        }
+    }
+    prefix + suffix + row[mb.len()]
+}
+
+//endregion
+
+#[cfg(test)]
+mod tests {
+    use crate::builder::BytecodeBuilder;
+    use crate::diff::{diff, diff_lines, lcs_len, op_text, similarity, Change, OpEdit};
+    use crate::opcodes::Opcode;
+    use crate::types::{
+        EnumConstruct, ObjField, RefEnumConstruct, RefField, RefFun, RefGlobal, Reg, Type,
+    };
+    use crate::Bytecode;
+
+    /// A class `$Main` with static functions, each returning an int constant.
+    /// The order of the functions changes their findexes.
+    fn program(funs: &[(&str, i32)], extra_string: &str) -> Bytecode {
+        let mut builder = BytecodeBuilder::new();
+        builder.intern_type(Type::Void);
+        let i32 = builder.intern_type(Type::I32);
+        let t = builder.fun_type(vec![], i32);
+        builder.string(extra_string);
+        let mut fields = Vec::new();
+        let mut bindings = Vec::new();
+        for (i, &(name, value)) in funs.iter().enumerate() {
+            let value = builder.int(value);
+            let findex = builder.add_function(
+                t,
+                vec![i32],
+                vec![
+                    Opcode::Int {
+                        dst: Reg(0),
+                        ptr: value,
+                    },
+                    Opcode::Ret { ret: Reg(0) },
+                ],
+            );
+            fields.push(ObjField {
+                name: builder.string(name),
+                t,
+            });
+            bindings.push((RefField(i), findex));
+        }
+        let class = builder.add_class("$Main", None, fields, vec![]);
+        let global = builder.add_global(class);
+        builder.set_class_global(class, global);
+        let class = builder.class_mut(class).unwrap();
+        class.bindings = bindings.into_iter().collect();
+        builder.build()
+    }
+
+    #[test]
+    fn match_by_name() {
+        let old = program(&[("a", 1), ("b", 2), ("c", 3)], "old");
+        let new = program(&[("d", 4), ("c", 30), ("b", 2)], "new");
+        let d = diff(&old, &new);
+
+        let changes: Vec<(&str, Change)> = d
+            .functions
+            .iter()
+            .map(|f| (f.name.as_str(), f.change))
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                ("$Main.a", Change::Removed),
+                ("$Main.c", Change::Changed),
+                ("$Main.d", Change::Added)
+            ]
+        );
+        let c = &d.functions[1];
+        assert_eq!((c.old, c.new), (Some(RefFun(2)), Some(RefFun(1))));
+        assert_eq!(
+            c.ops,
+            vec![
+                OpEdit::Removed {
+                    pos: 0,
+                    op: "Int reg0 3".to_string()
+                },
+                OpEdit::Added {
+                    pos: 0,
+                    op: "Int reg0 30".to_string()
+                }
+            ]
+        );
+        assert_eq!(d.types.len(), 1);
+        assert_eq!(d.types[0].change, Change::Changed);
+        assert!(d.strings_added.iter().any(|s| s == "new"));
+        assert!(d.strings_removed.iter().any(|s| s == "old"));
+        assert!(diff(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn lines() {
+        let lines = |s: &str| s.chars().map(String::from).collect::<Vec<_>>();
+        let edits = diff_lines(&lines("abcdef"), &lines("abxdf"));
+        assert_eq!(
+            edits,
+            vec![
+                OpEdit::Removed {
+                    pos: 2,
+                    op: "c".to_string()
+                },
+                OpEdit::Added {
+                    pos: 2,
+                    op: "x".to_string()
+                },
+                OpEdit::Removed {
+                    pos: 4,
+                    op: "e".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn operand_names() {
+        let mut builder = BytecodeBuilder::new();
+        let void = builder.intern_type(Type::Void);
+        let i32 = builder.intern_type(Type::I32);
+        let x = builder.string("x");
+        let class = builder.add_class("Foo", None, vec![ObjField { name: x, t: i32 }], vec![]);
+        let (name, ice) = (builder.string("Kind"), builder.string("Ice"));
+        let kind = builder.add_type(Type::Enum {
+            name,
+            global: RefGlobal(0),
+            constructs: vec![EnumConstruct {
+                name: ice,
+                params: vec![i32],
+            }],
+        });
+        let t = builder.fun_type(vec![class, kind], void);
+        builder.add_function(
+            t,
+            vec![class, kind, i32],
+            vec![
+                Opcode::Field {
+                    dst: Reg(2),
+                    obj: Reg(0),
+                    field: RefField(0),
+                },
+                Opcode::EnumField {
+                    dst: Reg(2),
+                    value: Reg(1),
+                    construct: RefEnumConstruct(0),
+                    field: RefField(0),
+                },
+            ],
+        );
+        let code = builder.build();
+        let f = &code.functions[0];
+        let text: Vec<String> = f.ops.iter().map(|o| op_text(&code, f, o)).collect();
+        assert_eq!(text, ["Field reg2 reg0 x", "EnumField reg2 reg1 Ice 0"]);
+    }
+
+    #[test]
+    fn lcs() {
+        let chars = |s: &str| s.chars().collect::<Vec<_>>();
+        assert_eq!(lcs_len(&chars("abcdef"), &chars("abxdf")), 4);
+        assert_eq!(lcs_len(&chars("abc"), &chars("xyz")), 0);
+        assert_eq!(lcs_len(&chars(""), &chars("abc")), 0);
+    }
+
+    #[test]
+    fn similar() {
+        let old = program(&[("a", 1), ("b", 2), ("c", 3)], "old");
+        let same = similarity(&old, &program(&[("c", 30), ("b", 2), ("a", 1)], "new"));
+        assert_eq!((same.matched, same.same_signature), (3, 3));
+        assert_eq!(same.score(), 1.0);
+
+        let new = program(&[("a", 1), ("d", 4)], "new");
+        let s = similarity(&old, &new);
+        assert_eq!((s.old_functions, s.new_functions, s.matched), (3, 2, 1));
+        // (2/3 + 1/3 + 1/3) / 3
+        assert!((s.score() - 4.0 / 9.0).abs() < 1e-9);
+    }
+}
@@ -15,8 +15,8 @@ use crate::types::{
     FunPtr, Function, Native, RefEnumConstruct, RefField, RefFloat, RefInt, RefString, RefType,
     Reg, Type, TypeFun, TypeObj,
 };
-use crate::Resolve;
 use crate::{Bytecode, RefFun};
+use crate::{Error, Resolve, Str};
 
 //region Display impls
 
@@ -284,19 +284,38 @@ pub struct EnhancedFmt;
 
 impl BytecodeFmt for EnhancedFmt {
     fn fmt_refint(&self, f: &mut Formatter, ctx: &Bytecode, v: RefInt) -> Result {
-        write!(f, "{}", ctx[v])
+        match ctx.try_resolve(v) {
+            Ok(i) => write!(f, "{i}"),
+            Err(e) => fmt_invalid(f, e),
+        }
     }
 
     fn fmt_reffloat(&self, f: &mut Formatter, ctx: &Bytecode, v: RefFloat) -> Result {
-        write!(f, "{}", ctx[v])
+        match ctx.try_resolve(v) {
+            Ok(x) => write!(f, "{x}"),
+            Err(e) => fmt_invalid(f, e),
+        }
     }
 
     fn fmt_refstring(&self, f: &mut Formatter, ctx: &Bytecode, v: RefString) -> Result {
-        f.write_str(&ctx[v])
+        // Not using resolve here since string 0 is a valid string
+        match ctx.strings.get(v.0) {
+            Some(s) => f.write_str(s),
+            None => fmt_invalid(
+                f,
+                Error::InvalidReference {
+                    kind: "string",
+                    index: v.0,
+                },
+            ),
+        }
     }
 
     fn fmt_reftype(&self, f: &mut Formatter, ctx: &Bytecode, v: RefType) -> Result {
-        let ty = &ctx[v];
+        let ty = match ctx.try_resolve(v) {
+            Ok(ty) => ty,
+            Err(e) => return fmt_invalid(f, e),
+        };
         self.fmt_type(f, ctx, ty)?;
         // No need to display @number if type is known
         if !v.is_known() && !ty.is_wrapper_type() {
@@ -324,7 +343,10 @@ impl BytecodeFmt for EnhancedFmt {
                 Display::fmt(&v, f)
             }
         } else if let Type::Virtual { fields } = parent {
-            self.fmt_refstring(f, ctx, fields[v.0].name)
+            match fields.get(v.0) {
+                Some(field) => self.fmt_refstring(f, ctx, field.name),
+                None => Display::fmt(&v, f),
+            }
         } else {
             Display::fmt(&v, f)
         }
@@ -338,14 +360,10 @@ impl BytecodeFmt for EnhancedFmt {
         parent: &Type,
     ) -> Result {
         match parent {
-            Type::Enum { constructs, .. } => {
-                let name = constructs[v.0].name;
-                if name.0 != 0 {
-                    self.fmt_refstring(f, ctx, name)
-                } else {
-                    Display::fmt(&v, f)
-                }
-            }
+            Type::Enum { constructs, .. } => match constructs.get(v.0) {
+                Some(c) if c.name.0 != 0 => self.fmt_refstring(f, ctx, c.name),
+                _ => Display::fmt(&v, f),
+            },
             _ => Display::fmt(&v, f),
         }
     }
@@ -356,22 +374,22 @@ impl BytecodeFmt for EnhancedFmt {
             Type::Obj(TypeObj { name, .. }) => self.fmt_refstring(f, ctx, *name),
             Type::Ref(reftype) => fmtools::write!(f,
                 "ref<"
-                |f| self.fmt_type(f, ctx, &ctx[*reftype])?;
+                |f| self.fmt_type_of(f, ctx, *reftype)?;
                 ">"
             ),
             Type::Virtual { fields } => fmtools::write!(f,
                 "virtual<"{fmtools::join(", ", fields.iter().map(|fi|
                     fmtools::fmt!{
                         |f| self.fmt_refstring(f, ctx, fi.name)?;": "
-                        match &ctx[fi.t] {
-                            Type::Virtual {..} => {
+                        match ctx.try_resolve(fi.t) {
+                            Ok(Type::Virtual {..}) => {
                                 {v}{fi.t}
                             }
-                            Type::Fun(fun) | Type::Method(fun) => {
+                            Ok(Type::Fun(fun) | Type::Method(fun)) => {
                                 {fun}{fi.t}
                             }
                             _ => {
-                                |f| self.fmt_type(f, ctx, &ctx[fi.t])?;
+                                |f| self.fmt_type_of(f, ctx, fi.t)?;
                             }
                         }
                     }
@@ -401,13 +419,22 @@ impl BytecodeFmt for EnhancedFmt {
 
     fn fmt_typefun(&self, f: &mut Formatter, ctx: &Bytecode, v: &TypeFun) -> Result {
         fmtools::write!(f,
-            "("{fmtools::join(", ", v.args.iter().map(|a| fmt(|f| self.fmt_type(f, ctx, &ctx[*a]))))}
-            ") -> "|f| self.fmt_type(f, ctx, &ctx[v.ret])?;
+            "("{fmtools::join(", ", v.args.iter().map(|a| fmt(|f| self.fmt_type_of(f, ctx, *a))))}
+            ") -> "|f| self.fmt_type_of(f, ctx, v.ret)?;
         )
     }
 
     fn fmt_reffun(&self, f: &mut Formatter, ctx: &Bytecode, v: RefFun) -> Result {
-        write!(f, "{}{}", v.name(ctx), v)
+        let name = match ctx.try_resolve(v) {
+            Ok(FunPtr::Fun(fun)) => fun.name,
+            Ok(FunPtr::Native(n)) => n.name,
+            Err(e) => return fmt_invalid(f, e),
+        };
+        match ctx.try_resolve(name) {
+            Ok(name) => f.write_str(&name)?,
+            Err(e) => fmt_invalid(f, e)?,
+        }
+        Display::fmt(&v, f)
     }
 
     fn fmt_native(&self, f: &mut Formatter, ctx: &Bytecode, v: &Native) -> Result {
@@ -417,7 +444,7 @@ impl BytecodeFmt for EnhancedFmt {
             fmt(|f| self.fmt_refstring(f, ctx, v.lib)),
             fmt(|f| self.fmt_refstring(f, ctx, v.name)),
             v.findex,
-            fmt(|f| self.fmt_type(f, ctx, &ctx[v.t]))
+            fmt(|f| self.fmt_type_of(f, ctx, v.t))
         )
     }
 
@@ -426,7 +453,7 @@ impl BytecodeFmt for EnhancedFmt {
             f,
             "fn {} {}",
             fmt(|f| self.fmt_reffun(f, ctx, v.findex)),
-            fmt(|f| self.fmt_type(f, ctx, &ctx[v.t]))
+            fmt(|f| self.fmt_type_of(f, ctx, v.t))
         )
     }
 
@@ -437,7 +464,7 @@ impl BytecodeFmt for EnhancedFmt {
             fmtools::fmt! {
                 |f| self.fmt_function_header(f, ctx, v)?;" ("{v.regs.len()}" regs, "{v.ops.len()}" ops)\n"
                 for (i, reg) in v.regs.iter().enumerate() {
-                    "    reg"{i:<2}" "|f| self.fmt_type(f, ctx, &ctx[*reg])?;"\n"
+                    "    reg"{i:<2}" "|f| self.fmt_type_of(f, ctx, *reg)?;"\n"
                 }
                 if let Some(debug) = &v.debug_info {
                     for ((i, o), (file, line)) in v.ops
@@ -445,7 +472,7 @@ impl BytecodeFmt for EnhancedFmt {
                         .enumerate()
                         .zip(debug.iter())
                     {
-                        {ctx.debug_files.as_ref().unwrap()[*file]:>12}":"{line:<3}" "{i:>3}": "{o.display(ctx, v, i as i32, 11)}"\n"
+                        {debug_file_name(ctx, *file):>12}":"{line:<3}" "{i:>3}": "{o.display(ctx, v, i as i32, 11)}"\n"
                     }
                 } else {
                     for (i, o) in v.ops
@@ -459,6 +486,21 @@ impl BytecodeFmt for EnhancedFmt {
     }
 }
 
+impl EnhancedFmt {
+    /// Format the type behind a reference, without the @number suffix
+    fn fmt_type_of(&self, f: &mut Formatter, ctx: &Bytecode, v: RefType) -> Result {
+        match ctx.try_resolve(v) {
+            Ok(ty) => self.fmt_type(f, ctx, ty),
+            Err(e) => fmt_invalid(f, e),
+        }
+    }
+}
+
+/// Placeholder displayed in place of an element that couldn't be resolved
+fn fmt_invalid(f: &mut Formatter, err: Error) -> Result {
+    f.write_str(&err.placeholder())
+}
+
 //region Display methods
 // Boilerplate code that makes using [BytecodeFmt] spark a bit more joy.
 
@@ -537,9 +579,10 @@ impl RefFun {
         bcfmt: Fmt,
         ctx: &'a Bytecode,
     ) -> impl Display + 'a {
-        fmt(move |f| match ctx.resolve(*self) {
-            FunPtr::Fun(fun) => bcfmt.fmt_function_header(f, ctx, fun),
-            FunPtr::Native(n) => bcfmt.fmt_native(f, ctx, n),
+        fmt(move |f| match ctx.try_resolve(*self) {
+            Ok(FunPtr::Fun(fun)) => bcfmt.fmt_function_header(f, ctx, fun),
+            Ok(FunPtr::Native(n)) => bcfmt.fmt_native(f, ctx, n),
+            Err(e) => fmt_invalid(f, e),
         })
     }
 
@@ -655,18 +698,20 @@ impl Opcode {
             }
             Opcode::CallMethod { dst, field, args } => {
                 let mut args = args.iter();
-                let arg0 = args.next().unwrap();
+                let Some(arg0) = args.next() else {
+                    return op!("{dst} = <missing this>.{field}()");
+                };
                 op!(
                     "{dst} = {}.{}({})",
                     arg0,
-                    field.display::<EnhancedFmt>(ctx, &ctx[parent[*arg0]]),
+                    field.display::<EnhancedFmt>(ctx, reg_type(ctx, parent, *arg0)),
                     fmtools::join(", ", args)
                 )
             }
             Opcode::CallThis { dst, field, args } => {
                 op!(
                     "{dst} = reg0.{}({})",
-                    field.display::<EnhancedFmt>(ctx, &ctx[parent.regs[0]]),
+                    field.display::<EnhancedFmt>(ctx, reg_type(ctx, parent, Reg(0))),
                     fmtools::join(", ", args)
                 )
             }
@@ -674,10 +719,10 @@ impl Opcode {
                 op!("{dst} = {fun}({})", fmtools::join(", ", args))
             }
             Opcode::StaticClosure { dst, fun } => {
-                op!("{dst} = {:?}", ctx.resolve(*fun))
+                op!("{dst} = {}", fun.display::<EnhancedFmt>(ctx))
             }
             Opcode::InstanceClosure { dst, fun, obj } => {
-                op!("{dst} = {obj}.{:?}", ctx.resolve(*fun))
+                op!("{dst} = {obj}.{}", fun.display::<EnhancedFmt>(ctx))
             }
             Opcode::GetGlobal { dst, global } => {
                 op!("{dst} = global@{}", global.0)
@@ -688,32 +733,32 @@ impl Opcode {
             Opcode::Field { dst, obj, field } => {
                 op!(
                     "{dst} = {obj}.{}",
-                    field.display::<EnhancedFmt>(ctx, &ctx[parent[*obj]])
+                    field.display::<EnhancedFmt>(ctx, reg_type(ctx, parent, *obj))
                 )
             }
             Opcode::SetField { obj, field, src } => {
                 op!(
                     "{obj}.{} = {src}",
-                    field.display::<EnhancedFmt>(ctx, &ctx[parent[*obj]])
+                    field.display::<EnhancedFmt>(ctx, reg_type(ctx, parent, *obj))
                 )
             }
             Opcode::GetThis { dst, field } => {
                 op!(
                     "{dst} = this.{}",
-                    field.display::<EnhancedFmt>(ctx, &ctx[parent.regs[0]])
+                    field.display::<EnhancedFmt>(ctx, reg_type(ctx, parent, Reg(0)))
                 )
             }
             Opcode::SetThis { field, src } => {
                 op!(
                     "this.{} = {src}",
-                    field.display::<EnhancedFmt>(ctx, &ctx[parent.regs[0]])
+                    field.display::<EnhancedFmt>(ctx, reg_type(ctx, parent, Reg(0)))
                 )
             }
             Opcode::DynGet { dst, obj, field } => {
-                op!("{dst} = {obj}[\"{}\"]", ctx[*field])
+                op!("{dst} = {obj}[\"{}\"]", field.display::<EnhancedFmt>(ctx))
             }
             Opcode::DynSet { obj, field, src } => {
-                op!("{obj}[\"{}\"] = {src}", ctx[*field])
+                op!("{obj}[\"{}\"] = {src}", field.display::<EnhancedFmt>(ctx))
             }
             Opcode::JTrue { cond, offset } => {
                 op!("if {cond} == true jump to {}", pos + offset + 1)
@@ -798,7 +843,19 @@ impl Opcode {
                 op!("{array}[{index}] = {src}")
             }
             Opcode::New { dst } => {
-                op!("{dst} = new {}", parent[*dst].display::<EnhancedFmt>(ctx))
+                op!(
+                    "{dst} = new {}",
+                    fmt(|f| match parent.regs.get(dst.0 as usize) {
+                        Some(t) => EnhancedFmt.fmt_reftype(f, ctx, *t),
+                        None => fmt_invalid(
+                            f,
+                            Error::InvalidReference {
+                                kind: "register",
+                                index: dst.0 as usize
+                            }
+                        ),
+                    })
+                )
             }
             Opcode::ArraySize { dst, array } => {
                 op!("{dst} = {array}.length")
@@ -819,14 +876,14 @@ impl Opcode {
             } => {
                 op!(
                     "{dst} = variant {} ({})",
-                    construct.display::<EnhancedFmt>(ctx, &ctx[parent[*dst]]),
+                    construct.display::<EnhancedFmt>(ctx, reg_type(ctx, parent, *dst)),
                     fmtools::join(", ", args)
                 )
             }
             Opcode::EnumAlloc { dst, construct } => {
                 op!(
                     "{dst} = new {}",
-                    construct.display::<EnhancedFmt>(ctx, &ctx[parent[*dst]])
+                    construct.display::<EnhancedFmt>(ctx, reg_type(ctx, parent, *dst))
                 )
             }
             Opcode::EnumIndex { dst, value } => {
@@ -840,7 +897,7 @@ impl Opcode {
             } => {
                 op!(
                     "{dst} = ({value} as {}).{}",
-                    construct.display::<EnhancedFmt>(ctx, &ctx[parent[*value]]),
+                    construct.display::<EnhancedFmt>(ctx, reg_type(ctx, parent, *value)),
                     field.0
                 )
             }
@@ -852,6 +909,31 @@ impl Opcode {
     }
 }
 
+/// Name of a debug file, or a placeholder if it can't be resolved
+fn debug_file_name(ctx: &Bytecode, file: usize) -> Str {
+    ctx.debug_files
+        .as_ref()
+        .and_then(|files| files.get(file))
+        .cloned()
+        .unwrap_or_else(|| {
+            Error::InvalidReference {
+                kind: "debug file",
+                index: file,
+            }
+            .placeholder()
+            .into()
+        })
+}
+
+/// Type of a register, [Type::Void] if it can't be resolved.
+fn reg_type<'a>(ctx: &'a Bytecode, parent: &Function, reg: Reg) -> &'a Type {
+    parent
+        .regs
+        .get(reg.0 as usize)
+        .and_then(|t| ctx.try_resolve(*t).ok())
+        .unwrap_or(&Type::Void)
+}
+
 #[cfg(test)]
 mod test {
     use std::fmt::{Display, Write};
@@ -861,11 +943,67 @@ mod test {
 
     use crate::fmt::{fmt, DisplayFmt, EnhancedFmt};
     use crate::fmt::{BytecodeFmt, DebugFmt};
-    use crate::types::{FunPtr, Reg};
-    use crate::Bytecode;
+    use crate::opcodes::Opcode;
+    use crate::types::{FunPtr, Function, RefField, RefFun, RefString, RefType, Reg, Type};
+    use crate::{Bytecode, Error, Resolve};
 
     struct Null;
 
+    #[test]
+    fn invalid_references() {
+        let code = Bytecode {
+            strings: vec!["".into()],
+            types: vec![Type::Void],
+            ..Default::default()
+        };
+        assert!(matches!(
+            code.try_resolve(RefString(3)),
+            Err(Error::InvalidReference {
+                kind: "string",
+                index: 3
+            })
+        ));
+        assert!(code.try_resolve(RefFun(0)).is_err());
+
+        let f = Function {
+            name: RefString(5),
+            t: RefType(7),
+            findex: RefFun(0),
+            regs: vec![RefType(0), RefType(12)],
+            ops: vec![],
+            debug_info: None,
+            assigns: None,
+            parent: None,
+        };
+        let display = |op: Opcode| op.display(&code, &f, 0, 0).to_string();
+        assert_eq!(
+            display(Opcode::String {
+                dst: Reg(0),
+                ptr: RefString(3)
+            }),
+            "String reg0 = \"<invalid string@3>\""
+        );
+        assert_eq!(
+            display(Opcode::Call0 {
+                dst: Reg(0),
+                fun: RefFun(2)
+            }),
+            "Call0 reg0 = <invalid function@2>()"
+        );
+        assert_eq!(
+            display(Opcode::Field {
+                dst: Reg(0),
+                obj: Reg(1),
+                field: RefField(0)
+            }),
+            "Field reg0 = reg1.<field0>"
+        );
+        assert_eq!(
+            f.display_header::<EnhancedFmt>(&code).to_string(),
+            "fn <invalid function@0> <invalid type@7>"
+        );
+    }
+
     impl Write for Null {
         fn write_str(&mut self, s: &str) -> std::fmt::Result {
             Ok(())
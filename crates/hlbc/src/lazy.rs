@@ -0,0 +1,608 @@
+//! Only the small sections (constants, types, globals, natives) are decoded when opening a file.
+//! Strings and function bodies stay in the underlying buffer and are decoded when first accessed,
+//! then cached. With the `mmap` feature, [LazyBytecode::open_mmap] memory-maps the file, so the parts
+//! that are never accessed aren't even read from disk.
+//!
+//! Use [LazyBytecode::header] to get a [Bytecode] without function bodies, to display names and signatures,
+//! and [LazyBytecode::into_bytecode] to get a regular [Bytecode] when everything is required.
+
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::str::from_utf8;
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use once_cell::sync::OnceCell;
+
+use crate::read::{read_varu, Header};
+use crate::types::{ConstantDef, Function, Native, RefFun, RefGlobal, RefString, RefType, Type};
+use crate::{Bytecode, Error, RefFunKnown, Result, Section, Str};
+
+/// A bytecode file whose strings and function bodies are decoded on first access.
+pub struct LazyBytecode {
+    data: Box<dyn AsRef<[u8]> + Send + Sync>,
+    index: Index,
+    strings_cache: Vec<OnceCell<Str>>,
+    functions_cache: Vec<OnceCell<Function>>,
+}
+
+/// Location of the strings and function bodies in a bytecode file
+pub(crate) struct Index {
+    /// Everything except strings and function bodies. Functions only have their header.
+    code: Bytecode,
+    has_debug: bool,
+    /// Offset and length of each string in the file
+    strings: Vec<(usize, usize)>,
+    /// Offset of each function in the file
+    functions: Vec<usize>,
+}
+
+impl LazyBytecode {
+    /// Read a bytecode file in memory and index it.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::from_bytes(fs::read(path)?)
+    }
+
+    /// Memory-map a bytecode file and index it.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be modified or truncated while the returned value is alive,
+    /// see [memmap2::Mmap::map].
+    #[cfg(feature = "mmap")]
+    pub unsafe fn open_mmap(path: impl AsRef<Path>) -> Result<Self> {
+        let file = fs::File::open(path)?;
+        Self::from_bytes(memmap2::Mmap::map(&file)?)
+    }
+
+    /// Index a bytecode file held in memory.
+    pub fn from_bytes(data: impl AsRef<[u8]> + Send + Sync + 'static) -> Result<Self> {
+        let data: Box<dyn AsRef<[u8]> + Send + Sync> = Box::new(data);
+        let index = Index::read(data.as_ref().as_ref())?;
+        Ok(Self {
+            strings_cache: (0..index.strings.len()).map(|_| OnceCell::new()).collect(),
+            functions_cache: (0..index.functions.len())
+                .map(|_| OnceCell::new())
+                .collect(),
+            data,
+            index,
+        })
+    }
+
+    fn bytes(&self) -> &[u8] {
+        self.data.as_ref().as_ref()
+    }
+
+    pub fn version(&self) -> u8 {
+        self.index.code.version
+    }
+
+    pub fn entrypoint(&self) -> RefFun {
+        self.index.code.entrypoint
+    }
+
+    pub fn ints(&self) -> &[i32] {
+        &self.index.code.ints
+    }
+
+    pub fn floats(&self) -> &[f64] {
+        &self.index.code.floats
+    }
+
+    pub fn debug_files(&self) -> Option<&[Str]> {
+        self.index.code.debug_files.as_deref()
+    }
+
+    pub fn types(&self) -> &[Type] {
+        &self.index.code.types
+    }
+
+    pub fn globals(&self) -> &[RefType] {
+        &self.index.code.globals
+    }
+
+    pub fn natives(&self) -> &[Native] {
+        &self.index.code.natives
+    }
+
+    pub fn constants(&self) -> Option<&[ConstantDef]> {
+        self.index.code.constants.as_deref()
+    }
+
+    /// Constant initializing a global
+    pub fn global_initializer(&self, global: RefGlobal) -> Option<&ConstantDef> {
+        let i = *self.index.code.globals_initializers.get(&global)?;
+        self.index.code.constants.as_ref()?.get(i)
+    }
+
+    pub fn nstrings(&self) -> usize {
+        self.index.nstrings()
+    }
+
+    pub fn nfunctions(&self) -> usize {
+        self.index.nfunctions()
+    }
+
+    /// Get a string, decoding it on first access.
+    pub fn string(&self, s: RefString) -> Result<Str> {
+        let cell = self.strings_cache.get(s.0).ok_or(Error::InvalidReference {
+            kind: "string",
+            index: s.0,
+        })?;
+        if let Some(s) = cell.get() {
+            return Ok(s.clone());
+        }
+        let (offset, len) = self.index.strings[s.0];
+        let s = decode_string(self.bytes(), offset, len)
+            .map_err(|e| e.at(Section::Strings, Some(s.0), offset))?;
+        Ok(cell.get_or_init(|| s).clone())
+    }
+
+    /// Like [LazyBytecode::string], but invalid UTF-8 is replaced instead of failing.
+    /// Panics if the reference is invalid.
+    pub fn string_lossy(&self, s: RefString) -> Str {
+        self.string(s).unwrap_or_else(|_| {
+            let (offset, len) = self.index.strings[s.0];
+            Str::from_ref(String::from_utf8_lossy(&self.bytes()[offset..offset + len]).as_ref())
+        })
+    }
+
+    /// Type and findex of the function at this index, without decoding its body.
+    pub fn function_header(&self, i: usize) -> Option<(RefType, RefFun)> {
+        self.index.code.functions.get(i).map(|f| (f.t, f.findex))
+    }
+
+    /// Get the function at this index (not findex), decoding it on first access.
+    pub fn function(&self, i: usize) -> Result<&Function> {
+        let cell = self.functions_cache.get(i).ok_or(Error::InvalidReference {
+            kind: "function",
+            index: i,
+        })?;
+        if let Some(f) = cell.get() {
+            return Ok(f);
+        }
+        let f = self.index.decode_function(self.bytes(), i)?;
+        Ok(cell.get_or_init(|| f))
+    }
+
+    /// Get a function by its findex, decoding it on first access.
+    /// Returns [None] if it is a native.
+    pub fn function_by_findex(&self, findex: RefFun) -> Result<Option<&Function>> {
+        match self.index.code.findexes.get(findex.0) {
+            Some(&RefFunKnown::Fun(i)) => self.function(i).map(Some),
+            Some(RefFunKnown::Native(_)) => Ok(None),
+            None => Err(Error::InvalidReference {
+                kind: "function",
+                index: findex.0,
+            }),
+        }
+    }
+
+    /// Build a [Bytecode] with every string decoded, but where functions only have their header
+    /// (no registers nor instructions). It is enough to display names and signatures,
+    /// bodies can be decoded with [LazyBytecode::function].
+    pub fn header(&self) -> Result<Bytecode> {
+        let strings = (0..self.nstrings())
+            .map(|i| self.string(RefString(i)))
+            .collect::<Result<_>>()?;
+        Ok(self.header_with(strings))
+    }
+
+    /// Like [LazyBytecode::header], but invalid UTF-8 in strings is replaced instead of failing.
+    pub fn header_lossy(&self) -> Bytecode {
+        let strings = (0..self.nstrings())
+            .map(|i| self.string_lossy(RefString(i)))
+            .collect();
+        self.header_with(strings)
+    }
+
+    fn header_with(&self, strings: Vec<Str>) -> Bytecode {
+        let mut code = Bytecode {
+            strings,
+            ..self.index.code.clone()
+        };
+        code.rebuild_indexes();
+        code
+    }
+
+    /// Like [LazyBytecode::into_bytecode], but keeps the already decoded elements available.
+    pub fn to_bytecode(&self) -> Result<Bytecode> {
+        let strings = self
+            .strings_cache
+            .iter()
+            .map(|c| c.get().cloned())
+            .collect();
+        let functions = self
+            .functions_cache
+            .iter()
+            .map(|c| c.get().cloned())
+            .collect();
+        let (strings, functions) = self.index.decode_all(self.bytes(), strings, functions)?;
+        let mut code = Bytecode {
+            strings,
+            functions,
+            ..self.index.code.clone()
+        };
+        code.rebuild_indexes();
+        Ok(code)
+    }
+
+    /// Decode everything that hasn't been accessed yet and build a regular [Bytecode].
+    /// With the `parallel` feature, decoding is spread over multiple threads.
+    pub fn into_bytecode(self) -> Result<Bytecode> {
+        let strings = self
+            .strings_cache
+            .into_iter()
+            .map(OnceCell::into_inner)
+            .collect();
+        let functions = self
+            .functions_cache
+            .into_iter()
+            .map(OnceCell::into_inner)
+            .collect();
+        self.index
+            .into_bytecode(self.data.as_ref().as_ref(), strings, functions)
+    }
+}
+
+impl Index {
+    pub(crate) fn read(bytes: &[u8]) -> Result<Self> {
+        let r = &mut &bytes[..];
+        let pos = |r: &&[u8]| bytes.len() - r.len();
+
+        // Attach the current location to errors
+        macro_rules! at {
+            ($section:ident, $index:expr, $e:expr) => {
+                match $e {
+                    Ok(v) => v,
+                    Err(e) => return Err(Error::from(e).at(Section::$section, $index, pos(r))),
+                }
+            };
+        }
+
+        let Header {
+            version,
+            has_debug,
+            nints,
+            nfloats,
+            nstrings,
+            nbytes,
+            ntypes,
+            nglobals,
+            nnatives,
+            nfunctions,
+            nconstants,
+            entrypoint,
+        } = at!(Header, None, Header::read(r));
+
+        let mut ints = vec![0i32; nints];
+        for (idx, i) in ints.iter_mut().enumerate() {
+            *i = at!(Ints, Some(idx), r.read_i32::<LittleEndian>());
+        }
+
+        let mut floats = vec![0f64; nfloats];
+        for (idx, i) in floats.iter_mut().enumerate() {
+            *i = at!(Floats, Some(idx), r.read_f64::<LittleEndian>());
+        }
+
+        let strings_start = pos(r) + 4;
+        let strings = at!(Strings, None, index_strings(r, nstrings))
+            .into_iter()
+            .map(|(offset, len)| (strings_start + offset, len))
+            .collect();
+
+        let bytes_section = if let Some(nbytes) = nbytes {
+            let size = at!(Bytes, None, r.read_i32::<LittleEndian>()) as usize;
+            let mut bytes = vec![0; size];
+            at!(Bytes, None, r.read_exact(&mut bytes));
+            let mut positions = Vec::with_capacity(nbytes);
+            for i in 0..nbytes {
+                positions.push(at!(Bytes, Some(i), read_varu(r)) as usize);
+            }
+            Some((bytes, positions))
+        } else {
+            None
+        };
+
+        let debug_files = if has_debug {
+            let n = at!(DebugFiles, None, read_varu(r)) as usize;
+            let start = pos(r) + 4;
+            let files = at!(DebugFiles, None, index_strings(r, n))
+                .into_iter()
+                .enumerate()
+                .map(|(i, (offset, len))| {
+                    decode_string(bytes, start + offset, len)
+                        .map_err(|e| e.at(Section::DebugFiles, Some(i), start + offset))
+                })
+                .collect::<Result<_>>()?;
+            Some(files)
+        } else {
+            None
+        };
+
+        let mut types = Vec::with_capacity(ntypes);
+        for i in 0..ntypes {
+            types.push(at!(Types, Some(i), Type::read(r)));
+        }
+
+        let mut globals = Vec::with_capacity(nglobals);
+        for i in 0..nglobals {
+            globals.push(at!(Globals, Some(i), RefType::read(r)));
+        }
+
+        let mut natives = Vec::with_capacity(nnatives);
+        for i in 0..nnatives {
+            natives.push(at!(Natives, Some(i), Native::read(r)));
+        }
+
+        let mut offsets = Vec::with_capacity(nfunctions);
+        let mut functions = Vec::with_capacity(nfunctions);
+        for i in 0..nfunctions {
+            offsets.push(pos(r));
+            let (t, findex) = at!(Functions, Some(i), Function::skip(r, has_debug, version));
+            functions.push(Function {
+                name: RefString(0),
+                t,
+                findex,
+                regs: Vec::new(),
+                ops: Vec::new(),
+                debug_info: None,
+                assigns: None,
+                parent: None,
+            });
+        }
+
+        let constants = if let Some(n) = nconstants {
+            let mut constants = Vec::with_capacity(n);
+            for i in 0..n {
+                constants.push(at!(Constants, Some(i), ConstantDef::read(r)))
+            }
+            Some(constants)
+        } else {
+            None
+        };
+
+        let mut code = Bytecode {
+            version,
+            entrypoint,
+            ints,
+            floats,
+            bytes: bytes_section,
+            debug_files,
+            types,
+            globals,
+            natives,
+            functions,
+            constants,
+            ..Default::default()
+        };
+        // Gives functions their name and parent
+        code.rebuild_indexes();
+
+        Ok(Self {
+            code,
+            has_debug,
+            strings,
+            functions: offsets,
+        })
+    }
+
+    pub(crate) fn nstrings(&self) -> usize {
+        self.strings.len()
+    }
+
+    pub(crate) fn nfunctions(&self) -> usize {
+        self.functions.len()
+    }
+
+    /// Decode a function body, taking its name and parent from the indexed header
+    fn decode_function(&self, bytes: &[u8], i: usize) -> Result<Function> {
+        let r = &mut &bytes[self.functions[i]..];
+        let mut f = Function::read(r, self.has_debug, self.code.version)
+            .map_err(|e| e.at(Section::Functions, Some(i), bytes.len() - r.len()))?;
+        let header = &self.code.functions[i];
+        f.name = header.name;
+        f.parent = header.parent;
+        Ok(f)
+    }
+
+    /// Build a regular [Bytecode], decoding every string and function that isn't already.
+    /// With the `parallel` feature, decoding is spread over multiple threads.
+    pub(crate) fn into_bytecode(
+        mut self,
+        bytes: &[u8],
+        strings: Vec<Option<Str>>,
+        functions: Vec<Option<Function>>,
+    ) -> Result<Bytecode> {
+        let (strings, functions) = self.decode_all(bytes, strings, functions)?;
+        self.code.strings = strings;
+        self.code.functions = functions;
+        self.code.rebuild_indexes();
+        Ok(self.code)
+    }
+
+    /// Decode every string and function that isn't already.
+    fn decode_all(
+        &self,
+        bytes: &[u8],
+        strings: Vec<Option<Str>>,
+        functions: Vec<Option<Function>>,
+    ) -> Result<(Vec<Str>, Vec<Function>)> {
+        #[cfg(feature = "parallel")]
+        use rayon::prelude::*;
+
+        // Iterate on a collection, in parallel if possible
+        macro_rules! iter {
+            ($e:expr) => {{
+                #[cfg(feature = "parallel")]
+                {
+                    $e.into_par_iter()
+                }
+                #[cfg(not(feature = "parallel"))]
+                {
+                    $e.into_iter()
+                }
+            }};
+        }
+
+        let strings = iter!(strings)
+            .zip(iter!(&self.strings))
+            .enumerate()
+            .map(|(i, (s, &(offset, len)))| match s {
+                Some(s) => Ok(s),
+                None => decode_string(bytes, offset, len)
+                    .map_err(|e| e.at(Section::Strings, Some(i), offset)),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let functions = iter!(functions)
+            .enumerate()
+            .map(|(i, f)| match f {
+                Some(f) => Ok(f),
+                None => self.decode_function(bytes, i),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok((strings, functions))
+    }
+}
+
+/// Read a strings section, returning the offset (relative to the start of the string data) and length of each string.
+fn index_strings(r: &mut &[u8], nstrings: usize) -> Result<Vec<(usize, usize)>> {
+    let size = r.read_i32::<LittleEndian>()? as usize;
+    if size > r.len() {
+        return Err(Error::malformed(format!(
+            "Strings data is {size} bytes long but only {} bytes remain",
+            r.len()
+        )));
+    }
+    *r = &r[size..];
+    let mut strings = Vec::with_capacity(nstrings);
+    let mut acc = 0;
+    for _ in 0..nstrings {
+        let ssize = read_varu(r)? as usize + 1;
+        if acc + ssize > size {
+            return Err(Error::malformed(format!(
+                "String of size {ssize} at offset {acc} is out of the strings data"
+            )));
+        }
+        strings.push((acc, ssize - 1));
+        acc += ssize;
+    }
+    Ok(strings)
+}
+
+fn decode_string(bytes: &[u8], offset: usize, len: usize) -> Result<Str> {
+    Ok(Str::from_ref(from_utf8(&bytes[offset..offset + len])?))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::builder::BytecodeBuilder;
+    use crate::lazy::LazyBytecode;
+    use crate::opcodes::Opcode;
+    use crate::types::{RefFun, RefString, Reg, Type};
+    use crate::Bytecode;
+
+    fn sample() -> Vec<u8> {
+        let mut builder = BytecodeBuilder::new();
+        let void = builder.intern_type(Type::Void);
+        let i32 = builder.intern_type(Type::I32);
+        let bytes = builder.intern_type(Type::Bytes);
+        let t = builder.fun_type(vec![], void);
+        builder.debug_file("Main.hx");
+        for i in 0..10 {
+            let s = builder.string(&format!("string {i}"));
+            let k = builder.int(i);
+            let f = builder.add_function(
+                t,
+                vec![void, i32, bytes],
+                vec![
+                    Opcode::Int {
+                        dst: Reg(1),
+                        ptr: k,
+                    },
+                    Opcode::String {
+                        dst: Reg(2),
+                        ptr: s,
+                    },
+                    Opcode::CallN {
+                        dst: Reg(0),
+                        fun: RefFun(0),
+                        args: vec![Reg(1), Reg(2)],
+                    },
+                    Opcode::Switch {
+                        reg: Reg(1),
+                        offsets: vec![1, 2],
+                        end: 0,
+                    },
+                    Opcode::Ret { ret: Reg(0) },
+                ],
+            );
+            builder.set_entrypoint(f);
+        }
+        let mut data = Vec::new();
+        builder.build().serialize(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn lazy_equals_eager() {
+        let data = sample();
+        let code = Bytecode::deserialize(&mut &data[..]).unwrap();
+        let lazy = LazyBytecode::from_bytes(data).unwrap();
+        assert_eq!(lazy.nstrings(), code.strings.len());
+        assert_eq!(lazy.nfunctions(), code.functions.len());
+        assert_eq!(lazy.types().len(), code.types.len());
+        assert_eq!(lazy.entrypoint(), code.entrypoint);
+
+        for (i, s) in code.strings.iter().enumerate() {
+            assert_eq!(&lazy.string(RefString(i)).unwrap(), s);
+        }
+        // Access in reverse order to make sure functions are decoded independently
+        for (i, f) in code.functions.iter().enumerate().rev() {
+            let lf = lazy.function(i).unwrap();
+            assert_eq!(lf.findex, f.findex);
+            assert_eq!(lf.regs, f.regs);
+            assert_eq!(format!("{:?}", lf.ops), format!("{:?}", f.ops));
+            assert_eq!(lf.debug_info, f.debug_info);
+            assert_eq!(lf.assigns, f.assigns);
+            assert_eq!(lf.name, f.name);
+            assert_eq!(lf.parent, f.parent);
+        }
+        assert!(lazy.function(code.functions.len()).is_err());
+        assert!(lazy.string(RefString(code.strings.len())).is_err());
+
+        let header = lazy.header().unwrap();
+        assert_eq!(header.strings, code.strings);
+        assert_eq!(header.fnames, code.fnames);
+        assert!(header.functions.iter().all(|f| f.ops.is_empty()));
+        let copy = lazy.to_bytecode().unwrap();
+        assert_eq!(
+            format!("{:?}", copy.functions),
+            format!("{:?}", code.functions)
+        );
+
+        let decoded = lazy.into_bytecode().unwrap();
+        assert_eq!(decoded.strings, code.strings);
+        assert_eq!(decoded.fnames, code.fnames);
+        for (a, b) in decoded.functions.iter().zip(&code.functions) {
+            assert_eq!(format!("{:?}", a.ops), format!("{:?}", b.ops));
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "parallel")]
+    fn parallel_equals_serial() {
+        let data = sample();
+        let serial = Bytecode::deserialize(&mut &data[..]).unwrap();
+        let parallel = Bytecode::deserialize_parallel(&data).unwrap();
+        assert_eq!(parallel.strings, serial.strings);
+        assert_eq!(parallel.fnames, serial.fnames);
+        assert_eq!(
+            format!("{:?}", parallel.functions),
+            format!("{:?}", serial.functions)
+        );
+        let mut reserialized = Vec::new();
+        parallel.serialize(&mut reserialized).unwrap();
+        assert_eq!(reserialized, data);
+    }
+}
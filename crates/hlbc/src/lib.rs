@@ -4,31 +4,49 @@
 //! #### Note about safety
 //! We don't deal with self-references, hence we deal with indexes into structures.
 //! Be careful when calling functions on Ref* objects, as no bound checking is done and every index is assumed to be valid.
+//! Use [Resolve::try_resolve] to resolve references that might be invalid,
+//! or [Bytecode::verify] to check every reference beforehand.
 
 extern crate core;
 
-use std::collections::HashMap;
-use std::fmt::Debug;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{Debug, Display, Formatter};
 use std::ops::Index;
 
 use crate::opcodes::Opcode;
 use crate::types::{
-    ConstantDef, FunPtr, Function, Native, ObjField, RefFloat, RefFun, RefGlobal, RefInt,
+    ConstantDef, FunPtr, Function, Native, ObjField, RefBytes, RefFloat, RefFun, RefGlobal, RefInt,
     RefString, RefType, Type, TypeObj,
 };
 
 /// Analysis functions and callgraph generation
 pub mod analysis;
+/// Text assembler and matching disassembler for functions
+pub mod asm;
+/// Constructing and patching bytecode
+pub mod builder;
+/// Compare two bytecode files by matching elements by name
+pub mod diff;
 pub mod fmt;
+/// Lazy loading of bytecode files
+pub mod lazy;
+/// Merge a bytecode into another
+pub mod link;
 /// Opcodes definitions.
 pub mod opcodes;
 /// All about reading bytecode
 mod read;
+/// Update the references to bytecode elements after they are moved
+pub mod remap;
+/// Remove unused elements and instructions to produce a smaller bytecode
+pub mod strip;
 /// Bytecode elements definitions.
 /// All the Ref* types in this modules are references to bytecode elements like constants or function.
 /// They are required since we cannot use rust references as that would make our structure self-referential.
 /// They makes the code look a bit more complicated than it actually is. Every Ref* struct is cheaply copyable.
 pub mod types;
+/// Bytecode verification
+pub mod verify;
 /// All about writing bytecode
 mod write;
 
@@ -40,12 +58,33 @@ pub type Str = flexstr::SharedStr;
 
 pub type Result<T> = core::result::Result<T, Error>;
 
+/// Oldest bytecode version that can be read and written
+pub const MIN_VERSION: u8 = 1;
+/// Latest bytecode version that can be read and written
+pub const MAX_VERSION: u8 = 5;
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
-    #[error("Malformed bytecode: {0}")]
-    MalformedBytecode(String),
+    #[error("Malformed bytecode: {msg}{}", .location.map(|l| format!(" ({l})")).unwrap_or_default())]
+    MalformedBytecode {
+        msg: String,
+        /// Where the error happened in the file, if known
+        location: Option<Location>,
+    },
     #[error("Unsupported bytecode version {version} (expected {min} <= version <= {max})")]
     UnsupportedVersion { version: u8, min: u8, max: u8 },
+    #[error("Bytecode version {version} can't contain {what} (requires version >= {min})")]
+    IncompatibleVersion {
+        version: u8,
+        what: &'static str,
+        min: u8,
+    },
+    #[error("Assembly error at line {line}: {msg}")]
+    AssemblyError { line: usize, msg: String },
+    #[error("Link error: {msg}")]
+    LinkError { msg: String },
+    #[error("Invalid {kind} reference @{index}")]
+    InvalidReference { kind: &'static str, index: usize },
     #[error("Value '{value}' is too big to be serialized (expected < {limit})")]
     ValueOutOfBounds { value: i32, limit: u32 },
     #[error(transparent)]
@@ -54,11 +93,111 @@ pub enum Error {
     Utf8Error(#[from] core::str::Utf8Error),
 }
 
+impl Error {
+    pub(crate) fn malformed(msg: impl Into<String>) -> Self {
+        Error::MalformedBytecode {
+            msg: msg.into(),
+            location: None,
+        }
+    }
+
+    /// Attach a location to an error happening while reading a file.
+    /// Unexpected EOF and invalid UTF-8 are reported as [Error::MalformedBytecode].
+    /// An error that already has a location keeps it.
+    pub(crate) fn at(self, section: Section, index: Option<usize>, offset: usize) -> Self {
+        let location = Some(Location {
+            section,
+            index,
+            offset,
+        });
+        match self {
+            Error::MalformedBytecode {
+                msg,
+                location: None,
+            } => Error::MalformedBytecode { msg, location },
+            Error::IoError(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                Error::MalformedBytecode {
+                    msg: String::from("Unexpected end of file"),
+                    location,
+                }
+            }
+            Error::Utf8Error(e) => Error::MalformedBytecode {
+                msg: format!("Invalid string: {e}"),
+                location,
+            },
+            e => e,
+        }
+    }
+
+    /// Short text to display in place of an element that couldn't be resolved
+    pub fn placeholder(&self) -> String {
+        match self {
+            Error::InvalidReference { kind, index } => format!("<invalid {kind}@{index}>"),
+            e => format!("<{e}>"),
+        }
+    }
+}
+
+/// Part of a bytecode file, in the order they appear
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Section {
+    Header,
+    Ints,
+    Floats,
+    Strings,
+    Bytes,
+    DebugFiles,
+    Types,
+    Globals,
+    Natives,
+    Functions,
+    Constants,
+}
+
+impl Display for Section {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Section::Header => "header",
+            Section::Ints => "ints",
+            Section::Floats => "floats",
+            Section::Strings => "strings",
+            Section::Bytes => "bytes",
+            Section::DebugFiles => "debug files",
+            Section::Types => "types",
+            Section::Globals => "globals",
+            Section::Natives => "natives",
+            Section::Functions => "functions",
+            Section::Constants => "constants",
+        })
+    }
+}
+
+/// Position of an error in a bytecode file
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Location {
+    pub section: Section,
+    /// Index of the element being read in the section
+    pub index: Option<usize>,
+    /// Offset in bytes from the start of the file
+    pub offset: usize,
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "in {}", self.section)?;
+        if let Some(index) = self.index {
+            write!(f, " #{index}")?;
+        }
+        write!(f, " at offset {:#x}", self.offset)
+    }
+}
+
 /// Bytecode structure containing all the information.
 /// Every field is public for flexibility, but you aren't encouraged to modify them.
 ///
 /// This type is like an arena, you usually work with custom
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Bytecode {
     /// Bytecode format version
     pub version: u8,
@@ -92,12 +231,56 @@ pub struct Bytecode {
     // Fields below are not part of the data.
     // Those are acceleration structures used to speed up lookup.
     /// Acceleration structure mapping function references (findex) to functions indexes in the native or function pool.
+    #[cfg_attr(feature = "serde", serde(skip))]
     findexes: Vec<RefFunKnown>,
     /// Acceleration structure mapping function names to function indexes in the function pool
+    #[cfg_attr(feature = "serde", serde(skip))]
     pub fnames: HashMap<Str, usize>,
+    #[cfg_attr(feature = "serde", serde(skip))]
     pub globals_initializers: HashMap<RefGlobal, usize>,
 }
 
+/// Only the data is deserialized, the acceleration structures are rebuilt afterwards.
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Bytecode {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> core::result::Result<Self, D::Error> {
+        #[derive(serde::Deserialize)]
+        struct Data {
+            version: u8,
+            entrypoint: RefFun,
+            ints: Vec<i32>,
+            floats: Vec<f64>,
+            strings: Vec<Str>,
+            bytes: Option<(Vec<u8>, Vec<usize>)>,
+            debug_files: Option<Vec<Str>>,
+            types: Vec<Type>,
+            globals: Vec<RefType>,
+            natives: Vec<Native>,
+            functions: Vec<Function>,
+            constants: Option<Vec<ConstantDef>>,
+        }
+
+        let data = Data::deserialize(d)?;
+        let mut code = Bytecode {
+            version: data.version,
+            entrypoint: data.entrypoint,
+            ints: data.ints,
+            floats: data.floats,
+            strings: data.strings,
+            bytes: data.bytes,
+            debug_files: data.debug_files,
+            types: data.types,
+            globals: data.globals,
+            natives: data.natives,
+            functions: data.functions,
+            constants: data.constants,
+            ..Default::default()
+        };
+        code.rebuild_indexes();
+        Ok(code)
+    }
+}
+
 impl Bytecode {
     /// Get the entrypoint function.
     pub fn entrypoint(&self) -> &Function {
@@ -122,6 +305,101 @@ impl Bytecode {
     pub fn functions<'a>(&'a self) -> impl Iterator<Item = FunPtr<'a>> + 'a {
         (0..self.findex_max()).map(RefFun).map(|r| self.resolve(r))
     }
+
+    /// Rebuild the acceleration structures and the derived data (flattened object fields, function names and parents).
+    /// This must be called after modifying the bytecode for lookups to stay correct.
+    /// [builder::BytecodeBuilder] does it for you.
+    pub fn rebuild_indexes(&mut self) {
+        // Global function indexes
+        let len = self
+            .functions
+            .iter()
+            .map(|f| f.findex.0 + 1)
+            .chain(self.natives.iter().map(|n| n.findex.0 + 1))
+            .max()
+            .unwrap_or(0);
+        self.findexes = vec![RefFunKnown::Fun(0); len];
+        for (i, f) in self.functions.iter().enumerate() {
+            self.findexes[f.findex.0] = RefFunKnown::Fun(i);
+        }
+        for (i, n) in self.natives.iter().enumerate() {
+            self.findexes[n.findex.0] = RefFunKnown::Native(i);
+        }
+
+        // Flatten types fields
+        // Start by collecting every fields in the hierarchy
+        // The order is important because we refer to fields by index
+        let types = &self.types;
+        let mut new_fields: Vec<Option<Vec<ObjField>>> = Vec::with_capacity(types.len());
+        for t in types {
+            if let Some(obj) = t.get_type_obj() {
+                let mut parent = obj.super_.and_then(|s| types.get(s.0));
+                let mut acc = VecDeque::with_capacity(obj.own_fields.len());
+                acc.extend(obj.own_fields.clone());
+                while let Some(p) = parent.and_then(|t| t.get_type_obj()) {
+                    for f in p.own_fields.iter().rev() {
+                        acc.push_front(f.clone());
+                    }
+                    parent = p.super_.and_then(|s| types.get(s.0));
+                }
+                new_fields.push(Some(acc.into()));
+            } else {
+                new_fields.push(None);
+            }
+        }
+        // Apply new fields
+        for (t, new) in self.types.iter_mut().zip(new_fields) {
+            if let Some(fields) = new {
+                t.get_type_obj_mut().unwrap().fields = fields;
+            }
+        }
+
+        // Give functions name based on object fields bindings and methods
+        for (i, t) in self.types.iter().enumerate() {
+            if let Some(TypeObj {
+                protos,
+                bindings,
+                fields,
+                ..
+            }) = t.get_type_obj()
+            {
+                for p in protos {
+                    if let Some(&RefFunKnown::Fun(x)) = self.findexes.get(p.findex.0) {
+                        self.functions[x].name = p.name;
+                        self.functions[x].parent = Some(RefType(i));
+                    }
+                }
+                for (fid, findex) in bindings {
+                    if let Some(field) = fields.get(fid.0) {
+                        if let Some(&RefFunKnown::Fun(x)) = self.findexes.get(findex.0) {
+                            self.functions[x].name = field.name;
+                            self.functions[x].parent = Some(RefType(i));
+                        }
+                    }
+                }
+            }
+        }
+
+        // Function names
+        self.fnames = HashMap::with_capacity(self.functions.len());
+        for (i, f) in self.functions.iter().enumerate() {
+            // FIXME duplicates ?
+            if let Some(name) = self.strings.get(f.name.0) {
+                self.fnames.insert(name.clone(), i);
+            }
+        }
+        if let Some(&RefFunKnown::Fun(x)) = self.findexes.get(self.entrypoint.0) {
+            self.fnames.insert(Str::from("init"), x);
+        }
+
+        self.globals_initializers = self
+            .constants
+            .iter()
+            .flatten()
+            .enumerate()
+            .map(|(i, c)| (c.global, i))
+            .collect();
+    }
 }
 
 impl Default for Bytecode {
@@ -155,12 +433,27 @@ enum RefFunKnown {
 
 //region Resolve
 
+/// Resolve a reference to a bytecode element.
 pub trait Resolve<I> {
     type Output<'a>
     where
         Self: 'a;
 
+    /// ### Panics
+    /// Panics if the reference is invalid, see [Resolve::try_resolve] for a fallible version.
     fn resolve(&self, index: I) -> Self::Output<'_>;
+
+    /// Fallible version of [Resolve::resolve], returns [Error::InvalidReference] instead of panicking.
+    ///
+    /// The default implementation calls [Resolve::resolve] and never fails,
+    /// implementors should override it to check the reference.
+    fn try_resolve(&self, index: I) -> Result<Self::Output<'_>> {
+        Ok(self.resolve(index))
+    }
+}
+
+fn invalid_ref(kind: &'static str, index: usize) -> Error {
+    Error::InvalidReference { kind, index }
 }
 
 impl Resolve<RefInt> for Bytecode {
@@ -169,6 +462,13 @@ impl Resolve<RefInt> for Bytecode {
     fn resolve(&self, index: RefInt) -> Self::Output<'_> {
         self.ints[index.0]
     }
+
+    fn try_resolve(&self, index: RefInt) -> Result<Self::Output<'_>> {
+        self.ints
+            .get(index.0)
+            .copied()
+            .ok_or_else(|| invalid_ref("int", index.0))
+    }
 }
 
 impl Resolve<RefFloat> for Bytecode {
@@ -177,6 +477,35 @@ impl Resolve<RefFloat> for Bytecode {
     fn resolve(&self, index: RefFloat) -> Self::Output<'_> {
         self.floats[index.0]
     }
+
+    fn try_resolve(&self, index: RefFloat) -> Result<Self::Output<'_>> {
+        self.floats
+            .get(index.0)
+            .copied()
+            .ok_or_else(|| invalid_ref("float", index.0))
+    }
+}
+
+impl Resolve<RefBytes> for Bytecode {
+    type Output<'a> = &'a [u8];
+
+    fn resolve(&self, index: RefBytes) -> Self::Output<'_> {
+        self.try_resolve(index).unwrap()
+    }
+
+    fn try_resolve(&self, index: RefBytes) -> Result<Self::Output<'_>> {
+        let (bytes, pos) = self
+            .bytes
+            .as_ref()
+            .ok_or_else(|| invalid_ref("bytes", index.0))?;
+        let start = *pos
+            .get(index.0)
+            .ok_or_else(|| invalid_ref("bytes", index.0))?;
+        let end = pos.get(index.0 + 1).copied().unwrap_or(bytes.len());
+        bytes
+            .get(start..end)
+            .ok_or_else(|| invalid_ref("bytes", index.0))
+    }
 }
 
 impl Resolve<RefString> for Bytecode {
@@ -189,6 +518,17 @@ impl Resolve<RefString> for Bytecode {
             Str::from_static("<none>")
         }
     }
+
+    fn try_resolve(&self, index: RefString) -> Result<Self::Output<'_>> {
+        if index.0 > 0 {
+            self.strings
+                .get(index.0)
+                .cloned()
+                .ok_or_else(|| invalid_ref("string", index.0))
+        } else {
+            Ok(Str::from_static("<none>"))
+        }
+    }
 }
 
 impl Resolve<RefType> for Bytecode {
@@ -197,6 +537,28 @@ impl Resolve<RefType> for Bytecode {
     fn resolve(&self, index: RefType) -> Self::Output<'_> {
         &self.types[index.0]
     }
+
+    fn try_resolve(&self, index: RefType) -> Result<Self::Output<'_>> {
+        self.types
+            .get(index.0)
+            .ok_or_else(|| invalid_ref("type", index.0))
+    }
+}
+
+impl Resolve<RefGlobal> for Bytecode {
+    type Output<'a> = RefType;
+
+    /// Get the type of a global
+    fn resolve(&self, index: RefGlobal) -> Self::Output<'_> {
+        self.globals[index.0]
+    }
+
+    fn try_resolve(&self, index: RefGlobal) -> Result<Self::Output<'_>> {
+        self.globals
+            .get(index.0)
+            .copied()
+            .ok_or_else(|| invalid_ref("global", index.0))
+    }
 }
 
 impl Resolve<RefFun> for Bytecode {
@@ -208,6 +570,15 @@ impl Resolve<RefFun> for Bytecode {
             RefFunKnown::Native(n) => FunPtr::Native(&self.natives[n]),
         }
     }
+
+    fn try_resolve(&self, index: RefFun) -> Result<Self::Output<'_>> {
+        match self.findexes.get(index.0) {
+            Some(RefFunKnown::Fun(fun)) => self.functions.get(*fun).map(FunPtr::Fun),
+            Some(RefFunKnown::Native(n)) => self.natives.get(*n).map(FunPtr::Native),
+            None => None,
+        }
+        .ok_or_else(|| invalid_ref("function", index.0))
+    }
 }
 
 //endregion
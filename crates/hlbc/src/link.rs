@@ -0,0 +1,618 @@
+//! Elements of the module are matched with the ones of the base :
+//! - strings, ints, floats and debug files by value
+//! - classes, structs, enums and abstracts by name. Their definitions must be identical, the module should be
+//!   compiled without dead code elimination so the classes it shares with the base keep all their fields and methods.
+//! - other types by structure
+//! - globals through the class or enum they belong to
+//! - natives by library and name
+//! - methods by class and name
+//!
+//! Matched elements are shared, the other ones are appended to the base. Every reference of the module is remapped.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use crate::diff::{function_name, type_definition, type_name};
+use crate::remap::Remapping;
+use crate::types::{Function, ObjField, RefFun, RefString, RefType, Reg, Type, TypeFun};
+use crate::{Bytecode, Error, Opcode, Result};
+
+#[derive(Debug, Clone, Default)]
+pub struct LinkOptions {
+    /// Methods of the base replaced by the method with the same name in the module, as `Class.method`.
+    /// A class name alone replaces every method of this class defined in the module.
+    pub replace: Vec<String>,
+    /// Call the entrypoint of the module at the start of the entrypoint of the base.
+    /// The module entrypoint initializes the globals of the module, then calls its `main`.
+    pub call_entrypoint: bool,
+}
+
+/// Summary of a [link]
+#[derive(Debug, Clone, Default)]
+pub struct LinkResult {
+    pub types_added: usize,
+    pub types_merged: usize,
+    pub globals_added: usize,
+    pub natives_added: usize,
+    pub functions_added: usize,
+    /// Methods of the module already in the base, the base version is kept
+    pub functions_merged: usize,
+    /// Methods of the base replaced by the ones of the module
+    pub replaced: Vec<String>,
+    /// Entrypoint of the module in the linked bytecode.
+    /// It initializes the globals of the module and must be called before its classes are used,
+    /// see [LinkOptions::call_entrypoint].
+    pub entrypoint: RefFun,
+}
+
+/// Merge `module` into `base`, see the [module documentation](self).
+///
+/// The entrypoint of the base is kept. Debug information of the module is dropped if the base has none.
+/// Linking fails if a named type is defined differently in the module.
+/// Nothing is changed if an error is returned.
+pub fn link(
+    base: &mut Bytecode,
+    mut module: Bytecode,
+    options: &LinkOptions,
+) -> Result<LinkResult> {
+    let error = |msg: String| Error::LinkError { msg };
+    if base.bytes.is_some() != module.bytes.is_some() {
+        return Err(error(format!(
+            "can't link bytecode version {} into version {}, bytes are stored differently",
+            module.version, base.version
+        )));
+    }
+    let mut result = LinkResult::default();
+
+    let (strings, new_strings) = merge_pool(&base.strings, &module.strings, Clone::clone);
+    let (ints, new_ints) = merge_pool(&base.ints, &module.ints, |&i| i);
+    let (floats, new_floats) = merge_pool(&base.floats, &module.floats, |f| f.to_bits());
+    let bytes = base
+        .bytes
+        .as_ref()
+        .zip(module.bytes.as_ref())
+        .map(|((_, base), (_, module))| Remapping::offset(module.len(), base.len()));
+    let (debug_files, new_debug_files) = match (&base.debug_files, &module.debug_files) {
+        (Some(base), Some(module)) => {
+            let (map, new) = merge_pool(base, module, Clone::clone);
+            (Some(map), new)
+        }
+        _ => (None, Vec::new()),
+    };
+
+    let (types, new_types) = merge_types(base, &module, &strings, &mut result)?;
+
+    // Globals of the merged classes and enums are shared
+    let mut globals = vec![None; module.globals.len()];
+    for (i, t) in module.types.iter().enumerate() {
+        if let (Some(g), Some(b)) = (
+            type_global(t),
+            base.types.get(types[i]).and_then(type_global),
+        ) {
+            if let Some(global) = globals.get_mut(g) {
+                *global = Some(b);
+            }
+        }
+    }
+    let mut new_globals = Vec::new();
+    let globals: Vec<usize> = globals
+        .into_iter()
+        .enumerate()
+        .map(|(i, g)| {
+            g.unwrap_or_else(|| {
+                new_globals.push(i);
+                base.globals.len() + new_globals.len() - 1
+            })
+        })
+        .collect();
+    result.globals_added = new_globals.len();
+
+    // Functions and natives share the same index space
+    let mut functions = vec![usize::MAX; module.findex_max()];
+    let mut next = base.findex_max();
+
+    let base_natives: HashMap<_, RefFun> = base
+        .natives
+        .iter()
+        .map(|n| ((n.lib(base), n.name(base)), n.findex))
+        .collect();
+    let mut new_natives = Vec::new();
+    for (i, n) in module.natives.iter().enumerate() {
+        functions[n.findex.0] = match base_natives.get(&(n.lib(&module), n.name(&module))) {
+            Some(f) => f.0,
+            None => {
+                new_natives.push(i);
+                next += 1;
+                next - 1
+            }
+        };
+    }
+    result.natives_added = new_natives.len();
+
+    let base_methods = methods_by_name(base);
+    let module_methods = methods_by_name(&module);
+    let mut used = vec![false; options.replace.len()];
+    let mut new_functions = Vec::new();
+    // Module function replacing a base function, by index in the functions
+    let mut replaced = Vec::new();
+    for (i, f) in module.functions.iter().enumerate() {
+        let matched = f
+            .parent
+            .filter(|p| types[p.0] < base.types.len())
+            .and_then(|_| function_name(&module, f))
+            .filter(|name| module_methods.get(name) == Some(&Some(i)))
+            .and_then(|name| Some((base_methods.get(&name).copied()??, name)));
+        match matched {
+            Some((b, name)) => {
+                functions[f.findex.0] = base.functions[b].findex.0;
+                let mut replace = false;
+                for (r, used) in options.replace.iter().zip(&mut used) {
+                    if replace_matches(r, &name) {
+                        *used = true;
+                        replace = true;
+                    }
+                }
+                if replace {
+                    if types[f.t.0] != base.functions[b].t.0 {
+                        return Err(error(format!(
+                            "{} has a different signature in the module",
+                            name
+                        )));
+                    }
+                    replaced.push((i, b));
+                    result.replaced.push(name);
+                } else {
+                    result.functions_merged += 1;
+                }
+            }
+            None => {
+                functions[f.findex.0] = next;
+                next += 1;
+                new_functions.push(i);
+            }
+        }
+    }
+    result.functions_added = new_functions.len();
+    if let Some((r, _)) = options.replace.iter().zip(used).find(|(_, used)| !used) {
+        return Err(error(format!(
+            "no method of the module matching '{}' is in the base",
+            r
+        )));
+    }
+    result.entrypoint = RefFun(functions[module.entrypoint.0]);
+
+    // Every mapping is known, we can now modify the bytecodes
+    if debug_files.is_none() {
+        for f in &mut module.functions {
+            f.debug_info = None;
+        }
+    }
+    let base_globals = base.globals.len();
+    Remapping {
+        types: Some(types),
+        strings: Some(strings),
+        ints: Some(ints),
+        floats: Some(floats),
+        bytes,
+        globals: Some(globals),
+        functions: Some(functions),
+        debug_files,
+    }
+    .apply(&mut module);
+
+    base.strings.extend(new_strings);
+    base.ints.extend(new_ints);
+    base.floats.extend(new_floats);
+    if let (Some((data, pos)), Some((module_data, module_pos))) = (&mut base.bytes, module.bytes) {
+        let offset = data.len();
+        pos.extend(module_pos.into_iter().map(|p| p + offset));
+        data.extend(module_data);
+    }
+    if let Some(files) = &mut base.debug_files {
+        files.extend(new_debug_files);
+    }
+    for i in new_types {
+        base.types
+            .push(std::mem::replace(&mut module.types[i], Type::Void));
+    }
+    for i in new_globals {
+        base.globals.push(module.globals[i]);
+    }
+    for c in module.constants.into_iter().flatten() {
+        if c.global.0 >= base_globals {
+            base.constants.get_or_insert_with(Vec::new).push(c);
+        }
+    }
+    for i in new_natives {
+        base.natives.push(module.natives[i].clone());
+    }
+
+    let mut module_functions: Vec<_> = module.functions.into_iter().map(Some).collect();
+    let has_debug = base.debug_files.is_some();
+    let mut take = |i: usize| {
+        let mut f: Function = module_functions[i].take().unwrap();
+        // Every function must have debug information if the bytecode has some
+        if has_debug && f.debug_info.is_none() {
+            f.debug_info = Some(vec![(0, 0); f.ops.len()]);
+        }
+        f
+    };
+    for i in new_functions {
+        base.functions.push(take(i));
+    }
+    for (i, b) in replaced {
+        base.functions[b] = take(i);
+    }
+    if options.call_entrypoint {
+        call_at_entrypoint(base, result.entrypoint);
+    }
+
+    base.rebuild_indexes();
+    Ok(result)
+}
+
+/// Insert a call to a function before the first instruction of the entrypoint
+fn call_at_entrypoint(code: &mut Bytecode, fun: RefFun) {
+    let Some(ret) = code
+        .functions
+        .iter()
+        .find(|f| f.findex == fun)
+        .and_then(|f| f.t.as_fun(code))
+        .map(|t| t.ret)
+    else {
+        return;
+    };
+    let entrypoint = code.entrypoint;
+    let Some(f) = code.functions.iter_mut().find(|f| f.findex == entrypoint) else {
+        return;
+    };
+    let dst = Reg(f.regs.len() as u32);
+    f.regs.push(ret);
+    // Jump offsets are relative, they stay valid
+    f.ops.insert(0, Opcode::Call0 { dst, fun });
+    if let Some(debug_info) = &mut f.debug_info {
+        let first = debug_info.first().copied().unwrap_or_default();
+        debug_info.insert(0, first);
+    }
+    // Positions are offset by one, 0 is for arguments
+    for (_, pos) in f.assigns.iter_mut().flatten() {
+        if *pos > 0 {
+            *pos += 1;
+        }
+    }
+}
+
+/// Index of each element of `other` in `base`, and the elements to append to `base`
+fn merge_pool<T: Clone, K: Eq + Hash>(
+    base: &[T],
+    other: &[T],
+    key: impl Fn(&T) -> K,
+) -> (Vec<usize>, Vec<T>) {
+    let mut index = HashMap::with_capacity(base.len());
+    for (i, v) in base.iter().enumerate() {
+        index.entry(key(v)).or_insert(i);
+    }
+    let mut added = Vec::new();
+    let map = other
+        .iter()
+        .map(|v| {
+            *index.entry(key(v)).or_insert_with(|| {
+                added.push(v.clone());
+                base.len() + added.len() - 1
+            })
+        })
+        .collect();
+    (map, added)
+}
+
+/// Index of each type of the module in the linked bytecode, and the module types to append in order
+fn merge_types(
+    base: &Bytecode,
+    module: &Bytecode,
+    strings: &[usize],
+    result: &mut LinkResult,
+) -> Result<(Vec<usize>, Vec<usize>)> {
+    let mut map = vec![None; module.types.len()];
+    let mut added = Vec::new();
+    let mut conflicts = Vec::new();
+
+    let mut named = HashMap::new();
+    for (i, t) in base.types.iter().enumerate() {
+        if let Some(name) = type_name(base, t) {
+            named.entry(name).or_insert(i);
+        }
+    }
+    for (i, t) in module.types.iter().enumerate() {
+        if let Some(name) = type_name(module, t) {
+            match named.get(&name) {
+                Some(&b) if type_definition(base, &base.types[b]) == type_definition(module, t) => {
+                    map[i] = Some(b);
+                    result.types_merged += 1;
+                }
+                Some(_) => conflicts.push(name),
+                None => {
+                    map[i] = Some(base.types.len() + added.len());
+                    added.push(i);
+                }
+            }
+        }
+    }
+    if !conflicts.is_empty() {
+        return Err(Error::LinkError {
+            msg: format!(
+                "types defined differently in the module : {}",
+                conflicts.join(", ")
+            ),
+        });
+    }
+
+    // Other types are matched by structure once the types they contain are mapped
+    let mut structural = HashMap::new();
+    for (i, t) in base.types.iter().enumerate() {
+        if type_name(base, t).is_none() {
+            structural.entry(format!("{:?}", t)).or_insert(i);
+        }
+    }
+    loop {
+        let mut progress = false;
+        for (i, t) in module.types.iter().enumerate() {
+            if map[i].is_some() {
+                continue;
+            }
+            if let Some(mapped) = map_structural(t, &map, strings) {
+                let index = *structural
+                    .entry(format!("{:?}", mapped))
+                    .or_insert_with(|| {
+                        added.push(i);
+                        base.types.len() + added.len() - 1
+                    });
+                if index < base.types.len() {
+                    result.types_merged += 1;
+                }
+                map[i] = Some(index);
+                progress = true;
+            }
+        }
+        if !progress {
+            break;
+        }
+    }
+    // Left are the types containing themselves through other structural types
+    for (i, t) in map.iter_mut().enumerate() {
+        if t.is_none() {
+            *t = Some(base.types.len() + added.len());
+            added.push(i);
+        }
+    }
+
+    result.types_added = added.len();
+    Ok((map.into_iter().flatten().collect(), added))
+}
+
+/// A structural type with its references replaced by the ones of the linked bytecode,
+/// or None if a type it contains isn't mapped yet
+fn map_structural(t: &Type, types: &[Option<usize>], strings: &[usize]) -> Option<Type> {
+    let ty = |t: &RefType| types.get(t.0).copied().flatten().map(RefType);
+    let fun = |f: &TypeFun| {
+        Some(TypeFun {
+            args: f.args.iter().map(ty).collect::<Option<_>>()?,
+            ret: ty(&f.ret)?,
+        })
+    };
+    Some(match t {
+        Type::Fun(f) => Type::Fun(fun(f)?),
+        Type::Method(f) => Type::Method(fun(f)?),
+        Type::Ref(t) => Type::Ref(ty(t)?),
+        Type::Null(t) => Type::Null(ty(t)?),
+        Type::Packed(t) => Type::Packed(ty(t)?),
+        Type::Virtual { fields } => Type::Virtual {
+            fields: fields
+                .iter()
+                .map(|f| {
+                    Some(ObjField {
+                        name: RefString(*strings.get(f.name.0)?),
+                        t: ty(&f.t)?,
+                    })
+                })
+                .collect::<Option<_>>()?,
+        },
+        t => t.clone(),
+    })
+}
+
+/// Global of a class or an enum
+fn type_global(t: &Type) -> Option<usize> {
+    match t {
+        Type::Obj(obj) | Type::Struct(obj) => obj.global.0.checked_sub(1),
+        Type::Enum { global, .. } => global.0.checked_sub(1),
+        _ => None,
+    }
+}
+
+/// Index of the methods by name, None if the name is ambiguous
+fn methods_by_name(code: &Bytecode) -> HashMap<String, Option<usize>> {
+    let mut methods = HashMap::new();
+    for (i, f) in code.functions.iter().enumerate() {
+        if f.parent.is_none() {
+            continue;
+        }
+        if let Some(name) = function_name(code, f) {
+            methods
+                .entry(name)
+                .and_modify(|m| *m = None)
+                .or_insert(Some(i));
+        }
+    }
+    methods
+}
+
+/// `Class.method` or `Class`, the `$` prefix of static classes is optional
+fn replace_matches(pattern: &str, name: &str) -> bool {
+    let name = name.trim_start_matches('$');
+    let pattern = pattern.trim_start_matches('$');
+    name == pattern
+        || name
+            .rsplit_once('.')
+            .map(|(class, _)| class == pattern)
+            .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::builder::BytecodeBuilder;
+    use crate::link::{link, LinkOptions};
+    use crate::types::{FunPtr, ObjField, ObjProto, RefFun, Reg, Type};
+    use crate::{Bytecode, Opcode, Resolve};
+
+    /// `class Foo { var x: Int; function greet() }`, and a function calling a native
+    fn program(builder: &mut BytecodeBuilder, text: &str, native: &str) -> (RefFun, RefFun) {
+        let void = builder.intern_type(Type::Void);
+        let i32 = builder.intern_type(Type::I32);
+        let t = builder.fun_type(vec![], void);
+        let print = builder.add_native("std", native, t);
+        let s = builder.string(text);
+        let greet = builder.add_function(
+            t,
+            vec![void],
+            vec![
+                Opcode::String {
+                    dst: Reg(0),
+                    ptr: s,
+                },
+                Opcode::Call0 {
+                    dst: Reg(0),
+                    fun: print,
+                },
+                Opcode::Ret { ret: Reg(0) },
+            ],
+        );
+        let (x, greet_name) = (builder.string("x"), builder.string("greet"));
+        builder.add_class(
+            "Foo",
+            None,
+            vec![ObjField { name: x, t: i32 }],
+            vec![ObjProto {
+                name: greet_name,
+                findex: greet,
+                pindex: 0,
+            }],
+        );
+        (greet, print)
+    }
+
+    #[test]
+    fn link_module() {
+        let mut builder = BytecodeBuilder::new();
+        let (greet, _) = program(&mut builder, "hello", "print");
+        let void = builder.intern_type(Type::Void);
+        let t = builder.fun_type(vec![], void);
+        let entrypoint = builder.add_function(
+            t,
+            vec![void],
+            vec![
+                Opcode::Call0 {
+                    dst: Reg(0),
+                    fun: greet,
+                },
+                Opcode::Ret { ret: Reg(0) },
+            ],
+        );
+        builder.set_entrypoint(entrypoint);
+        let mut base = builder.build();
+        let types = base.types.len();
+
+        let mut builder = BytecodeBuilder::new();
+        // Types in a different order
+        builder.intern_type(Type::Bool);
+        let (_, log) = program(&mut builder, "world", "log");
+        let void = builder.intern_type(Type::Void);
+        let t = builder.fun_type(vec![], void);
+        let main = builder.add_function(
+            t,
+            vec![void],
+            vec![
+                Opcode::Call0 {
+                    dst: Reg(0),
+                    fun: log,
+                },
+                Opcode::Ret { ret: Reg(0) },
+            ],
+        );
+        builder.set_entrypoint(main);
+        let module = builder.build();
+
+        let options = LinkOptions {
+            replace: vec!["Foo.greet".to_string()],
+            call_entrypoint: true,
+        };
+        let result = link(&mut base, module, &options).unwrap();
+
+        // Only bool is new
+        assert_eq!(result.types_added, 1);
+        assert_eq!(base.types.len(), types + 1);
+        assert_eq!(result.natives_added, 1);
+        assert_eq!(result.functions_added, 1);
+        assert_eq!(result.replaced, ["Foo.greet"]);
+        assert!(base.verify().is_empty());
+
+        // Same findex, new body
+        let greet = match base.resolve(greet) {
+            FunPtr::Fun(f) => f,
+            _ => unreachable!(),
+        };
+        assert!(matches!(greet.ops[0], Opcode::String { ptr, .. } if &*base[ptr] == "world"));
+        let log = match greet.ops[1] {
+            Opcode::Call0 { fun, .. } => fun,
+            _ => unreachable!(),
+        };
+        assert!(matches!(base.resolve(log), FunPtr::Native(n) if n.name(&base) == "log"));
+        let main = result.entrypoint.as_fn(&base).unwrap();
+        assert!(matches!(main.ops[0], Opcode::Call0 { fun, .. } if fun == log));
+        // Called first by the base entrypoint
+        assert!(
+            matches!(base.entrypoint().ops[0], Opcode::Call0 { fun, .. } if fun == result.entrypoint)
+        );
+
+        let mut out = Vec::new();
+        base.serialize(&mut out).unwrap();
+        let new = Bytecode::deserialize(&mut out.as_slice()).unwrap();
+        assert_eq!(new.findex_max(), base.findex_max());
+    }
+
+    #[test]
+    fn link_errors() {
+        let build = || {
+            let mut builder = BytecodeBuilder::new();
+            let (greet, _) = program(&mut builder, "hello", "print");
+            builder.set_entrypoint(greet);
+            builder.build()
+        };
+        let mut base = build();
+
+        let options = LinkOptions {
+            replace: vec!["Bar".to_string()],
+            ..Default::default()
+        };
+        assert!(link(&mut base, build(), &options).is_err());
+        // Untouched
+        assert_eq!(base.findex_max(), 2);
+
+        // Foo with another field type
+        let mut builder = BytecodeBuilder::new();
+        let bool = builder.intern_type(Type::Bool);
+        let x = builder.string("x");
+        builder.add_class("Foo", None, vec![ObjField { name: x, t: bool }], vec![]);
+        let void = builder.intern_type(Type::Void);
+        let t = builder.fun_type(vec![], void);
+        let main = builder.add_function(t, vec![void], vec![Opcode::Ret { ret: Reg(0) }]);
+        builder.set_entrypoint(main);
+        assert!(link(&mut base, builder.build(), &LinkOptions::default()).is_err());
+        assert_eq!(base.findex_max(), 2);
+
+        // Everything is shared
+        let result = link(&mut base, build(), &LinkOptions::default()).unwrap();
+        assert_eq!(result.types_added, 0);
+        assert_eq!(result.functions_merged, 1);
+        assert_eq!(base.findex_max(), 2);
+    }
+}
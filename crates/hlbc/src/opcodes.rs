@@ -9,6 +9,7 @@ pub type JumpOffset = i32;
 /// Opcodes definitions. The fields are the opcode arguments.
 /// The methods for this struct are generated through a macro because there is no way I would have written code for 98 opcodes.
 #[derive(Debug, Clone, hlbc_derive::OpcodeHelper)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Opcode {
     /// Copy value from *src* into *dst*
     Mov {
@@ -557,10 +558,63 @@ pub enum Opcode {
     Nop,
 }
 
+/// A single opcode argument, as returned by [Opcode::operands].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Operand<'a> {
+    Reg(Reg),
+    Regs(&'a [Reg]),
+    Int(RefInt),
+    Float(RefFloat),
+    Bytes(RefBytes),
+    String(RefString),
+    Type(RefType),
+    Fun(RefFun),
+    Field(RefField),
+    Global(RefGlobal),
+    EnumConstruct(RefEnumConstruct),
+    Bool(ValBool),
+    Offset(JumpOffset),
+    Offsets(&'a [JumpOffset]),
+}
+
+/// Mutable access to a single opcode argument, as returned by [Opcode::operands_mut].
+#[derive(Debug, PartialEq, Eq)]
+pub enum OperandMut<'a> {
+    Reg(&'a mut Reg),
+    Regs(&'a mut Vec<Reg>),
+    Int(&'a mut RefInt),
+    Float(&'a mut RefFloat),
+    Bytes(&'a mut RefBytes),
+    String(&'a mut RefString),
+    Type(&'a mut RefType),
+    Fun(&'a mut RefFun),
+    Field(&'a mut RefField),
+    Global(&'a mut RefGlobal),
+    EnumConstruct(&'a mut RefEnumConstruct),
+    Bool(&'a mut ValBool),
+    Offset(&'a mut JumpOffset),
+    Offsets(&'a mut Vec<JumpOffset>),
+}
+
+impl Opcode {
+    /// Absolute positions this opcode may jump to, given its own position.
+    pub fn jump_targets(&self, pos: usize) -> Vec<isize> {
+        let target = |offset: &JumpOffset| pos as isize + *offset as isize + 1;
+        self.operands()
+            .iter()
+            .flat_map(|o| match o {
+                Operand::Offset(offset) => vec![target(offset)],
+                Operand::Offsets(offsets) => offsets.iter().map(target).collect(),
+                _ => Vec::new(),
+            })
+            .collect()
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use crate::opcodes::Opcode;
-    use crate::types::Reg;
+    use crate::opcodes::{Opcode, Operand, OperandMut};
+    use crate::types::{RefFun, Reg};
 
     #[test]
     fn test_doc() {
@@ -577,4 +631,49 @@ mod test {
             Opcode::Null { dst: Reg(0) }.description()
         );
     }
+
+    #[test]
+    fn test_operands() {
+        let op = Opcode::Call2 {
+            dst: Reg(0),
+            fun: RefFun(3),
+            arg0: Reg(1),
+            arg1: Reg(2),
+        };
+        assert_eq!(
+            op.operands(),
+            vec![
+                Operand::Reg(Reg(0)),
+                Operand::Fun(RefFun(3)),
+                Operand::Reg(Reg(1)),
+                Operand::Reg(Reg(2))
+            ]
+        );
+        let op = Opcode::Switch {
+            reg: Reg(0),
+            offsets: vec![0, 2],
+            end: 3,
+        };
+        assert_eq!(op.jump_targets(10), vec![11, 13, 14]);
+    }
+
+    #[test]
+    fn test_operands_mut() {
+        let mut op = Opcode::from_name("Call1").unwrap();
+        for o in op.operands_mut() {
+            match o {
+                OperandMut::Reg(r) => r.0 += 1,
+                OperandMut::Fun(f) => *f = RefFun(4),
+                _ => unreachable!(),
+            }
+        }
+        assert!(matches!(
+            op,
+            Opcode::Call1 {
+                dst: Reg(1),
+                fun: RefFun(4),
+                arg0: Reg(1)
+            }
+        ));
+    }
 }
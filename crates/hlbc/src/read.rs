@@ -1,6 +1,9 @@
-use std::collections::{HashMap, VecDeque};
+use std::collections::HashMap;
 use std::fs;
-use std::io::{BufReader, Read};
+use std::io;
+#[cfg(not(feature = "parallel"))]
+use std::io::BufReader;
+use std::io::Read;
 use std::path::Path;
 use std::str::from_utf8;
 
@@ -10,69 +13,59 @@ use crate::types::{
     EnumConstruct, Function, Native, ObjField, ObjProto, RefField, RefFloat, RefInt, RefString,
     RefType, Type, TypeFun, TypeObj,
 };
-use crate::{Bytecode, ConstantDef, Opcode, RefFun, RefFunKnown, RefGlobal, Str};
-use crate::{Error, Result};
+use crate::{Bytecode, ConstantDef, Opcode, RefFun, RefGlobal, Str};
+use crate::{Error, Result, Section, MAX_VERSION, MIN_VERSION};
 
 impl Bytecode {
     /// Load the bytecode from any source.
     /// Must be a valid hashlink bytecode binary.
     pub fn deserialize(r: &mut impl Read) -> Result<Bytecode> {
-        let mut header = [0u8; 3];
-        r.read_exact(&mut header)?;
-        if header != [b'H', b'L', b'B'] {
-            return Err(Error::MalformedBytecode(format!(
-                "Invalid magic header (expected: {:?}, got: {header:?})",
-                b"HLB"
-            )));
-        }
-        let version = r.read_u8()?;
-        if version < 4 && version > 5 {
-            return Err(Error::UnsupportedVersion {
-                version,
-                min: 4,
-                max: 5,
-            });
+        let r = &mut PosReader::new(r);
+
+        // Attach the current location to errors
+        macro_rules! at {
+            ($section:ident, $index:expr, $e:expr) => {
+                match $e {
+                    Ok(v) => v,
+                    Err(e) => return Err(Error::from(e).at(Section::$section, $index, r.pos)),
+                }
+            };
         }
-        let flags = read_varu(r)?;
-        let has_debug = flags & 1 == 1;
-        let nints = read_varu(r)? as usize;
-        let nfloats = read_varu(r)? as usize;
-        let nstrings = read_varu(r)? as usize;
-        let nbytes = if version >= 5 {
-            Some(read_varu(r)? as usize)
-        } else {
-            None
-        };
-        let ntypes = read_varu(r)? as usize;
-        let nglobals = read_varu(r)? as usize;
-        let nnatives = read_varu(r)? as usize;
-        let nfunctions = read_varu(r)? as usize;
-        let nconstants = if version >= 4 {
-            Some(read_varu(r)? as usize)
-        } else {
-            None
-        };
-        let entrypoint = RefFun::read(r)?;
+
+        let Header {
+            version,
+            has_debug,
+            nints,
+            nfloats,
+            nstrings,
+            nbytes,
+            ntypes,
+            nglobals,
+            nnatives,
+            nfunctions,
+            nconstants,
+            entrypoint,
+        } = at!(Header, None, Header::read(r));
 
         let mut ints = vec![0i32; nints];
-        for i in ints.iter_mut() {
-            *i = r.read_i32::<LittleEndian>()?;
+        for (idx, i) in ints.iter_mut().enumerate() {
+            *i = at!(Ints, Some(idx), r.read_i32::<LittleEndian>());
         }
 
         let mut floats = vec![0f64; nfloats];
-        for i in floats.iter_mut() {
-            *i = r.read_f64::<LittleEndian>()?;
+        for (idx, i) in floats.iter_mut().enumerate() {
+            *i = at!(Floats, Some(idx), r.read_f64::<LittleEndian>());
         }
 
-        let strings = read_strings(r, nstrings)?;
+        let strings = at!(Strings, None, read_strings(r, nstrings, Section::Strings));
 
         let bytes = if let Some(nbytes) = nbytes {
-            let size = r.read_i32::<LittleEndian>()? as usize;
+            let size = at!(Bytes, None, r.read_i32::<LittleEndian>()) as usize;
             let mut bytes = vec![0; size];
-            r.read_exact(&mut bytes)?;
+            at!(Bytes, None, r.read_exact(&mut bytes));
             let mut pos = Vec::with_capacity(nbytes);
-            for _ in 0..nbytes {
-                pos.push(read_varu(r)? as usize);
+            for i in 0..nbytes {
+                pos.push(at!(Bytes, Some(i), read_varu(r)) as usize);
             }
             Some((bytes, pos))
         } else {
@@ -80,128 +73,51 @@ impl Bytecode {
         };
 
         let debug_files = if has_debug {
-            let n = read_varu(r)? as usize;
-            Some(read_strings(r, n)?)
+            let n = at!(DebugFiles, None, read_varu(r)) as usize;
+            Some(at!(
+                DebugFiles,
+                None,
+                read_strings(r, n, Section::DebugFiles)
+            ))
         } else {
             None
         };
 
         let mut types = Vec::with_capacity(ntypes);
-        for _ in 0..ntypes {
-            types.push(Type::read(r)?);
+        for i in 0..ntypes {
+            types.push(at!(Types, Some(i), Type::read(r)));
         }
 
         let mut globals = Vec::with_capacity(nglobals);
-        for _ in 0..nglobals {
-            globals.push(RefType::read(r)?);
+        for i in 0..nglobals {
+            globals.push(at!(Globals, Some(i), RefType::read(r)));
         }
 
         let mut natives = Vec::with_capacity(nnatives);
-        for _ in 0..nnatives {
-            natives.push(Native::read(r)?);
+        for i in 0..nnatives {
+            natives.push(at!(Natives, Some(i), Native::read(r)));
         }
 
         let mut functions = Vec::with_capacity(nfunctions);
-        for _ in 0..nfunctions {
-            functions.push(Function::read(r, has_debug, version)?);
+        for i in 0..nfunctions {
+            functions.push(at!(
+                Functions,
+                Some(i),
+                Function::read(r, has_debug, version)
+            ));
         }
 
         let constants = if let Some(n) = nconstants {
             let mut constants = Vec::with_capacity(n);
-            for _ in 0..n {
-                constants.push(ConstantDef::read(r)?)
+            for i in 0..n {
+                constants.push(at!(Constants, Some(i), ConstantDef::read(r)))
             }
             Some(constants)
         } else {
             None
         };
 
-        // Parsing is finished, we now build links between everything
-
-        // Global function indexes
-        let mut findexes = vec![RefFunKnown::Fun(0); nfunctions + nnatives];
-        for (i, f) in functions.iter().enumerate() {
-            findexes[f.findex.0] = RefFunKnown::Fun(i);
-        }
-        for (i, n) in natives.iter().enumerate() {
-            findexes[n.findex.0] = RefFunKnown::Native(i);
-        }
-
-        // Flatten types fields
-        // Start by collecting every fields in the hierarchy
-        // The order is important because we refer to fields by index
-        let mut new_fields: Vec<Option<Vec<ObjField>>> = Vec::with_capacity(types.len());
-        for t in &types {
-            if let Some(obj) = t.get_type_obj() {
-                let mut parent = obj.super_.as_ref().map(|s| &types[s.0]);
-                let mut acc = VecDeque::with_capacity(obj.own_fields.len());
-                acc.extend(obj.own_fields.clone());
-                while let Some(p) = parent.and_then(|t| t.get_type_obj()) {
-                    for f in p.own_fields.iter().rev() {
-                        acc.push_front(f.clone());
-                    }
-                    parent = p.super_.as_ref().map(|s| &types[s.0]);
-                }
-                new_fields.push(Some(acc.into()));
-            } else {
-                new_fields.push(None);
-            }
-        }
-        // Apply new fields
-        for (t, new) in types.iter_mut().zip(new_fields.into_iter()) {
-            if let Some(fields) = new {
-                t.get_type_obj_mut().unwrap().fields = fields;
-            }
-        }
-
-        // Give functions name based on object fields bindings and methods
-        for (i, t) in types.iter().enumerate() {
-            if let Some(TypeObj {
-                protos, bindings, ..
-            }) = t.get_type_obj()
-            {
-                for p in protos {
-                    if let RefFunKnown::Fun(x) = findexes[p.findex.0] {
-                        functions[x].name = p.name;
-                        functions[x].parent = Some(RefType(i));
-                    }
-                }
-                for (fid, findex) in bindings {
-                    if let Some(field) = t.get_type_obj().map(|o| &o.fields[fid.0]) {
-                        if let RefFunKnown::Fun(x) = findexes[findex.0] {
-                            functions[x].name = field.name;
-                            functions[x].parent = Some(RefType(i));
-                        }
-                    }
-                }
-            }
-        }
-
-        // Function names
-        let mut fnames = HashMap::with_capacity(functions.len());
-        for (i, f) in functions.iter().enumerate() {
-            // FIXME duplicates ?
-            fnames.insert(strings[f.name.0].clone(), i);
-        }
-        fnames.insert(
-            Str::from("init"),
-            match findexes[entrypoint.0] {
-                RefFunKnown::Fun(x) => x,
-                _ => 0,
-            },
-        );
-
-        let globals_initializers = if let Some(constants) = &constants {
-            let mut tmp = HashMap::with_capacity(constants.len());
-            for (i, c) in constants.iter().enumerate() {
-                tmp.insert(c.global, i);
-            }
-            tmp
-        } else {
-            HashMap::new()
-        };
-
-        Ok(Bytecode {
+        let mut code = Bytecode {
             version,
             entrypoint,
             ints,
@@ -214,14 +130,109 @@ impl Bytecode {
             natives,
             functions,
             constants,
-            findexes,
-            fnames,
-            globals_initializers,
-        })
+            ..Default::default()
+        };
+        // Parsing is finished, we now build links between everything
+        code.rebuild_indexes();
+        Ok(code)
     }
 
+    /// Load the bytecode from a file.
+    /// With the `parallel` feature, functions are decoded on multiple threads.
+    /// See [crate::lazy::LazyBytecode] to only decode what is accessed.
     pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
-        Self::deserialize(&mut BufReader::new(fs::File::open(path)?))
+        #[cfg(feature = "parallel")]
+        {
+            Self::from_bytes(&fs::read(path)?)
+        }
+        #[cfg(not(feature = "parallel"))]
+        {
+            Self::deserialize(&mut BufReader::new(fs::File::open(path)?))
+        }
+    }
+
+    /// Load the bytecode from a buffer.
+    /// With the `parallel` feature, functions are decoded on multiple threads.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        #[cfg(feature = "parallel")]
+        {
+            Self::deserialize_parallel(bytes)
+        }
+        #[cfg(not(feature = "parallel"))]
+        {
+            Self::deserialize(&mut &bytes[..])
+        }
+    }
+
+    /// Load the bytecode, decoding strings and functions on multiple threads.
+    /// Function boundaries are found with a first sequential pass over the file.
+    /// The result is the same as [Bytecode::deserialize].
+    #[cfg(feature = "parallel")]
+    pub fn deserialize_parallel(bytes: &[u8]) -> Result<Self> {
+        let index = crate::lazy::Index::read(bytes)?;
+        let strings = vec![None; index.nstrings()];
+        let functions = vec![None; index.nfunctions()];
+        index.into_bytecode(bytes, strings, functions)
+    }
+}
+
+/// Counts and flags at the start of a bytecode file
+pub(crate) struct Header {
+    pub(crate) version: u8,
+    pub(crate) has_debug: bool,
+    pub(crate) nints: usize,
+    pub(crate) nfloats: usize,
+    pub(crate) nstrings: usize,
+    pub(crate) nbytes: Option<usize>,
+    pub(crate) ntypes: usize,
+    pub(crate) nglobals: usize,
+    pub(crate) nnatives: usize,
+    pub(crate) nfunctions: usize,
+    pub(crate) nconstants: Option<usize>,
+    pub(crate) entrypoint: RefFun,
+}
+
+impl Header {
+    pub(crate) fn read(r: &mut impl Read) -> Result<Self> {
+        let mut header = [0u8; 3];
+        r.read_exact(&mut header)?;
+        if header != [b'H', b'L', b'B'] {
+            return Err(Error::malformed(format!(
+                "Invalid magic header (expected: {:?}, got: {header:?})",
+                b"HLB"
+            )));
+        }
+        let version = r.read_u8()?;
+        if !(MIN_VERSION..=MAX_VERSION).contains(&version) {
+            return Err(Error::UnsupportedVersion {
+                version,
+                min: MIN_VERSION,
+                max: MAX_VERSION,
+            });
+        }
+        let flags = read_varu(r)?;
+        Ok(Header {
+            version,
+            has_debug: flags & 1 == 1,
+            nints: read_varu(r)? as usize,
+            nfloats: read_varu(r)? as usize,
+            nstrings: read_varu(r)? as usize,
+            nbytes: if version >= 5 {
+                Some(read_varu(r)? as usize)
+            } else {
+                None
+            },
+            ntypes: read_varu(r)? as usize,
+            nglobals: read_varu(r)? as usize,
+            nnatives: read_varu(r)? as usize,
+            nfunctions: read_varu(r)? as usize,
+            nconstants: if version >= 4 {
+                Some(read_varu(r)? as usize)
+            } else {
+                None
+            },
+            entrypoint: RefFun::read(r)?,
+        })
     }
 }
 
@@ -385,9 +396,7 @@ impl Type {
             20 => Ok(Method(TypeFun::read(r)?)),
             21 => Ok(Struct(TypeObj::read(r)?)),
             22 => Ok(Packed(RefType::read(r)?)),
-            other => Err(Error::MalformedBytecode(format!(
-                "Invalid type kind '{other}'"
-            ))),
+            other => Err(Error::malformed(format!("Invalid type kind '{other}'"))),
         }
     }
 }
@@ -418,43 +427,15 @@ impl Function {
             ops.push(Opcode::read(r)?);
         }
 
-        // This is extracted from the hashlink source code, do not count on me to explain what it does
         let debug_info = if has_debug {
             let mut tmp = Vec::with_capacity(nops);
-            let mut currfile: i32 = -1;
-            let mut currline: i32 = 0;
-            let mut i = 0;
-            while i < nops {
-                let mut c = r.read_u8()? as i32;
-                if c & 1 != 0 {
-                    c >>= 1;
-                    currfile = (c << 8) | (r.read_u8()? as i32);
-                } else if c & 2 != 0 {
-                    let delta = c >> 6;
-                    let mut count = (c >> 2) & 15;
-                    while count > 0 {
-                        count -= 1;
-                        tmp.push((currfile as usize, currline as usize));
-                        i += 1;
-                    }
-                    currline += delta;
-                } else if c & 4 != 0 {
-                    currline += c >> 3;
-                    tmp.push((currfile as usize, currline as usize));
-                    i += 1;
-                } else {
-                    let b2 = r.read_u8()? as i32;
-                    let b3 = r.read_u8()? as i32;
-                    currline = (c >> 3) | (b2 << 5) | (b3 << 13);
-                    tmp.push((currfile as usize, currline as usize));
-                    i += 1;
-                }
-            }
+            read_debug_info(r, nops, |file, line| tmp.push((file, line)))?;
             Some(tmp)
         } else {
             None
         };
 
+        // Versions 1 and 2 only have the line information
         let assigns = if has_debug && version >= 3 {
             let len = read_varu(r)? as usize;
             let mut assigns = Vec::with_capacity(len);
@@ -476,6 +457,73 @@ impl Function {
             parent: None,
         })
     }
+
+    /// Advance the reader past a function, only decoding its type and findex.
+    pub(crate) fn skip(
+        r: &mut impl Read,
+        has_debug: bool,
+        version: u8,
+    ) -> Result<(RefType, RefFun)> {
+        let t = RefType::read(r)?;
+        let findex = RefFun::read(r)?;
+        let nregs = read_varu(r)? as usize;
+        let nops = read_varu(r)? as usize;
+        for _ in 0..nregs {
+            read_vari(r)?;
+        }
+        for _ in 0..nops {
+            Opcode::skip(r)?;
+        }
+        if has_debug {
+            read_debug_info(r, nops, |_, _| {})?;
+            if version >= 3 {
+                for _ in 0..read_varu(r)? {
+                    read_vari(r)?;
+                    read_vari(r)?;
+                }
+            }
+        }
+        Ok((t, findex))
+    }
+}
+
+/// Decode the (file, line) debug information for each of the `nops` instructions of a function
+fn read_debug_info(
+    r: &mut impl Read,
+    nops: usize,
+    mut push: impl FnMut(usize, usize),
+) -> Result<()> {
+    // This is extracted from the hashlink source code, do not count on me to explain what it does
+    let mut currfile: i32 = -1;
+    let mut currline: i32 = 0;
+    let mut i = 0;
+    while i < nops {
+        let mut c = r.read_u8()? as i32;
+        if c & 1 != 0 {
+            c >>= 1;
+            currfile = (c << 8) | (r.read_u8()? as i32);
+        } else if c & 2 != 0 {
+            let delta = c >> 6;
+            let mut count = (c >> 2) & 15;
+            while count > 0 {
+                count -= 1;
+                push(currfile as usize, currline as usize);
+                i += 1;
+            }
+            currline += delta;
+        } else if c & 4 != 0 {
+            currline += c >> 3;
+            push(currfile as usize, currline as usize);
+            i += 1;
+        } else {
+            let b2 = r.read_u8()? as i32;
+            let b3 = r.read_u8()? as i32;
+            currline = (c >> 3) | (b2 << 5) | (b3 << 13);
+            push(currfile as usize, currline as usize);
+            i += 1;
+        }
+    }
+    Ok(())
 }
 
 impl ConstantDef {
@@ -509,7 +557,7 @@ pub(crate) fn read_vari(r: &mut impl Read) -> Result<i32> {
 pub(crate) fn read_varu(r: &mut impl Read) -> Result<u32> {
     let i = read_vari(r)?;
     if i < 0 {
-        Err(Error::MalformedBytecode(format!(
+        Err(Error::malformed(format!(
             "Got negative index '{i}' (expected >= 0)"
         )))
     } else {
@@ -517,18 +565,51 @@ pub(crate) fn read_varu(r: &mut impl Read) -> Result<u32> {
     }
 }
 
-fn read_strings(r: &mut impl Read, nstrings: usize) -> Result<Vec<Str>> {
+/// Reader keeping track of its offset in the file, to locate errors
+pub(crate) struct PosReader<R> {
+    inner: R,
+    pub(crate) pos: usize,
+}
+
+impl<R: Read> PosReader<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        Self { inner, pos: 0 }
+    }
+}
+
+impl<R: Read> Read for PosReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+fn read_strings(
+    r: &mut PosReader<impl Read>,
+    nstrings: usize,
+    section: Section,
+) -> Result<Vec<Str>> {
     let mut strings = Vec::with_capacity(nstrings);
     let mut string_data = vec![0u8; r.read_i32::<LittleEndian>()? as usize];
+    let start = r.pos;
     r.read_exact(&mut string_data)?;
     let mut acc = 0;
-    for _ in 0..nstrings {
-        let ssize = read_varu(r)? as usize + 1;
+    for i in 0..nstrings {
+        let ssize = read_varu(r).map_err(|e| e.at(section, Some(i), r.pos))? as usize + 1;
+        if acc + ssize > string_data.len() {
+            return Err(Error::malformed(format!(
+                "String of size {ssize} is out of the strings data ({} bytes)",
+                string_data.len()
+            ))
+            .at(section, Some(i), r.pos));
+        }
         //println!("size: {ssize} {:?}", &string_data[acc..(acc + ssize)]);
         //let cstr = unsafe { CStr::from_bytes_with_nul_unchecked(&string_data[acc..(acc + ssize)]) };
-        strings.push(Str::from_ref(from_utf8(
-            &string_data[acc..(acc + ssize - 1)],
-        )?));
+        strings.push(Str::from_ref(
+            from_utf8(&string_data[acc..(acc + ssize - 1)])
+                .map_err(|e| Error::from(e).at(section, Some(i), start + acc))?,
+        ));
         acc += ssize;
     }
     Ok(strings)
@@ -540,7 +621,7 @@ mod tests {
 
     use crate::fmt::EnhancedFmt;
     use crate::types::RefFun;
-    use crate::{Bytecode, Resolve};
+    use crate::{Bytecode, Error, Resolve, MAX_VERSION};
 
     #[test]
     fn test_deserialize_all() {
@@ -555,6 +636,60 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_deserialize_unsupported_version() {
+        for version in [0, MAX_VERSION + 1] {
+            let data = [b'H', b'L', b'B', version];
+            assert!(matches!(
+                Bytecode::deserialize(&mut data.as_slice()),
+                Err(Error::UnsupportedVersion { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_malformed_location() {
+        use crate::builder::BytecodeBuilder;
+        use crate::opcodes::Opcode;
+        use crate::types::{Reg, Type};
+        use crate::{Location, Section};
+
+        let mut builder = BytecodeBuilder::new();
+        let void = builder.intern_type(Type::Void);
+        let t = builder.fun_type(vec![], void);
+        let f = builder.add_function(t, vec![void], vec![Opcode::Ret { ret: Reg(0) }]);
+        builder.set_entrypoint(f);
+        let mut data = Vec::new();
+        builder.build().serialize(&mut data).unwrap();
+
+        let location = |data: &[u8]| match Bytecode::deserialize(&mut &data[..]) {
+            Err(Error::MalformedBytecode { location, .. }) => location,
+            other => panic!("expected a malformed bytecode error, got {other:?}"),
+        };
+
+        // The function is the last element of the file
+        let truncated = &data[..data.len() - 1];
+        assert_eq!(
+            location(truncated),
+            Some(Location {
+                section: Section::Functions,
+                index: Some(0),
+                offset: truncated.len(),
+            })
+        );
+
+        let mut bad_magic = data.clone();
+        bad_magic[0] = b'X';
+        assert_eq!(
+            location(&bad_magic),
+            Some(Location {
+                section: Section::Header,
+                index: None,
+                offset: 3,
+            })
+        );
+    }
+
     #[test]
     fn test_deserialize_wartales() {
         let path = "E:\\Games\\Wartales\\hlboot.dat";
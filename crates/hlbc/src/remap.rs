@@ -0,0 +1,293 @@
+use crate::opcodes::OperandMut;
+use crate::types::{
+    ConstantField, RefBytes, RefFloat, RefFun, RefGlobal, RefInt, RefString, RefType,
+};
+use crate::{Bytecode, Type};
+
+/// Mutable reference to an element of the bytecode, see [visit_refs_mut]
+#[derive(Debug)]
+pub enum RefMut<'a> {
+    Type(&'a mut RefType),
+    String(&'a mut RefString),
+    Int(&'a mut RefInt),
+    Float(&'a mut RefFloat),
+    /// Only for bytecode version 5 and later, bytes are stored in the strings pool before that
+    Bytes(&'a mut RefBytes),
+    Global(&'a mut RefGlobal),
+    /// Function or native
+    Fun(&'a mut RefFun),
+    /// Index in [Bytecode::debug_files]
+    DebugFile(&'a mut usize),
+}
+
+/// Call `f` on every reference to an element in the bytecode : types, natives, functions (header, instructions
+/// and debug information) and constants.
+///
+/// Instructions are visited with [crate::Opcode::operands_mut].
+/// Globals of classes and enums (stored with an offset of 1) and the fields of constants (stored as plain indexes)
+/// are given as their Ref* types.
+pub fn visit_refs_mut(code: &mut Bytecode, mut f: impl FnMut(RefMut)) {
+    let f = &mut f;
+
+    // Globals of classes and enums are stored with an offset of 1, 0 being no global
+    fn global_plus_one(g: &mut RefGlobal, f: &mut impl FnMut(RefMut)) {
+        if g.0 >= 1 {
+            let mut tmp = RefGlobal(g.0 - 1);
+            f(RefMut::Global(&mut tmp));
+            g.0 = tmp.0 + 1;
+        }
+    }
+
+    // Computed before borrowing the constants
+    let kinds: Vec<_> = code
+        .constants
+        .iter()
+        .flatten()
+        .map(|c| c.field_kinds(code))
+        .collect();
+    // Before v5, bytes are stored in the strings pool
+    let bytes_in_strings = code.bytes.is_none();
+
+    f(RefMut::Fun(&mut code.entrypoint));
+    for t in &mut code.types {
+        match t {
+            Type::Fun(fun) | Type::Method(fun) => {
+                for arg in &mut fun.args {
+                    f(RefMut::Type(arg));
+                }
+                f(RefMut::Type(&mut fun.ret));
+            }
+            Type::Obj(obj) | Type::Struct(obj) => {
+                f(RefMut::String(&mut obj.name));
+                if let Some(super_) = &mut obj.super_ {
+                    f(RefMut::Type(super_));
+                }
+                global_plus_one(&mut obj.global, f);
+                for field in obj.own_fields.iter_mut().chain(&mut obj.fields) {
+                    f(RefMut::String(&mut field.name));
+                    f(RefMut::Type(&mut field.t));
+                }
+                for p in &mut obj.protos {
+                    f(RefMut::String(&mut p.name));
+                    f(RefMut::Fun(&mut p.findex));
+                }
+                for fun in obj.bindings.values_mut() {
+                    f(RefMut::Fun(fun));
+                }
+            }
+            Type::Ref(inner) | Type::Null(inner) | Type::Packed(inner) => f(RefMut::Type(inner)),
+            Type::Virtual { fields } => {
+                for field in fields {
+                    f(RefMut::String(&mut field.name));
+                    f(RefMut::Type(&mut field.t));
+                }
+            }
+            Type::Abstract { name } => f(RefMut::String(name)),
+            Type::Enum {
+                name,
+                global,
+                constructs,
+            } => {
+                f(RefMut::String(name));
+                global_plus_one(global, f);
+                for c in constructs {
+                    f(RefMut::String(&mut c.name));
+                    for p in &mut c.params {
+                        f(RefMut::Type(p));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    for g in &mut code.globals {
+        f(RefMut::Type(g));
+    }
+    for n in &mut code.natives {
+        f(RefMut::String(&mut n.lib));
+        f(RefMut::String(&mut n.name));
+        f(RefMut::Type(&mut n.t));
+        f(RefMut::Fun(&mut n.findex));
+    }
+    for fun in &mut code.functions {
+        f(RefMut::String(&mut fun.name));
+        f(RefMut::Type(&mut fun.t));
+        f(RefMut::Fun(&mut fun.findex));
+        if let Some(parent) = &mut fun.parent {
+            f(RefMut::Type(parent));
+        }
+        for r in &mut fun.regs {
+            f(RefMut::Type(r));
+        }
+        for o in &mut fun.ops {
+            for operand in o.operands_mut() {
+                match operand {
+                    OperandMut::Type(t) => f(RefMut::Type(t)),
+                    OperandMut::String(s) => f(RefMut::String(s)),
+                    OperandMut::Int(i) => f(RefMut::Int(i)),
+                    OperandMut::Float(v) => f(RefMut::Float(v)),
+                    OperandMut::Global(g) => f(RefMut::Global(g)),
+                    OperandMut::Fun(fun) => f(RefMut::Fun(fun)),
+                    OperandMut::Bytes(b) if bytes_in_strings => {
+                        let mut tmp = RefString(b.0);
+                        f(RefMut::String(&mut tmp));
+                        *b = RefBytes(tmp.0);
+                    }
+                    OperandMut::Bytes(b) => f(RefMut::Bytes(b)),
+                    _ => {}
+                }
+            }
+        }
+        for (file, _) in fun.debug_info.iter_mut().flatten() {
+            f(RefMut::DebugFile(file));
+        }
+        for (name, _) in fun.assigns.iter_mut().flatten() {
+            f(RefMut::String(name));
+        }
+    }
+    for (c, kinds) in code.constants.iter_mut().flatten().zip(kinds) {
+        f(RefMut::Global(&mut c.global));
+        for (value, kind) in c.fields.iter_mut().zip(kinds) {
+            macro_rules! visit {
+                ($variant:ident, $ref:ident) => {{
+                    let mut tmp = $ref(*value);
+                    f(RefMut::$variant(&mut tmp));
+                    *value = tmp.0;
+                }};
+            }
+            match kind {
+                ConstantField::Int => visit!(Int, RefInt),
+                ConstantField::Float => visit!(Float, RefFloat),
+                ConstantField::String => visit!(String, RefString),
+                ConstantField::Type => visit!(Type, RefType),
+                ConstantField::Global => visit!(Global, RefGlobal),
+                ConstantField::Bool => {}
+            }
+        }
+    }
+}
+
+/// New index of the elements of each pool, indexed by the old index.
+/// A pool without mapping is left untouched, as are references out of the bounds of a mapping.
+#[derive(Debug, Clone, Default)]
+pub struct Remapping {
+    pub types: Option<Vec<usize>>,
+    pub strings: Option<Vec<usize>>,
+    pub ints: Option<Vec<usize>>,
+    pub floats: Option<Vec<usize>>,
+    pub bytes: Option<Vec<usize>>,
+    pub globals: Option<Vec<usize>>,
+    /// Functions and natives share the same index space
+    pub functions: Option<Vec<usize>>,
+    pub debug_files: Option<Vec<usize>>,
+}
+
+impl Remapping {
+    /// Mapping adding an offset to every index of a pool of `len` elements
+    pub fn offset(len: usize, offset: usize) -> Vec<usize> {
+        (offset..offset + len).collect()
+    }
+
+    /// Update every reference in the bytecode. This doesn't move the elements of the pools,
+    /// [Bytecode::rebuild_indexes] must be called after the pools are reordered.
+    pub fn apply(&self, code: &mut Bytecode) {
+        fn map(mapping: &Option<Vec<usize>>, i: &mut usize) {
+            if let Some(&new) = mapping.as_ref().and_then(|m| m.get(*i)) {
+                *i = new;
+            }
+        }
+
+        visit_refs_mut(code, |r| match r {
+            RefMut::Type(t) => map(&self.types, &mut t.0),
+            RefMut::String(s) => map(&self.strings, &mut s.0),
+            RefMut::Int(i) => map(&self.ints, &mut i.0),
+            RefMut::Float(v) => map(&self.floats, &mut v.0),
+            RefMut::Bytes(b) => map(&self.bytes, &mut b.0),
+            RefMut::Global(g) => map(&self.globals, &mut g.0),
+            RefMut::Fun(f) => map(&self.functions, &mut f.0),
+            RefMut::DebugFile(file) => map(&self.debug_files, file),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::builder::BytecodeBuilder;
+    use crate::remap::{visit_refs_mut, RefMut, Remapping};
+    use crate::types::{ObjField, ObjProto, RefGlobal, RefString, Reg, Type};
+    use crate::Opcode;
+
+    #[test]
+    fn swap_types_and_functions() {
+        let mut builder = BytecodeBuilder::new();
+        let void = builder.intern_type(Type::Void);
+        let i32 = builder.intern_type(Type::I32);
+        let t = builder.fun_type(vec![], void);
+        let method = builder.add_function(t, vec![void], vec![Opcode::Ret { ret: Reg(0) }]);
+        let name = builder.string("Foo");
+        let class = builder.add_class(
+            "Foo",
+            None,
+            vec![ObjField { name, t: i32 }],
+            vec![ObjProto {
+                name,
+                findex: method,
+                pindex: 0,
+            }],
+        );
+        builder.class_mut(class).unwrap().global = RefGlobal(1);
+        let global = builder.add_global(class);
+        let other = builder.add_global(i32);
+        let main = builder.add_function(
+            t,
+            vec![void, class],
+            vec![
+                Opcode::GetGlobal {
+                    dst: Reg(1),
+                    global,
+                },
+                Opcode::Call0 {
+                    dst: Reg(0),
+                    fun: method,
+                },
+                Opcode::Ret { ret: Reg(0) },
+            ],
+        );
+        builder.set_entrypoint(main);
+        let mut code = builder.build();
+
+        // Swap void and i32, the two functions and the two globals, and shift the strings
+        Remapping {
+            types: Some(vec![i32.0, void.0, t.0, class.0]),
+            functions: Some(vec![main.0, method.0]),
+            globals: Some(vec![other.0, global.0]),
+            strings: Some(Remapping::offset(code.strings.len(), 1)),
+            ..Default::default()
+        }
+        .apply(&mut code);
+
+        assert_eq!(code.entrypoint, method);
+        let f = &code.functions[main.0];
+        assert_eq!(f.findex, method);
+        assert_eq!(f.regs, [i32, class]);
+        assert!(matches!(f.ops[0], Opcode::GetGlobal { global: g, .. } if g == other));
+        assert!(matches!(f.ops[1], Opcode::Call0 { fun, .. } if fun == main));
+        // The pools are not reordered
+        assert_eq!(code.globals, [class, void]);
+        let obj = class.as_obj(&code).unwrap();
+        assert_eq!(obj.name, RefString(name.0 + 1));
+        assert_eq!(obj.own_fields[0].t, void);
+        assert_eq!(obj.protos[0].findex, main);
+        // Still 1-indexed
+        assert_eq!(obj.global, RefGlobal(other.0 + 1));
+
+        let mut strings = 0;
+        visit_refs_mut(&mut code, |r| {
+            if let RefMut::String(_) = r {
+                strings += 1;
+            }
+        });
+        // Class name, own and flattened field names, method name and function names
+        assert_eq!(strings, 6);
+    }
+}
@@ -0,0 +1,413 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::analysis::reachability::Reachability;
+use crate::opcodes::OperandMut;
+use crate::remap::{visit_refs_mut, RefMut, Remapping};
+use crate::types::{RefFun, RefGlobal, RefString, RefType};
+use crate::{Bytecode, Opcode};
+
+/// A transformation applied by [strip]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Pass {
+    /// Remove the functions, natives, types and globals unreachable from the entrypoint,
+    /// see [Reachability]
+    Unreachable,
+    /// Remove [Opcode::Nop] and the [Opcode::Label] no instruction jumps to
+    Nops,
+    /// Merge duplicated strings, ints and floats, and remove the unused ones
+    Pools,
+    /// Remove the debug information : debug files, instructions positions and variable names
+    DebugInfo,
+}
+
+impl Pass {
+    /// Every pass except [Pass::DebugInfo]
+    pub const DEFAULT: &[Pass] = &[Pass::Unreachable, Pass::Nops, Pass::Pools];
+}
+
+/// Number of elements removed by [strip]
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct StripStats {
+    pub functions: usize,
+    pub natives: usize,
+    pub types: usize,
+    pub globals: usize,
+    pub ops: usize,
+    pub strings: usize,
+    pub ints: usize,
+    pub floats: usize,
+}
+
+/// Apply passes in order, then rebuild the acceleration structures.
+/// Every reference to a removed or moved element is updated so the result can be serialized right away.
+/// [Pass::Pools] should come last to remove the constants only used by removed elements.
+pub fn strip(code: &mut Bytecode, passes: &[Pass]) -> StripStats {
+    let mut stats = StripStats::default();
+    for pass in passes {
+        match pass {
+            Pass::Unreachable => remove_unreachable(code, &mut stats),
+            Pass::Nops => {
+                for f in &mut code.functions {
+                    stats.ops += remove_nops(&mut f.ops, &mut f.debug_info, &mut f.assigns);
+                }
+            }
+            Pass::Pools => compact_pools(code, &mut stats),
+            Pass::DebugInfo => {
+                code.debug_files = None;
+                for f in &mut code.functions {
+                    f.debug_info = None;
+                    f.assigns = None;
+                }
+            }
+        }
+        code.rebuild_indexes();
+    }
+    stats
+}
+
+/// Keep the elements of a pool for which `keep` is true.
+/// Returns the new index of each element, or [usize::MAX] if removed.
+fn retain_indexed<T>(pool: &mut Vec<T>, keep: impl Fn(usize) -> bool) -> Vec<usize> {
+    let mut map = vec![usize::MAX; pool.len()];
+    let mut i = 0;
+    let mut next = 0;
+    pool.retain(|_| {
+        let kept = keep(i);
+        if kept {
+            map[i] = next;
+            next += 1;
+        }
+        i += 1;
+        kept
+    });
+    map
+}
+
+fn remove_unreachable(code: &mut Bytecode, stats: &mut StripStats) {
+    let reach = Reachability::new(code);
+
+    // Functions and natives share the same index space
+    let mut findexes = vec![usize::MAX; code.findex_max()];
+    let mut next = 0;
+    for (i, new) in findexes.iter_mut().enumerate() {
+        if reach.is_fun_reachable(RefFun(i)) {
+            *new = next;
+            next += 1;
+        }
+    }
+    let functions = code.functions.len();
+    code.functions.retain(|f| reach.is_fun_reachable(f.findex));
+    stats.functions += functions - code.functions.len();
+    let natives = code.natives.len();
+    code.natives.retain(|n| reach.is_fun_reachable(n.findex));
+    stats.natives += natives - code.natives.len();
+
+    let types = retain_indexed(&mut code.types, |t| reach.is_type_reachable(RefType(t)));
+    stats.types += types.iter().filter(|&&t| t == usize::MAX).count();
+    let globals = retain_indexed(&mut code.globals, |g| {
+        reach.is_global_reachable(RefGlobal(g))
+    });
+    stats.globals += globals.iter().filter(|&&g| g == usize::MAX).count();
+
+    // Kept elements only reference kept elements
+    Remapping {
+        types: Some(types),
+        globals: Some(globals),
+        functions: Some(findexes),
+        ..Default::default()
+    }
+    .apply(code);
+}
+
+/// Remove [Opcode::Nop] and the [Opcode::Label] no instruction jumps to, fixing jump offsets
+/// and debug information. Returns the number of instructions removed.
+fn remove_nops(
+    ops: &mut Vec<Opcode>,
+    debug_info: &mut Option<Vec<(usize, usize)>>,
+    assigns: &mut Option<Vec<(RefString, usize)>>,
+) -> usize {
+    let targets: HashSet<isize> = ops
+        .iter()
+        .enumerate()
+        .flat_map(|(i, o)| o.jump_targets(i))
+        .collect();
+    let keep: Vec<bool> = ops
+        .iter()
+        .enumerate()
+        .map(|(i, o)| match o {
+            Opcode::Nop => false,
+            Opcode::Label => targets.contains(&(i as isize)),
+            _ => true,
+        })
+        .collect();
+    let removed = keep.iter().filter(|&&k| !k).count();
+    if removed == 0 {
+        return 0;
+    }
+
+    // New position of each instruction, a removed instruction is replaced by the next one
+    let mut new_pos = Vec::with_capacity(ops.len() + 1);
+    let mut next = 0;
+    for &k in &keep {
+        new_pos.push(next);
+        if k {
+            next += 1;
+        }
+    }
+    new_pos.push(next);
+    let remap = |pos: isize| new_pos[pos.clamp(0, new_pos.len() as isize - 1) as usize] as isize;
+
+    for (i, o) in ops.iter_mut().enumerate() {
+        for operand in o.operands_mut() {
+            let offsets: Vec<&mut i32> = match operand {
+                OperandMut::Offset(offset) => vec![offset],
+                OperandMut::Offsets(offsets) => offsets.iter_mut().collect(),
+                _ => continue,
+            };
+            for offset in offsets {
+                let target = i as isize + *offset as isize + 1;
+                *offset = (remap(target) - new_pos[i] as isize - 1) as i32;
+            }
+        }
+    }
+
+    let mut i = 0;
+    ops.retain(|_| {
+        i += 1;
+        keep[i - 1]
+    });
+    if let Some(debug_info) = debug_info {
+        let mut i = 0;
+        debug_info.retain(|_| {
+            i += 1;
+            keep.get(i - 1).copied().unwrap_or(true)
+        });
+    }
+    // Negative positions (stored as huge numbers) refer to the arguments
+    for (_, pos) in assigns.iter_mut().flatten() {
+        if let Some(&new) = new_pos.get(*pos) {
+            *pos = new;
+        }
+    }
+    removed
+}
+
+/// Merge duplicated constants and remove the unused ones
+fn compact_pools(code: &mut Bytecode, stats: &mut StripStats) {
+    let mut strings = HashSet::new();
+    let mut ints = HashSet::new();
+    let mut floats = HashSet::new();
+    // The empty string is used as the 'no name' value
+    strings.insert(0);
+    visit_refs_mut(code, |r| match r {
+        RefMut::String(s) => {
+            strings.insert(s.0);
+        }
+        RefMut::Int(i) => {
+            ints.insert(i.0);
+        }
+        RefMut::Float(f) => {
+            floats.insert(f.0);
+        }
+        _ => {}
+    });
+
+    /// Keep the first occurrence of each used value
+    fn dedup<T, K: Eq + std::hash::Hash>(
+        pool: &mut Vec<T>,
+        used: &HashSet<usize>,
+        key: impl Fn(&T) -> K,
+    ) -> Vec<usize> {
+        let mut map = vec![usize::MAX; pool.len()];
+        let mut first = HashMap::new();
+        let mut new_pool = Vec::with_capacity(used.len());
+        for (i, v) in std::mem::take(pool).into_iter().enumerate() {
+            if !used.contains(&i) {
+                continue;
+            }
+            map[i] = *first.entry(key(&v)).or_insert_with(|| {
+                new_pool.push(v);
+                new_pool.len() - 1
+            });
+        }
+        *pool = new_pool;
+        map
+    }
+
+    let len = (code.strings.len(), code.ints.len(), code.floats.len());
+    let strings = dedup(&mut code.strings, &strings, |s| s.clone());
+    let ints = dedup(&mut code.ints, &ints, |&i| i);
+    let floats = dedup(&mut code.floats, &floats, |f| f.to_bits());
+    stats.strings += len.0 - code.strings.len();
+    stats.ints += len.1 - code.ints.len();
+    stats.floats += len.2 - code.floats.len();
+
+    Remapping {
+        strings: Some(strings),
+        ints: Some(ints),
+        floats: Some(floats),
+        ..Default::default()
+    }
+    .apply(code);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::builder::BytecodeBuilder;
+    use crate::opcodes::Opcode;
+    use crate::strip::{strip, Pass, StripStats};
+    use crate::types::{ObjField, ObjProto, RefInt, RefString, Reg, Type};
+    use crate::Bytecode;
+
+    #[test]
+    fn strip_roundtrip() {
+        let mut builder = BytecodeBuilder::new();
+        let void = builder.intern_type(Type::Void);
+        let i32 = builder.intern_type(Type::I32);
+        let t = builder.fun_type(vec![], void);
+        let unused = builder.string("unused");
+        let _ = builder.int(42);
+        let dead = builder.add_function(
+            t,
+            vec![void],
+            vec![
+                Opcode::String {
+                    dst: Reg(0),
+                    ptr: unused,
+                },
+                Opcode::Ret { ret: Reg(0) },
+            ],
+        );
+        let name = builder.string("Unused");
+        builder.add_class(
+            "Unused",
+            None,
+            vec![ObjField { name, t: i32 }],
+            vec![ObjProto {
+                name,
+                findex: dead,
+                pindex: 0,
+            }],
+        );
+        let hello = builder.string("hello");
+        let one = builder.int(1);
+        let main = builder.add_function(
+            t,
+            vec![void, i32, i32],
+            vec![
+                Opcode::Int {
+                    dst: Reg(1),
+                    ptr: one,
+                },
+                Opcode::Nop,
+                Opcode::Label,
+                Opcode::JFalse {
+                    cond: Reg(1),
+                    offset: 3,
+                },
+                Opcode::Nop,
+                // Not a jump target
+                Opcode::Label,
+                Opcode::Decr { dst: Reg(1) },
+                Opcode::Incr { dst: Reg(1) },
+                Opcode::JAlways { offset: -7 },
+                Opcode::Ret { ret: Reg(0) },
+            ],
+        );
+        builder.function_mut(main).unwrap().debug_info = Some((0..10).map(|i| (0, i)).collect());
+        builder.set_entrypoint(main);
+        builder.debug_file("Main.hx");
+        let mut code = builder.build();
+        // Duplicate strings
+        code.strings.push("hello".into());
+        let main_fn = &mut code.functions[1];
+        main_fn.ops.insert(
+            0,
+            Opcode::String {
+                dst: Reg(0),
+                ptr: RefString(code.strings.len() - 1),
+            },
+        );
+        main_fn.ops.insert(
+            1,
+            Opcode::String {
+                dst: Reg(0),
+                ptr: hello,
+            },
+        );
+        main_fn
+            .debug_info
+            .as_mut()
+            .unwrap()
+            .splice(0..0, [(0, 0), (0, 0)]);
+        code.rebuild_indexes();
+
+        let stats = strip(&mut code, Pass::DEFAULT);
+        assert_eq!(
+            stats,
+            StripStats {
+                functions: 1,
+                types: 1,
+                ops: 3,
+                strings: 3,
+                ints: 1,
+                ..Default::default()
+            }
+        );
+        assert!(code.verify().is_empty());
+        let f = code.entrypoint();
+        assert_eq!(f.findex.0, 0);
+        assert_eq!(f.debug_info.as_ref().unwrap().len(), f.ops.len());
+        assert_eq!(
+            format!("{:?}", &f.ops[..3]),
+            format!(
+                "{:?}",
+                [
+                    Opcode::String {
+                        dst: Reg(0),
+                        ptr: RefString(1)
+                    },
+                    Opcode::String {
+                        dst: Reg(0),
+                        ptr: RefString(1)
+                    },
+                    Opcode::Int {
+                        dst: Reg(1),
+                        ptr: RefInt::default()
+                    }
+                ]
+            )
+        );
+        // The jumps now skip over less instructions
+        assert_eq!(
+            format!("{:?}", &f.ops[3..]),
+            format!(
+                "{:?}",
+                [
+                    Opcode::Label,
+                    Opcode::JFalse {
+                        cond: Reg(1),
+                        offset: 1,
+                    },
+                    Opcode::Decr { dst: Reg(1) },
+                    Opcode::Incr { dst: Reg(1) },
+                    Opcode::JAlways { offset: -5 },
+                    Opcode::Ret { ret: Reg(0) },
+                ]
+            )
+        );
+
+        let mut out = Vec::new();
+        code.serialize(&mut out).unwrap();
+        let new = Bytecode::deserialize(&mut out.as_slice()).unwrap();
+        assert_eq!(new.strings, code.strings);
+        assert_eq!(
+            format!("{:?}", new.entrypoint().ops),
+            format!("{:?}", f.ops)
+        );
+
+        strip(&mut code, &[Pass::DebugInfo]);
+        assert!(code.debug_files.is_none());
+        assert!(code.entrypoint().debug_info.is_none());
+    }
+}
@@ -1,26 +1,37 @@
 use std::collections::HashMap;
 use std::ops::Index;
 
-use crate::{Bytecode, Opcode, Resolve, Str};
+use crate::{Bytecode, Error, Opcode, Resolve, Result, Str};
 
 /// A register argument
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Default, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Reg(pub u32);
 
+/// Used by the `name()` convenience methods, returns a placeholder instead of panicking on an invalid reference.
+fn resolve_name(code: &Bytecode, name: RefString) -> Str {
+    code.try_resolve(name)
+        .unwrap_or_else(|e| e.placeholder().into())
+}
+
 /// A reference to the i32 constant pool
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RefInt(pub usize);
 
 /// A reference to the f64 constant pool
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RefFloat(pub usize);
 
 /// A reference to the bytes constant pool
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RefBytes(pub usize);
 
 /// Reference to the string constant pool
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RefString(pub usize);
 
 impl RefString {
@@ -32,14 +43,17 @@ impl RefString {
 
 /// An inline bool value
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ValBool(pub bool);
 
 /// A reference to a global
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Default, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RefGlobal(pub usize);
 
 /// An object field definition
 #[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ObjField {
     /// Field name
     pub name: RefString,
@@ -49,16 +63,18 @@ pub struct ObjField {
 
 impl ObjField {
     pub fn name(&self, code: &Bytecode) -> Str {
-        code.resolve(self.name)
+        resolve_name(code, self.name)
     }
 }
 
 /// A reference to an object field
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RefField(pub usize);
 
 /// An object method definition
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ObjProto {
     /// Method name
     pub name: RefString,
@@ -70,12 +86,13 @@ pub struct ObjProto {
 
 impl ObjProto {
     pub fn name(&self, code: &Bytecode) -> Str {
-        code.resolve(self.name)
+        resolve_name(code, self.name)
     }
 }
 
 /// An enum variant definition
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct EnumConstruct {
     /// Variant name
     pub name: RefString,
@@ -85,16 +102,18 @@ pub struct EnumConstruct {
 
 impl EnumConstruct {
     pub fn name(&self, code: &Bytecode) -> Str {
-        code.resolve(self.name)
+        resolve_name(code, self.name)
     }
 }
 
 /// A reference to an enum variant
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RefEnumConstruct(pub usize);
 
 /// Common type for [Type::Fun] and [Type::Method]
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct TypeFun {
     pub args: Vec<RefType>,
     pub ret: RefType,
@@ -102,6 +121,7 @@ pub struct TypeFun {
 
 /// Common type for [Type::Obj] and [Type::Struct]
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct TypeObj {
     pub name: RefString,
     pub super_: Option<RefType>,
@@ -120,7 +140,7 @@ pub struct TypeObj {
 
 impl TypeObj {
     pub fn name(&self, code: &Bytecode) -> Str {
-        code.resolve(self.name)
+        resolve_name(code, self.name)
     }
 
     /// Get the static part of this class
@@ -135,6 +155,7 @@ impl TypeObj {
 
 /// Type available in the hashlink type system. Every type is one of those.
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Type {
     Void,
     UI8,
@@ -200,7 +221,8 @@ impl Type {
 }
 
 /// Reference to a type in the constant pool
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RefType(pub usize);
 
 impl RefType {
@@ -236,29 +258,28 @@ impl RefType {
         }
     }
 
+    /// Returns `None` if this isn't a function type or if the reference is invalid
     pub fn as_fun<'a>(&self, ctx: &'a Bytecode) -> Option<&'a TypeFun> {
-        match ctx.resolve(*self) {
-            Type::Fun(fun) => Some(fun),
-            Type::Method(fun) => Some(fun),
-            _ => None,
-        }
+        ctx.try_resolve(*self).ok()?.get_type_fun()
     }
 
+    /// Returns `None` if this isn't an object type or if the reference is invalid
     pub fn as_obj<'a>(&self, ctx: &'a Bytecode) -> Option<&'a TypeObj> {
-        ctx.resolve(*self).get_type_obj()
+        ctx.try_resolve(*self).ok()?.get_type_obj()
     }
 
     pub fn field<'a>(&self, field: RefField, ctx: &'a Bytecode) -> Option<&'a ObjField> {
-        self.as_obj(ctx).map(|obj| &obj.fields[field.0])
+        self.as_obj(ctx).and_then(|obj| obj.fields.get(field.0))
     }
 
     pub fn method<'a>(&self, meth: usize, ctx: &'a Bytecode) -> Option<&'a ObjProto> {
-        self.as_obj(ctx).map(|obj| &obj.protos[meth])
+        self.as_obj(ctx).and_then(|obj| obj.protos.get(meth))
     }
 }
 
 /// A native function reference. Contains no code but indicates the library from where to load it.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Native {
     /// Native function name
     pub name: RefString,
@@ -270,11 +291,11 @@ pub struct Native {
 
 impl Native {
     pub fn name(&self, code: &Bytecode) -> Str {
-        code.resolve(self.name)
+        resolve_name(code, self.name)
     }
 
     pub fn lib(&self, code: &Bytecode) -> Str {
-        code.resolve(self.lib)
+        resolve_name(code, self.lib)
     }
 
     /// Get the native function signature type
@@ -294,6 +315,7 @@ impl Native {
 
 /// A function definition with its code.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Function {
     /// Functions have no name per se, this is the name of the field or method they are attached to
     pub name: RefString,
@@ -320,9 +342,20 @@ impl Function {
         self[reg]
     }
 
+    /// Fallible version of [Function::regtype]
+    pub fn try_regtype(&self, reg: Reg) -> Result<RefType> {
+        self.regs
+            .get(reg.0 as usize)
+            .copied()
+            .ok_or(Error::InvalidReference {
+                kind: "register",
+                index: reg.0 as usize,
+            })
+    }
+
     /// Convenience method to resolve the function name
     pub fn name(&self, code: &Bytecode) -> Str {
-        code.resolve(self.name)
+        resolve_name(code, self.name)
     }
 
     /// Get the function signature type
@@ -389,18 +422,19 @@ impl Index<Reg> for Function {
 
 /// Index reference to a function or a native in the pool (findex)
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RefFun(pub usize);
 
 impl RefFun {
     /// Useful when you already know you should be getting a Function
     pub fn as_fn<'a>(&self, code: &'a Bytecode) -> Option<&'a Function> {
-        code.resolve(*self).as_fn()
+        code.try_resolve(*self).ok()?.as_fn()
     }
 
     pub fn name(&self, code: &Bytecode) -> Str {
-        match code.resolve(*self) {
-            FunPtr::Fun(fun) => fun.name(code),
-            FunPtr::Native(n) => n.name(code),
+        match code.try_resolve(*self) {
+            Ok(fun) => fun.name(code),
+            Err(e) => e.placeholder().into(),
         }
     }
 
@@ -460,7 +494,50 @@ impl<'a> FunPtr<'a> {
 
 /// A constant definition
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ConstantDef {
     pub global: RefGlobal,
+    /// Value of each field of the global object, see [ConstantField]
     pub fields: Vec<usize>,
 }
+
+/// What the value of a constant field is, depending on the type of the field
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ConstantField {
+    /// Index in the ints pool
+    Int,
+    /// Index in the floats pool
+    Float,
+    /// The value itself
+    Bool,
+    /// Index in the strings pool
+    String,
+    /// Index in the types pool
+    Type,
+    /// Index of a global holding the value
+    Global,
+}
+
+impl ConstantDef {
+    /// Kind of each field value, from the field types of the global object
+    pub fn field_kinds(&self, code: &Bytecode) -> Vec<ConstantField> {
+        let fields = code
+            .globals
+            .get(self.global.0)
+            .and_then(|t| t.as_obj(code))
+            .map(|obj| obj.fields.as_slice())
+            .unwrap_or_default();
+        (0..self.fields.len())
+            .map(
+                |i| match fields.get(i).and_then(|f| code.types.get(f.t.0)) {
+                    Some(Type::I32) => ConstantField::Int,
+                    Some(Type::F64) => ConstantField::Float,
+                    Some(Type::Bool) => ConstantField::Bool,
+                    Some(Type::Bytes) => ConstantField::String,
+                    Some(Type::Type) => ConstantField::Type,
+                    _ => ConstantField::Global,
+                },
+            )
+            .collect()
+    }
+}
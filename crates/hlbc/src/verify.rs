@@ -0,0 +1,898 @@
+//! Bytecode verification.
+//!
+//! Most of this library assumes every index in the bytecode is valid (see the note about safety in the crate docs).
+//! [Bytecode::verify] checks those assumptions beforehand and additionally type-checks instructions
+//! against their register types, so that malformed bytecode can be reported instead of crashing later.
+
+use std::fmt;
+use std::fmt::{Display, Formatter};
+
+use crate::opcodes::{Opcode, Operand};
+use crate::types::{FunPtr, Function, ObjField, RefFun, RefGlobal, RefString, RefType, Reg, Type};
+use crate::{Bytecode, RefFunKnown, Resolve};
+
+/// Where a [Diagnostic] has been found.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Location {
+    /// The bytecode header (entrypoint)
+    Header,
+    /// A type definition (index in the type pool)
+    Type(RefType),
+    /// A global definition
+    Global(RefGlobal),
+    /// A native definition (index in the native pool)
+    Native(usize),
+    /// A constant definition (index in the constant pool)
+    Constant(usize),
+    /// A function, optionally at a specific instruction
+    Function { findex: RefFun, pos: Option<usize> },
+}
+
+/// The problem described by a [Diagnostic].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiagnosticKind {
+    /// An index pointing outside of its pool
+    InvalidReference { kind: &'static str, index: usize },
+    /// Two functions or natives share the same findex
+    DuplicateFindex(RefFun),
+    /// The type of something isn't what it should be
+    UnexpectedType {
+        /// What was expected, e.g. "function" or "object"
+        expected: &'static str,
+        found: RefType,
+    },
+    /// The register type isn't what the instruction expects
+    UnexpectedRegType {
+        reg: Reg,
+        expected: &'static str,
+        found: RefType,
+    },
+    /// The returned register type doesn't match the function signature
+    ReturnTypeMismatch {
+        reg: Reg,
+        expected: RefType,
+        found: RefType,
+    },
+    /// Wrong number of arguments for a call or an enum construction
+    ArgCount { expected: usize, found: usize },
+    /// A jump targeting an instruction outside the function
+    JumpOutOfBounds { target: isize },
+    /// A backward jump must always target a label
+    BackwardJumpNotLabel { target: usize },
+    /// A function with no instructions
+    EmptyFunction,
+    /// The last instruction doesn't end the function (return, throw or jump)
+    FallThroughEnd,
+    /// The number of debug info entries is not equal to the number of instructions
+    DebugInfoLength { expected: usize, found: usize },
+    /// The entrypoint is a native function
+    NativeEntrypoint,
+}
+
+/// A problem found in the bytecode by [Bytecode::verify].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub location: Location,
+    pub kind: DiagnosticKind,
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Location::Header => write!(f, "header"),
+            Location::Type(t) => write!(f, "type@{}", t.0),
+            Location::Global(g) => write!(f, "global@{}", g.0),
+            Location::Native(n) => write!(f, "native #{n}"),
+            Location::Constant(c) => write!(f, "constant #{c}"),
+            Location::Function { findex, pos: None } => write!(f, "fn@{}", findex.0),
+            Location::Function {
+                findex,
+                pos: Some(pos),
+            } => write!(f, "fn@{} op {pos}", findex.0),
+        }
+    }
+}
+
+impl Display for DiagnosticKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            DiagnosticKind::InvalidReference { kind, index } => {
+                write!(f, "invalid {kind} reference {index}")
+            }
+            DiagnosticKind::DuplicateFindex(findex) => write!(f, "duplicate findex {}", findex.0),
+            DiagnosticKind::UnexpectedType { expected, found } => {
+                write!(f, "expected {expected} type, found type@{}", found.0)
+            }
+            DiagnosticKind::UnexpectedRegType {
+                reg,
+                expected,
+                found,
+            } => write!(f, "expected {reg} to be {expected}, found type@{}", found.0),
+            DiagnosticKind::ReturnTypeMismatch {
+                reg,
+                expected,
+                found,
+            } => write!(
+                f,
+                "returning {reg} of type@{} but the function returns type@{}",
+                found.0, expected.0
+            ),
+            DiagnosticKind::ArgCount { expected, found } => {
+                write!(f, "expected {expected} arguments, found {found}")
+            }
+            DiagnosticKind::JumpOutOfBounds { target } => {
+                write!(f, "jump target {target} is out of bounds")
+            }
+            DiagnosticKind::BackwardJumpNotLabel { target } => {
+                write!(f, "backward jump to {target} does not target a label")
+            }
+            DiagnosticKind::EmptyFunction => write!(f, "function has no instructions"),
+            DiagnosticKind::FallThroughEnd => {
+                write!(f, "last instruction does not terminate the function")
+            }
+            DiagnosticKind::DebugInfoLength { expected, found } => {
+                write!(f, "expected {expected} debug info entries, found {found}")
+            }
+            DiagnosticKind::NativeEntrypoint => write!(f, "entrypoint is a native function"),
+        }
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.kind)
+    }
+}
+
+impl Bytecode {
+    /// Check the whole bytecode for invalid references and ill-typed instructions.
+    /// An empty list means the bytecode can be safely used with the rest of this library.
+    pub fn verify(&self) -> Vec<Diagnostic> {
+        let mut v = Verifier {
+            code: self,
+            diags: Vec::new(),
+        };
+        v.verify_header();
+        for i in 0..self.types.len() {
+            v.verify_type(RefType(i));
+        }
+        for (i, &t) in self.globals.iter().enumerate() {
+            v.check_type(Location::Global(RefGlobal(i)), t);
+        }
+        for (i, n) in self.natives.iter().enumerate() {
+            let loc = Location::Native(i);
+            v.check_string(loc, n.lib);
+            v.check_string(loc, n.name);
+            v.check_fun_type(loc, n.t);
+        }
+        if let Some(constants) = &self.constants {
+            for (i, c) in constants.iter().enumerate() {
+                v.check_global(Location::Constant(i), c.global);
+            }
+        }
+        for f in &self.functions {
+            v.verify_function(f);
+        }
+        v.diags
+    }
+}
+
+struct Verifier<'a> {
+    code: &'a Bytecode,
+    diags: Vec<Diagnostic>,
+}
+
+impl<'a> Verifier<'a> {
+    fn report(&mut self, location: Location, kind: DiagnosticKind) {
+        self.diags.push(Diagnostic { location, kind });
+    }
+
+    fn invalid(&mut self, location: Location, kind: &'static str, index: usize) {
+        self.report(location, DiagnosticKind::InvalidReference { kind, index });
+    }
+
+    fn check_index(&mut self, loc: Location, kind: &'static str, index: usize, len: usize) -> bool {
+        if index >= len {
+            self.invalid(loc, kind, index);
+            false
+        } else {
+            true
+        }
+    }
+
+    fn check_string(&mut self, loc: Location, s: RefString) {
+        self.check_index(loc, "string", s.0, self.code.strings.len());
+    }
+
+    fn check_global(&mut self, loc: Location, g: RefGlobal) {
+        self.check_index(loc, "global", g.0, self.code.globals.len());
+    }
+
+    /// Check a 1-based global reference as found in type definitions, 0 meaning none.
+    fn check_type_global(&mut self, loc: Location, g: RefGlobal) {
+        if g.0 > 0 {
+            self.check_global(loc, RefGlobal(g.0 - 1));
+        }
+    }
+
+    fn check_type(&mut self, loc: Location, t: RefType) -> Option<&'a Type> {
+        if self.check_index(loc, "type", t.0, self.code.types.len()) {
+            Some(&self.code.types[t.0])
+        } else {
+            None
+        }
+    }
+
+    fn check_fun_type(&mut self, loc: Location, t: RefType) {
+        if let Some(ty) = self.check_type(loc, t) {
+            if ty.get_type_fun().is_none() {
+                self.report(
+                    loc,
+                    DiagnosticKind::UnexpectedType {
+                        expected: "function",
+                        found: t,
+                    },
+                );
+            }
+        }
+    }
+
+    fn check_fields(&mut self, loc: Location, fields: &[ObjField]) {
+        for f in fields {
+            self.check_string(loc, f.name);
+            self.check_type(loc, f.t);
+        }
+    }
+
+    /// Get the signature type of a function or native without assuming anything is valid.
+    fn fun_type(&self, findex: RefFun) -> Option<RefType> {
+        match self.code.try_resolve(findex).ok()? {
+            FunPtr::Fun(f) => Some(f.t),
+            FunPtr::Native(n) => Some(n.t),
+        }
+    }
+
+    fn check_findex(&mut self, loc: Location, findex: RefFun) -> bool {
+        self.check_index(loc, "function", findex.0, self.code.findex_max())
+    }
+
+    fn verify_header(&mut self) {
+        let code = self.code;
+        let mut seen = vec![false; code.findex_max()];
+        let findexes = code
+            .functions
+            .iter()
+            .map(|f| f.findex)
+            .chain(code.natives.iter().map(|n| n.findex));
+        for findex in findexes {
+            if let Some(seen) = seen.get_mut(findex.0) {
+                if *seen {
+                    self.report(Location::Header, DiagnosticKind::DuplicateFindex(findex));
+                }
+                *seen = true;
+            } else {
+                self.invalid(Location::Header, "function", findex.0);
+            }
+        }
+
+        if self.check_findex(Location::Header, code.entrypoint) {
+            if let RefFunKnown::Native(_) = code.findexes[code.entrypoint.0] {
+                self.report(Location::Header, DiagnosticKind::NativeEntrypoint);
+            }
+        }
+    }
+
+    fn verify_type(&mut self, t: RefType) {
+        let loc = Location::Type(t);
+        match &self.code.types[t.0] {
+            Type::Fun(fun) | Type::Method(fun) => {
+                for &arg in &fun.args {
+                    self.check_type(loc, arg);
+                }
+                self.check_type(loc, fun.ret);
+            }
+            Type::Obj(obj) | Type::Struct(obj) => {
+                self.check_string(loc, obj.name);
+                if let Some(super_) = obj.super_ {
+                    if let Some(ty) = self.check_type(loc, super_) {
+                        if ty.get_type_obj().is_none() {
+                            self.report(
+                                loc,
+                                DiagnosticKind::UnexpectedType {
+                                    expected: "object",
+                                    found: super_,
+                                },
+                            );
+                        }
+                    }
+                }
+                self.check_type_global(loc, obj.global);
+                self.check_fields(loc, &obj.own_fields);
+                for p in &obj.protos {
+                    self.check_string(loc, p.name);
+                    self.check_findex(loc, p.findex);
+                }
+                for (field, &findex) in &obj.bindings {
+                    self.check_index(loc, "field", field.0, obj.fields.len());
+                    self.check_findex(loc, findex);
+                }
+            }
+            Type::Ref(inner) | Type::Null(inner) | Type::Packed(inner) => {
+                self.check_type(loc, *inner);
+            }
+            Type::Virtual { fields } => self.check_fields(loc, fields),
+            Type::Abstract { name } => self.check_string(loc, *name),
+            Type::Enum {
+                name,
+                global,
+                constructs,
+            } => {
+                self.check_string(loc, *name);
+                self.check_type_global(loc, *global);
+                for c in constructs {
+                    self.check_string(loc, c.name);
+                    for &p in &c.params {
+                        self.check_type(loc, p);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn verify_function(&mut self, f: &'a Function) {
+        let loc = Location::Function {
+            findex: f.findex,
+            pos: None,
+        };
+        self.check_string(loc, f.name);
+        self.check_fun_type(loc, f.t);
+        let mut regs_valid = true;
+        for &r in &f.regs {
+            regs_valid &= self.check_type(loc, r).is_some();
+        }
+
+        if let Some(debug_info) = &f.debug_info {
+            if debug_info.len() != f.ops.len() {
+                self.report(
+                    loc,
+                    DiagnosticKind::DebugInfoLength {
+                        expected: f.ops.len(),
+                        found: debug_info.len(),
+                    },
+                );
+            }
+            if let Some(files) = &self.code.debug_files {
+                for (pos, &(file, _)) in debug_info.iter().enumerate() {
+                    let loc = Location::Function {
+                        findex: f.findex,
+                        pos: Some(pos),
+                    };
+                    self.check_index(loc, "debug file", file, files.len());
+                }
+            }
+        }
+        if let Some(assigns) = &f.assigns {
+            for &(name, _) in assigns {
+                self.check_string(loc, name);
+            }
+        }
+
+        match f.ops.last() {
+            None => self.report(loc, DiagnosticKind::EmptyFunction),
+            Some(
+                Opcode::Ret { .. }
+                | Opcode::Throw { .. }
+                | Opcode::Rethrow { .. }
+                | Opcode::JAlways { .. },
+            ) => {}
+            Some(_) => self.report(
+                Location::Function {
+                    findex: f.findex,
+                    pos: Some(f.ops.len() - 1),
+                },
+                DiagnosticKind::FallThroughEnd,
+            ),
+        }
+
+        for (pos, op) in f.ops.iter().enumerate() {
+            let mut ctx = OpCtx {
+                v: self,
+                f,
+                loc: Location::Function {
+                    findex: f.findex,
+                    pos: Some(pos),
+                },
+            };
+            // Type-checking would only produce noise on top of invalid operands
+            if ctx.verify_operands(op, pos) && regs_valid {
+                ctx.verify_types(op);
+            }
+        }
+    }
+}
+
+/// Verification context of a single instruction
+struct OpCtx<'a, 'v> {
+    v: &'v mut Verifier<'a>,
+    f: &'a Function,
+    loc: Location,
+}
+
+impl<'a, 'v> OpCtx<'a, 'v> {
+    fn report(&mut self, kind: DiagnosticKind) {
+        self.v.report(self.loc, kind);
+    }
+
+    fn check_reg(&mut self, r: Reg) -> bool {
+        self.v
+            .check_index(self.loc, "register", r.0 as usize, self.f.regs.len())
+    }
+
+    /// Check every operand points to something that exists.
+    fn verify_operands(&mut self, op: &Opcode, pos: usize) -> bool {
+        let code = self.v.code;
+        let loc = self.loc;
+        let mut valid = true;
+        for operand in op.operands() {
+            valid &= match operand {
+                Operand::Reg(r) => self.check_reg(r),
+                Operand::Regs(regs) => {
+                    // Report every invalid register, not only the first one
+                    regs.iter().filter(|&&r| !self.check_reg(r)).count() == 0
+                }
+                Operand::Int(i) => self.v.check_index(loc, "int", i.0, code.ints.len()),
+                Operand::Float(i) => self.v.check_index(loc, "float", i.0, code.floats.len()),
+                Operand::Bytes(i) => {
+                    let len = code.bytes.as_ref().map(|(_, pos)| pos.len()).unwrap_or(0);
+                    self.v.check_index(loc, "bytes", i.0, len)
+                }
+                Operand::String(i) => self.v.check_index(loc, "string", i.0, code.strings.len()),
+                Operand::Type(t) => self.v.check_type(loc, t).is_some(),
+                Operand::Fun(findex) => self.v.check_findex(loc, findex),
+                Operand::Global(g) => self.v.check_index(loc, "global", g.0, code.globals.len()),
+                // Those depend on register types, checked along with them
+                Operand::Field(_) | Operand::EnumConstruct(_) => true,
+                Operand::Bool(_) | Operand::Offset(_) | Operand::Offsets(_) => true,
+            };
+        }
+
+        for target in op.jump_targets(pos) {
+            if target < 0 || target as usize >= self.f.ops.len() {
+                self.report(DiagnosticKind::JumpOutOfBounds { target });
+                valid = false;
+            } else if (target as usize) <= pos
+                && !matches!(self.f.ops[target as usize], Opcode::Label)
+            {
+                self.report(DiagnosticKind::BackwardJumpNotLabel {
+                    target: target as usize,
+                });
+            }
+        }
+        valid
+    }
+
+    fn reg_type(&self, r: Reg) -> (RefType, &'a Type) {
+        let t = self.f.regs[r.0 as usize];
+        (t, &self.v.code.types[t.0])
+    }
+
+    fn unexpected(&mut self, reg: Reg, expected: &'static str) {
+        let found = self.f.regs[reg.0 as usize];
+        self.report(DiagnosticKind::UnexpectedRegType {
+            reg,
+            expected,
+            found,
+        });
+    }
+
+    fn expect(&mut self, reg: Reg, expected: &'static str, ok: impl FnOnce(&Type) -> bool) {
+        if !ok(self.reg_type(reg).1) {
+            self.unexpected(reg, expected);
+        }
+    }
+
+    fn check_arg_count(&mut self, expected: usize, found: usize) {
+        if expected != found {
+            self.report(DiagnosticKind::ArgCount { expected, found });
+        }
+    }
+
+    fn check_field(&mut self, fields: usize, field: usize) {
+        self.v.check_index(self.loc, "field", field, fields);
+    }
+
+    /// Check the number of fields of an object-like register and the field index.
+    fn check_obj_field(&mut self, reg: Reg, field: usize, allow_virtual: bool) {
+        match self.reg_type(reg).1 {
+            Type::Obj(obj) | Type::Struct(obj) => self.check_field(obj.fields.len(), field),
+            Type::Virtual { fields } if allow_virtual => self.check_field(fields.len(), field),
+            _ if allow_virtual => self.unexpected(reg, "an object or a virtual"),
+            _ => self.unexpected(reg, "an object"),
+        }
+    }
+
+    fn check_call(&mut self, fun: RefFun, nargs: usize) {
+        let code = self.v.code;
+        if let Some(fun) = self
+            .v
+            .fun_type(fun)
+            .and_then(|t| code.types.get(t.0))
+            .and_then(Type::get_type_fun)
+        {
+            self.check_arg_count(fun.args.len(), nargs);
+        }
+    }
+
+    /// Check the enum type of a register and optionally the construct and the field index inside this construct.
+    fn check_enum(
+        &mut self,
+        reg: Reg,
+        construct: usize,
+        field: Option<usize>,
+        nargs: Option<usize>,
+    ) {
+        if let Type::Enum { constructs, .. } = self.reg_type(reg).1 {
+            if self
+                .v
+                .check_index(self.loc, "enum construct", construct, constructs.len())
+            {
+                let params = constructs[construct].params.len();
+                if let Some(field) = field {
+                    self.check_field(params, field);
+                }
+                if let Some(nargs) = nargs {
+                    self.check_arg_count(params, nargs);
+                }
+            }
+        } else {
+            self.unexpected(reg, "an enum");
+        }
+    }
+
+    /// Type-check an instruction, assuming its operands are valid.
+    fn verify_types(&mut self, op: &Opcode) {
+        match op {
+            Opcode::Call0 { fun, .. } => self.check_call(*fun, 0),
+            Opcode::Call1 { fun, .. } => self.check_call(*fun, 1),
+            Opcode::Call2 { fun, .. } => self.check_call(*fun, 2),
+            Opcode::Call3 { fun, .. } => self.check_call(*fun, 3),
+            Opcode::Call4 { fun, .. } => self.check_call(*fun, 4),
+            Opcode::CallN { fun, args, .. } => self.check_call(*fun, args.len()),
+            Opcode::CallMethod { field, args, .. } => {
+                if let Some(&this) = args.first() {
+                    match self.reg_type(this).1 {
+                        // Method calls on objects go through the vtable
+                        Type::Obj(_) | Type::Struct(_) => {}
+                        Type::Virtual { fields } => self.check_field(fields.len(), field.0),
+                        _ => self.unexpected(this, "an object or a virtual"),
+                    }
+                } else {
+                    self.check_arg_count(1, 0);
+                }
+            }
+            // `this` is the first register, which doesn't exist if the function has no registers
+            Opcode::CallThis { .. } if self.check_reg(Reg(0)) => {
+                self.expect(Reg(0), "an object", |t| t.get_type_obj().is_some())
+            }
+            Opcode::CallClosure { fun, .. } => self.expect(*fun, "a function", |t| {
+                matches!(t, Type::Fun(_) | Type::Dyn)
+            }),
+            Opcode::StaticClosure { dst, .. } => self.expect(*dst, "a function", |t| {
+                matches!(t, Type::Fun(_) | Type::Dyn)
+            }),
+            Opcode::InstanceClosure { dst, fun, .. } => {
+                self.expect(*dst, "a function", |t| {
+                    matches!(t, Type::Fun(_) | Type::Dyn)
+                });
+                // The bound object becomes the first argument
+                let code = self.v.code;
+                if let Some(ty) = self
+                    .v
+                    .fun_type(*fun)
+                    .and_then(|t| code.types.get(t.0))
+                    .and_then(Type::get_type_fun)
+                {
+                    if ty.args.is_empty() {
+                        self.check_arg_count(1, 0);
+                    }
+                }
+            }
+            Opcode::Field { obj, field, .. } | Opcode::SetField { obj, field, .. } => {
+                self.check_obj_field(*obj, field.0, true)
+            }
+            Opcode::GetThis { field, .. } | Opcode::SetThis { field, .. }
+                if self.check_reg(Reg(0)) =>
+            {
+                self.check_obj_field(Reg(0), field.0, false)
+            }
+            Opcode::New { dst } => self.expect(*dst, "an object", |t| {
+                matches!(
+                    t,
+                    Type::Obj(_) | Type::Struct(_) | Type::Virtual { .. } | Type::DynObj
+                )
+            }),
+            Opcode::ArraySize { array, .. }
+            | Opcode::GetArray { array, .. }
+            | Opcode::SetArray { array, .. } => {
+                self.expect(*array, "an array", |t| matches!(t, Type::Array))
+            }
+            Opcode::MakeEnum {
+                dst,
+                construct,
+                args,
+            } => self.check_enum(*dst, construct.0, None, Some(args.len())),
+            Opcode::EnumAlloc { dst, construct } => self.check_enum(*dst, construct.0, None, None),
+            Opcode::EnumIndex { value, .. } => {
+                self.expect(*value, "an enum", |t| matches!(t, Type::Enum { .. }))
+            }
+            Opcode::EnumField {
+                value,
+                construct,
+                field,
+                ..
+            } => self.check_enum(*value, construct.0, Some(field.0), None),
+            // Only used on single construct enums (closure captures)
+            Opcode::SetEnumField { value, field, .. } => {
+                self.check_enum(*value, 0, Some(field.0), None)
+            }
+            Opcode::Ret { ret } => {
+                let code = self.v.code;
+                if let Some(expected) = code
+                    .types
+                    .get(self.f.t.0)
+                    .and_then(Type::get_type_fun)
+                    .map(|t| t.ret)
+                {
+                    let found = self.reg_type(*ret).0;
+                    if found != expected {
+                        self.report(DiagnosticKind::ReturnTypeMismatch {
+                            reg: *ret,
+                            expected,
+                            found,
+                        });
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::opcodes::Opcode;
+    use crate::types::{
+        EnumConstruct, Function, RefEnumConstruct, RefField, RefFun, RefString, RefType, Reg, Type,
+        TypeFun,
+    };
+    use crate::verify::{DiagnosticKind, Location};
+    use crate::{Bytecode, RefFunKnown};
+
+    /// Types : void, i32, fn():void, fn(i32):i32, enum { A(i32) }
+    fn code_with(ops: Vec<Opcode>, regs: Vec<RefType>) -> Bytecode {
+        let mut code = Bytecode {
+            strings: vec!["".into(), "main".into()],
+            types: vec![
+                Type::Void,
+                Type::I32,
+                Type::Fun(TypeFun {
+                    args: vec![],
+                    ret: RefType(0),
+                }),
+                Type::Fun(TypeFun {
+                    args: vec![RefType(1)],
+                    ret: RefType(1),
+                }),
+                Type::Enum {
+                    name: RefString(1),
+                    global: Default::default(),
+                    constructs: vec![EnumConstruct {
+                        name: RefString(1),
+                        params: vec![RefType(1)],
+                    }],
+                },
+            ],
+            ..Default::default()
+        };
+        code.functions.push(Function {
+            name: RefString(1),
+            t: RefType(2),
+            findex: RefFun(0),
+            regs,
+            ops,
+            debug_info: None,
+            assigns: None,
+            parent: None,
+        });
+        code.findexes.push(RefFunKnown::Fun(0));
+        code
+    }
+
+    fn kinds(code: &Bytecode) -> Vec<(Option<usize>, DiagnosticKind)> {
+        code.verify()
+            .into_iter()
+            .map(|d| match d.location {
+                Location::Function { pos, .. } => (pos, d.kind),
+                _ => (None, d.kind),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn valid() {
+        let code = code_with(
+            vec![
+                Opcode::Label,
+                Opcode::JAlways { offset: 0 },
+                Opcode::JAlways { offset: -3 },
+                Opcode::Ret { ret: Reg(0) },
+            ],
+            vec![RefType(0)],
+        );
+        assert_eq!(kinds(&code), vec![]);
+    }
+
+    #[test]
+    fn invalid_references() {
+        let code = code_with(
+            vec![
+                Opcode::Int {
+                    dst: Reg(1),
+                    ptr: Default::default(),
+                },
+                Opcode::Call0 {
+                    dst: Reg(0),
+                    fun: RefFun(3),
+                },
+                Opcode::Ret { ret: Reg(0) },
+            ],
+            vec![RefType(0)],
+        );
+        assert_eq!(
+            kinds(&code),
+            vec![
+                (
+                    Some(0),
+                    DiagnosticKind::InvalidReference {
+                        kind: "register",
+                        index: 1
+                    }
+                ),
+                (
+                    Some(0),
+                    DiagnosticKind::InvalidReference {
+                        kind: "int",
+                        index: 0
+                    }
+                ),
+                (
+                    Some(1),
+                    DiagnosticKind::InvalidReference {
+                        kind: "function",
+                        index: 3
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn jumps() {
+        let code = code_with(
+            vec![
+                Opcode::JAlways { offset: 5 },
+                Opcode::Nop,
+                Opcode::JAlways { offset: -2 },
+            ],
+            vec![],
+        );
+        assert_eq!(
+            kinds(&code),
+            vec![
+                (Some(0), DiagnosticKind::JumpOutOfBounds { target: 6 }),
+                (Some(2), DiagnosticKind::BackwardJumpNotLabel { target: 1 }),
+            ]
+        );
+        let code = code_with(vec![Opcode::Nop], vec![]);
+        assert_eq!(
+            kinds(&code),
+            vec![(Some(0), DiagnosticKind::FallThroughEnd)]
+        );
+    }
+
+    #[test]
+    fn this_without_registers() {
+        let code = code_with(
+            vec![
+                Opcode::GetThis {
+                    dst: Reg(0),
+                    field: RefField(0),
+                },
+                Opcode::CallThis {
+                    dst: Reg(0),
+                    field: RefField(0),
+                    args: vec![],
+                },
+            ],
+            vec![],
+        );
+        let this = DiagnosticKind::InvalidReference {
+            kind: "register",
+            index: 0,
+        };
+        assert_eq!(
+            kinds(&code),
+            vec![
+                (Some(1), DiagnosticKind::FallThroughEnd),
+                (Some(0), this.clone()),
+                (Some(1), this)
+            ]
+        );
+    }
+
+    #[test]
+    fn types() {
+        let code = code_with(
+            vec![
+                Opcode::Call0 {
+                    dst: Reg(0),
+                    fun: RefFun(0),
+                },
+                Opcode::Call1 {
+                    dst: Reg(0),
+                    fun: RefFun(0),
+                    arg0: Reg(1),
+                },
+                Opcode::Field {
+                    dst: Reg(1),
+                    obj: Reg(1),
+                    field: RefField(0),
+                },
+                Opcode::EnumField {
+                    dst: Reg(1),
+                    value: Reg(2),
+                    construct: RefEnumConstruct(0),
+                    field: RefField(1),
+                },
+                Opcode::Ret { ret: Reg(1) },
+            ],
+            vec![RefType(0), RefType(1), RefType(4)],
+        );
+        assert_eq!(
+            kinds(&code),
+            vec![
+                (
+                    Some(1),
+                    DiagnosticKind::ArgCount {
+                        expected: 0,
+                        found: 1
+                    }
+                ),
+                (
+                    Some(2),
+                    DiagnosticKind::UnexpectedRegType {
+                        reg: Reg(1),
+                        expected: "an object or a virtual",
+                        found: RefType(1)
+                    }
+                ),
+                (
+                    Some(3),
+                    DiagnosticKind::InvalidReference {
+                        kind: "field",
+                        index: 1
+                    }
+                ),
+                (
+                    Some(4),
+                    DiagnosticKind::ReturnTypeMismatch {
+                        reg: Reg(1),
+                        expected: RefType(0),
+                        found: RefType(1)
+                    }
+                ),
+            ]
+        );
+    }
+}
@@ -5,26 +5,55 @@ use byteorder::{LittleEndian, WriteBytesExt};
 
 use crate::types::{RefField, RefFloat, RefFun, RefGlobal, RefInt, RefString, RefType, TypeFun};
 use crate::{Bytecode, ConstantDef, Function, Native, ObjField, Str, Type, TypeObj};
-use crate::{Error, Result};
+use crate::{Error, Result, MAX_VERSION, MIN_VERSION};
 
 impl Bytecode {
     /// Serialize the bytecode to any sink.
-    /// Bytecode is serialized to the same format.
+    /// Bytecode is serialized to the same format, following the layout of [Bytecode::version].
     pub fn serialize(&self, w: &mut impl Write) -> Result<()> {
-        w.write_all(&[b'H', b'L', b'B'])?;
-        w.write_u8(self.version)?;
+        let version = self.version;
+        if !(MIN_VERSION..=MAX_VERSION).contains(&version) {
+            return Err(Error::UnsupportedVersion {
+                version,
+                min: MIN_VERSION,
+                max: MAX_VERSION,
+            });
+        }
+        let has_bytes = version >= 5;
+        if !has_bytes && self.bytes.as_ref().map_or(false, |(b, _)| !b.is_empty()) {
+            return Err(Error::IncompatibleVersion {
+                version,
+                what: "a bytes pool",
+                min: 5,
+            });
+        }
+        let has_constants = version >= 4;
+        if !has_constants && self.constants.as_ref().map_or(false, |c| !c.is_empty()) {
+            return Err(Error::IncompatibleVersion {
+                version,
+                what: "constants",
+                min: 4,
+            });
+        }
+        let constants = self.constants.as_deref().unwrap_or_default();
+
+        w.write_all(b"HLB")?;
+        w.write_u8(version)?;
         write_var(w, if self.debug_files.is_some() { 1 } else { 0 })?;
         write_var(w, self.ints.len() as i32)?;
         write_var(w, self.floats.len() as i32)?;
         write_var(w, self.strings.len() as i32)?;
-        if let Some((_, pos)) = &self.bytes {
-            write_var(w, pos.len() as i32)?;
+        if has_bytes {
+            write_var(
+                w,
+                self.bytes.as_ref().map_or(0, |(_, pos)| pos.len()) as i32,
+            )?;
         }
         write_var(w, self.types.len() as i32)?;
         write_var(w, self.globals.len() as i32)?;
         write_var(w, self.natives.len() as i32)?;
         write_var(w, self.functions.len() as i32)?;
-        if let Some(constants) = &self.constants {
+        if has_constants {
             write_var(w, constants.len() as i32)?;
         }
         self.entrypoint.write(w)?;
@@ -35,7 +64,11 @@ impl Bytecode {
             w.write_f64::<LittleEndian>(f)?;
         }
         write_strings(w, &self.strings)?;
-        if let Some((bytes, pos)) = &self.bytes {
+        if has_bytes {
+            let (bytes, pos) = match &self.bytes {
+                Some((bytes, pos)) => (&bytes[..], &pos[..]),
+                None => (&[][..], &[][..]),
+            };
             w.write_i32::<LittleEndian>(bytes.len() as i32)?;
             w.write_all(bytes)?;
             for &p in pos {
@@ -56,12 +89,10 @@ impl Bytecode {
             n.write(w)?;
         }
         for f in &self.functions {
-            f.write(w)?;
+            f.write(w, self.debug_files.is_some(), version)?;
         }
-        if let Some(constants) = &self.constants {
-            for c in constants {
-                c.write(w)?;
-            }
+        for c in constants {
+            c.write(w)?;
         }
         Ok(())
     }
@@ -237,7 +268,7 @@ impl Native {
 }
 
 impl Function {
-    pub(crate) fn write(&self, w: &mut impl Write) -> Result<()> {
+    pub(crate) fn write(&self, w: &mut impl Write, has_debug: bool, version: u8) -> Result<()> {
         self.t.write(w)?;
         self.findex.write(w)?;
         write_var(w, self.regs.len() as i32)?;
@@ -249,6 +280,9 @@ impl Function {
             o.write(w)?;
         }
         // https://github.com/HaxeFoundation/haxe/blob/ea57ab1ef60d212228c8657b7bc5b1085c62714e/src/generators/genhl.ml#L3910
+        if !has_debug {
+            return Ok(());
+        }
         if let Some(debug_info) = &self.debug_info {
             let mut curfile: i32 = -1;
             let mut curpos = 0;
@@ -280,12 +314,20 @@ impl Function {
             let old_curpos = curpos;
             flush_repeat(w, &mut curpos, &mut rcount, old_curpos)?;
         }
-        if let Some(assigns) = &self.assigns {
+        // Versions 1 and 2 only have the line information
+        let assigns = self.assigns.as_deref().unwrap_or_default();
+        if version >= 3 {
             write_var(w, assigns.len() as i32)?;
             for (s, p) in assigns {
                 s.write(w)?;
                 write_var(w, *p as i32)?;
             }
+        } else if !assigns.is_empty() {
+            return Err(Error::IncompatibleVersion {
+                version,
+                what: "variable assigns",
+                min: 3,
+            });
         }
         Ok(())
     }
@@ -315,8 +357,8 @@ pub(crate) fn write_var(w: &mut impl Write, value: i32) -> Result<()> {
             });
         } else {
             w.write_u8(((value >> 24) | 0xE0) as u8)?;
-            w.write_u8(((value >> 16) | 0xFF) as u8)?;
-            w.write_u8(((value >> 8) | 0xFF) as u8)?;
+            w.write_u8(((value >> 16) & 0xFF) as u8)?;
+            w.write_u8(((value >> 8) & 0xFF) as u8)?;
             w.write_u8((value & 0xFF) as u8)?;
         }
     } else if value < 0x80 {
@@ -331,8 +373,8 @@ pub(crate) fn write_var(w: &mut impl Write, value: i32) -> Result<()> {
         });
     } else {
         w.write_u8(((value >> 24) | 0xC0) as u8)?;
-        w.write_u8(((value >> 16) | 0xFF) as u8)?;
-        w.write_u8(((value >> 8) | 0xFF) as u8)?;
+        w.write_u8(((value >> 16) & 0xFF) as u8)?;
+        w.write_u8(((value >> 8) & 0xFF) as u8)?;
         w.write_u8((value & 0xFF) as u8)?;
     }
     Ok(())
@@ -386,8 +428,98 @@ mod tests {
     use std::fs;
 
     use crate::fmt::EnhancedFmt;
-    use crate::types::RefFun;
-    use crate::{Bytecode, Resolve};
+    use crate::opcodes::Opcode;
+    use crate::read::read_vari;
+    use crate::types::{Function, RefFun, RefString, RefType, Reg, Type, TypeFun};
+    use crate::write::write_var;
+    use crate::{Bytecode, ConstantDef, Error, Resolve, MAX_VERSION, MIN_VERSION};
+
+    #[test]
+    fn var_roundtrip() {
+        for v in [
+            0,
+            1,
+            0x7F,
+            0x80,
+            0x1FFF,
+            0x2000,
+            0x12345,
+            0x1FFF_FFFF,
+            -1,
+            -0x1FFF,
+            -0x2000,
+            -0x12345,
+        ] {
+            let mut buf = Vec::new();
+            write_var(&mut buf, v).unwrap();
+            assert_eq!(read_vari(&mut &buf[..]).unwrap(), v);
+        }
+    }
+
+    /// A single `fn main():void` with debug information
+    fn minimal(version: u8) -> Bytecode {
+        Bytecode {
+            version,
+            strings: vec!["".into(), "x".into()],
+            debug_files: Some(vec!["Main.hx".into()]),
+            types: vec![
+                Type::Void,
+                Type::Fun(TypeFun {
+                    args: vec![],
+                    ret: RefType(0),
+                }),
+            ],
+            functions: vec![Function {
+                name: RefString(0),
+                t: RefType(1),
+                findex: RefFun(0),
+                regs: vec![RefType(0)],
+                ops: vec![Opcode::Ret { ret: Reg(0) }],
+                debug_info: Some(vec![(0, 3)]),
+                assigns: (version >= 3).then(|| vec![(RefString(1), 0)]),
+                parent: None,
+            }],
+            bytes: (version >= 5).then(|| (vec![1, 2, 3], vec![0])),
+            constants: (version >= 4).then(Vec::new),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn ser_deser_versions() {
+        for version in MIN_VERSION..=MAX_VERSION {
+            let code = minimal(version);
+            let mut out = Vec::new();
+            code.serialize(&mut out).unwrap();
+            let new = Bytecode::deserialize(&mut out.as_slice()).unwrap();
+            assert_eq!(new.version, version);
+            assert_eq!(new.bytes.is_some(), version >= 5);
+            assert_eq!(new.constants.is_some(), version >= 4);
+            assert_eq!(new.functions[0].assigns.is_some(), version >= 3);
+            assert_eq!(new.functions[0].debug_info, Some(vec![(0, 3)]));
+            let mut again = Vec::new();
+            new.serialize(&mut again).unwrap();
+            assert_eq!(out, again);
+        }
+    }
+
+    #[test]
+    fn ser_incompatible_version() {
+        let mut code = minimal(3);
+        code.constants = Some(vec![ConstantDef {
+            global: Default::default(),
+            fields: vec![],
+        }]);
+        assert!(matches!(
+            code.serialize(&mut Vec::new()),
+            Err(Error::IncompatibleVersion { min: 4, .. })
+        ));
+        code.version = MAX_VERSION + 1;
+        assert!(matches!(
+            code.serialize(&mut Vec::new()),
+            Err(Error::UnsupportedVersion { .. })
+        ));
+    }
 
     //#[test]
     fn ser_eq_deser() {
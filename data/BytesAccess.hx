@@ -0,0 +1,8 @@
+class BytesAccess {
+    static function main() {
+        var b = new hl.Bytes(8);
+        b.setUI8(0, 255);
+        b.setUI16(2, 65535);
+        trace(b.getUI8(0) + b.getUI16(2));
+    }
+}
@@ -0,0 +1,12 @@
+class FloatCompare {
+    static function main() {
+        var a = Math.NaN;
+        var b = 1.0;
+        if (!(a < b)) {
+            trace("not less");
+        }
+        if (!(a >= b)) {
+            trace("not greater or equal");
+        }
+    }
+}
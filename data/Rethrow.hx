@@ -0,0 +1,13 @@
+class Rethrow {
+    static function main() {
+        try {
+            try {
+                throw "error";
+            } catch (e) {
+                throw e;
+            }
+        } catch (e) {
+            trace(e);
+        }
+    }
+}
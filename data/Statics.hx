@@ -0,0 +1,10 @@
+class Statics {
+    static var count = 0;
+    static var name:String;
+
+    static function main() {
+        count++;
+        name = "statics";
+        trace(name + count);
+    }
+}
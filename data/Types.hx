@@ -0,0 +1,8 @@
+class Types {
+    static function main() {
+        var d:Dynamic = 1;
+        trace(Type.typeof(d));
+        trace(Std.isOfType(d, Int));
+        trace(Type.getClassName(Types));
+    }
+}
@@ -0,0 +1,15 @@
+class Unsigned {
+    static function main() {
+        var a:UInt = 0xFFFFFFF0;
+        var b:UInt = 3;
+        var div = Std.int(a / b);
+        var mod = a % b;
+        var shr = a >>> 2;
+        if (a < b) {
+            trace(div);
+        }
+        if (a >= b) {
+            trace(mod + shr);
+        }
+    }
+}
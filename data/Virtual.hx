@@ -0,0 +1,19 @@
+class Virtual {
+    static function main() {
+        var i:IRun = new Impl();
+        var f = i.run;
+        trace(f());
+    }
+}
+
+interface IRun {
+    function run():Int;
+}
+
+class Impl implements IRun {
+    public function new() {}
+
+    public function run() {
+        return 1;
+    }
+}